@@ -0,0 +1,54 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+extern crate cbindgen;
+
+use std::env;
+use std::path::Path;
+
+/// Environment variable which makes the build update the checked-in header in src/ffi.
+const UPDATE_HEADER_ENV: &str = "TAPYRUS_SPV_UPDATE_HEADER";
+
+/// Generate the C header from FFI functions into OUT_DIR. The checked-in header is updated only if
+/// TAPYRUS_SPV_UPDATE_HEADER is set, and CI checks that it is in sync with FFI functions.
+///
+/// The header is not needed to build the library, so failures are reported as warnings.
+fn main() {
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=src/ffi/c.rs");
+    println!("cargo:rerun-if-env-changed={}", UPDATE_HEADER_ENV);
+
+    let (crate_dir, out_dir) = match (env::var("CARGO_MANIFEST_DIR"), env::var("OUT_DIR")) {
+        (Ok(crate_dir), Ok(out_dir)) => (crate_dir, out_dir),
+        _ => {
+            println!("cargo:warning=CARGO_MANIFEST_DIR or OUT_DIR is not set.");
+            return;
+        }
+    };
+    let crate_dir = Path::new(&crate_dir);
+
+    let config = match cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("cargo:warning=Can not read cbindgen.toml: {}", e);
+            return;
+        }
+    };
+    let bindings = match cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/ffi/c.rs"))
+        .generate()
+    {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            println!("cargo:warning=Can not generate C header: {}", e);
+            return;
+        }
+    };
+
+    bindings.write_to_file(Path::new(&out_dir).join("tapyrus_spv.h"));
+    if env::var_os(UPDATE_HEADER_ENV).is_some() {
+        bindings.write_to_file(crate_dir.join("src/ffi/tapyrus_spv.h"));
+    }
+}
@@ -5,9 +5,23 @@ extern crate tapyrus_spv;
 
 extern crate log;
 
-use tapyrus::consensus::deserialize;
-use tapyrus::network::constants::Network;
-use tapyrus_spv::{ChainParams, Options, SPV};
+use bitcoin_hashes::sha256d;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::{Address, BitcoinHash, Block};
+use tapyrus_spv::{
+    BlockIndex, ChainParams, ColorIdentifier, FilterMode, NodeHandle, Options, SyncStatus, Wallet,
+    WalletOptions, DEFAULT_FEE_RATE, DEFAULT_MAX_OUTBOUND_PEERS, SPV,
+};
+use tokio::prelude::Future;
 
 /// This Genesis Block HEX is for test.
 ///
@@ -18,18 +32,708 @@ use tapyrus_spv::{ChainParams, Options, SPV};
 /// public key: 02260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a
 const GENESIS_FOR_TEST: &str = "01000000000000000000000000000000000000000000000000000000000000000000000019225b47d8c3eefd0dab934ba4b633940d032a7f5a192a4ddece08f566f1cfb95d5022ed80bde51d7436cadcb10455a2e5523fea9e46dc9ee5dec0037387e1b137aaba5d40fd3748264662cd991ac70e8d9ae3e06a1ea8956d74b36aa6419ca428f9baf24dc4df95637dc524d6374ef59ef6d15aba25020de3c35da969b1329ec961488067010000002001000000000000000000000000000000000000000000000000000000000000000000000000222102260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212affffffff0100f2052a010000001976a9143733df9979ee67615b16aff5b210d894557325df88ac00000000";
 
-fn main() {
-    env_logger::init();
-
-    let params = Options {
-        remote: "127.0.0.1:12383".to_string(),
-        datadir: "/tmp/tapyrus-spv".to_string(),
-        chain_params: ChainParams {
-            network: Network::Regtest,
-            genesis: deserialize(&hex::decode(GENESIS_FOR_TEST).unwrap()).unwrap(),
-        },
+/// Data directory which is used when it is not configured. It is placed in the home directory.
+const DEFAULT_DATADIR_NAME: &str = ".tapyrus-spv";
+
+/// File name of the wallet seed in datadir.
+const WALLET_SEED_FILE_NAME: &str = "wallet.seed";
+
+/// Size of the wallet seed which is generated at the first use.
+const WALLET_SEED_SIZE: usize = 32;
+
+/// Timeout of sync in seconds for commands other than `sync`.
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// Interval of checking progress of the node.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Settings which are read from command line arguments and the config file. Arguments take
+/// precedence over the config file.
+struct Settings<'a> {
+    matches: &'a ArgMatches<'a>,
+    file: HashMap<String, Vec<String>>,
+}
+
+impl<'a> Settings<'a> {
+    fn new(matches: &'a ArgMatches<'a>) -> Result<Settings<'a>, String> {
+        let file = match matches.value_of("conf") {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| format!("Can not read config file {}: {}", path, e))?;
+                parse_config(&content)?
+            }
+            None => HashMap::new(),
+        };
+        Ok(Settings { matches, file })
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        self.matches
+            .value_of(name)
+            .map(|value| value.to_string())
+            .or_else(|| {
+                self.file
+                    .get(name)
+                    .and_then(|values| values.last().cloned())
+            })
+    }
+
+    fn values(&self, name: &str) -> Vec<String> {
+        match self.matches.values_of(name) {
+            Some(values) => values.map(|value| value.to_string()).collect(),
+            None => self.file.get(name).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.value(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: \"{}\"", name, value)),
+            None => Ok(default),
+        }
+    }
+
+    fn log_level(&self) -> String {
+        self.value("log-level")
+            .unwrap_or_else(|| "error,tapyrus_spv=info".to_string())
+    }
+
+    fn datadir(&self) -> PathBuf {
+        match self.value("datadir") {
+            Some(datadir) => PathBuf::from(datadir),
+            None => std::env::var("HOME")
+                .map(PathBuf::from)
+                .unwrap_or_default()
+                .join(DEFAULT_DATADIR_NAME),
+        }
+    }
+
+    fn chain_params(&self) -> Result<ChainParams, String> {
+        let network = self
+            .value("network")
+            .unwrap_or_else(|| "regtest".to_string());
+        let genesis_hex = match (self.value("genesis"), self.value("genesis-file")) {
+            (Some(genesis_hex), _) => genesis_hex,
+            (None, Some(path)) => fs::read_to_string(&path)
+                .map_err(|e| format!("Can not read genesis file {}: {}", path, e))?,
+            (None, None) if network == "regtest" => GENESIS_FOR_TEST.to_string(),
+            (None, None) => return Err("genesis or genesis-file is required.".to_string()),
+        };
+        let bytes = hex::decode(genesis_hex.trim())
+            .map_err(|_| "Genesis block should be hex string.".to_string())?;
+        let genesis: Block =
+            deserialize(&bytes).map_err(|e| format!("Genesis block is invalid: {:?}", e))?;
+        ChainParams::from_name(&network, genesis).ok_or_else(|| {
+            format!(
+                "network should be \"prod\", \"dev\", \"bitcoin\", \"testnet\", \"regtest\" or a network id: \"{}\"",
+                network
+            )
+        })
+    }
+
+    fn parse_values<T: std::str::FromStr>(&self, name: &str) -> Result<Vec<T>, String> {
+        self.values(name)
+            .iter()
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: \"{}\"", name, value))
+            })
+            .collect()
+    }
+
+    fn filter_mode(&self) -> Result<FilterMode, String> {
+        match self.value("filter").as_ref().map(|s| s.as_str()) {
+            Some("bloom") | None => Ok(FilterMode::BloomFilter),
+            Some("compact") => Ok(FilterMode::CompactFilter),
+            Some(filter) => Err(format!(
+                "filter should be \"bloom\" or \"compact\": \"{}\"",
+                filter
+            )),
+        }
+    }
+
+    fn options(&self) -> Result<Options, String> {
+        let remotes = self
+            .values("remote")
+            .iter()
+            .flat_map(|remotes| remotes.split(','))
+            .map(|remote| remote.trim().to_string())
+            .filter(|remote| !remote.is_empty())
+            .collect();
+
+        Ok(Options {
+            remotes,
+            max_outbound_peers: self.parse("max-peers", DEFAULT_MAX_OUTBOUND_PEERS)?,
+            datadir: self.datadir().to_string_lossy().to_string(),
+            chain_params: ChainParams {
+                dns_seeds: self.values("dns-seed"),
+                federations: self.parse_values("federation")?,
+                checkpoints: self.parse_values("checkpoint")?,
+                trusted_checkpoint: self.parse_values("trusted-checkpoint")?.pop(),
+                ..self.chain_params()?
+            },
+            filter_mode: self.filter_mode()?,
+        })
+    }
+
+    fn timeout(&self) -> Result<Duration, String> {
+        Ok(Duration::from_secs(
+            self.parse("timeout", DEFAULT_TIMEOUT_SECS)?,
+        ))
+    }
+}
+
+/// Parse config file. Each line is `name=value` where name is a long name of an option. Empty
+/// lines and lines which start with `#` are ignored. Options which can be given multiple times
+/// such as `remote` can appear in multiple lines.
+fn parse_config(content: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut config: HashMap<String, Vec<String>> = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap().trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("Config file line {} should be name=value.", i + 1))?
+            .trim();
+        config
+            .entry(name.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+    Ok(config)
+}
+
+/// Start the node and wait until `done` returns true with the sync status or the timeout
+/// elapses. Return the handle of the running node and whether `done` returned true.
+fn start_and_wait<F>(spv: &SPV, timeout: Duration, done: F) -> Result<(NodeHandle, bool), String>
+where
+    F: Fn(&SyncStatus) -> bool,
+{
+    let handle = spv
+        .start()
+        .map_err(|e| format!("Can not start SPV node: {:?}", e))?;
+    let started_at = Instant::now();
+    loop {
+        if done(&spv.sync_status()) {
+            return Ok((handle, true));
+        }
+        if started_at.elapsed() >= timeout {
+            return Ok((handle, false));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Stop the node and return the error which stopped it.
+fn stop(handle: NodeHandle) -> Result<(), String> {
+    handle
+        .stop()
+        .map_err(|e| format!("SPV node failed: {:?}", e))
+}
+
+/// Start the node and wait until the wallet is synced. The node keeps running.
+fn sync_wallet(spv: &SPV, timeout: Duration) -> Result<NodeHandle, String> {
+    let (handle, scanned) = start_and_wait(spv, timeout, SyncStatus::is_scanned)?;
+    if !scanned {
+        let status = spv.sync_status();
+        stop(handle)?;
+        return Err(format!(
+            "Timed out before wallet was synced. height: {}, target height: {}, scanned height: {}, peers: {}",
+            status.height, status.target_height, status.scanned_height, status.peer_count
+        ));
+    }
+    Ok(handle)
+}
+
+/// Load the wallet whose seed is in datadir. The seed is generated if it doesn't exist.
+fn load_wallet(spv: &SPV, settings: &Settings) -> Result<(), String> {
+    let datadir = settings.datadir();
+    let path = datadir.join(WALLET_SEED_FILE_NAME);
+    let seed = if path.exists() {
+        let seed_hex = fs::read_to_string(&path)
+            .map_err(|e| format!("Can not read wallet seed {}: {}", path.display(), e))?;
+        hex::decode(seed_hex.trim()).map_err(|_| "Wallet seed is broken.".to_string())?
+    } else {
+        let mut seed = vec![0u8; WALLET_SEED_SIZE];
+        rand::thread_rng().fill_bytes(&mut seed);
+        write_secret_file(&datadir, &path, &hex::encode(&seed))
+            .map_err(|e| format!("Can not write wallet seed {}: {}", path.display(), e))?;
+        seed
+    };
+
+    let chain_params = settings.chain_params()?;
+    let coin_type = settings.parse("coin-type", chain_params.coin_type)?;
+    let options = WalletOptions::new(chain_params.network, coin_type);
+    let wallet = Wallet::open(&seed, options, &datadir).map_err(|e| format!("{:?}", e))?;
+    spv.load_wallet(wallet);
+    Ok(())
+}
+
+/// Write the file which only the owner can read.
+fn write_secret_file(dir: &Path, path: &Path, content: &str) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+fn parse_color_id(color_id_hex: &str) -> Result<ColorIdentifier, String> {
+    let bytes =
+        hex::decode(color_id_hex).map_err(|_| "Color id should be hex string.".to_string())?;
+    ColorIdentifier::from_slice(&bytes).map_err(|e| format!("Color id is invalid: {:?}", e))
+}
+
+fn status(spv: &SPV, settings: &Settings) -> Result<(), String> {
+    let (handle, synced) = start_and_wait(spv, settings.timeout()?, SyncStatus::is_synced)?;
+    let status = spv.sync_status();
+    let tip = spv.tip();
+    let federation = spv.federation();
+    stop(handle)?;
+
+    println!("height: {}", status.height);
+    println!("target_height: {}", status.target_height);
+    println!("peers: {}", status.peer_count);
+    if let Some(tip) = tip {
+        println!("tip: {}", tip.header.bitcoin_hash());
+    }
+    if let Some(federation) = federation {
+        println!("federation: {}", federation);
+    }
+    println!("synced: {}", synced);
+    Ok(())
+}
+
+fn getheader(spv: &SPV, settings: &Settings, matches: &ArgMatches) -> Result<(), String> {
+    let block = matches.value_of("block").unwrap();
+    let (handle, _) = start_and_wait(spv, settings.timeout()?, SyncStatus::is_synced)?;
+    let index: Option<BlockIndex> = match block.parse::<i32>() {
+        Ok(height) => spv.header(height),
+        Err(_) => {
+            let hash: sha256d::Hash = block
+                .parse()
+                .map_err(|_| "Block should be height or block hash.".to_string())?;
+            spv.header_by_hash(&hash)
+        }
+    };
+    stop(handle)?;
+
+    let index = index.ok_or_else(|| format!("Block {} is not found.", block))?;
+    println!("{}", hex::encode(serialize(&index.header)));
+    Ok(())
+}
+
+fn getnewaddress(spv: &SPV, settings: &Settings) -> Result<(), String> {
+    load_wallet(spv, settings)?;
+    stop(sync_wallet(spv, settings.timeout()?)?)?;
+    println!("{}", spv.receive_address().unwrap());
+    Ok(())
+}
+
+fn getbalance(spv: &SPV, settings: &Settings, matches: &ArgMatches) -> Result<(), String> {
+    let min_confirmations = matches
+        .value_of("minconf")
+        .unwrap()
+        .parse()
+        .map_err(|_| "minconf should be a number.".to_string())?;
+    let color_id = match matches.value_of("color") {
+        Some(color_id) => Some(parse_color_id(color_id)?),
+        None => None,
     };
 
-    let spv = SPV::new(params);
-    spv.run();
+    load_wallet(spv, settings)?;
+    if let Some(color_id) = color_id {
+        spv.watch_color(color_id);
+    }
+    stop(sync_wallet(spv, settings.timeout()?)?)?;
+
+    let balance = match color_id {
+        Some(color_id) => spv
+            .token_balances(min_confirmations)
+            .remove(&color_id)
+            .unwrap_or_default(),
+        None => spv.balance(min_confirmations),
+    };
+    println!("confirmed: {}", balance.confirmed);
+    println!("unconfirmed: {}", balance.unconfirmed);
+    Ok(())
+}
+
+fn send(spv: &SPV, settings: &Settings, matches: &ArgMatches) -> Result<(), String> {
+    let address: Address = matches
+        .value_of("address")
+        .unwrap()
+        .parse()
+        .map_err(|_| "Address is invalid.".to_string())?;
+    let amount: u64 = matches
+        .value_of("amount")
+        .unwrap()
+        .parse()
+        .map_err(|_| "Amount should be a number.".to_string())?;
+    let fee_rate: u64 = match matches.value_of("fee-rate") {
+        Some(fee_rate) => fee_rate
+            .parse()
+            .map_err(|_| "fee-rate should be a number.".to_string())?,
+        None => DEFAULT_FEE_RATE,
+    };
+    let min_confirmations = matches
+        .value_of("minconf")
+        .unwrap()
+        .parse()
+        .map_err(|_| "minconf should be a number.".to_string())?;
+    let color_id = match matches.value_of("color") {
+        Some(color_id) => Some(parse_color_id(color_id)?),
+        None => None,
+    };
+
+    load_wallet(spv, settings)?;
+    if let Some(color_id) = color_id {
+        spv.watch_color(color_id);
+    }
+    let timeout = settings.timeout()?;
+    let handle = sync_wallet(spv, timeout)?;
+
+    let tx = spv
+        .build_transaction(min_confirmations, |builder| {
+            builder.fee_rate(fee_rate);
+            match color_id {
+                Some(color_id) => builder.transfer(&color_id, &address.script_pubkey(), amount),
+                None => builder.pay(address.script_pubkey(), amount),
+            };
+        })
+        .map_err(|e| format!("Can not build transaction: {:?}", e))?;
+    let txid = tx.txid();
+
+    // Wait until a peer downloads the transaction, or the broadcast finishes.
+    let result = spv.broadcast(tx);
+    let started_at = Instant::now();
+    loop {
+        match spv.requested_peers(&txid) {
+            Some(peers) if peers > 0 => break,
+            Some(_) => {}
+            None => {
+                result
+                    .wait()
+                    .map_err(|e| format!("Broadcast failed: {:?}", e))?;
+                break;
+            }
+        }
+        if started_at.elapsed() >= timeout {
+            stop(handle)?;
+            return Err(format!("Timed out before peers requested {}.", txid));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    stop(handle)?;
+
+    println!("{}", txid);
+    Ok(())
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let color = Arg::with_name("color")
+        .long("color")
+        .value_name("COLOR_ID")
+        .help("Hex of color identifier of the token");
+    let minconf = Arg::with_name("minconf")
+        .long("minconf")
+        .value_name("N")
+        .default_value("1")
+        .help("The number of confirmations which outputs need");
+
+    App::new("spv")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("SPV node for Tapyrus")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("conf")
+                .short("c")
+                .long("conf")
+                .value_name("FILE")
+                .help("Config file which has name=value lines of long options")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .value_name("ADDR")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Remote peer address like 127.0.0.1:12383. The port can be omitted.")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .value_name("NETWORK")
+                .help(
+                    "prod, dev, bitcoin, testnet, regtest, or network id of a custom network \
+                     optionally followed by :MAGIC_HEX [default: regtest]",
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("datadir")
+                .long("datadir")
+                .value_name("DIR")
+                .help("Data directory [default: ~/.tapyrus-spv]")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("genesis")
+                .long("genesis")
+                .value_name("HEX")
+                .help("Hex of the genesis block")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("genesis-file")
+                .long("genesis-file")
+                .value_name("FILE")
+                .help("File which has hex of the genesis block")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("dns-seed")
+                .long("dns-seed")
+                .value_name("HOST")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Host name of a DNS seed which returns addresses of peers")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("federation")
+                .long("federation")
+                .value_name("HEIGHT:PUBKEY")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Aggregated public key of a federation which signs blocks from the height")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("HEIGHT:HASH")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Block hash which the chain must have at the height")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("trusted-checkpoint")
+                .long("trusted-checkpoint")
+                .value_name("HEIGHT:HEADER:PUBKEY")
+                .help(
+                    "Block header which the chain starts from, and the aggregated public key \
+                     which signs blocks after it",
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("FILTER")
+                .possible_values(&["bloom", "compact"])
+                .help("The way to find transactions [default: bloom]")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("max-peers")
+                .long("max-peers")
+                .value_name("N")
+                .help("The maximum number of outbound peers")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("coin-type")
+                .long("coin-type")
+                .value_name("N")
+                .help("Coin type of BIP44 path of the wallet [default: 1165 on prod and bitcoin, 1 on others]")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("FILTER")
+                .help("Log filter like \"info\" or \"error,tapyrus_spv=debug\"")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Timeout of sync for commands other than sync [default: 60]")
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("sync").about("Run the node and keep following the tip"))
+        .subcommand(SubCommand::with_name("status").about("Print sync status of the node"))
+        .subcommand(
+            SubCommand::with_name("getheader")
+                .about("Print hex of the block header")
+                .arg(
+                    Arg::with_name("block")
+                        .value_name("HEIGHT|HASH")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("getnewaddress").about("Print unused address of the wallet"),
+        )
+        .subcommand(
+            SubCommand::with_name("getbalance")
+                .about("Print balance of the wallet")
+                .arg(color.clone())
+                .arg(minconf.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Send TPC or tokens from the wallet and print txid")
+                .arg(Arg::with_name("address").required(true))
+                .arg(Arg::with_name("amount").required(true))
+                .arg(color)
+                .arg(minconf)
+                .arg(
+                    Arg::with_name("fee-rate")
+                        .long("fee-rate")
+                        .value_name("RATE")
+                        .help("Fee rate in tapyrus per 1000 bytes [default: 1000]"),
+                ),
+        )
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    let settings = Settings::new(matches)?;
+    env_logger::Builder::new()
+        .parse_filters(&settings.log_level())
+        .init();
+
+    let spv = SPV::new(settings.options()?);
+    match matches.subcommand() {
+        ("sync", _) => spv.run().map_err(|e| format!("SPV node failed: {:?}", e)),
+        ("status", _) => status(&spv, &settings),
+        ("getheader", Some(matches)) => getheader(&spv, &settings, matches),
+        ("getnewaddress", _) => getnewaddress(&spv, &settings),
+        ("getbalance", Some(matches)) => getbalance(&spv, &settings, matches),
+        ("send", Some(matches)) => send(&spv, &settings, matches),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let matches = app().get_matches();
+    if let Err(e) = run(&matches) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tapyrus::network::constants::Network;
+    use tapyrus_spv::DEV_NETWORK_ID;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            "# comment\n\
+             network = testnet\n\
+             \n\
+             remote=127.0.0.1:2360\n\
+             remote=127.0.0.2:2360\n",
+        )
+        .unwrap();
+        assert_eq!(config["network"], vec!["testnet"]);
+        assert_eq!(config["remote"], vec!["127.0.0.1:2360", "127.0.0.2:2360"]);
+
+        assert!(parse_config("network").is_err());
+    }
+
+    #[test]
+    fn test_arguments_take_precedence() {
+        let matches = app().get_matches_from(vec![
+            "spv",
+            "--network",
+            "regtest",
+            "--remote",
+            "127.0.0.3:2360",
+            "status",
+        ]);
+        let settings = Settings {
+            matches: &matches,
+            file: parse_config("network=testnet\nremote=127.0.0.1:2360\ndatadir=/tmp/spv\n")
+                .unwrap(),
+        };
+        let options = settings.options().unwrap();
+        assert_eq!(options.chain_params.network, Network::Regtest);
+        assert_eq!(options.remotes, vec!["127.0.0.3:2360"]);
+        assert_eq!(options.datadir, "/tmp/spv");
+    }
+
+    #[test]
+    fn test_network_presets() {
+        let matches = app().get_matches_from(vec!["spv", "--network", "dev", "status"]);
+        let settings = Settings {
+            matches: &matches,
+            file: HashMap::new(),
+        };
+        assert!(settings.options().is_err());
+
+        let matches = app().get_matches_from(vec![
+            "spv",
+            "--network",
+            "dev",
+            "--genesis",
+            GENESIS_FOR_TEST,
+            "--dns-seed",
+            "seed.example.com",
+            "status",
+        ]);
+        let settings = Settings {
+            matches: &matches,
+            file: HashMap::new(),
+        };
+        let options = settings.options().unwrap();
+        assert_eq!(options.chain_params.network_id, DEV_NETWORK_ID);
+        assert_eq!(options.chain_params.network, Network::Testnet);
+        assert_eq!(options.chain_params.dns_seeds, vec!["seed.example.com"]);
+    }
+
+    #[test]
+    fn test_report_start_error() {
+        let matches = app().get_matches_from(vec![
+            "spv",
+            "--network",
+            "regtest",
+            "--genesis",
+            GENESIS_FOR_TEST,
+            "--datadir",
+            "",
+            "--remote",
+            "localhost:2360",
+            "getheader",
+            "0",
+        ]);
+        let settings = Settings {
+            matches: &matches,
+            file: HashMap::new(),
+        };
+        let spv = SPV::new(settings.options().unwrap());
+        let expected = Err("Can not start SPV node: InvalidRemote(\"localhost:2360\")".to_string());
+        assert_eq!(status(&spv, &settings), expected);
+        let (_, matches) = matches.subcommand();
+        assert_eq!(getheader(&spv, &settings, matches.unwrap()), expected);
+    }
 }
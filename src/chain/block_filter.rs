@@ -0,0 +1,433 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! Compact block filters (BIP158).
+//!
+//! A basic filter is a Golomb-coded set of scriptPubKeys which are created or spent in a block.
+//! Each filter is committed by a filter header which chains the filter hash with the previous
+//! filter header, so that the filter header chain can be compared between peers.
+
+use crate::chain::Error;
+use bitcoin_hashes::{sha256d, Hash, HashEngine};
+use std::io::{self, Cursor};
+use tapyrus::consensus::encode::{self, Decodable, Encodable, VarInt};
+
+/// Filter type of basic filter.
+pub const BASIC_FILTER_TYPE: u8 = 0;
+
+/// Golomb-Rice coding parameter of basic filter.
+const P: u8 = 19;
+
+/// Inverse of false positive rate of basic filter.
+const M: u64 = 784_931;
+
+/// Basic block filter which is described in BIP158.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockFilter {
+    /// Serialized filter which starts with the number of elements.
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// Create filter from serialized content.
+    pub fn new(content: Vec<u8>) -> BlockFilter {
+        BlockFilter { content }
+    }
+
+    /// Build basic filter of the block from scriptPubKeys. Empty and duplicated elements are
+    /// ignored.
+    pub fn build(block_hash: &sha256d::Hash, elements: &[&[u8]]) -> BlockFilter {
+        let mut elements: Vec<&[u8]> = elements.iter().filter(|e| !e.is_empty()).cloned().collect();
+        elements.sort();
+        elements.dedup();
+
+        let n = elements.len() as u64;
+        let mut values = hashed_set(block_hash, n * M, &elements);
+        values.sort();
+
+        let mut content = vec![];
+        VarInt(n).consensus_encode(&mut content).unwrap();
+        let mut writer = BitWriter::new(content);
+        let mut last = 0;
+        for value in values {
+            golomb_encode(&mut writer, value - last);
+            last = value;
+        }
+
+        BlockFilter {
+            content: writer.finish(),
+        }
+    }
+
+    /// Return true if any of `query` may be in the filter.
+    pub fn match_any(
+        &self,
+        block_hash: &sha256d::Hash,
+        query: &[&[u8]],
+    ) -> Result<bool, encode::Error> {
+        let (n, f, data) = self.decode_n()?;
+        if n == 0 || query.is_empty() {
+            return Ok(false);
+        }
+
+        let mut queries = hashed_set(block_hash, f, query);
+        queries.sort();
+
+        let mut reader = BitReader::new(data);
+        let mut value = 0;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..n {
+            value += golomb_decode(&mut reader)?;
+            while let Some(query) = queries.peek() {
+                if *query < value {
+                    queries.next();
+                } else if *query == value {
+                    return Ok(true);
+                } else {
+                    break;
+                }
+            }
+            if queries.peek().is_none() {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Return true if all of `query` may be in the filter.
+    pub fn match_all(
+        &self,
+        block_hash: &sha256d::Hash,
+        query: &[&[u8]],
+    ) -> Result<bool, encode::Error> {
+        let (n, f, data) = self.decode_n()?;
+        if query.is_empty() {
+            return Ok(true);
+        }
+
+        let mut queries = hashed_set(block_hash, f, query);
+        queries.sort();
+        queries.dedup();
+
+        let mut reader = BitReader::new(data);
+        let mut value = 0;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..n {
+            value += golomb_decode(&mut reader)?;
+            while let Some(query) = queries.peek() {
+                if *query < value {
+                    return Ok(false);
+                } else if *query == value {
+                    queries.next();
+                } else {
+                    break;
+                }
+            }
+            if queries.peek().is_none() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Return N, the range F = N * M of hashed elements and the Golomb-coded data.
+    fn decode_n(&self) -> Result<(u64, u64, &[u8]), encode::Error> {
+        let mut cursor = Cursor::new(&self.content[..]);
+        let n = VarInt::consensus_decode(&mut cursor)?.0;
+        let data = &self.content[cursor.position() as usize..];
+
+        // Each element takes P + 1 bits at least, so N is bounded by the length of the filter.
+        if n > (data.len() as u64 * 8) / (u64::from(P) + 1) {
+            return Err(encode::Error::ParseFailed(
+                "too many elements for the filter length",
+            ));
+        }
+        let f = n.checked_mul(M).ok_or(encode::Error::ParseFailed(
+            "too many elements in the filter",
+        ))?;
+        Ok((n, f, data))
+    }
+
+    /// Return the hash of the filter.
+    pub fn filter_hash(&self) -> sha256d::Hash {
+        sha256d::Hash::hash(&self.content)
+    }
+
+    /// Return the filter header which commits to this filter and the previous filter header.
+    pub fn filter_header(&self, prev_header: &sha256d::Hash) -> sha256d::Hash {
+        filter_header(&self.filter_hash(), prev_header)
+    }
+}
+
+/// Return the filter header from the filter hash and the previous filter header. The previous
+/// filter header of the genesis block is zero.
+pub fn filter_header(filter_hash: &sha256d::Hash, prev_header: &sha256d::Hash) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&filter_hash[..]);
+    engine.input(&prev_header[..]);
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Filter header of a block in the active chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterHeaderIndex {
+    /// Hash of the block
+    pub block_hash: sha256d::Hash,
+    /// Filter header of the basic filter of the block
+    pub filter_header: sha256d::Hash,
+}
+
+/// This is a trait which presents interfaces to access filter headers of blocks in the active
+/// chain. Filter headers are indexed by height, and the block hash is kept with each filter header
+/// to detect reorg.
+pub trait FilterHeaderStore {
+    /// Return height of the last filter header. It is -1 if the store is empty.
+    fn height(&self) -> i32;
+
+    /// Return filter header at the height.
+    fn get(&self, height: i32) -> Option<FilterHeaderIndex>;
+
+    /// Append filter header of the next block.
+    fn push(&mut self, index: FilterHeaderIndex) -> Result<(), Error>;
+
+    /// Remove filter headers above the height.
+    fn truncate(&mut self, height: i32) -> Result<(), Error>;
+}
+
+/// Map elements into range [0, F) by SipHash keyed with the block hash. F is N * M.
+fn hashed_set(block_hash: &sha256d::Hash, f: u64, elements: &[&[u8]]) -> Vec<u64> {
+    let k0 = u64::from_le_bytes(to_8_bytes(&block_hash[0..8]));
+    let k1 = u64::from_le_bytes(to_8_bytes(&block_hash[8..16]));
+    elements
+        .iter()
+        .map(|e| ((siphash24(k0, k1, e) as u128 * f as u128) >> 64) as u64)
+        .collect()
+}
+
+fn to_8_bytes(data: &[u8]) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(data);
+    bytes
+}
+
+fn golomb_encode(writer: &mut BitWriter, value: u64) {
+    let mut q = value >> P;
+    while q > 0 {
+        writer.write_bit(true);
+        q -= 1;
+    }
+    writer.write_bit(false);
+    writer.write_bits(value, P);
+}
+
+fn golomb_decode(reader: &mut BitReader) -> Result<u64, encode::Error> {
+    let mut q = 0;
+    while reader.read_bit()? {
+        q += 1;
+    }
+    let r = reader.read_bits(P)?;
+    Ok((q << P) + r)
+}
+
+/// Write bits from the most significant bit of each byte.
+struct BitWriter {
+    buf: Vec<u8>,
+    offset: u8,
+}
+
+impl BitWriter {
+    fn new(buf: Vec<u8>) -> BitWriter {
+        BitWriter { buf, offset: 8 }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.offset == 8 {
+            self.buf.push(0);
+            self.offset = 0;
+        }
+        if bit {
+            *self.buf.last_mut().unwrap() |= 0x80 >> self.offset;
+        }
+        self.offset += 1;
+    }
+
+    fn write_bits(&mut self, value: u64, n: u8) {
+        for i in (0..n).rev() {
+            self.write_bit(value & (1 << i) != 0);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Read bits from the most significant bit of each byte.
+struct BitReader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(buf: &'a [u8]) -> BitReader<'a> {
+        BitReader { buf, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, encode::Error> {
+        let byte = self
+            .buf
+            .get(self.position / 8)
+            .ok_or_else(|| encode::Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, n: u8) -> Result<u64, encode::Error> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+/// SipHash-2-4
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let blocks = data.len() / 8;
+    for i in 0..blocks {
+        let m = u64::from_le_bytes(to_8_bytes(&data[i * 8..i * 8 + 8]));
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    let mut b = (data.len() as u64) << 56;
+    for (i, byte) in data[blocks * 8..].iter().enumerate() {
+        b |= (*byte as u64) << (8 * i);
+    }
+    v[3] ^= b;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= b;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13);
+    v[1] ^= v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16);
+    v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21);
+    v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17);
+    v[1] ^= v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::hex::FromHex;
+
+    #[test]
+    fn test_siphash24() {
+        // test vectors from the SipHash paper
+        let k0 = 0x0706_0504_0302_0100;
+        let k1 = 0x0f0e_0d0c_0b0a_0908;
+        assert_eq!(siphash24(k0, k1, &[]), 0x726f_db47_dd0e_0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn test_basic_filter() {
+        // test vector of testnet genesis block in BIP158
+        let block_hash = sha256d::Hash::from_hex(
+            "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+        )
+        .unwrap();
+        let script = hex::decode("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac").unwrap();
+
+        let filter = BlockFilter::build(&block_hash, &[&script[..]]);
+        assert_eq!(filter.content, hex::decode("019dfca8").unwrap());
+        assert_eq!(
+            filter.filter_header(&sha256d::Hash::default()),
+            sha256d::Hash::from_hex(
+                "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+            )
+            .unwrap()
+        );
+
+        assert!(filter.match_any(&block_hash, &[&script[..]]).unwrap());
+        assert!(!filter.match_any(&block_hash, &[&script[1..]]).unwrap());
+        assert!(!filter.match_any(&block_hash, &[]).unwrap());
+    }
+
+    #[test]
+    fn test_match_any() {
+        let block_hash = sha256d::Hash::hash(&[1]);
+        let elements: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 25]).collect();
+        let refs: Vec<&[u8]> = elements.iter().map(|e| &e[..]).collect();
+        let filter = BlockFilter::build(&block_hash, &refs);
+
+        for element in &refs {
+            assert!(filter.match_any(&block_hash, &[element]).unwrap());
+        }
+        let others: Vec<Vec<u8>> = (100..200u8).map(|i| vec![i; 25]).collect();
+        let others: Vec<&[u8]> = others.iter().map(|e| &e[..]).collect();
+        assert!(!filter.match_any(&block_hash, &others).unwrap());
+        assert!(filter
+            .match_any(&block_hash, &[others[0], refs[50]])
+            .unwrap());
+
+        assert!(filter.match_all(&block_hash, &refs).unwrap());
+        assert!(filter.match_all(&block_hash, &[]).unwrap());
+        assert!(!filter
+            .match_all(&block_hash, &[others[0], refs[50]])
+            .unwrap());
+
+        // other block hash makes different filter.
+        let other_hash = sha256d::Hash::hash(&[2]);
+        assert_ne!(BlockFilter::build(&other_hash, &refs), filter);
+
+        // empty filter matches nothing.
+        let empty = BlockFilter::build(&block_hash, &[]);
+        assert_eq!(empty.content, vec![0]);
+        assert!(!empty.match_any(&block_hash, &refs).unwrap());
+        assert!(!empty.match_all(&block_hash, &refs).unwrap());
+
+        // truncated filter can not be decoded.
+        let mut truncated = filter.clone();
+        truncated.content.truncate(10);
+        assert!(truncated.match_any(&block_hash, &others).is_err());
+
+        // N which overflows N * M is rejected.
+        let mut content = vec![];
+        VarInt(u64::max_value())
+            .consensus_encode(&mut content)
+            .unwrap();
+        content.extend_from_slice(&[0xff; 32]);
+        let huge = BlockFilter::new(content);
+        assert!(huge.match_any(&block_hash, &others).is_err());
+    }
+}
@@ -10,8 +10,11 @@ use tapyrus::BlockHeader;
 /// 'height', 'next_blockhash' for that.
 #[derive(Debug, Clone, PartialEq)]
 pub struct BlockIndex {
+    /// The block header
     pub header: BlockHeader,
+    /// Height of the block
     pub height: i32,
+    /// Hash of the next block in the active chain. It is zero if the block is the tip.
     pub next_blockhash: sha256d::Hash,
 }
 
@@ -2,56 +2,366 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::chain::{BlockIndex, Error};
+use crate::chain::{proof, BlockIndex, Checkpoint, Error, Federation, Federations};
 use bitcoin_hashes::{sha256d, Hash};
 use core::cmp;
 use hex;
-use tapyrus::{BitcoinHash, Block, BlockHeader};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tapyrus::secp256k1::{All, PublicKey, Secp256k1};
+use tapyrus::{BitcoinHash, BlockHeader};
+
+/// The number of blocks which are used to calculate median time past.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Maximum amount of time that a block timestamp is allowed to exceed the current time.
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Blocks in side branches which are deeper than this from the tip are discarded.
+const MAX_SIDE_BRANCH_DEPTH: i32 = 100;
 
 /// This struct presents the way to use single chain.
+///
+/// The active chain is kept in the store. Headers which are not in the active chain are kept in
+/// side branches, and the chain is reorganized when one of them becomes longer than the active
+/// chain.
+///
+/// The chain starts from the first block in the store, which is the genesis block or a trusted
+/// checkpoint. Blocks at heights of checkpoints must have the hashes of the checkpoints.
 #[derive(Debug)]
 pub struct Chain<T>
 where
     T: ChainStore,
 {
     store: T,
+    /// Block indexes which are not in the active chain, keyed by block hash.
+    side_branches: HashMap<sha256d::Hash, BlockIndex>,
+    /// Federations which sign blocks, including ones which are not activated yet.
+    federations: Federations,
+    /// Block hashes of checkpoints indexed by height.
+    checkpoints: BTreeMap<i32, sha256d::Hash>,
+    secp: Secp256k1<All>,
+}
+
+/// The result of connecting a block header to the chain.
+#[derive(Debug, PartialEq)]
+pub enum ConnectResult {
+    /// The header extended the active chain.
+    Extended(BlockIndex),
+    /// The header was stored in a side branch which is not longer than the active chain.
+    SideBranch(BlockIndex),
+    /// The header made a side branch longer than the active chain, so the chain was reorganized.
+    Reorganized(Reorg),
+    /// The header is already known.
+    AlreadyKnown,
+}
+
+/// Blocks which were switched by a reorganization.
+#[derive(Debug, PartialEq)]
+pub struct Reorg {
+    /// The height of the last block which is common to old and new chain.
+    pub fork_height: i32,
+    /// Blocks removed from the active chain, from old tip to the fork point.
+    pub disconnected: Vec<BlockIndex>,
+    /// Blocks added to the active chain, from the fork point to new tip.
+    pub connected: Vec<BlockIndex>,
 }
 
 impl<T: ChainStore> Chain<T> {
-    pub fn new(store: T) -> Chain<T> {
-        Chain { store }
+    /// Create chain whose first block is signed by `aggregated_public_key`. Federations which
+    /// were saved in the store are loaded.
+    pub fn new(store: T, aggregated_public_key: PublicKey) -> Chain<T> {
+        let mut federations = Federations::new(store.base_height(), aggregated_public_key);
+        for federation in store.federations() {
+            if let Err(e) = federations.add(federation) {
+                warn!("Ignore saved federation {}: {:?}", federation, e);
+            }
+        }
+
+        Chain {
+            store,
+            side_branches: HashMap::new(),
+            federations,
+            checkpoints: BTreeMap::new(),
+            secp: Secp256k1::new(),
+        }
     }
 }
 
 impl<T: ChainStore> Chain<T> {
-    // validate block header and connect to chain tip.
-    // TODO: implement validation
-    pub fn connect_block_header(&mut self, header: BlockHeader) -> Result<(), Error> {
+    /// Validate block header and connect it to the chain.
+    ///
+    /// The header may extend the active chain or a side branch. If a side branch gets longer than
+    /// the active chain, the chain is reorganized to the side branch.
+    pub fn connect_block_header(&mut self, header: BlockHeader) -> Result<ConnectResult, Error> {
+        let hash = header.bitcoin_hash();
+        if self.is_known(&hash) {
+            return Ok(ConnectResult::AlreadyKnown);
+        }
+
+        let prev = self
+            .find_block_index(&header.prev_blockhash)
+            .ok_or(Error::PrevBlockNotFound)?;
+
+        self.check_checkpoints(&hash, prev.height + 1)?;
+        self.validate_block_header(&header, &prev)?;
+        let extends_tip = header.prev_blockhash == self.tip().header.bitcoin_hash();
+
         let block_index = BlockIndex {
             header,
-            height: self.height() + 1,
+            height: prev.height + 1,
             next_blockhash: sha256d::Hash::default(),
         };
 
-        if log_enabled!(log::Level::Trace) {
-            let hash = hex::encode(block_index.header.bitcoin_hash().into_inner());
-            trace!(
-                "Connect new block to tip. height: {}, hash: {}",
-                block_index.height,
-                hash
+        if extends_tip {
+            if log_enabled!(log::Level::Trace) {
+                let hash = hex::encode(hash.into_inner());
+                trace!(
+                    "Connect new block to tip. height: {}, hash: {}",
+                    block_index.height,
+                    hash
+                );
+            }
+
+            self.store.update_tip(&block_index)?;
+            self.log_federation_change(&block_index);
+            self.prune_side_branches();
+            return Ok(ConnectResult::Extended(block_index));
+        }
+
+        debug!(
+            "Receive block in side branch. height: {}, hash: {}",
+            block_index.height, hash
+        );
+        self.side_branches.insert(hash, block_index.clone());
+
+        if block_index.height > self.height() {
+            let reorg = self.reorganize(block_index)?;
+            return Ok(ConnectResult::Reorganized(reorg));
+        }
+
+        Ok(ConnectResult::SideBranch(block_index))
+    }
+
+    /// Switch the active chain to the side branch which has `new_tip`.
+    fn reorganize(&mut self, new_tip: BlockIndex) -> Result<Reorg, Error> {
+        // collect blocks in the side branch from new tip to the fork point.
+        let mut connected = vec![];
+        let mut index = new_tip;
+        loop {
+            let prev = self
+                .side_branches
+                .get(&index.header.prev_blockhash)
+                .cloned();
+            connected.push(index);
+            match prev {
+                Some(prev) => index = prev,
+                None => break,
+            }
+        }
+        connected.reverse();
+        let fork_height = connected[0].height - 1;
+
+        info!(
+            "Reorganize chain. fork height: {}, old tip height: {}, new tip height: {}",
+            fork_height,
+            self.height(),
+            connected.last().unwrap().height
+        );
+
+        let mut disconnected = vec![];
+        while self.height() > fork_height {
+            let index = self.store.disconnect_tip()?;
+            self.side_branches
+                .insert(index.header.bitcoin_hash(), index.clone());
+            disconnected.push(index);
+        }
+
+        for index in &connected {
+            self.side_branches.remove(&index.header.bitcoin_hash());
+            self.store.update_tip(index)?;
+            self.log_federation_change(index);
+        }
+
+        self.prune_side_branches();
+
+        Ok(Reorg {
+            fork_height,
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Check the block at the height doesn't conflict with checkpoints.
+    ///
+    /// The hash must match the checkpoint at the height, and the block must not fork from the
+    /// active chain below checkpoints which the active chain has reached.
+    fn check_checkpoints(&self, hash: &sha256d::Hash, height: i32) -> Result<(), Error> {
+        match self.checkpoints.get(&height) {
+            Some(checkpoint) if checkpoint != hash => return Err(Error::CheckpointMismatch),
+            _ => {}
+        }
+
+        let tip_height = self.height();
+        let last_checkpoint = self.checkpoints.keys().rev().find(|h| **h <= tip_height);
+        match last_checkpoint {
+            Some(checkpoint_height) if height <= *checkpoint_height => {
+                Err(Error::ForkBeforeCheckpoint)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Log that the block which is connected to the active chain is the first block of a new
+    /// federation.
+    fn log_federation_change(&self, index: &BlockIndex) {
+        if index.height <= self.base_height() {
+            return;
+        }
+        if let Some(federation) = self.federations.activated_at(index.height) {
+            info!(
+                "Aggregated public key of federation was changed at height {}: {}",
+                index.height,
+                hex::encode(&federation.aggregated_public_key.serialize()[..])
             );
         }
+    }
 
-        self.store.update_tip(&block_index);
+    /// Discard blocks in side branches which are too deep to cause reorganization. It is called
+    /// whenever the tip advances, so that side branches don't grow without bound.
+    fn prune_side_branches(&mut self) {
+        if self.side_branches.is_empty() {
+            return;
+        }
+        let min_height = self.height() - MAX_SIDE_BRANCH_DEPTH;
+        self.side_branches
+            .retain(|_, index| index.height > min_height);
+    }
+
+    /// Remove blocks in side branches whose previous block was removed.
+    fn remove_orphans(&mut self) {
+        loop {
+            let orphans: Vec<sha256d::Hash> = self
+                .side_branches
+                .iter()
+                .filter(|(_, index)| !self.is_known(&index.header.prev_blockhash))
+                .map(|(hash, _)| *hash)
+                .collect();
+            if orphans.is_empty() {
+                return;
+            }
+            for hash in orphans {
+                self.side_branches.remove(&hash);
+            }
+        }
+    }
+
+    /// Check the header can be connected to `prev`.
+    ///
+    /// * `time` must be later than median time past and must not be too far in the future.
+    ///   Median time past is not checked for the first blocks after a trusted checkpoint.
+    /// * `proof` must be a valid signature by the aggregated public key of the federation which
+    ///   is active at the height of the header. If the header is higher than the tip and no
+    ///   federation is activated at its height, UnknownFederation is returned instead of
+    ///   InvalidProof, because it may be signed by a federation which was not added.
+    fn validate_block_header(&self, header: &BlockHeader, prev: &BlockIndex) -> Result<(), Error> {
+        // Median time past is unknown until the chain has enough blocks after a trusted
+        // checkpoint.
+        let base_height = self.base_height();
+        let has_median_time_past =
+            base_height == 0 || prev.height - base_height + 1 >= MEDIAN_TIME_SPAN as i32;
+        if has_median_time_past && header.time <= self.median_time_past(prev) {
+            return Err(Error::TimeTooOld);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if header.time as u64 > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(Error::TimeTooNew);
+        }
+
+        let height = prev.height + 1;
+        let federation = self.federations.get(height);
+        if !proof::verify_block_proof(&self.secp, header, &federation.aggregated_public_key)? {
+            if height > self.height() && self.federations.activated_at(height).is_none() {
+                return Err(Error::UnknownFederation(height));
+            }
+            return Err(Error::InvalidProof);
+        }
 
         Ok(())
     }
 
+    /// Return median of timestamps of last 11 blocks until `index`.
+    fn median_time_past(&self, index: &BlockIndex) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut index = Some(index.clone());
+        while let Some(i) = index {
+            times.push(i.header.time);
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            index = self.get_prev(&i);
+        }
+        times.sort();
+        times[times.len() / 2]
+    }
+
+    /// Return the previous block of `index` either in the active chain or in side branches.
+    fn get_prev(&self, index: &BlockIndex) -> Option<BlockIndex> {
+        if index.height <= self.base_height() {
+            return None;
+        }
+        self.find_block_index(&index.header.prev_blockhash)
+    }
+
+    /// Find block index either in the active chain or in side branches.
+    fn find_block_index(&self, hash: &sha256d::Hash) -> Option<BlockIndex> {
+        self.side_branches
+            .get(hash)
+            .cloned()
+            .or_else(|| self.store.get_by_hash(hash))
+    }
+
+    /// Return whether the block is known either in the active chain or in side branches.
+    pub fn is_known(&self, hash: &sha256d::Hash) -> bool {
+        self.side_branches.contains_key(hash) || self.store.contains(hash)
+    }
+
+    /// Return whether the block is in the active chain.
+    pub fn contains(&self, hash: &sha256d::Hash) -> bool {
+        self.store.contains(hash)
+    }
+
+    /// Return height of the last block in the active chain which is an ancestor of the block.
+    /// The block may be either in the active chain or in side branches. Return None if the block
+    /// is unknown or its branch was pruned.
+    pub fn find_fork(&self, hash: &sha256d::Hash) -> Option<i32> {
+        let mut index = self.find_block_index(hash)?;
+        while !self.contains(&index.header.bitcoin_hash()) {
+            index = self.get_prev(&index)?;
+        }
+        Some(index.height)
+    }
+
+    /// Return specific block in the active chain which is indicated by block hash.
+    pub fn get_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex> {
+        self.store.get_by_hash(hash)
+    }
+
     /// Return height of tip.
     pub fn height(&self) -> i32 {
         self.store.height()
     }
 
+    /// Return height of the first block of the chain. It is 0 unless the chain starts from a
+    /// trusted checkpoint.
+    pub fn base_height(&self) -> i32 {
+        self.store.base_height()
+    }
+
     /// Return specific block which is indicated by height.
     pub fn get(&self, height: i32) -> Option<BlockIndex> {
         self.store.get(height)
@@ -63,26 +373,108 @@ impl<T: ChainStore> Chain<T> {
         self.get(self.height()).unwrap()
     }
 
+    /// Add the federation which replaces the aggregated public key from its activation height,
+    /// and save all federations to the store.
+    ///
+    /// Blocks which the chain already has must be signed by the new key from the activation
+    /// height until the next federation is activated. Blocks in side branches which are not signed
+    /// by it are removed.
+    ///
+    /// Federations are not detected from the chain, because `BlockHeader` of the tapyrus crate
+    /// doesn't have the xfield in which Tapyrus Core signals the next aggregated public key. Every
+    /// federation must be added by the user. When a block after the tip is signed by a federation
+    /// which was not added, `connect_block_header` returns UnknownFederation, and the chain doesn't
+    /// grow until the federation is added.
+    pub fn add_federation(&mut self, federation: Federation) -> Result<(), Error> {
+        let mut federations = self.federations.clone();
+        if !federations.add(federation)? {
+            return Ok(());
+        }
+
+        let signed_by_federation = |height: i32| federations.get(height) == &federation;
+        let mut height = federation.activation_height;
+        while signed_by_federation(height) {
+            let index = match self.get(height) {
+                Some(index) => index,
+                None => break,
+            };
+            if !proof::verify_block_proof(
+                &self.secp,
+                &index.header,
+                &federation.aggregated_public_key,
+            )? {
+                return Err(Error::InvalidProof);
+            }
+            height += 1;
+        }
+
+        let mut invalid = Vec::new();
+        for (hash, index) in &self.side_branches {
+            if signed_by_federation(index.height)
+                && !proof::verify_block_proof(
+                    &self.secp,
+                    &index.header,
+                    &federation.aggregated_public_key,
+                )?
+            {
+                invalid.push(*hash);
+            }
+        }
+
+        self.store.save_federations(federations.changes())?;
+        for hash in invalid {
+            self.side_branches.remove(&hash);
+        }
+        self.remove_orphans();
+        self.federations = federations;
+        Ok(())
+    }
+
+    /// Add the checkpoint. If the active chain already has the block at the height, the block must
+    /// have the hash of the checkpoint.
+    pub fn add_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        if let Some(index) = self.get(checkpoint.height) {
+            if index.header.bitcoin_hash() != checkpoint.hash {
+                return Err(Error::CheckpointMismatch);
+            }
+        }
+
+        self.checkpoints.insert(checkpoint.height, checkpoint.hash);
+        self.side_branches
+            .retain(|_, index| index.height > checkpoint.height);
+        Ok(())
+    }
+
+    /// Return the federation which signs the tip of the active chain.
+    pub fn federation(&self) -> Federation {
+        *self.federations.get(self.height())
+    }
+
+    /// Write block headers which are not persisted yet to the store.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.store.flush()
+    }
+
     /// Return block hash list for indicate which blocks are include in block.
+    ///
+    /// The locator is built from the active chain, so that the peer can find the fork point even
+    /// if it is on another branch. It ends with the first block of the chain.
     pub fn get_locator(&self) -> Vec<sha256d::Hash> {
         let mut step: i32 = 1;
         let mut have = Vec::<sha256d::Hash>::with_capacity(32);
 
+        let base_height = self.base_height();
         let mut index = self.tip();
 
         loop {
             have.push(index.header.bitcoin_hash());
 
-            // Stop when we have added the genesis block.
-            if index.height == 0 {
+            // Stop when we have added the first block.
+            if index.height == base_height {
                 break;
             }
 
-            let height = cmp::max(index.height - step, 0);
-
-            // TODO: Add implementation for forked chain.
-            // Check this chain contains `index`. If it is true, update `index` like below. If it is
-            // not true, we should explore ancestor block and set to 'index'.
+            let height = cmp::max(index.height - step, base_height);
             index = self.get(height).unwrap();
 
             if have.len() > 10 {
@@ -100,20 +492,12 @@ pub trait ChainStore {
     /// Initialize chain store.
     /// This method should be called before start to use store.
     ///
-    /// ## implemnt
-    /// You should implement process which should be done before use store such as setting genesis
-    /// block.
-    fn initialize(&mut self, genesis: Block) {
-        if let None = self.get(0) {
-            let genesis = BlockIndex {
-                header: genesis.header,
-                height: 0,
-                next_blockhash: sha256d::Hash::default(),
-            };
+    /// `base` is the first block of the chain. It is the genesis block, or a trusted checkpoint
+    /// at non-zero height. The store should do nothing if it already has blocks.
+    fn initialize(&mut self, base: BlockIndex) -> Result<(), Error>;
 
-            self.update_tip(&genesis);
-        }
-    }
+    /// Return height of the first block in this chain.
+    fn base_height(&self) -> i32;
 
     /// Return height of tip.
     fn height(&self) -> i32;
@@ -121,8 +505,37 @@ pub trait ChainStore {
     /// Return specific block which is indicated by height.
     fn get(&self, height: i32) -> Option<BlockIndex>;
 
+    /// Return specific block which is indicated by block hash.
+    fn get_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex>;
+
+    /// Return whether the block is in this chain.
+    fn contains(&self, hash: &sha256d::Hash) -> bool {
+        self.get_by_hash(hash).is_some()
+    }
+
     /// Update chain tip to passed BlockIndex.
-    fn update_tip(&mut self, index: &BlockIndex);
+    fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error>;
+
+    /// Remove the tip from the chain and return it. `next_blockhash` of new tip is cleared.
+    /// The first block can not be removed.
+    fn disconnect_tip(&mut self) -> Result<BlockIndex, Error>;
+
+    /// Return federations which were saved by `save_federations`. Federation of the genesis block
+    /// is not included.
+    fn federations(&self) -> Vec<Federation> {
+        vec![]
+    }
+
+    /// Save federations which replaced the aggregated public key of the genesis block. The store
+    /// doesn't have to keep them if the block headers are not persisted.
+    fn save_federations(&mut self, _federations: &[Federation]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Write buffered data to the persistent storage. It is called when the SPV node stops.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 
     /// Return latest block in this chain.
     fn tip(&self) -> BlockIndex {
@@ -135,7 +548,11 @@ pub trait ChainStore {
 mod tests {
     use super::*;
     use crate::chain::store::OnMemoryChainStore;
-    use crate::test_helper::{get_chain, get_test_block_hash, get_test_headers};
+    use crate::test_helper::{
+        create_signed_header, create_signed_header_with_key, get_chain, get_chain_from,
+        get_test_aggregated_public_key, get_test_block_hash, get_test_headers,
+        get_test_next_aggregated_public_key, NEXT_AGGREGATED_PRIVATE_KEY_HEX,
+    };
     use tapyrus::consensus::serialize;
 
     fn build_chain(height: usize) -> Chain<OnMemoryChainStore> {
@@ -146,7 +563,7 @@ mod tests {
         }
 
         for header in get_test_headers(1, height) {
-            let _ = chain.connect_block_header(header);
+            chain.connect_block_header(header).unwrap();
         }
 
         chain
@@ -171,6 +588,362 @@ mod tests {
         assert_eq!(chain.get(0).unwrap().next_blockhash, hash);
     }
 
+    #[test]
+    fn test_connect_block_header_validation() {
+        let mut chain = build_chain(10);
+
+        // header whose previous block is unknown.
+        let header = get_test_headers(12, 1).pop().unwrap();
+        match chain.connect_block_header(header) {
+            Err(Error::PrevBlockNotFound) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // header which has older time than median time past.
+        let mut header = get_test_headers(11, 1).pop().unwrap();
+        header.time = chain.get(1).unwrap().header.time;
+        match chain.connect_block_header(header) {
+            Err(Error::TimeTooOld) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // header which has time too far in the future.
+        let mut header = get_test_headers(11, 1).pop().unwrap();
+        header.time = u32::max_value();
+        match chain.connect_block_header(header) {
+            Err(Error::TimeTooNew) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // header after the tip which is not signed by the known federations.
+        let mut header = get_test_headers(11, 1).pop().unwrap();
+        header.time += 1;
+        match chain.connect_block_header(header) {
+            Err(Error::UnknownFederation(11)) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // header which has invalid proof at the height which the active chain has.
+        let block9 = chain.get(9).unwrap().header;
+        let mut fork10 = create_signed_header(&block9, 1);
+        fork10.time += 1;
+        match chain.connect_block_header(fork10) {
+            Err(Error::InvalidProof) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        assert_eq!(chain.height(), 10);
+
+        let header = get_test_headers(11, 1).pop().unwrap();
+        match chain.connect_block_header(header) {
+            Ok(ConnectResult::Extended(index)) => assert_eq!(index.height, 11),
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        assert_eq!(chain.height(), 11);
+
+        // header which is already in the chain.
+        let header = get_test_headers(5, 1).pop().unwrap();
+        assert_eq!(
+            chain.connect_block_header(header).unwrap(),
+            ConnectResult::AlreadyKnown
+        );
+        assert_eq!(chain.height(), 11);
+    }
+
+    #[test]
+    fn test_get_by_hash() {
+        let mut chain = build_chain(10);
+        let block9 = get_test_headers(9, 1).pop().unwrap();
+        let fork10 = create_signed_header(&block9, 1);
+        chain.connect_block_header(fork10.clone()).unwrap();
+
+        let index = chain.get_by_hash(&get_test_block_hash(5)).unwrap();
+        assert_eq!(index.height, 5);
+        assert!(chain.contains(&get_test_block_hash(5)));
+        assert!(chain.is_known(&get_test_block_hash(5)));
+
+        // block in side branch is known but is not in the active chain.
+        assert_eq!(chain.get_by_hash(&fork10.bitcoin_hash()), None);
+        assert!(!chain.contains(&fork10.bitcoin_hash()));
+        assert!(chain.is_known(&fork10.bitcoin_hash()));
+
+        assert!(!chain.is_known(&get_test_block_hash(11)));
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let mut chain = build_chain(10);
+        let block9 = get_test_headers(9, 1).pop().unwrap();
+        let block10 = get_test_headers(10, 1).pop().unwrap();
+
+        // competing block at height 10 is kept in side branch.
+        let fork10 = create_signed_header(&block9, 1);
+        match chain.connect_block_header(fork10.clone()).unwrap() {
+            ConnectResult::SideBranch(index) => assert_eq!(index.height, 10),
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        assert_eq!(chain.tip().header, block10);
+
+        // the side branch becomes longer than the active chain.
+        let fork11 = create_signed_header(&fork10, 1);
+        match chain.connect_block_header(fork11.clone()).unwrap() {
+            ConnectResult::Reorganized(reorg) => {
+                assert_eq!(reorg.fork_height, 9);
+                let disconnected: Vec<BlockHeader> =
+                    reorg.disconnected.into_iter().map(|i| i.header).collect();
+                assert_eq!(disconnected, vec![block10.clone()]);
+                let connected: Vec<BlockHeader> =
+                    reorg.connected.into_iter().map(|i| i.header).collect();
+                assert_eq!(connected, vec![fork10.clone(), fork11.clone()]);
+            }
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        assert_eq!(chain.height(), 11);
+        assert_eq!(chain.tip().header, fork11);
+        assert_eq!(chain.find_fork(&block10.bitcoin_hash()), Some(9));
+        assert_eq!(chain.find_fork(&fork11.bitcoin_hash()), Some(11));
+        assert_eq!(chain.get(9).unwrap().next_blockhash, fork10.bitcoin_hash());
+        assert_eq!(chain.get(10).unwrap().next_blockhash, fork11.bitcoin_hash());
+
+        // the original chain gets longer again.
+        let block11 = get_test_headers(11, 1).pop().unwrap();
+        match chain.connect_block_header(block11.clone()).unwrap() {
+            ConnectResult::SideBranch(index) => assert_eq!(index.height, 11),
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        let block12 = create_signed_header(&block11, 2);
+        match chain.connect_block_header(block12.clone()).unwrap() {
+            ConnectResult::Reorganized(reorg) => {
+                assert_eq!(reorg.fork_height, 9);
+                assert_eq!(reorg.disconnected.len(), 2);
+                assert_eq!(reorg.connected.len(), 3);
+            }
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        assert_eq!(chain.height(), 12);
+        assert_eq!(chain.get(10).unwrap().header, block10);
+        assert_eq!(chain.get(11).unwrap().header, block11);
+        assert_eq!(chain.tip().header, block12);
+        assert_eq!(chain.get(9).unwrap().next_blockhash, block10.bitcoin_hash());
+    }
+
+    #[test]
+    fn test_prune_side_branches() {
+        let mut chain = build_chain(10);
+        let block9 = get_test_headers(9, 1).pop().unwrap();
+        let fork10 = create_signed_header(&block9, 1);
+        chain.connect_block_header(fork10.clone()).unwrap();
+
+        // the side branch is kept until the active chain gets MAX_SIDE_BRANCH_DEPTH blocks
+        // deeper without reorganization.
+        let mut tip = chain.tip().header;
+        while chain.height() < 10 + MAX_SIDE_BRANCH_DEPTH - 1 {
+            tip = create_signed_header(&tip, 0);
+            chain.connect_block_header(tip.clone()).unwrap();
+        }
+        assert!(chain.is_known(&fork10.bitcoin_hash()));
+
+        tip = create_signed_header(&tip, 0);
+        chain.connect_block_header(tip).unwrap();
+        assert!(!chain.is_known(&fork10.bitcoin_hash()));
+        assert!(chain.side_branches.is_empty());
+    }
+
+    #[test]
+    fn test_federation_change() {
+        let mut chain = build_chain(10);
+        let next = Federation {
+            activation_height: 11,
+            aggregated_public_key: get_test_next_aggregated_public_key(),
+        };
+        assert_eq!(
+            chain.federation().aggregated_public_key,
+            get_test_aggregated_public_key()
+        );
+
+        // block which is already connected must be signed by the new key.
+        let mut federation = next;
+        federation.activation_height = 10;
+        match chain.add_federation(federation) {
+            Err(Error::InvalidProof) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        chain.add_federation(next).unwrap();
+        assert_eq!(chain.federation().activation_height, 0);
+
+        // block which is signed by the old key after activation height.
+        let block11 = get_test_headers(11, 1).pop().unwrap();
+        match chain.connect_block_header(block11) {
+            Err(Error::InvalidProof) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        let block10 = chain.tip().header;
+        let block11 = create_signed_header_with_key(&block10, 1, NEXT_AGGREGATED_PRIVATE_KEY_HEX);
+        let block12 = create_signed_header_with_key(&block11, 1, NEXT_AGGREGATED_PRIVATE_KEY_HEX);
+        chain.connect_block_header(block11).unwrap();
+        chain.connect_block_header(block12).unwrap();
+        assert_eq!(chain.federation(), next);
+
+        // blocks in a side branch are validated with the key which is active at their height.
+        let block9 = chain.get(9).unwrap().header;
+        let fork10 = create_signed_header(&block9, 2);
+        let fork11 = create_signed_header_with_key(&fork10, 2, NEXT_AGGREGATED_PRIVATE_KEY_HEX);
+        match chain.connect_block_header(fork10.clone()).unwrap() {
+            ConnectResult::SideBranch(index) => assert_eq!(index.height, 10),
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        match chain.connect_block_header(fork11).unwrap() {
+            ConnectResult::SideBranch(index) => assert_eq!(index.height, 11),
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        let fork11 = create_signed_header(&fork10, 3);
+        match chain.connect_block_header(fork11) {
+            Err(Error::InvalidProof) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // the federation is kept when the chain is disconnected below its activation height.
+        chain.store.disconnect_tip().unwrap();
+        chain.store.disconnect_tip().unwrap();
+        assert_eq!(chain.federation().activation_height, 0);
+        chain.add_federation(next).unwrap();
+    }
+
+    #[test]
+    fn test_federation_change_verifies_stored_blocks() {
+        let mut chain = build_chain(10);
+        let next = Federation {
+            activation_height: 11,
+            aggregated_public_key: get_test_next_aggregated_public_key(),
+        };
+        chain.add_federation(next).unwrap();
+        let block10 = chain.tip().header;
+        let block11 = create_signed_header_with_key(&block10, 1, NEXT_AGGREGATED_PRIVATE_KEY_HEX);
+        let block12 = create_signed_header_with_key(&block11, 1, NEXT_AGGREGATED_PRIVATE_KEY_HEX);
+        chain.connect_block_header(block11).unwrap();
+        chain.connect_block_header(block12.clone()).unwrap();
+
+        // blocks which were connected before the federation is known.
+        chain.federations = Federations::new(0, get_test_aggregated_public_key());
+        let fork11 = create_signed_header(&block10, 2);
+        let fork12 = create_signed_header(&fork11, 2);
+        chain.connect_block_header(fork11.clone()).unwrap();
+        chain.connect_block_header(fork12.clone()).unwrap();
+        chain
+            .connect_block_header(create_signed_header(&block12, 1))
+            .unwrap();
+
+        // the block above the activation height is not signed by the new key.
+        match chain.add_federation(next) {
+            Err(Error::InvalidProof) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        assert_eq!(chain.federation().activation_height, 0);
+
+        chain.store.disconnect_tip().unwrap();
+        chain.add_federation(next).unwrap();
+        assert_eq!(chain.federation(), next);
+        assert!(!chain.is_known(&fork11.bitcoin_hash()));
+        assert!(!chain.is_known(&fork12.bitcoin_hash()));
+        assert!(chain.side_branches.is_empty());
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut chain = build_chain(10);
+
+        // checkpoint which conflicts with the active chain.
+        let checkpoint = Checkpoint {
+            height: 5,
+            hash: get_test_block_hash(6),
+        };
+        match chain.add_checkpoint(checkpoint) {
+            Err(Error::CheckpointMismatch) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        chain
+            .add_checkpoint(Checkpoint {
+                height: 5,
+                hash: get_test_block_hash(5),
+            })
+            .unwrap();
+        chain
+            .add_checkpoint(Checkpoint {
+                height: 12,
+                hash: get_test_block_hash(12),
+            })
+            .unwrap();
+
+        // fork below the checkpoint which the active chain has passed.
+        let block3 = get_test_headers(3, 1).pop().unwrap();
+        match chain.connect_block_header(create_signed_header(&block3, 1)) {
+            Err(Error::ForkBeforeCheckpoint) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // fork above the checkpoint is allowed.
+        let block9 = get_test_headers(9, 1).pop().unwrap();
+        let fork10 = create_signed_header(&block9, 1);
+        match chain.connect_block_header(fork10).unwrap() {
+            ConnectResult::SideBranch(index) => assert_eq!(index.height, 10),
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // block which doesn't match the checkpoint.
+        let block11 = get_test_headers(11, 1).pop().unwrap();
+        chain.connect_block_header(block11.clone()).unwrap();
+        match chain.connect_block_header(create_signed_header(&block11, 1)) {
+            Err(Error::CheckpointMismatch) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        let block12 = get_test_headers(12, 1).pop().unwrap();
+        chain.connect_block_header(block12).unwrap();
+        assert_eq!(chain.height(), 12);
+    }
+
+    #[test]
+    fn test_start_from_checkpoint() {
+        let mut chain = get_chain_from(10);
+        assert_eq!(chain.base_height(), 10);
+        assert_eq!(chain.height(), 10);
+        assert_eq!(chain.get_locator(), vec![get_test_block_hash(10)]);
+
+        // headers before the checkpoint can't be connected.
+        let header = get_test_headers(10, 1).pop().unwrap();
+        assert_eq!(
+            chain.connect_block_header(header).unwrap(),
+            ConnectResult::AlreadyKnown
+        );
+        let block8 = get_test_headers(8, 1).pop().unwrap();
+        match chain.connect_block_header(create_signed_header(&block8, 1)) {
+            Err(Error::PrevBlockNotFound) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        for header in get_test_headers(11, 30) {
+            chain.connect_block_header(header).unwrap();
+        }
+        assert_eq!(chain.height(), 40);
+        assert_eq!(chain.get(5), None);
+
+        let locator = chain.get_locator();
+        assert_eq!(locator.first(), Some(&get_test_block_hash(40)));
+        assert_eq!(locator.last(), Some(&get_test_block_hash(10)));
+        assert_eq!(locator.len(), 16);
+
+        // reorganization above the checkpoint.
+        let mut fork = get_test_headers(10, 1).pop().unwrap();
+        for i in 0..31 {
+            fork = create_signed_header(&fork, i);
+            chain.connect_block_header(fork.clone()).unwrap();
+        }
+        assert_eq!(chain.height(), 41);
+        assert_eq!(chain.find_fork(&get_test_block_hash(40)), Some(10));
+    }
+
     #[test]
     fn test_get_locator() {
         // when chain size is 1
@@ -0,0 +1,183 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! Checkpoints of the chain.
+//!
+//! A checkpoint fixes the block hash at its height, so that the chain can't be reorganized below
+//! it. A trusted checkpoint also has the block header and the aggregated public key which signs
+//! blocks after it, and the chain can start from it instead of the genesis block.
+
+use crate::chain::{BlockIndex, Error};
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256d;
+use std::fmt;
+use std::str::FromStr;
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::secp256k1::PublicKey;
+use tapyrus::{BitcoinHash, BlockHeader};
+
+/// Block hash which the active chain must have at the height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    /// Height of the block
+    pub height: i32,
+    /// Hash of the block
+    pub hash: sha256d::Hash,
+}
+
+impl fmt::Display for Checkpoint {
+    /// Format as `<height>:<block hash>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.height, self.hash)
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = Error;
+
+    /// Parse `<height>:<block hash>`.
+    fn from_str(s: &str) -> Result<Checkpoint, Error> {
+        let mut iter = s.trim().splitn(2, ':');
+        let height = iter
+            .next()
+            .and_then(|height| height.parse().ok())
+            .ok_or(Error::InvalidCheckpoint)?;
+        let hash = iter
+            .next()
+            .and_then(|hash| sha256d::Hash::from_hex(hash).ok())
+            .ok_or(Error::InvalidCheckpoint)?;
+        Ok(Checkpoint { height, hash })
+    }
+}
+
+/// Block header which is trusted without validation. The chain can start from it instead of the
+/// genesis block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustedCheckpoint {
+    /// Height of the block
+    pub height: i32,
+    /// The block header
+    pub header: BlockHeader,
+    /// The aggregated public key of the federation which signs blocks after this block
+    pub aggregated_public_key: PublicKey,
+}
+
+impl TrustedCheckpoint {
+    /// Return the block index which is the first block of the chain.
+    pub fn block_index(&self) -> BlockIndex {
+        BlockIndex {
+            header: self.header.clone(),
+            height: self.height,
+            next_blockhash: sha256d::Hash::default(),
+        }
+    }
+
+    /// Return the checkpoint of this block.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            height: self.height,
+            hash: self.header.bitcoin_hash(),
+        }
+    }
+}
+
+impl fmt::Display for TrustedCheckpoint {
+    /// Format as `<height>:<serialized block header in hex>:<aggregated public key in hex>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.height,
+            hex::encode(serialize(&self.header)),
+            hex::encode(&self.aggregated_public_key.serialize()[..])
+        )
+    }
+}
+
+impl FromStr for TrustedCheckpoint {
+    type Err = Error;
+
+    /// Parse `<height>:<serialized block header in hex>:<aggregated public key in hex>`.
+    fn from_str(s: &str) -> Result<TrustedCheckpoint, Error> {
+        let mut iter = s.trim().splitn(3, ':');
+        let height = iter
+            .next()
+            .and_then(|height| height.parse().ok())
+            .filter(|height| *height >= 0)
+            .ok_or(Error::InvalidCheckpoint)?;
+        let header = iter
+            .next()
+            .and_then(|header| hex::decode(header).ok())
+            .and_then(|header| deserialize(&header).ok())
+            .ok_or(Error::InvalidCheckpoint)?;
+        let aggregated_public_key = iter
+            .next()
+            .and_then(|key| hex::decode(key).ok())
+            .and_then(|key| PublicKey::from_slice(&key).ok())
+            .ok_or(Error::InvalidCheckpoint)?;
+        Ok(TrustedCheckpoint {
+            height,
+            header,
+            aggregated_public_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{
+        get_test_aggregated_public_key, get_test_block_hash, get_test_headers,
+        AGGREGATED_PUBLIC_KEY_HEX,
+    };
+
+    #[test]
+    fn test_parse_checkpoint() {
+        let checkpoint = Checkpoint {
+            height: 10,
+            hash: get_test_block_hash(10),
+        };
+        let s = checkpoint.to_string();
+        assert_eq!(s, format!("10:{}", get_test_block_hash(10)));
+        assert_eq!(s.parse::<Checkpoint>().unwrap(), checkpoint);
+
+        assert!("10".parse::<Checkpoint>().is_err());
+        assert!("10:00".parse::<Checkpoint>().is_err());
+    }
+
+    #[test]
+    fn test_parse_trusted_checkpoint() {
+        let header = get_test_headers(10, 1).pop().unwrap();
+        let trusted = TrustedCheckpoint {
+            height: 10,
+            header: header.clone(),
+            aggregated_public_key: get_test_aggregated_public_key(),
+        };
+        let s = trusted.to_string();
+        assert_eq!(
+            s,
+            format!(
+                "10:{}:{}",
+                hex::encode(serialize(&header)),
+                AGGREGATED_PUBLIC_KEY_HEX
+            )
+        );
+        assert_eq!(s.parse::<TrustedCheckpoint>().unwrap(), trusted);
+        assert_eq!(
+            trusted.checkpoint(),
+            Checkpoint {
+                height: 10,
+                hash: get_test_block_hash(10),
+            }
+        );
+        assert_eq!(trusted.block_index().height, 10);
+
+        assert!(format!("10:{}", hex::encode(serialize(&header)))
+            .parse::<TrustedCheckpoint>()
+            .is_err());
+        assert!(format!("10:00:{}", AGGREGATED_PUBLIC_KEY_HEX)
+            .parse::<TrustedCheckpoint>()
+            .is_err());
+    }
+}
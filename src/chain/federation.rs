@@ -0,0 +1,190 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! Federations which sign blocks.
+//!
+//! The federation of a Tapyrus network can replace its aggregated public key. Each federation
+//! signs blocks from its activation height until the next federation is activated. The first
+//! federation is the one which signs the first block of the chain, that is the genesis block or a
+//! trusted checkpoint.
+
+use crate::chain::Error;
+use std::fmt;
+use std::str::FromStr;
+use tapyrus::secp256k1::PublicKey;
+
+/// Aggregated public key of a federation and the height of the first block which it signs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Federation {
+    /// Height of the first block which is signed by this federation
+    pub activation_height: i32,
+    /// The aggregated public key of this federation
+    pub aggregated_public_key: PublicKey,
+}
+
+impl fmt::Display for Federation {
+    /// Format as `<activation height>:<aggregated public key in hex>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.activation_height,
+            hex::encode(&self.aggregated_public_key.serialize()[..])
+        )
+    }
+}
+
+impl FromStr for Federation {
+    type Err = Error;
+
+    /// Parse `<activation height>:<aggregated public key in hex>`.
+    fn from_str(s: &str) -> Result<Federation, Error> {
+        let mut iter = s.trim().splitn(2, ':');
+        let activation_height = iter
+            .next()
+            .and_then(|height| height.parse().ok())
+            .ok_or(Error::InvalidFederation)?;
+        let aggregated_public_key = iter
+            .next()
+            .and_then(|key| hex::decode(key).ok())
+            .and_then(|key| PublicKey::from_slice(&key).ok())
+            .ok_or(Error::InvalidFederation)?;
+        Ok(Federation {
+            activation_height,
+            aggregated_public_key,
+        })
+    }
+}
+
+/// History of federations sorted by activation height.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Federations {
+    federations: Vec<Federation>,
+}
+
+impl Federations {
+    /// Create history which has only the federation of the first block of the chain.
+    pub fn new(base_height: i32, base_key: PublicKey) -> Federations {
+        Federations {
+            federations: vec![Federation {
+                activation_height: base_height,
+                aggregated_public_key: base_key,
+            }],
+        }
+    }
+
+    /// Add the federation to the history. Return false if it is already known.
+    ///
+    /// The federation of the first block can not be replaced, and only one federation can be
+    /// activated at each height.
+    pub fn add(&mut self, federation: Federation) -> Result<bool, Error> {
+        if federation.activation_height <= self.federations[0].activation_height {
+            return Err(Error::InvalidFederation);
+        }
+
+        match self
+            .federations
+            .binary_search_by_key(&federation.activation_height, |f| f.activation_height)
+        {
+            Ok(i) if self.federations[i] == federation => Ok(false),
+            Ok(_) => Err(Error::ConflictingFederation),
+            Err(i) => {
+                self.federations.insert(i, federation);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Return the federation which signs the block at the height. The first federation is
+    /// returned for blocks below the first block of the chain.
+    pub fn get(&self, height: i32) -> &Federation {
+        let i = self
+            .federations
+            .iter()
+            .position(|f| f.activation_height > height)
+            .unwrap_or(self.federations.len());
+        &self.federations[i.saturating_sub(1)]
+    }
+
+    /// Return federations which are activated after the first block of the chain.
+    pub fn changes(&self) -> &[Federation] {
+        &self.federations[1..]
+    }
+
+    /// Return the federation which is activated at the height.
+    pub fn activated_at(&self, height: i32) -> Option<&Federation> {
+        self.federations
+            .iter()
+            .find(|f| f.activation_height == height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{get_test_aggregated_public_key, get_test_next_aggregated_public_key};
+
+    #[test]
+    fn test_federations() {
+        let genesis_key = get_test_aggregated_public_key();
+        let next_key = get_test_next_aggregated_public_key();
+        let mut federations = Federations::new(0, genesis_key);
+        assert_eq!(federations.get(100).aggregated_public_key, genesis_key);
+
+        let next = Federation {
+            activation_height: 10,
+            aggregated_public_key: next_key,
+        };
+        assert_eq!(federations.add(next).unwrap(), true);
+        assert_eq!(federations.add(next).unwrap(), false);
+        assert_eq!(federations.get(9).aggregated_public_key, genesis_key);
+        assert_eq!(federations.get(10), &next);
+        assert_eq!(federations.get(100), &next);
+        assert_eq!(federations.changes(), &[next]);
+        assert_eq!(federations.activated_at(10), Some(&next));
+        assert_eq!(federations.activated_at(11), None);
+
+        // another key at the same height.
+        let conflict = Federation {
+            activation_height: 10,
+            aggregated_public_key: genesis_key,
+        };
+        match federations.add(conflict) {
+            Err(Error::ConflictingFederation) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+
+        // federation of the first block can not be replaced.
+        let genesis = Federation {
+            activation_height: 0,
+            aggregated_public_key: next_key,
+        };
+        match federations.add(genesis) {
+            Err(Error::InvalidFederation) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_federation() {
+        let federation = Federation {
+            activation_height: 10,
+            aggregated_public_key: get_test_aggregated_public_key(),
+        };
+        let s = federation.to_string();
+        assert_eq!(
+            s,
+            "10:02260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a"
+        );
+        assert_eq!(s.parse::<Federation>().unwrap(), federation);
+
+        assert!("10".parse::<Federation>().is_err());
+        assert!(
+            "a:02260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a"
+                .parse::<Federation>()
+                .is_err()
+        );
+        assert!("10:0226".parse::<Federation>().is_err());
+    }
+}
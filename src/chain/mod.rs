@@ -7,18 +7,63 @@
 //! This is a module for storing chains which is consisted of block headers and provide useful API
 //! to access block headers in the chain.
 
+pub mod block_filter;
 mod block_index;
 mod chain;
+mod checkpoint;
+mod federation;
+pub mod partial_merkle_tree;
+pub mod proof;
 pub mod store;
 
+pub use block_filter::{BlockFilter, FilterHeaderIndex, FilterHeaderStore};
 pub use block_index::BlockIndex;
 pub use chain::Chain;
 pub use chain::ChainStore;
+pub use chain::ConnectResult;
+pub use checkpoint::{Checkpoint, TrustedCheckpoint};
+pub use federation::{Federation, Federations};
+pub use partial_merkle_tree::{
+    verify_merkle_block, MatchedTransaction, MerkleProofError, PartialMerkleTree,
+};
+pub use proof::aggregated_public_key;
 
+/// Errors in chain module
 #[derive(Debug)]
 pub enum Error {
+    /// Stored data can not be decoded.
     EncodeError(tapyrus::consensus::encode::Error),
+    /// Stored hash has invalid length.
     BitcoinHashesError(bitcoin_hashes::Error),
+    /// Reading or writing the store failed.
+    IoError(std::io::Error),
+    /// The genesis block doesn't have aggregated public key in its coinbase.
+    InvalidGenesisBlock,
+    /// The previous block of the header is unknown.
+    PrevBlockNotFound,
+    /// The first block of the chain, which is the genesis block or a trusted checkpoint, can not
+    /// be disconnected.
+    CannotDisconnectGenesis,
+    /// The block time is not later than median time past.
+    TimeTooOld,
+    /// The block time is too far in the future.
+    TimeTooNew,
+    /// The block proof is not a valid signature by the aggregated public key.
+    InvalidProof,
+    /// The block after the tip of the active chain is not signed by the known federations. The
+    /// aggregated public key may have been replaced by a federation which was not added.
+    UnknownFederation(i32),
+    /// The federation can not be parsed or its activation height is not above the first block of
+    /// the chain.
+    InvalidFederation,
+    /// Another federation is activated at the same height.
+    ConflictingFederation,
+    /// The checkpoint can not be parsed.
+    InvalidCheckpoint,
+    /// The block hash doesn't match the checkpoint at its height.
+    CheckpointMismatch,
+    /// The header forks from the active chain below a checkpoint which the chain has passed.
+    ForkBeforeCheckpoint,
 }
 
 impl From<tapyrus::consensus::encode::Error> for Error {
@@ -27,6 +72,12 @@ impl From<tapyrus::consensus::encode::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
 impl From<bitcoin_hashes::Error> for Error {
     fn from(e: bitcoin_hashes::Error) -> Error {
         Error::BitcoinHashesError(e)
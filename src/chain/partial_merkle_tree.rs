@@ -0,0 +1,417 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! Verification of partial merkle trees in `merkleblock` messages (BIP37).
+//!
+//! Tapyrus block header has two merkle roots. `merkle_root` is calculated from txids, and
+//! `im_merkle_root` is calculated from malleability-fixed txids which don't cover scriptSig of
+//! inputs. Tapyrus nodes build partial merkle trees from malleability-fixed txids, so the trees
+//! are verified against `im_merkle_root`.
+
+use crate::chain::BlockIndex;
+use bitcoin_hashes::{sha256d, Hash, HashEngine};
+
+/// The maximum number of transactions in a block. A block is at most 4MB and a transaction is
+/// at least 60 bytes.
+pub const MAX_TRANSACTIONS_IN_BLOCK: u32 = 4_000_000 / 60;
+
+/// Errors which occur when a partial merkle tree is verified.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MerkleProofError {
+    /// The tree has no transactions.
+    NoTransactions,
+    /// The number of transactions exceeds MAX_TRANSACTIONS_IN_BLOCK.
+    TooManyTransactions,
+    /// There are more hashes than transactions.
+    TooManyHashes,
+    /// The tree needs more hashes than provided.
+    NotEnoughHashes,
+    /// The tree needs more flag bits than provided.
+    NotEnoughBits,
+    /// Some hashes were not used to calculate the root.
+    NotAllHashesUsed,
+    /// Some flag bytes were not used to calculate the root.
+    NotAllBitsUsed,
+    /// Two children of a node have the same hash (CVE-2012-2459).
+    IdenticalHashesFound,
+    /// The calculated root doesn't match `im_merkle_root` of the block header.
+    MerkleRootMismatch,
+    /// The number of match flags differs from the number of transactions.
+    MatchesLengthMismatch,
+}
+
+/// Transaction which matched the filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedTransaction {
+    /// Malleability-fixed txid of the transaction
+    pub txid: sha256d::Hash,
+    /// Position of the transaction in the block
+    pub position: u32,
+}
+
+/// Partial merkle tree which proves that some transactions are included in a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialMerkleTree {
+    total_transactions: u32,
+    hashes: Vec<sha256d::Hash>,
+    bits: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Create tree from fields of merkleblock message. `flags` are packed per 8 in a byte, least
+    /// significant bit first.
+    pub fn new(total_transactions: u32, hashes: Vec<sha256d::Hash>, flags: &[u8]) -> Self {
+        let bits = (0..flags.len() * 8)
+            .map(|i| flags[i / 8] & (1 << (i % 8)) != 0)
+            .collect();
+        PartialMerkleTree {
+            total_transactions,
+            hashes,
+            bits,
+        }
+    }
+
+    /// Create tree which proves transactions whose `matches` flag is true. `txids` are all
+    /// malleability-fixed txids in the block, and `matches` must have the same length.
+    pub fn from_txids(txids: &[sha256d::Hash], matches: &[bool]) -> Result<Self, MerkleProofError> {
+        if txids.len() != matches.len() {
+            return Err(MerkleProofError::MatchesLengthMismatch);
+        }
+        if txids.is_empty() {
+            return Err(MerkleProofError::NoTransactions);
+        }
+        if txids.len() > MAX_TRANSACTIONS_IN_BLOCK as usize {
+            return Err(MerkleProofError::TooManyTransactions);
+        }
+
+        let mut tree = PartialMerkleTree {
+            total_transactions: txids.len() as u32,
+            hashes: vec![],
+            bits: vec![],
+        };
+        let height = tree.height();
+        tree.traverse_and_build(height, 0, txids, matches);
+        Ok(tree)
+    }
+
+    /// Return the number of transactions in the block.
+    pub fn total_transactions(&self) -> u32 {
+        self.total_transactions
+    }
+
+    /// Return hashes in depth-first order.
+    pub fn hashes(&self) -> &[sha256d::Hash] {
+        &self.hashes
+    }
+
+    /// Return flag bits packed per 8 in a byte.
+    pub fn flags(&self) -> Vec<u8> {
+        let mut flags = vec![0u8; (self.bits.len() + 7) / 8];
+        for (i, bit) in self.bits.iter().enumerate() {
+            flags[i / 8] |= (*bit as u8) << (i % 8);
+        }
+        flags
+    }
+
+    /// Calculate merkle root and return it with matched transactions.
+    pub fn extract_matches(
+        &self,
+    ) -> Result<(sha256d::Hash, Vec<MatchedTransaction>), MerkleProofError> {
+        if self.total_transactions == 0 {
+            return Err(MerkleProofError::NoTransactions);
+        }
+        if self.total_transactions > MAX_TRANSACTIONS_IN_BLOCK {
+            return Err(MerkleProofError::TooManyTransactions);
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err(MerkleProofError::TooManyHashes);
+        }
+        if self.bits.len() < self.hashes.len() {
+            return Err(MerkleProofError::NotEnoughBits);
+        }
+
+        let mut bits_used = 0;
+        let mut hashes_used = 0;
+        let mut matches = vec![];
+        let root = self.traverse_and_extract(
+            self.height(),
+            0,
+            &mut bits_used,
+            &mut hashes_used,
+            &mut matches,
+        )?;
+
+        // All bits except padding of the last byte must be used.
+        if (bits_used + 7) / 8 != (self.bits.len() + 7) / 8 {
+            return Err(MerkleProofError::NotAllBitsUsed);
+        }
+        if hashes_used != self.hashes.len() {
+            return Err(MerkleProofError::NotAllHashesUsed);
+        }
+        Ok((root, matches))
+    }
+
+    /// Return the height of the tree. Leaves are at height 0.
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    /// Return the number of nodes at the height.
+    fn width(&self, height: u32) -> u32 {
+        ((self.total_transactions as u64 + (1 << height) - 1) >> height) as u32
+    }
+
+    fn calc_hash(&self, height: u32, pos: u32, txids: &[sha256d::Hash]) -> sha256d::Hash {
+        if height == 0 {
+            return txids[pos as usize];
+        }
+
+        let left = self.calc_hash(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.calc_hash(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        parent_hash(&left, &right)
+    }
+
+    fn traverse_and_build(
+        &mut self,
+        height: u32,
+        pos: u32,
+        txids: &[sha256d::Hash],
+        matches: &[bool],
+    ) {
+        let start = (pos as usize) << height;
+        let end = std::cmp::min((pos as usize + 1) << height, txids.len());
+        let parent_of_match = matches[start..end].iter().any(|m| *m);
+        self.bits.push(parent_of_match);
+
+        if height == 0 || !parent_of_match {
+            let hash = self.calc_hash(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.width(height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    fn traverse_and_extract(
+        &self,
+        height: u32,
+        pos: u32,
+        bits_used: &mut usize,
+        hashes_used: &mut usize,
+        matches: &mut Vec<MatchedTransaction>,
+    ) -> Result<sha256d::Hash, MerkleProofError> {
+        let parent_of_match = *self
+            .bits
+            .get(*bits_used)
+            .ok_or(MerkleProofError::NotEnoughBits)?;
+        *bits_used += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self
+                .hashes
+                .get(*hashes_used)
+                .ok_or(MerkleProofError::NotEnoughHashes)?;
+            *hashes_used += 1;
+
+            if height == 0 && parent_of_match {
+                matches.push(MatchedTransaction {
+                    txid: hash,
+                    position: pos,
+                });
+            }
+            return Ok(hash);
+        }
+
+        let left =
+            self.traverse_and_extract(height - 1, pos * 2, bits_used, hashes_used, matches)?;
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right = self.traverse_and_extract(
+                height - 1,
+                pos * 2 + 1,
+                bits_used,
+                hashes_used,
+                matches,
+            )?;
+            if right == left {
+                return Err(MerkleProofError::IdenticalHashesFound);
+            }
+            right
+        } else {
+            left
+        };
+        Ok(parent_hash(&left, &right))
+    }
+}
+
+fn parent_hash(left: &sha256d::Hash, right: &sha256d::Hash) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Verify the partial merkle tree against `im_merkle_root` of the block and return matched
+/// transactions.
+pub fn verify_merkle_block(
+    index: &BlockIndex,
+    tree: &PartialMerkleTree,
+) -> Result<Vec<MatchedTransaction>, MerkleProofError> {
+    let (root, matches) = tree.extract_matches()?;
+    if root != index.header.im_merkle_root {
+        return Err(MerkleProofError::MerkleRootMismatch);
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{
+        create_signed_block, get_test_genesis_block, get_test_merkle_root, get_test_transactions,
+        get_test_txids,
+    };
+
+    #[test]
+    fn test_extract_matches() {
+        let txids = get_test_txids();
+        let tree = PartialMerkleTree::from_txids(&txids, &[false, true, false, false]).unwrap();
+
+        // root, left node, leaf 0, leaf 1 and right node are traversed.
+        assert_eq!(tree.flags(), vec![0b01011]);
+        assert_eq!(tree.hashes().len(), 3);
+        assert_eq!(tree.hashes()[0], txids[0]);
+        assert_eq!(tree.hashes()[1], txids[1]);
+
+        let tree = PartialMerkleTree::new(4, tree.hashes().to_vec(), &tree.flags());
+        let (root, matches) = tree.extract_matches().unwrap();
+        assert_eq!(root, get_test_merkle_root());
+        assert_eq!(
+            matches,
+            vec![MatchedTransaction {
+                txid: txids[1],
+                position: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_extract_matches_with_odd_transactions() {
+        let txids = &get_test_txids()[..3];
+        for pattern in 0..8u8 {
+            let matches: Vec<bool> = (0..3).map(|i| pattern & (1 << i) != 0).collect();
+            let tree = PartialMerkleTree::from_txids(txids, &matches).unwrap();
+            let (root, extracted) = tree.extract_matches().unwrap();
+
+            assert_eq!(root, tree.calc_hash(2, 0, txids));
+            let positions: Vec<u32> = extracted.iter().map(|m| m.position).collect();
+            let expected: Vec<u32> = (0..3).filter(|i| matches[*i as usize]).collect();
+            assert_eq!(positions, expected);
+        }
+    }
+
+    #[test]
+    fn test_extract_matches_fails_with_malformed_tree() {
+        let txids = get_test_txids();
+        let tree = PartialMerkleTree::from_txids(&txids, &[false, true, false, false]).unwrap();
+        let hashes = tree.hashes().to_vec();
+        let flags = tree.flags();
+
+        let extract = |total, hashes: Vec<sha256d::Hash>, flags: &[u8]| {
+            PartialMerkleTree::new(total, hashes, flags)
+                .extract_matches()
+                .err()
+        };
+
+        assert_eq!(
+            extract(0, vec![], &[]),
+            Some(MerkleProofError::NoTransactions)
+        );
+        assert_eq!(
+            extract(MAX_TRANSACTIONS_IN_BLOCK + 1, hashes.clone(), &flags),
+            Some(MerkleProofError::TooManyTransactions)
+        );
+        assert_eq!(
+            extract(1, hashes.clone(), &flags),
+            Some(MerkleProofError::TooManyHashes)
+        );
+        assert_eq!(
+            extract(4, hashes[..2].to_vec(), &flags),
+            Some(MerkleProofError::NotEnoughHashes)
+        );
+        let mut extra_hashes = hashes.clone();
+        extra_hashes.push(txids[0]);
+        assert_eq!(
+            extract(4, extra_hashes, &flags),
+            Some(MerkleProofError::NotAllHashesUsed)
+        );
+        assert_eq!(
+            extract(4, hashes.clone(), &[flags[0], 0]),
+            Some(MerkleProofError::NotAllBitsUsed)
+        );
+
+        // duplicated last transaction makes the same root.
+        let mut duplicated = txids[..3].to_vec();
+        duplicated.push(txids[2]);
+        let tree = PartialMerkleTree::from_txids(&duplicated, &[false, false, true, true]).unwrap();
+        assert_eq!(
+            tree.extract_matches().err(),
+            Some(MerkleProofError::IdenticalHashesFound)
+        );
+    }
+
+    #[test]
+    fn test_from_txids_fails_with_invalid_input() {
+        let txids = get_test_txids();
+        assert_eq!(
+            PartialMerkleTree::from_txids(&txids, &[true]),
+            Err(MerkleProofError::MatchesLengthMismatch)
+        );
+        assert_eq!(
+            PartialMerkleTree::from_txids(&[], &[]),
+            Err(MerkleProofError::NoTransactions)
+        );
+    }
+
+    #[test]
+    fn test_verify_merkle_block() {
+        let block = create_signed_block(&get_test_genesis_block().header, get_test_transactions(3));
+        let txids: Vec<sha256d::Hash> = block.txdata.iter().map(|tx| tx.malfix_txid()).collect();
+        let mut index = BlockIndex {
+            header: block.header,
+            height: 1,
+            next_blockhash: Default::default(),
+        };
+
+        let tree = PartialMerkleTree::from_txids(&txids, &[false, false, true]).unwrap();
+        let matches = verify_merkle_block(&index, &tree).unwrap();
+        assert_eq!(
+            matches,
+            vec![MatchedTransaction {
+                txid: txids[2],
+                position: 2,
+            }]
+        );
+
+        // the tree is verified against im_merkle_root, not merkle_root.
+        let txids: Vec<sha256d::Hash> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let tree = PartialMerkleTree::from_txids(&txids, &[false, false, true]).unwrap();
+        assert_eq!(
+            verify_merkle_block(&index, &tree),
+            Err(MerkleProofError::MerkleRootMismatch)
+        );
+
+        index.header.im_merkle_root = index.header.merkle_root;
+        assert!(verify_merkle_block(&index, &tree).is_ok());
+    }
+}
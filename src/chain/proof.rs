@@ -0,0 +1,174 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! Verification of block proofs.
+//!
+//! Tapyrus blocks are not mined. Instead, each block header carries a `proof` which is a Schnorr
+//! signature created by the federation with the aggregated private key. The signed message is the
+//! double SHA256 hash of the header without the proof field.
+
+use crate::chain::Error;
+use bitcoin_hashes::{sha256, sha256d, Hash, HashEngine};
+use num_bigint::BigUint;
+use tapyrus::consensus::Encodable;
+use tapyrus::secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+use tapyrus::{Block, BlockHeader};
+
+/// The order of the secp256k1 curve.
+pub const CURVE_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+/// The prime of the secp256k1 field.
+pub const FIELD_SIZE: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xfc, 0x2f,
+];
+
+/// Return aggregated public key of the federation which is embedded in the coinbase of genesis
+/// block.
+///
+/// The scriptSig of the genesis coinbase input is a single push of the compressed public key.
+pub fn aggregated_public_key(genesis: &Block) -> Result<PublicKey, Error> {
+    let script_sig = genesis
+        .txdata
+        .first()
+        .and_then(|tx| tx.input.first())
+        .map(|input| input.script_sig.as_bytes())
+        .ok_or(Error::InvalidGenesisBlock)?;
+
+    if script_sig.len() != 34 || script_sig[0] != 0x21 {
+        return Err(Error::InvalidGenesisBlock);
+    }
+
+    PublicKey::from_slice(&script_sig[1..]).map_err(|_| Error::InvalidGenesisBlock)
+}
+
+/// Return the hash which is signed by the federation. It is the hash of the header without proof.
+pub fn header_hash_for_sign(header: &BlockHeader) -> Result<sha256d::Hash, Error> {
+    let mut data = Vec::with_capacity(104);
+    header.version.consensus_encode(&mut data)?;
+    header.prev_blockhash.consensus_encode(&mut data)?;
+    header.merkle_root.consensus_encode(&mut data)?;
+    header.im_merkle_root.consensus_encode(&mut data)?;
+    header.time.consensus_encode(&mut data)?;
+    Ok(sha256d::Hash::hash(&data))
+}
+
+/// Verify the proof in block header was signed with the aggregated public key.
+pub fn verify_block_proof(
+    secp: &Secp256k1<All>,
+    header: &BlockHeader,
+    public_key: &PublicKey,
+) -> Result<bool, Error> {
+    let hash = header_hash_for_sign(header)?;
+    Ok(verify_schnorr(
+        secp,
+        &header.proof[..],
+        public_key,
+        &hash.into_inner(),
+    ))
+}
+
+/// Verify Schnorr signature which is used in Tapyrus.
+///
+/// The signature is 64 bytes `r || s`. It is valid when `R = s*G - e*P` has quadratic residue y
+/// coordinate and its x coordinate equals to `r`, where `e = SHA256(r || P || message)`.
+pub fn verify_schnorr(
+    secp: &Secp256k1<All>,
+    sig: &[u8],
+    public_key: &PublicKey,
+    message: &[u8; 32],
+) -> bool {
+    if sig.len() != 64 {
+        return false;
+    }
+    let (r, s) = sig.split_at(32);
+
+    let n = BigUint::from_bytes_be(&CURVE_ORDER);
+    let p = BigUint::from_bytes_be(&FIELD_SIZE);
+    if BigUint::from_bytes_be(r) >= p {
+        return false;
+    }
+
+    // s*G. SecretKey::from_slice fails when s is zero or not less than the curve order.
+    let s_g = match SecretKey::from_slice(s) {
+        Ok(s) => PublicKey::from_secret_key(secp, &s),
+        Err(_) => return false,
+    };
+
+    // -e*P
+    let mut engine = sha256::Hash::engine();
+    engine.input(r);
+    engine.input(&public_key.serialize());
+    engine.input(message);
+    let e = BigUint::from_bytes_be(&sha256::Hash::from_engine(engine).into_inner()) % &n;
+    let negated_e = (&n - e) % &n;
+    let mut e_p = *public_key;
+    if e_p.mul_assign(secp, &to_32_bytes(&negated_e)).is_err() {
+        return false;
+    }
+
+    // R = s*G - e*P
+    let point = match s_g.combine(&e_p) {
+        Ok(point) => point.serialize_uncompressed(),
+        Err(_) => return false,
+    };
+
+    if &point[1..33] != r {
+        return false;
+    }
+
+    // Check y coordinate is quadratic residue with Euler's criterion.
+    let y = BigUint::from_bytes_be(&point[33..65]);
+    let exponent = (&p - BigUint::from(1u32)) >> 1;
+    y.modpow(&exponent, &p) == BigUint::from(1u32)
+}
+
+/// Convert to 32 bytes big endian array.
+pub fn to_32_bytes(value: &BigUint) -> [u8; 32] {
+    let bytes = value.to_bytes_be();
+    let mut result = [0u8; 32];
+    result[32 - bytes.len()..].copy_from_slice(&bytes);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{
+        get_test_aggregated_public_key, get_test_genesis_block, get_test_headers,
+    };
+
+    #[test]
+    fn test_aggregated_public_key() {
+        let genesis = get_test_genesis_block();
+        assert_eq!(
+            aggregated_public_key(&genesis).unwrap(),
+            get_test_aggregated_public_key()
+        );
+    }
+
+    #[test]
+    fn test_verify_block_proof() {
+        let secp = Secp256k1::new();
+        let public_key = get_test_aggregated_public_key();
+
+        for header in get_test_headers(0, 10) {
+            assert!(verify_block_proof(&secp, &header, &public_key).unwrap());
+        }
+
+        // modified header must not be valid.
+        let mut header = get_test_headers(1, 1).pop().unwrap();
+        header.time += 1;
+        assert!(!verify_block_proof(&secp, &header, &public_key).unwrap());
+
+        // proof signed by other key must not be valid.
+        let other_key =
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[1; 32]).unwrap());
+        let header = get_test_headers(1, 1).pop().unwrap();
+        assert!(!verify_block_proof(&secp, &header, &other_key).unwrap());
+    }
+}
@@ -0,0 +1,513 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{BlockIndex, ChainStore, Error, Federation};
+use bitcoin_hashes::sha256d;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tapyrus::consensus::{deserialize, serialize, Encodable};
+use tapyrus::BitcoinHash;
+
+/// File name of block headers file in datadir.
+pub const HEADERS_FILE_NAME: &str = "headers.dat";
+
+/// File name of federations file in datadir.
+pub const FEDERATIONS_FILE_NAME: &str = "federations.dat";
+
+/// Size of serialized `next_blockhash` which is placed at the end of each record.
+const NEXT_BLOCKHASH_SIZE: u64 = 32;
+
+/// The maximum size of a serialized BlockIndex. Records with larger length are treated as broken.
+const MAX_RECORD_SIZE: u32 = 4096;
+
+/// This is a chain store which persists block indexes into a file in datadir.
+///
+/// The file is a sequence of records. Each record is a 4 bytes little endian length followed by
+/// the serialized BlockIndex. Records are only appended, except that `next_blockhash` of the
+/// previous tip is overwritten in place after a new tip was appended and synced.
+///
+/// The first record is the genesis block or a trusted checkpoint, and the following records have
+/// consecutive heights. The offset of each record is indexed on memory by height, and the height
+/// is indexed by block hash when the file is opened. If the last record is broken because the
+/// process was killed while writing it, the record is discarded, and `next_blockhash` of the
+/// last two records are repaired.
+///
+/// Federations which replaced the aggregated public key are kept in another file next to the
+/// headers file. Each line of the file is `<activation height>:<aggregated public key>`.
+pub struct FileChainStore {
+    file: File,
+    /// Offsets of records indexed by height from the first block.
+    offsets: Vec<u64>,
+    /// Height of the first block.
+    base_height: i32,
+    /// Heights of blocks indexed by block hash.
+    heights: HashMap<sha256d::Hash, i32>,
+    /// The position where next record will be written.
+    end: u64,
+    federations_path: PathBuf,
+    federations: Vec<Federation>,
+}
+
+impl FileChainStore {
+    /// Open headers file in `datadir`. The directory and the file are created if not exist.
+    pub fn open(datadir: &Path) -> Result<FileChainStore, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let path = datadir.join(HEADERS_FILE_NAME);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        let federations_path = datadir.join(FEDERATIONS_FILE_NAME);
+        let federations = load_federations(&federations_path)?;
+
+        let mut store = FileChainStore {
+            file,
+            offsets: vec![],
+            base_height: 0,
+            heights: HashMap::new(),
+            end: 0,
+            federations_path,
+            federations,
+        };
+        store.load()?;
+
+        info!(
+            "Load block headers from {}. height: {}",
+            path.display(),
+            store.height()
+        );
+
+        Ok(store)
+    }
+
+    /// Build index of records. The broken records at the end of the file are truncated.
+    fn load(&mut self) -> Result<(), Error> {
+        let file_len = self.file.metadata()?.len();
+        self.file.seek(SeekFrom::Start(0))?;
+
+        let mut offset = 0;
+        while offset < file_len {
+            match self.read_record(file_len) {
+                Ok(index) if self.offsets.is_empty() || index.height == self.height() + 1 => {
+                    if self.offsets.is_empty() {
+                        self.base_height = index.height;
+                    }
+                    self.offsets.push(offset);
+                    self.heights
+                        .insert(index.header.bitcoin_hash(), index.height);
+                    offset = self.file.seek(SeekFrom::Current(0))?;
+                }
+                Ok(_) | Err(Error::EncodeError(_)) => break,
+                Err(Error::IoError(ref e))
+                    if e.kind() == ErrorKind::UnexpectedEof
+                        || e.kind() == ErrorKind::InvalidData =>
+                {
+                    break
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if offset < file_len {
+            warn!(
+                "Headers file has broken record at offset {}. Truncate {} bytes.",
+                offset,
+                file_len - offset
+            );
+            self.file.set_len(offset)?;
+        }
+
+        self.end = offset;
+        self.repair_next_blockhash()
+    }
+
+    /// Make the tip point no block, and the previous block point the tip. They are broken if
+    /// the process was killed while the tip was being updated or disconnected.
+    fn repair_next_blockhash(&mut self) -> Result<(), Error> {
+        if self.offsets.is_empty() {
+            return Ok(());
+        }
+
+        let tip = self.read_record_at(self.offsets.len() - 1)?;
+        if tip.next_blockhash != sha256d::Hash::default() {
+            warn!("The tip of headers file points the next block. Repair it.");
+            self.set_next_blockhash(self.end, &sha256d::Hash::default())?;
+        }
+
+        if self.offsets.len() >= 2 {
+            let prev = self.read_record_at(self.offsets.len() - 2)?;
+            let tip_hash = tip.header.bitcoin_hash();
+            if prev.next_blockhash != tip_hash {
+                warn!("The previous block of the tip doesn't point the tip. Repair it.");
+                let tip_offset = self.offsets[self.offsets.len() - 1];
+                self.set_next_blockhash(tip_offset, &tip_hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a record at current position of the file. The record must end before `limit`.
+    fn read_record(&self, limit: u64) -> Result<BlockIndex, Error> {
+        let mut file = &self.file;
+        let len = file.read_u32::<LittleEndian>()?;
+        let remaining = limit.saturating_sub(file.seek(SeekFrom::Current(0))?);
+        if len > MAX_RECORD_SIZE || u64::from(len) > remaining {
+            return Err(Error::IoError(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid record length {}", len),
+            )));
+        }
+        let mut buf = vec![0u8; len as usize];
+        file.read_exact(&mut buf)?;
+        Ok(deserialize(&buf)?)
+    }
+
+    /// Read the record which is at `index` in offsets.
+    fn read_record_at(&self, index: usize) -> Result<BlockIndex, Error> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.offsets[index]))?;
+        self.read_record(self.end)
+    }
+
+    /// Append a record at the end of the file.
+    fn append(&mut self, index: &BlockIndex) -> Result<(), Error> {
+        let bytes = serialize(index);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_u32::<LittleEndian>(bytes.len() as u32)?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+
+        self.offsets.push(self.end);
+        self.heights
+            .insert(index.header.bitcoin_hash(), index.height);
+        self.end += 4 + bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Overwrite `next_blockhash` of the record which ends at `record_end`.
+    fn set_next_blockhash(&mut self, record_end: u64, hash: &sha256d::Hash) -> Result<(), Error> {
+        self.file
+            .seek(SeekFrom::Start(record_end - NEXT_BLOCKHASH_SIZE))?;
+        hash.consensus_encode(&mut self.file)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl ChainStore for FileChainStore {
+    fn initialize(&mut self, base: BlockIndex) -> Result<(), Error> {
+        if self.offsets.is_empty() {
+            self.base_height = base.height;
+            self.append(&base)?;
+        }
+        Ok(())
+    }
+
+    fn base_height(&self) -> i32 {
+        self.base_height
+    }
+
+    fn height(&self) -> i32 {
+        self.base_height + self.offsets.len() as i32 - 1
+    }
+
+    fn get(&self, height: i32) -> Option<BlockIndex> {
+        if height < self.base_height {
+            return None;
+        }
+
+        let index = (height - self.base_height) as usize;
+        if index >= self.offsets.len() {
+            return None;
+        }
+
+        match self.read_record_at(index) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                error!("Can not read block index at height {}: {:?}", height, e);
+                None
+            }
+        }
+    }
+
+    fn get_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex> {
+        self.heights.get(hash).and_then(|height| self.get(*height))
+    }
+
+    /// The new tip is appended and synced before the previous tip points it, so that the
+    /// previous tip never points a block which is not in the file.
+    fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error> {
+        let prev_end = self.end;
+        self.append(index)?;
+
+        if self.offsets.len() >= 2 {
+            self.set_next_blockhash(prev_end, &index.header.bitcoin_hash())?;
+        }
+        Ok(())
+    }
+
+    fn disconnect_tip(&mut self) -> Result<BlockIndex, Error> {
+        if self.offsets.len() <= 1 {
+            return Err(Error::CannotDisconnectGenesis);
+        }
+
+        let index = self.tip();
+        self.heights.remove(&index.header.bitcoin_hash());
+        let offset = self.offsets.pop().unwrap();
+        self.file.set_len(offset)?;
+        self.end = offset;
+        self.set_next_blockhash(offset, &sha256d::Hash::default())?;
+        Ok(index)
+    }
+
+    fn federations(&self) -> Vec<Federation> {
+        self.federations.clone()
+    }
+
+    /// The file is replaced atomically by renaming a temporary file.
+    fn save_federations(&mut self, federations: &[Federation]) -> Result<(), Error> {
+        let tmp_path = self.federations_path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for federation in federations {
+                writeln!(file, "{}", federation)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.federations_path)?;
+        self.federations = federations.to_vec();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Read federations file. Broken lines are ignored.
+fn load_federations(path: &Path) -> Result<Vec<Federation>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(Error::from(e)),
+    };
+
+    let mut federations = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match line.parse() {
+            Ok(federation) => federations.push(federation),
+            Err(_) => warn!("Ignore broken line in federations file: \"{}\"", line),
+        }
+    }
+    Ok(federations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{get_test_block_index, get_test_next_aggregated_public_key, TempDir};
+
+    fn open_store(dir: &TempDir) -> FileChainStore {
+        let mut store = FileChainStore::open(dir.path()).unwrap();
+        store.initialize(get_test_block_index(0)).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new("file_chain_store_test_store");
+        let mut store = open_store(&dir);
+
+        assert_eq!(store.height(), 0);
+        assert_eq!(store.get(0), Some(get_test_block_index(0)));
+
+        for i in 1..11 {
+            store.update_tip(&get_test_block_index(i)).unwrap();
+        }
+        assert_eq!(store.height(), 10);
+        assert_eq!(store.tip(), get_test_block_index(10));
+
+        let mut expected = get_test_block_index(3);
+        expected.next_blockhash = get_test_block_index(4).header.bitcoin_hash();
+        assert_eq!(store.get(3), Some(expected));
+        assert_eq!(store.get(11), None);
+
+        assert_eq!(store.disconnect_tip().unwrap(), get_test_block_index(10));
+        assert_eq!(store.height(), 9);
+        assert_eq!(store.tip(), get_test_block_index(9));
+
+        // new tip can be connected after disconnection.
+        store.update_tip(&get_test_block_index(10)).unwrap();
+        assert_eq!(store.tip(), get_test_block_index(10));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = TempDir::new("file_chain_store_test_reopen");
+        {
+            let mut store = open_store(&dir);
+            for i in 1..6 {
+                store.update_tip(&get_test_block_index(i)).unwrap();
+            }
+        }
+
+        let mut store = open_store(&dir);
+        assert_eq!(store.height(), 5);
+        assert_eq!(store.tip(), get_test_block_index(5));
+
+        // hash index is rebuilt.
+        let hash = get_test_block_index(3).header.bitcoin_hash();
+        assert_eq!(store.get_by_hash(&hash).unwrap().height, 3);
+
+        store.update_tip(&get_test_block_index(6)).unwrap();
+        assert_eq!(
+            store.get(5).unwrap().next_blockhash,
+            get_test_block_index(6).header.bitcoin_hash()
+        );
+    }
+
+    #[test]
+    fn test_truncated_last_record() {
+        let dir = TempDir::new("file_chain_store_test_truncated_last_record");
+        {
+            let mut store = open_store(&dir);
+            for i in 1..6 {
+                store.update_tip(&get_test_block_index(i)).unwrap();
+            }
+        }
+
+        // drop a part of the last record like the process was killed while writing.
+        let path = dir.path().join(HEADERS_FILE_NAME);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 10).unwrap();
+
+        let mut store = open_store(&dir);
+        assert_eq!(store.height(), 4);
+        assert_eq!(store.tip(), get_test_block_index(4));
+
+        // new record can be appended after the broken record was truncated.
+        store.update_tip(&get_test_block_index(5)).unwrap();
+        assert_eq!(store.height(), 5);
+        assert_eq!(store.tip(), get_test_block_index(5));
+
+        let store = open_store(&dir);
+        assert_eq!(store.height(), 5);
+    }
+
+    #[test]
+    fn test_repair_next_blockhash() {
+        let dir = TempDir::new("file_chain_store_test_repair_next_blockhash");
+        {
+            let mut store = open_store(&dir);
+            for i in 1..6 {
+                store.update_tip(&get_test_block_index(i)).unwrap();
+            }
+
+            // the process was killed after the new tip was appended, but before the previous
+            // tip was updated.
+            store.append(&get_test_block_index(6)).unwrap();
+            assert_eq!(
+                store.get(5).unwrap().next_blockhash,
+                sha256d::Hash::default()
+            );
+
+            // and a broken pointer to a block which is not in the file.
+            let hash = get_test_block_index(7).header.bitcoin_hash();
+            let end = store.end;
+            store.set_next_blockhash(end, &hash).unwrap();
+        }
+
+        let store = open_store(&dir);
+        assert_eq!(store.height(), 6);
+        assert_eq!(store.tip(), get_test_block_index(6));
+        assert_eq!(
+            store.get(5).unwrap().next_blockhash,
+            get_test_block_index(6).header.bitcoin_hash()
+        );
+    }
+
+    #[test]
+    fn test_invalid_record_length() {
+        let dir = TempDir::new("file_chain_store_test_invalid_record_length");
+        {
+            let mut store = open_store(&dir);
+            for i in 1..4 {
+                store.update_tip(&get_test_block_index(i)).unwrap();
+            }
+        }
+
+        // append a record whose length is larger than the file.
+        let path = dir.path().join(HEADERS_FILE_NAME);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_u32::<LittleEndian>(0xffff_ffff).unwrap();
+        file.write_all(&[0u8; 16]).unwrap();
+
+        let store = open_store(&dir);
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.tip(), get_test_block_index(3));
+    }
+
+    #[test]
+    fn test_start_from_checkpoint() {
+        let dir = TempDir::new("file_chain_store_test_start_from_checkpoint");
+        {
+            let mut store = FileChainStore::open(dir.path()).unwrap();
+            store.initialize(get_test_block_index(10)).unwrap();
+            for i in 11..16 {
+                store.update_tip(&get_test_block_index(i)).unwrap();
+            }
+            assert_eq!(store.base_height(), 10);
+            assert_eq!(store.get(9), None);
+        }
+
+        // the first block is kept when the store is opened again.
+        let mut store = open_store(&dir);
+        assert_eq!(store.base_height(), 10);
+        assert_eq!(store.height(), 15);
+        assert_eq!(store.get(0), None);
+        assert_eq!(store.tip(), get_test_block_index(15));
+        let hash = get_test_block_index(12).header.bitcoin_hash();
+        assert_eq!(store.get_by_hash(&hash).unwrap().height, 12);
+
+        for _ in 11..16 {
+            store.disconnect_tip().unwrap();
+        }
+        assert!(store.disconnect_tip().is_err());
+        assert_eq!(store.tip().height, 10);
+    }
+
+    #[test]
+    fn test_save_federations() {
+        let dir = TempDir::new("file_chain_store_test_save_federations");
+        let federation = Federation {
+            activation_height: 10,
+            aggregated_public_key: get_test_next_aggregated_public_key(),
+        };
+        {
+            let mut store = open_store(&dir);
+            assert_eq!(store.federations(), vec![]);
+            store.save_federations(&[federation]).unwrap();
+            assert_eq!(store.federations(), vec![federation]);
+        }
+
+        // broken lines are ignored.
+        let path = dir.path().join(FEDERATIONS_FILE_NAME);
+        let mut content = fs::read_to_string(&path).unwrap();
+        content.push_str("broken\n");
+        fs::write(&path, content).unwrap();
+
+        let store = open_store(&dir);
+        assert_eq!(store.federations(), vec![federation]);
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Error, FilterHeaderIndex, FilterHeaderStore};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tapyrus::consensus::{deserialize, Encodable};
+
+/// File name of filter headers file in datadir.
+pub const FILTER_HEADERS_FILE_NAME: &str = "filter_headers.dat";
+
+/// Size of a record. block hash(32bytes) + filter header(32bytes)
+const RECORD_SIZE: u64 = 64;
+
+/// This is a filter header store which persists filter headers into a file in datadir.
+///
+/// The file is a sequence of fixed size records, so that the record at a height is found without
+/// index. If the last record is broken because the process was killed while writing it, the record
+/// is discarded.
+pub struct FileFilterHeaderStore {
+    file: File,
+    /// The number of records.
+    len: u64,
+}
+
+impl FileFilterHeaderStore {
+    /// Open filter headers file in `datadir`. The directory and the file are created if not exist.
+    pub fn open(datadir: &Path) -> Result<FileFilterHeaderStore, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let path = datadir.join(FILTER_HEADERS_FILE_NAME);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        let file_len = file.metadata()?.len();
+        if file_len % RECORD_SIZE != 0 {
+            warn!(
+                "Filter headers file has broken record. Truncate {} bytes.",
+                file_len % RECORD_SIZE
+            );
+            file.set_len(file_len - file_len % RECORD_SIZE)?;
+        }
+
+        let store = FileFilterHeaderStore {
+            file,
+            len: file_len / RECORD_SIZE,
+        };
+        info!(
+            "Load filter headers from {}. height: {}",
+            path.display(),
+            store.height()
+        );
+        Ok(store)
+    }
+
+    fn read_record(&self, height: i32) -> Result<FilterHeaderIndex, Error> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(height as u64 * RECORD_SIZE))?;
+        let mut buf = [0u8; RECORD_SIZE as usize];
+        file.read_exact(&mut buf)?;
+        Ok(FilterHeaderIndex {
+            block_hash: deserialize(&buf[..32])?,
+            filter_header: deserialize(&buf[32..])?,
+        })
+    }
+}
+
+impl FilterHeaderStore for FileFilterHeaderStore {
+    fn height(&self) -> i32 {
+        self.len as i32 - 1
+    }
+
+    fn get(&self, height: i32) -> Option<FilterHeaderIndex> {
+        if height < 0 || height > self.height() {
+            return None;
+        }
+
+        match self.read_record(height) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                error!("Can not read filter header at height {}: {:?}", height, e);
+                None
+            }
+        }
+    }
+
+    fn push(&mut self, index: FilterHeaderIndex) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(RECORD_SIZE as usize);
+        index.block_hash.consensus_encode(&mut bytes)?;
+        index.filter_header.consensus_encode(&mut bytes)?;
+
+        self.file.seek(SeekFrom::Start(self.len * RECORD_SIZE))?;
+        self.file.write_all(&bytes)?;
+        self.file.flush()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: i32) -> Result<(), Error> {
+        let len = (height + 1) as u64;
+        if len < self.len {
+            self.file.set_len(len * RECORD_SIZE)?;
+            self.len = len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::TempDir;
+    use bitcoin_hashes::{sha256d, Hash};
+
+    fn index(i: u8) -> FilterHeaderIndex {
+        FilterHeaderIndex {
+            block_hash: sha256d::Hash::hash(&[i]),
+            filter_header: sha256d::Hash::hash(&[i, i]),
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new("file_filter_header_store_test_store");
+        {
+            let mut store = FileFilterHeaderStore::open(dir.path()).unwrap();
+            assert_eq!(store.height(), -1);
+            assert_eq!(store.get(0), None);
+
+            for i in 0..5 {
+                store.push(index(i)).unwrap();
+            }
+            assert_eq!(store.height(), 4);
+            assert_eq!(store.get(2), Some(index(2)));
+            assert_eq!(store.get(5), None);
+
+            store.truncate(2).unwrap();
+            assert_eq!(store.height(), 2);
+            store.push(index(10)).unwrap();
+            assert_eq!(store.get(3), Some(index(10)));
+        }
+
+        let store = FileFilterHeaderStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.get(3), Some(index(10)));
+    }
+
+    #[test]
+    fn test_truncated_last_record() {
+        let dir = TempDir::new("file_filter_header_store_test_truncated_last_record");
+        {
+            let mut store = FileFilterHeaderStore::open(dir.path()).unwrap();
+            for i in 0..3 {
+                store.push(index(i)).unwrap();
+            }
+        }
+
+        let path = dir.path().join(FILTER_HEADERS_FILE_NAME);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(RECORD_SIZE * 3 - 10).unwrap();
+
+        let mut store = FileFilterHeaderStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 1);
+        store.push(index(2)).unwrap();
+        assert_eq!(store.get(2), Some(index(2)));
+    }
+}
@@ -2,6 +2,12 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+mod file_chain_store;
+mod file_filter_header_store;
 mod on_memory_chain_store;
+mod on_memory_filter_header_store;
 
+pub use file_chain_store::FileChainStore;
+pub use file_filter_header_store::FileFilterHeaderStore;
 pub use on_memory_chain_store::OnMemoryChainStore;
+pub use on_memory_filter_header_store::OnMemoryFilterHeaderStore;
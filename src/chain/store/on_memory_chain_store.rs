@@ -2,58 +2,86 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::chain::{BlockIndex, ChainStore};
+use crate::chain::{BlockIndex, ChainStore, Error};
 use bitcoin_hashes::sha256d;
-use tapyrus::{BitcoinHash, Block};
+use std::collections::HashMap;
+use tapyrus::BitcoinHash;
 
 pub struct OnMemoryChainStore {
+    /// Block indexes from the first block of the chain.
     headers: Vec<BlockIndex>,
+    /// Height of the first block.
+    base_height: i32,
+    /// Heights of blocks indexed by block hash.
+    heights: HashMap<sha256d::Hash, i32>,
 }
 
 impl ChainStore for OnMemoryChainStore {
-    fn initialize(&mut self, genesis: Block) {
-        if let None = self.get(0) {
-            let genesis = BlockIndex {
-                header: genesis.header,
-                height: 0,
-                next_blockhash: sha256d::Hash::default(),
-            };
-
-            self.headers = vec![genesis];
+    fn initialize(&mut self, base: BlockIndex) -> Result<(), Error> {
+        if self.headers.is_empty() {
+            self.base_height = base.height;
+            self.heights = HashMap::new();
+            self.heights.insert(base.header.bitcoin_hash(), base.height);
+            self.headers = vec![base];
         }
+        Ok(())
+    }
+
+    fn base_height(&self) -> i32 {
+        self.base_height
     }
 
     fn height(&self) -> i32 {
-        self.headers.len() as i32 - 1
+        self.base_height + self.headers.len() as i32 - 1
     }
 
     fn get(&self, height: i32) -> Option<BlockIndex> {
-        match self.headers.get(height as usize) {
-            Some(index) => Some(index.clone()),
-            None => None,
+        if height < self.base_height {
+            return None;
         }
+        self.headers
+            .get((height - self.base_height) as usize)
+            .cloned()
+    }
+
+    fn get_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex> {
+        self.heights.get(hash).and_then(|height| self.get(*height))
     }
 
-    fn update_tip(&mut self, index: &BlockIndex) {
-        let mut tip = self.tip_mut();
-        tip.next_blockhash = index.header.bitcoin_hash();
+    fn update_tip(&mut self, index: &BlockIndex) -> Result<(), Error> {
+        let hash = index.header.bitcoin_hash();
+        let tip = self.tip_mut();
+        tip.next_blockhash = hash;
 
+        self.heights.insert(hash, index.height);
         self.headers.push(index.clone());
+        Ok(())
+    }
+
+    fn disconnect_tip(&mut self) -> Result<BlockIndex, Error> {
+        if self.headers.len() <= 1 {
+            return Err(Error::CannotDisconnectGenesis);
+        }
+
+        let index = self.headers.pop().unwrap();
+        self.heights.remove(&index.header.bitcoin_hash());
+        self.tip_mut().next_blockhash = sha256d::Hash::default();
+        Ok(index)
     }
 }
 
 impl OnMemoryChainStore {
     pub fn new() -> OnMemoryChainStore {
-        OnMemoryChainStore { headers: vec![] }
-    }
-
-    fn get_mut(&mut self, height: i32) -> Option<&mut BlockIndex> {
-        self.headers.get_mut(height as usize)
+        OnMemoryChainStore {
+            headers: vec![],
+            base_height: 0,
+            heights: HashMap::new(),
+        }
     }
 
     fn tip_mut(&mut self) -> &mut BlockIndex {
-        // Genesis block always exist, so we can call unwrap()
-        self.get_mut(self.height()).unwrap()
+        // The first block always exist, so we can call unwrap()
+        self.headers.last_mut().unwrap()
     }
 }
 
@@ -62,25 +90,24 @@ mod tests {
     use super::*;
     use crate::chain::ChainStore;
     use crate::test_helper::get_test_block_index;
-    use tapyrus::blockdata::constants::genesis_block;
-    use tapyrus::{BitcoinHash, Network};
+    use tapyrus::BitcoinHash;
 
     #[test]
     fn test_store() {
         let mut store = OnMemoryChainStore::new();
-        store.initialize(genesis_block(Network::Regtest));
+        store.initialize(get_test_block_index(0)).unwrap();
 
         assert!(store.get(0).is_some());
         assert_eq!(store.height(), 0);
 
         // test update_tip
-        store.update_tip(&get_test_block_index(1));
+        store.update_tip(&get_test_block_index(1)).unwrap();
         assert_eq!(store.height(), 1);
         assert_eq!(store.tip(), get_test_block_index(1));
 
         // update tip to 10
         for i in 2..11 {
-            store.update_tip(&get_test_block_index(i));
+            store.update_tip(&get_test_block_index(i)).unwrap();
         }
         assert_eq!(store.height(), 10);
         assert_eq!(store.tip(), get_test_block_index(10));
@@ -89,5 +116,48 @@ mod tests {
         let mut expected = get_test_block_index(3);
         expected.next_blockhash = get_test_block_index(4).header.bitcoin_hash();
         assert_eq!(store.get(3), Some(expected));
+
+        // test get_by_hash()
+        let hash = get_test_block_index(3).header.bitcoin_hash();
+        assert_eq!(store.get_by_hash(&hash), store.get(3));
+        assert!(store.contains(&hash));
+
+        // test disconnect_tip()
+        let hash = get_test_block_index(10).header.bitcoin_hash();
+        assert!(store.contains(&hash));
+        assert_eq!(store.disconnect_tip().unwrap(), get_test_block_index(10));
+        assert!(!store.contains(&hash));
+        assert_eq!(store.height(), 9);
+        assert_eq!(store.tip(), get_test_block_index(9));
+    }
+
+    #[test]
+    fn test_disconnect_genesis() {
+        let mut store = OnMemoryChainStore::new();
+        store.initialize(get_test_block_index(0)).unwrap();
+
+        assert!(store.disconnect_tip().is_err());
+        assert_eq!(store.height(), 0);
+    }
+
+    #[test]
+    fn test_start_from_checkpoint() {
+        let mut store = OnMemoryChainStore::new();
+        store.initialize(get_test_block_index(10)).unwrap();
+        assert_eq!(store.base_height(), 10);
+        assert_eq!(store.height(), 10);
+        assert_eq!(store.get(0), None);
+        assert_eq!(store.get(9), None);
+        assert_eq!(store.tip(), get_test_block_index(10));
+
+        store.update_tip(&get_test_block_index(11)).unwrap();
+        assert_eq!(store.height(), 11);
+        assert_eq!(store.tip(), get_test_block_index(11));
+        let hash = get_test_block_index(11).header.bitcoin_hash();
+        assert_eq!(store.get_by_hash(&hash), Some(get_test_block_index(11)));
+
+        assert_eq!(store.disconnect_tip().unwrap(), get_test_block_index(11));
+        assert!(store.disconnect_tip().is_err());
+        assert_eq!(store.height(), 10);
     }
 }
@@ -0,0 +1,72 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Error, FilterHeaderIndex, FilterHeaderStore};
+
+pub struct OnMemoryFilterHeaderStore {
+    headers: Vec<FilterHeaderIndex>,
+}
+
+impl OnMemoryFilterHeaderStore {
+    pub fn new() -> OnMemoryFilterHeaderStore {
+        OnMemoryFilterHeaderStore { headers: vec![] }
+    }
+}
+
+impl FilterHeaderStore for OnMemoryFilterHeaderStore {
+    fn height(&self) -> i32 {
+        self.headers.len() as i32 - 1
+    }
+
+    fn get(&self, height: i32) -> Option<FilterHeaderIndex> {
+        if height < 0 {
+            return None;
+        }
+        self.headers.get(height as usize).cloned()
+    }
+
+    fn push(&mut self, index: FilterHeaderIndex) -> Result<(), Error> {
+        self.headers.push(index);
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: i32) -> Result<(), Error> {
+        self.headers.truncate((height + 1) as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::{sha256d, Hash};
+
+    fn index(i: u8) -> FilterHeaderIndex {
+        FilterHeaderIndex {
+            block_hash: sha256d::Hash::hash(&[i]),
+            filter_header: sha256d::Hash::hash(&[i, i]),
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let mut store = OnMemoryFilterHeaderStore::new();
+        assert_eq!(store.height(), -1);
+        assert_eq!(store.get(0), None);
+
+        for i in 0..5 {
+            store.push(index(i)).unwrap();
+        }
+        assert_eq!(store.height(), 4);
+        assert_eq!(store.get(2), Some(index(2)));
+        assert_eq!(store.get(-1), None);
+
+        store.truncate(1).unwrap();
+        assert_eq!(store.height(), 1);
+        assert_eq!(store.get(2), None);
+
+        store.truncate(-1).unwrap();
+        assert_eq!(store.height(), -1);
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! # Chain parameters
+//!
+//! Parameters of the network which the SPV node works on. A Tapyrus network is identified by its
+//! network id, and magic bytes of P2P messages are derived from it.
+//!
+//! Presets of the public networks fill in the network id, magic bytes, default port and coin
+//! type. Their genesis blocks, DNS seeds and checkpoints are not embedded yet, so the genesis block
+//! is passed to the presets as well as to `ChainParams::custom`, and DNS seeds and checkpoints can
+//! be added to the returned parameters.
+
+use crate::chain::{Checkpoint, Federation, TrustedCheckpoint};
+use crate::network::{ProtocolVersion, BITCOIN_PROTOCOL_VERSION, TAPYRUS_PROTOCOL_VERSION};
+use crate::wallet::{TAPYRUS_COIN_TYPE, TESTNET_COIN_TYPE};
+use std::net::{IpAddr, SocketAddr};
+use tapyrus::network::constants::Network;
+use tapyrus::Block;
+
+/// Network id of Tapyrus production network.
+pub const PROD_NETWORK_ID: u32 = 1;
+
+/// Network id of Tapyrus development network.
+pub const DEV_NETWORK_ID: u32 = 1_905_960_821;
+
+/// Default P2P port of Tapyrus production network.
+pub const PROD_DEFAULT_PORT: u16 = 2357;
+
+/// Default P2P port of Tapyrus development network. Custom networks use it too.
+pub const DEV_DEFAULT_PORT: u16 = 12383;
+
+/// Magic bytes of a Tapyrus network are the sum of its network id and this value.
+const NETWORK_MAGIC_BASE: u32 = 33_550_335;
+
+/// Return magic bytes of P2P messages on the Tapyrus network.
+///
+/// Tapyrus Core sends the sum of the network id and 33550335 in big endian. Magic bytes are read
+/// as little endian integer by the message codec, so the byte order is swapped here.
+pub fn network_magic(network_id: u32) -> u32 {
+    network_id.wrapping_add(NETWORK_MAGIC_BASE).swap_bytes()
+}
+
+/// Parameters for Blockchain network
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// Network type which decides the format of addresses and keys
+    pub network: Network,
+    /// Network id of the Tapyrus network
+    pub network_id: u32,
+    /// Magic bytes of P2P messages as little endian integer
+    pub magic: u32,
+    /// Port of remote peers whose address doesn't have a port
+    pub default_port: u16,
+    /// Coin type in BIP44 paths which wallets use by default
+    pub coin_type: u32,
+    /// Protocol version which the node sends and requires of peers
+    pub protocol_version: ProtocolVersion,
+    /// Host names of DNS seeds which return addresses of peers
+    pub dns_seeds: Vec<String>,
+    /// Genesis block for network to be connected
+    pub genesis: Block,
+    /// Federations which replace the aggregated public key in the genesis block from their
+    /// activation heights.
+    pub federations: Vec<Federation>,
+    /// Block hashes which the active chain must have at their heights.
+    pub checkpoints: Vec<Checkpoint>,
+    /// Block header which the chain starts from instead of the genesis block. Block headers
+    /// before it are not downloaded, and transactions in them are not found. It can't be used
+    /// with FilterMode::CompactFilter.
+    pub trusted_checkpoint: Option<TrustedCheckpoint>,
+}
+
+impl ChainParams {
+    /// Parameters of Tapyrus production network with the genesis block of the network. Addresses
+    /// are encoded for mainnet.
+    pub fn prod(genesis: Block) -> ChainParams {
+        ChainParams {
+            network: Network::Bitcoin,
+            default_port: PROD_DEFAULT_PORT,
+            coin_type: TAPYRUS_COIN_TYPE,
+            ..ChainParams::custom(PROD_NETWORK_ID, network_magic(PROD_NETWORK_ID), genesis)
+        }
+    }
+
+    /// Parameters of Tapyrus development network with the genesis block of the network. Addresses
+    /// are encoded for testnet.
+    pub fn dev(genesis: Block) -> ChainParams {
+        ChainParams::custom(DEV_NETWORK_ID, network_magic(DEV_NETWORK_ID), genesis)
+    }
+
+    /// Parameters of a custom Tapyrus network which has arbitrary network id and magic bytes.
+    /// Addresses are encoded for testnet.
+    pub fn custom(network_id: u32, magic: u32, genesis: Block) -> ChainParams {
+        ChainParams {
+            network: Network::Testnet,
+            network_id,
+            magic,
+            default_port: DEV_DEFAULT_PORT,
+            coin_type: TESTNET_COIN_TYPE,
+            protocol_version: TAPYRUS_PROTOCOL_VERSION,
+            dns_seeds: vec![],
+            genesis,
+            federations: vec![],
+            checkpoints: vec![],
+            trusted_checkpoint: None,
+        }
+    }
+
+    /// Parameters of the network which uses magic bytes and the default port of the Bitcoin
+    /// network type.
+    pub fn legacy(network: Network, genesis: Block) -> ChainParams {
+        let default_port = match network {
+            Network::Bitcoin => 8333,
+            Network::Testnet => 18333,
+            Network::Regtest => 18444,
+        };
+        let coin_type = match network {
+            Network::Bitcoin => TAPYRUS_COIN_TYPE,
+            Network::Testnet | Network::Regtest => TESTNET_COIN_TYPE,
+        };
+        ChainParams {
+            network,
+            default_port,
+            coin_type,
+            protocol_version: BITCOIN_PROTOCOL_VERSION,
+            ..ChainParams::custom(0, network.magic(), genesis)
+        }
+    }
+
+    /// Return parameters of the network which has the name. The name is one of:
+    ///
+    /// * `prod` or `dev` for public Tapyrus networks.
+    /// * `bitcoin`, `testnet` or `regtest` for legacy networks.
+    /// * `<network id>` for a custom network whose magic bytes are derived from the network id.
+    /// * `<network id>:<magic bytes in hex>` for a custom network. Magic bytes are written in the
+    ///   order in which they are sent, like `01fff000`.
+    pub fn from_name(name: &str, genesis: Block) -> Option<ChainParams> {
+        match name {
+            "prod" => Some(ChainParams::prod(genesis)),
+            "dev" => Some(ChainParams::dev(genesis)),
+            "bitcoin" => Some(ChainParams::legacy(Network::Bitcoin, genesis)),
+            "testnet" => Some(ChainParams::legacy(Network::Testnet, genesis)),
+            "regtest" => Some(ChainParams::legacy(Network::Regtest, genesis)),
+            _ => {
+                let mut iter = name.splitn(2, ':');
+                let network_id = iter.next()?.parse().ok()?;
+                let magic = match iter.next() {
+                    Some(magic) => {
+                        let bytes = hex::decode(magic).ok()?;
+                        if bytes.len() != 4 {
+                            return None;
+                        }
+                        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                    }
+                    None => network_magic(network_id),
+                };
+                Some(ChainParams::custom(network_id, magic, genesis))
+            }
+        }
+    }
+
+    /// Parse address of a remote peer. The default port is used if it doesn't have a port.
+    pub fn remote_addr(&self, remote: &str) -> Option<SocketAddr> {
+        remote.parse().ok().or_else(|| {
+            remote
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, self.default_port))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+
+    #[test]
+    fn test_network_magic() {
+        assert_eq!(
+            network_magic(PROD_NETWORK_ID).to_le_bytes(),
+            [0x01, 0xff, 0xf0, 0x00]
+        );
+        assert_eq!(
+            network_magic(DEV_NETWORK_ID).to_le_bytes(),
+            [0x73, 0x9a, 0x97, 0x74]
+        );
+    }
+
+    #[test]
+    fn test_from_name() {
+        let genesis = get_test_genesis_block();
+
+        let prod = ChainParams::from_name("prod", genesis.clone()).unwrap();
+        assert_eq!(prod.network, Network::Bitcoin);
+        assert_eq!(prod.network_id, PROD_NETWORK_ID);
+        assert_eq!(prod.magic, network_magic(PROD_NETWORK_ID));
+        assert_eq!(prod.default_port, PROD_DEFAULT_PORT);
+        assert_eq!(prod.coin_type, TAPYRUS_COIN_TYPE);
+        assert_eq!(prod.protocol_version, TAPYRUS_PROTOCOL_VERSION);
+
+        let dev = ChainParams::from_name("dev", genesis.clone()).unwrap();
+        assert_eq!(dev.network, Network::Testnet);
+        assert_eq!(dev.network_id, DEV_NETWORK_ID);
+        assert_eq!(dev.magic, network_magic(DEV_NETWORK_ID));
+        assert_eq!(dev.default_port, DEV_DEFAULT_PORT);
+        assert_eq!(dev.coin_type, TESTNET_COIN_TYPE);
+
+        let regtest = ChainParams::from_name("regtest", genesis.clone()).unwrap();
+        assert_eq!(regtest.network, Network::Regtest);
+        assert_eq!(regtest.magic, Network::Regtest.magic());
+        assert_eq!(regtest.protocol_version, BITCOIN_PROTOCOL_VERSION);
+
+        let custom = ChainParams::from_name("10", genesis.clone()).unwrap();
+        assert_eq!(custom.network_id, 10);
+        assert_eq!(custom.magic, network_magic(10));
+        assert_eq!(custom.protocol_version, TAPYRUS_PROTOCOL_VERSION);
+
+        let custom = ChainParams::from_name("10:0a0b0c0d", genesis.clone()).unwrap();
+        assert_eq!(custom.network_id, 10);
+        assert_eq!(custom.magic.to_le_bytes(), [0x0a, 0x0b, 0x0c, 0x0d]);
+
+        assert!(ChainParams::from_name("mainnet", genesis.clone()).is_none());
+        assert!(ChainParams::from_name("10:0a0b0c", genesis.clone()).is_none());
+        assert!(ChainParams::from_name("10:zz", genesis).is_none());
+    }
+
+    #[test]
+    fn test_remote_addr() {
+        let params = ChainParams::dev(get_test_genesis_block());
+        assert_eq!(
+            params.remote_addr("127.0.0.1:2360"),
+            Some("127.0.0.1:2360".parse().unwrap())
+        );
+        assert_eq!(
+            params.remote_addr("127.0.0.1"),
+            Some("127.0.0.1:12383".parse().unwrap())
+        );
+        assert_eq!(
+            params.remote_addr("::1"),
+            Some("[::1]:12383".parse().unwrap())
+        );
+        assert_eq!(params.remote_addr("localhost:2360"), None);
+    }
+}
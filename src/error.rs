@@ -0,0 +1,46 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain;
+use crate::network;
+
+/// Errors in starting and running the SPV node
+#[derive(Debug)]
+pub enum Error {
+    /// The node is already running. It must be stopped before it is started again.
+    AlreadyRunning,
+    /// The address of a remote peer can not be parsed.
+    InvalidRemote(String),
+    /// Block headers in datadir start from a block at the height which is neither the genesis
+    /// block nor the trusted checkpoint.
+    UnknownChainBase(i32),
+    /// FilterMode::CompactFilter can not be used with the chain from a trusted checkpoint.
+    CompactFilterFromCheckpoint,
+    /// The chain can not be initialized, or the chain store can not be opened or flushed.
+    ChainError(chain::Error),
+    /// Stores of peers can not be opened, or the node stopped with the network error.
+    NetworkError(network::Error),
+    /// The node thread or the runtime can not be started.
+    IoError(std::io::Error),
+    /// The node thread panicked.
+    Panicked,
+}
+
+impl From<chain::Error> for Error {
+    fn from(e: chain::Error) -> Error {
+        Error::ChainError(e)
+    }
+}
+
+impl From<network::Error> for Error {
+    fn from(e: network::Error) -> Error {
+        Error::NetworkError(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! # Event module
+//!
+//! Events which the SPV node emits while it runs. Embedders subscribe to them to show sync
+//! progress and notifications of the wallet.
+
+use bitcoin_hashes::sha256d;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Found transactions are notified as confirmed when they have this number of confirmations.
+pub const NOTIFY_CONFIRMATIONS: u32 = 6;
+
+/// Event of the SPV node
+///
+/// Transactions are identified by `Transaction::txid`, which covers scriptSig of inputs, like
+/// `SPV::requested_peers` and rejections of broadcast transactions. Outpoints refer to
+/// malleability-fixed txids instead.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The tip of the active chain was updated.
+    NewTip {
+        /// Height of the new tip
+        height: i32,
+        /// Hash of the new tip
+        hash: sha256d::Hash,
+    },
+    /// Blocks above `fork_height` were removed from the active chain by reorganization.
+    Reorg {
+        /// Height of the last common block of the old and new chains
+        fork_height: i32,
+        /// Height of the new tip
+        height: i32,
+        /// Hash of the new tip
+        hash: sha256d::Hash,
+    },
+    /// Connection and handshake with a peer was established.
+    PeerConnected {
+        /// ID of the peer
+        peer_id: u64,
+        /// Address of the peer
+        addr: SocketAddr,
+    },
+    /// Connection with a peer was closed.
+    PeerDisconnected {
+        /// ID of the peer
+        peer_id: u64,
+        /// Address of the peer
+        addr: SocketAddr,
+    },
+    /// Progress of block header download.
+    SyncProgress {
+        /// Height of the active chain
+        height: i32,
+        /// The highest start height which connected peers reported. It is not less than
+        /// `height`.
+        target_height: i32,
+    },
+    /// A transaction which pays to watched scripts or spends watched outputs was found in a
+    /// block.
+    TransactionReceived {
+        /// Txid of the transaction
+        txid: sha256d::Hash,
+        /// Height of the block which contains the transaction
+        height: i32,
+    },
+    /// A found transaction reached NOTIFY_CONFIRMATIONS.
+    TransactionConfirmed {
+        /// Txid of the transaction
+        txid: sha256d::Hash,
+        /// Height of the block which contains the transaction
+        height: i32,
+    },
+}
+
+type Callback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Default)]
+struct Subscribers {
+    senders: Vec<UnboundedSender<Event>>,
+    callbacks: Vec<Callback>,
+}
+
+/// Deliver events to subscribers. Clones of the bus share subscribers.
+///
+/// Subscribers are either channels or callbacks. Callbacks are called on threads of the SPV node,
+/// so they should return quickly. They are called after subscribers are unlocked, so they can
+/// subscribe or emit events. A channel is unsubscribed when its receiver is dropped.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl EventBus {
+    /// Create bus without subscribers.
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// Return stream of events which are emitted after this call.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = unbounded_channel();
+        self.subscribers.lock().unwrap().senders.push(sender);
+        receiver
+    }
+
+    /// Call `callback` with events which are emitted after this call.
+    pub fn subscribe_fn<F>(&self, callback: F)
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .callbacks
+            .push(Arc::new(callback));
+    }
+
+    /// Send the event to all subscribers.
+    pub fn emit(&self, event: Event) {
+        trace!("Emit event: {:?}", event);
+        let callbacks = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let senders = std::mem::replace(&mut subscribers.senders, vec![]);
+            subscribers.senders = senders
+                .into_iter()
+                .filter_map(|mut sender| sender.try_send(event.clone()).ok().map(|_| sender))
+                .collect();
+            subscribers.callbacks.clone()
+        };
+        for callback in callbacks {
+            callback(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::Hash;
+    use tokio::prelude::{Future, Stream};
+
+    #[test]
+    fn test_emit_events() {
+        let events = EventBus::new();
+        let event = Event::NewTip {
+            height: 1,
+            hash: sha256d::Hash::hash(&[1]),
+        };
+
+        // events before subscription are not delivered.
+        events.emit(event.clone());
+        let receiver = events.subscribe();
+        let dropped = events.subscribe();
+        let received = Arc::new(Mutex::new(vec![]));
+        let callback_received = received.clone();
+        events.subscribe_fn(move |event| callback_received.lock().unwrap().push(event.clone()));
+
+        drop(dropped);
+        events.clone().emit(event.clone());
+        assert_eq!(events.subscribers.lock().unwrap().senders.len(), 1);
+        assert_eq!(*received.lock().unwrap(), vec![event.clone()]);
+
+        let (received, _) = receiver.into_future().wait().ok().unwrap();
+        assert_eq!(received, Some(event));
+    }
+
+    #[test]
+    fn test_subscribe_in_callback() {
+        let events = EventBus::new();
+        let received = Arc::new(Mutex::new(0));
+        let callback_events = events.clone();
+        let callback_received = received.clone();
+        events.subscribe_fn(move |_| {
+            *callback_received.lock().unwrap() += 1;
+            let _ = callback_events.subscribe();
+        });
+
+        events.emit(Event::NewTip {
+            height: 1,
+            hash: sha256d::Hash::hash(&[1]),
+        });
+        assert_eq!(*received.lock().unwrap(), 1);
+    }
+}
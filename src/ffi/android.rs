@@ -5,11 +5,22 @@
 extern crate android_logger;
 extern crate jni;
 
-use self::jni::objects::{JClass, JString};
+use self::jni::objects::{JClass, JObject, JString, JValue};
+use self::jni::sys::{jint, jintArray, jlong, jlongArray, jstring};
 use self::jni::JNIEnv;
-use crate::tapyrus_spv_run;
+use crate::ffi::c::{
+    addresses, federation, ffi_call, header, header_by_hash, header_hex, load_wallet,
+    parse_block_hash, parse_options, receive_address, ref_arg, status_code, tip, token_balance,
+    watch_address, FfiError, TapyrusSpvEvent,
+};
+use crate::{
+    tapyrus_spv_free, tapyrus_spv_last_error_message, NodeHandle, Options, SPV, TAPYRUS_SPV_OK,
+};
 use android_logger::{Config, FilterBuilder};
 use log::Level;
+use std::ffi::CStr;
+use std::ptr;
+use tapyrus::BitcoinHash;
 
 /// Make it possible to show logs on android
 #[no_mangle]
@@ -27,6 +38,60 @@ pub extern "system" fn Java_com_chaintope_tapyrus_spv_FFI_enableLog(_env: JNIEnv
     );
 }
 
+/// Run `f` catching panics. If it failed, throw RuntimeException with the error message and
+/// return `default`.
+fn jni_call<T, F>(env: &JNIEnv, default: T, f: F) -> T
+where
+    F: FnOnce() -> Result<T, FfiError>,
+{
+    let mut value = None;
+    let code = ffi_call(|| {
+        value = Some(f()?);
+        Ok(())
+    });
+    if code != TAPYRUS_SPV_OK {
+        let message = unsafe { CStr::from_ptr(tapyrus_spv_last_error_message()) };
+        if let Err(e) = env.throw_new("java/lang/RuntimeException", message.to_string_lossy()) {
+            error!("Can not throw java exception: {:?}", e);
+        }
+    }
+    value.unwrap_or(default)
+}
+
+fn string_arg(env: &JNIEnv, string: JString, name: &str) -> Result<String, FfiError> {
+    env.get_string(string)
+        .map(String::from)
+        .map_err(|_| FfiError::invalid_argument(format!("{} is not a valid string.", name)))
+}
+
+fn new_string(env: &JNIEnv, string: String) -> Result<jstring, FfiError> {
+    env.new_string(string)
+        .map(|string| string.into_inner())
+        .map_err(|e| FfiError::invalid_argument(format!("can not create string: {:?}", e)))
+}
+
+fn new_long_array(env: &JNIEnv, values: &[i64]) -> Result<jlongArray, FfiError> {
+    let array = env
+        .new_long_array(values.len() as i32)
+        .and_then(|array| env.set_long_array_region(array, 0, values).map(|_| array));
+    array.map_err(|e| FfiError::invalid_argument(format!("can not create array: {:?}", e)))
+}
+
+fn options_arg(
+    env: &JNIEnv,
+    remote: JString,
+    network: JString,
+    genesisHex: JString,
+    datadir: JString,
+) -> Result<Options, FfiError> {
+    parse_options(
+        &string_arg(env, remote, "remote")?,
+        &string_arg(env, network, "network")?,
+        &string_arg(env, genesisHex, "genesisHex")?,
+        &string_arg(env, datadir, "datadir")?,
+    )
+}
+
 /// Run spv node
 #[no_mangle]
 pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRun(
@@ -35,16 +100,362 @@ pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRun(
     remote: JString,
     network: JString,
     genesisHex: JString,
+    datadir: JString,
 ) {
-    tapyrus_spv_run(
-        env.get_string(remote)
-            .expect("invalid pattern string")
-            .as_ptr(),
-        env.get_string(network)
-            .expect("invalid pattern string")
-            .as_ptr(),
-        env.get_string(genesisHex)
-            .expect("invalid pattern string")
-            .as_ptr(),
-    )
+    jni_call(&env, (), || {
+        let options = options_arg(&env, remote, network, genesisHex, datadir)?;
+        SPV::new(options).run()?;
+        Ok(())
+    })
+}
+
+/// Create SPV instance and return its handle. It must be released with `spvFree`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvNew(
+    env: JNIEnv,
+    _: JClass,
+    remote: JString,
+    network: JString,
+    genesisHex: JString,
+    datadir: JString,
+) -> jlong {
+    jni_call(&env, 0, || {
+        let options = options_arg(&env, remote, network, genesisHex, datadir)?;
+        Ok(Box::into_raw(Box::new(SPV::new(options))) as jlong)
+    })
+}
+
+/// Release SPV instance which was created by `spvNew`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvFree(
+    _env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) {
+    tapyrus_spv_free(spv as *mut SPV);
+}
+
+/// Run SPV node of the instance. This function blocks while the node keeps following the tip.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvRunInstance(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) {
+    jni_call(&env, (), || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        spv.run()?;
+        Ok(())
+    })
+}
+
+/// Start SPV node on a background thread and return the handle of the node. Apps should stop the
+/// node with `spvStop` when they go to background.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvStart(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jlong {
+    jni_call(&env, 0, || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        Ok(Box::into_raw(Box::new(spv.start()?)) as jlong)
+    })
+}
+
+/// Stop SPV node and release the handle which was returned by `spvStart`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvStop(
+    env: JNIEnv,
+    _: JClass,
+    handle: jlong,
+) {
+    jni_call(&env, (), || {
+        if handle == 0 {
+            return Err(FfiError::invalid_argument("handle is null."));
+        }
+        Box::from_raw(handle as *mut NodeHandle).stop()?;
+        Ok(())
+    })
+}
+
+/// Return status of SPV node. The values are the same as TAPYRUS_SPV_STATUS_* constants.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_spvStatus(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jint {
+    jni_call(&env, 0, || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        Ok(status_code(spv.status()))
+    })
+}
+
+/// Watch transactions which pay to the address.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_watchAddress(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    address: JString,
+) {
+    jni_call(&env, (), || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        watch_address(spv, &string_arg(&env, address, "address")?)
+    })
+}
+
+/// Return height of the active chain which the node has synced.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getHeight(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jint {
+    jni_call(&env, 0, || Ok(ref_arg(spv as *const SPV, "spv")?.height()))
+}
+
+/// Return hex of the tip block hash.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getTipHash(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jstring {
+    jni_call(&env, ptr::null_mut(), || {
+        let tip = tip(ref_arg(spv as *const SPV, "spv")?)?;
+        new_string(&env, tip.header.bitcoin_hash().to_string())
+    })
+}
+
+/// Return hex of the serialized block header at the height in the active chain.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getHeader(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    height: jint,
+) -> jstring {
+    jni_call(&env, ptr::null_mut(), || {
+        let index = header(ref_arg(spv as *const SPV, "spv")?, height)?;
+        new_string(&env, header_hex(&index))
+    })
+}
+
+/// Return hex of the serialized block header whose hash is `hash` in the active chain.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getHeaderByHash(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    hash: JString,
+) -> jstring {
+    jni_call(&env, ptr::null_mut(), || {
+        let hash = parse_block_hash(&string_arg(&env, hash, "hash")?)?;
+        let index = header_by_hash(ref_arg(spv as *const SPV, "spv")?, &hash)?;
+        new_string(&env, header_hex(&index))
+    })
+}
+
+/// Return hex of the aggregated public key which signs the tip of the active chain.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getAggregatedPublicKey(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jstring {
+    jni_call(&env, ptr::null_mut(), || {
+        let federation = federation(ref_arg(spv as *const SPV, "spv")?)?;
+        new_string(
+            &env,
+            hex::encode(&federation.aggregated_public_key.serialize()[..]),
+        )
+    })
+}
+
+/// Return the height from which the aggregated public key of the active chain tip is active.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getAggregatedPublicKeyHeight(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jint {
+    jni_call(&env, 0, || {
+        Ok(federation(ref_arg(spv as *const SPV, "spv")?)?.activation_height)
+    })
+}
+
+/// Return progress of block header download as
+/// `[height, targetHeight, scannedHeight, peerCount]`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getSyncStatus(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jintArray {
+    jni_call(&env, ptr::null_mut(), || {
+        let status = ref_arg(spv as *const SPV, "spv")?.sync_status();
+        let values = [
+            status.height,
+            status.target_height,
+            status.scanned_height,
+            status.peer_count as i32,
+        ];
+        let array = env
+            .new_int_array(values.len() as i32)
+            .and_then(|array| env.set_int_array_region(array, 0, &values).map(|_| array));
+        array.map_err(|e| FfiError::invalid_argument(format!("can not create array: {:?}", e)))
+    })
+}
+
+/// Return the number of peers which the node is connected to.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getPeerCount(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jint {
+    jni_call(&env, 0, || {
+        Ok(ref_arg(spv as *const SPV, "spv")?.peer_count() as jint)
+    })
+}
+
+/// Create HD wallet from hex of the seed and load it.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_loadWallet(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    seedHex: JString,
+    coinType: jint,
+) {
+    jni_call(&env, (), || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        load_wallet(spv, &string_arg(&env, seedHex, "seedHex")?, coinType as u32)
+    })
+}
+
+/// Return the first unused receive address of the loaded wallet.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getReceiveAddress(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jstring {
+    jni_call(&env, ptr::null_mut(), || {
+        let address = receive_address(ref_arg(spv as *const SPV, "spv")?)?;
+        new_string(&env, address)
+    })
+}
+
+/// Return comma separated addresses of all keys which the loaded wallet derived.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getAddresses(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+) -> jstring {
+    jni_call(&env, ptr::null_mut(), || {
+        let addresses = addresses(ref_arg(spv as *const SPV, "spv")?)?;
+        new_string(&env, addresses)
+    })
+}
+
+/// Return balance of watched addresses as `[confirmed, unconfirmed]`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getBalance(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    minConfirmations: jint,
+) -> jlongArray {
+    jni_call(&env, ptr::null_mut(), || {
+        let balance = ref_arg(spv as *const SPV, "spv")?.balance(minConfirmations as u32);
+        new_long_array(
+            &env,
+            &[balance.confirmed as i64, balance.unconfirmed as i64],
+        )
+    })
+}
+
+/// Return balance of the token in watched addresses as `[confirmed, unconfirmed]`.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_getTokenBalance(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    colorId: JString,
+    minConfirmations: jint,
+) -> jlongArray {
+    jni_call(&env, ptr::null_mut(), || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        let color_id = string_arg(&env, colorId, "colorId")?;
+        let balance = token_balance(spv, &color_id, minConfirmations as u32)?;
+        new_long_array(
+            &env,
+            &[balance.confirmed as i64, balance.unconfirmed as i64],
+        )
+    })
+}
+
+/// Call `onEvent(int kind, int height, int otherHeight, long peerId, String data)` of the
+/// listener with events of the node. Arguments are the same as fields of TapyrusSpvEvent.
+#[no_mangle]
+pub unsafe extern "C" fn Java_com_chaintope_tapyrus_spv_FFI_subscribe(
+    env: JNIEnv,
+    _: JClass,
+    spv: jlong,
+    listener: JObject,
+) {
+    jni_call(&env, (), || {
+        let spv = ref_arg(spv as *const SPV, "spv")?;
+        let vm = env
+            .get_java_vm()
+            .map_err(|e| FfiError::invalid_argument(format!("can not get java vm: {:?}", e)))?;
+        let listener = env.new_global_ref(listener).map_err(|e| {
+            FfiError::invalid_argument(format!("can not make global reference: {:?}", e))
+        })?;
+
+        spv.subscribe_fn(move |event| {
+            let env = match vm.attach_current_thread() {
+                Ok(env) => env,
+                Err(e) => {
+                    error!("Can not attach thread to java vm: {:?}", e);
+                    return;
+                }
+            };
+
+            let (event, _data) = TapyrusSpvEvent::new(event);
+            let data = if event.data.is_null() {
+                JObject::null()
+            } else {
+                let data = CStr::from_ptr(event.data).to_string_lossy();
+                match env.new_string(data) {
+                    Ok(data) => JObject::from(data),
+                    Err(e) => {
+                        error!("Can not create java string: {:?}", e);
+                        return;
+                    }
+                }
+            };
+
+            let result = env.call_method(
+                listener.as_obj(),
+                "onEvent",
+                "(IIIJLjava/lang/String;)V",
+                &[
+                    JValue::Int(event.kind),
+                    JValue::Int(event.height),
+                    JValue::Int(event.other_height),
+                    JValue::Long(event.peer_id as i64),
+                    JValue::Object(data),
+                ],
+            );
+            if let Err(e) = result {
+                error!("Can not call event listener: {:?}", e);
+            }
+        });
+        Ok(())
+    })
 }
@@ -2,59 +2,1124 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::{ChainParams, Options, SPV};
+//! C interface of the SPV node.
+//!
+//! Functions return TAPYRUS_SPV_OK or one of TAPYRUS_SPV_ERROR_* codes, and results are written
+//! to out parameters. The message of the last error on the calling thread is returned by
+//! `tapyrus_spv_last_error_message`. Panics are caught and never unwind into the caller.
+
+use crate::{
+    Balance, BlockIndex, ChainParams, ColorIdentifier, Error, Event, Federation, FilterMode,
+    NodeHandle, NodeStatus, Options, Wallet, WalletOptions, DEFAULT_MAX_OUTBOUND_PEERS, SPV,
+};
+use bitcoin_hashes::sha256d;
 use env_logger::Env;
-use std::ffi::CStr;
-use std::os::raw::c_char;
-use tapyrus::consensus::deserialize;
-use tapyrus::Network;
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use tapyrus::consensus::{deserialize, serialize};
+use tapyrus::{Address, BitcoinHash};
+
+/// The function succeeded.
+pub const TAPYRUS_SPV_OK: i32 = 0;
+/// An argument is null, not a valid UTF-8 string or can not be parsed.
+pub const TAPYRUS_SPV_ERROR_INVALID_ARGUMENT: i32 = 1;
+/// The node is already running.
+pub const TAPYRUS_SPV_ERROR_ALREADY_RUNNING: i32 = 2;
+/// The library panicked. The instance may be in inconsistent state.
+pub const TAPYRUS_SPV_ERROR_PANIC: i32 = 3;
+/// The requested data is not found, e.g. block header above the tip.
+pub const TAPYRUS_SPV_ERROR_NOT_FOUND: i32 = 4;
+/// The function requires a wallet which is loaded by `tapyrus_spv_load_wallet`.
+pub const TAPYRUS_SPV_ERROR_NO_WALLET: i32 = 5;
+/// The node can not be started or stopped with an error, e.g. stores in datadir are broken.
+pub const TAPYRUS_SPV_ERROR_NODE_FAILED: i32 = 6;
+
+/// Error which is returned to the caller as error code and message.
+pub(crate) struct FfiError {
+    code: i32,
+    message: String,
+}
+
+impl FfiError {
+    pub(crate) fn new<S: Into<String>>(code: i32, message: S) -> FfiError {
+        FfiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn invalid_argument<S: Into<String>>(message: S) -> FfiError {
+        FfiError::new(TAPYRUS_SPV_ERROR_INVALID_ARGUMENT, message)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run `f` catching panics, record the error message and return the error code.
+pub(crate) fn ffi_call<F>(f: F) -> i32
+where
+    F: FnOnce() -> Result<(), FfiError>,
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        Err(FfiError::new(
+            TAPYRUS_SPV_ERROR_PANIC,
+            panic_message(payload),
+        ))
+    });
+
+    let (code, message) = match result {
+        Ok(()) => (TAPYRUS_SPV_OK, None),
+        Err(e) => {
+            error!("FFI call failed: {}", e.message);
+            let message = CString::new(e.message.replace('\0', ""))
+                .expect("message should not contain null.");
+            (e.code, Some(message))
+        }
+    };
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+    code
+}
+
+/// Return the string which `ptr` points to.
+pub(crate) unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, FfiError> {
+    if ptr.is_null() {
+        return Err(FfiError::invalid_argument(format!("{} is null.", name)));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| FfiError::invalid_argument(format!("{} is not a UTF-8 string.", name)))
+}
+
+/// Return the reference which `ptr` points to.
+pub(crate) unsafe fn ref_arg<'a, T>(ptr: *const T, name: &str) -> Result<&'a T, FfiError> {
+    ptr.as_ref()
+        .ok_or_else(|| FfiError::invalid_argument(format!("{} is null.", name)))
+}
+
+/// Return the mutable reference which `ptr` points to.
+pub(crate) unsafe fn mut_arg<'a, T>(ptr: *mut T, name: &str) -> Result<&'a mut T, FfiError> {
+    ptr.as_mut()
+        .ok_or_else(|| FfiError::invalid_argument(format!("{} is null.", name)))
+}
+
+/// Return string which the caller releases with `tapyrus_spv_free_string`.
+pub(crate) fn into_c_string(string: String) -> Result<*mut c_char, FfiError> {
+    CString::new(string)
+        .map(CString::into_raw)
+        .map_err(|_| FfiError::new(TAPYRUS_SPV_ERROR_PANIC, "string contains null."))
+}
+
+/// Release string which was returned by functions of this library. It does nothing if `string`
+/// is null.
+///
+/// # Safety
+///
+/// `string` must be a string returned by this library and must not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_free_string(string: *mut c_char) {
+    ffi_call(|| {
+        if !string.is_null() {
+            drop(CString::from_raw(string));
+        }
+        Ok(())
+    });
+}
 
-/// initialize logger
+/// Return the message of the error which the last function called on this thread returned, or
+/// null if it succeeded. The string is valid until the next function call on this thread.
+#[no_mangle]
+pub extern "C" fn tapyrus_spv_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| match *last_error.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// initialize logger. Calling it again has no effect.
 #[no_mangle]
 pub extern "C" fn tapyrus_enable_log() {
     let env = Env::new()
         .filter("RUST_LOG")
         .write_style("error,tapyrus_spv=trace");
 
-    env_logger::try_init_from_env(env).unwrap();
+    let _ = env_logger::try_init_from_env(env);
 }
 
-/// run spv
+/// Build options from arguments of `tapyrus_spv_new`.
+pub(crate) fn parse_options(
+    remote: &str,
+    network: &str,
+    genesis_hex: &str,
+    datadir: &str,
+) -> Result<Options, FfiError> {
+    let remotes = remote
+        .split(',')
+        .map(|remote| remote.trim().to_string())
+        .filter(|remote| !remote.is_empty())
+        .collect();
+
+    let genesis = hex::decode(genesis_hex)
+        .map_err(|_| FfiError::invalid_argument("genesis_hex is invalid hex."))
+        .and_then(|bytes| {
+            deserialize(&bytes)
+                .map_err(|_| FfiError::invalid_argument("genesis_hex is invalid block data."))
+        })?;
+    let chain_params = ChainParams::from_name(network, genesis).ok_or_else(|| {
+        FfiError::invalid_argument(
+            "network should be \"prod\", \"dev\", \"bitcoin\", \"testnet\", \"regtest\" or a network id.",
+        )
+    })?;
+
+    Ok(Options {
+        remotes,
+        max_outbound_peers: DEFAULT_MAX_OUTBOUND_PEERS,
+        datadir: datadir.to_string(),
+        chain_params,
+        filter_mode: FilterMode::BloomFilter,
+    })
+}
+
+/// Create SPV instance and write it to `spv`. It must be released with `tapyrus_spv_free`.
+///
+/// `remote` is a comma separated list of remote peer addresses. The default port of the network
+/// is used for addresses without a port. `network` is "prod" or "dev" for public Tapyrus
+/// networks, "bitcoin", "testnet" or "regtest" for legacy networks, or the network id of a custom
+/// network optionally followed by ":" and hex of its magic bytes. Block headers are stored in
+/// `datadir`, or kept on memory if it is an empty string.
+///
+/// # Safety
+///
+/// Arguments must be valid null terminated strings and `spv` must be a valid pointer.
 #[no_mangle]
-pub extern "C" fn tapyrus_spv_run(
+pub unsafe extern "C" fn tapyrus_spv_new(
     remote: *const c_char,
     network: *const c_char,
     genesis_hex: *const c_char,
-) {
-    let remote = unsafe { CStr::from_ptr(remote) }
-        .to_str()
-        .expect("wrong string passed as remote address.")
-        .to_string();
+    datadir: *const c_char,
+    spv: *mut *mut SPV,
+) -> i32 {
+    ffi_call(|| {
+        let out = mut_arg(spv, "spv")?;
+        let options = parse_options(
+            str_arg(remote, "remote")?,
+            str_arg(network, "network")?,
+            str_arg(genesis_hex, "genesis_hex")?,
+            str_arg(datadir, "datadir")?,
+        )?;
+        *out = Box::into_raw(Box::new(SPV::new(options)));
+        Ok(())
+    })
+}
 
-    let network = unsafe { CStr::from_ptr(network) }
-        .to_str()
-        .expect("wrong string passed as network.");
+/// Release SPV instance which was created by `tapyrus_spv_new`. It does nothing if `spv` is null.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and must not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_free(spv: *mut SPV) {
+    ffi_call(|| {
+        if !spv.is_null() {
+            drop(Box::from_raw(spv));
+        }
+        Ok(())
+    });
+}
 
-    let network = match network {
-        "bitcoin" => Network::Bitcoin,
-        "testnet" => Network::Testnet,
-        "regtest" => Network::Regtest,
-        _ => panic!("network should be \"bitcoin\" or \"testnet\" or \"regtest\""),
-    };
+/// Create SPV instance and run it. This function blocks while the node keeps following the tip
+/// of the chain. TAPYRUS_SPV_ERROR_NODE_FAILED is returned if the node can not be started or it
+/// stopped with an error.
+///
+/// Arguments are the same as `tapyrus_spv_new`.
+///
+/// # Safety
+///
+/// Arguments must be valid null terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_run(
+    remote: *const c_char,
+    network: *const c_char,
+    genesis_hex: *const c_char,
+    datadir: *const c_char,
+) -> i32 {
+    ffi_call(|| {
+        let options = parse_options(
+            str_arg(remote, "remote")?,
+            str_arg(network, "network")?,
+            str_arg(genesis_hex, "genesis_hex")?,
+            str_arg(datadir, "datadir")?,
+        )?;
+        SPV::new(options).run()?;
+        Ok(())
+    })
+}
 
-    let genesis_hex = unsafe { CStr::from_ptr(genesis_hex) }
-        .to_str()
-        .expect("wrong string passed as genesis_hex.");
+impl From<Error> for FfiError {
+    fn from(e: Error) -> FfiError {
+        match e {
+            Error::AlreadyRunning => FfiError::new(
+                TAPYRUS_SPV_ERROR_ALREADY_RUNNING,
+                "SPV node is already running.",
+            ),
+            Error::InvalidRemote(remote) => {
+                FfiError::invalid_argument(format!("can not parse remote: \"{}\"", remote))
+            }
+            Error::Panicked => FfiError::new(TAPYRUS_SPV_ERROR_PANIC, "SPV node panicked."),
+            e => FfiError::new(
+                TAPYRUS_SPV_ERROR_NODE_FAILED,
+                format!("SPV node failed: {:?}", e),
+            ),
+        }
+    }
+}
+
+/// Run SPV node of the instance. This function blocks while the node keeps following the tip of
+/// the chain, so other functions should be called from other threads. Errors are the same as
+/// `tapyrus_spv_run`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new`.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_run_instance(spv: *const SPV) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        spv.run()?;
+        Ok(())
+    })
+}
+
+/// The node is not running.
+pub const TAPYRUS_SPV_STATUS_STOPPED: i32 = 0;
+/// The node is following the tip of the chain.
+pub const TAPYRUS_SPV_STATUS_RUNNING: i32 = 1;
+/// The node is shutting down.
+pub const TAPYRUS_SPV_STATUS_STOPPING: i32 = 2;
+
+/// Return TAPYRUS_SPV_STATUS_* constant of the status.
+pub(crate) fn status_code(status: NodeStatus) -> i32 {
+    match status {
+        NodeStatus::Stopped => TAPYRUS_SPV_STATUS_STOPPED,
+        NodeStatus::Running => TAPYRUS_SPV_STATUS_RUNNING,
+        NodeStatus::Stopping => TAPYRUS_SPV_STATUS_STOPPING,
+    }
+}
 
-    let genesis = deserialize(&hex::decode(genesis_hex).expect("genesis_hex is invalid hex."))
-        .expect("genesis_hex is invalid block data");
+/// Start SPV node of the instance on a background thread and write the handle to `handle`. The
+/// node must be stopped with `tapyrus_spv_stop`. Stores in datadir are opened before this
+/// function returns, and TAPYRUS_SPV_ERROR_NODE_FAILED is returned if they can not be opened.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `handle` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_start(spv: *const SPV, handle: *mut *mut NodeHandle) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(handle, "handle")?;
+        *out = Box::into_raw(Box::new(spv.start()?));
+        Ok(())
+    })
+}
+
+/// Stop SPV node and release the handle. This function blocks until the node stopped and block
+/// headers are flushed. The node can be started again with `tapyrus_spv_start`.
+/// TAPYRUS_SPV_ERROR_NODE_FAILED is returned if the node stopped with an error.
+///
+/// # Safety
+///
+/// `handle` must be a pointer returned by `tapyrus_spv_start` and must not be used after this
+/// call.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_stop(handle: *mut NodeHandle) -> i32 {
+    ffi_call(|| {
+        if handle.is_null() {
+            return Err(FfiError::invalid_argument("handle is null."));
+        }
+        Box::from_raw(handle).stop()?;
+        Ok(())
+    })
+}
+
+/// Write one of TAPYRUS_SPV_STATUS_* constants to `status`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `status` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_status(spv: *const SPV, status: *mut i32) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        *mut_arg(status, "status")? = status_code(spv.status());
+        Ok(())
+    })
+}
+
+/// Parse the address and watch its script.
+pub(crate) fn watch_address(spv: &SPV, address: &str) -> Result<(), FfiError> {
+    let address: Address = address
+        .parse()
+        .map_err(|_| FfiError::invalid_argument("address is invalid."))?;
+    spv.watch_script(address.script_pubkey());
+    Ok(())
+}
+
+/// Watch transactions which pay to the address.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `address` must be a valid null
+/// terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_watch_address(spv: *const SPV, address: *const c_char) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        watch_address(spv, str_arg(address, "address")?)
+    })
+}
+
+/// Get balance of unspent outputs which pay to watched addresses. Outputs which have
+/// `min_confirmations` are counted as confirmed, and others are counted as unconfirmed.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new`. `confirmed` and `unconfirmed` must be
+/// valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_balance(
+    spv: *const SPV,
+    min_confirmations: u32,
+    confirmed: *mut u64,
+    unconfirmed: *mut u64,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let confirmed = mut_arg(confirmed, "confirmed")?;
+        let unconfirmed = mut_arg(unconfirmed, "unconfirmed")?;
+        let balance = spv.balance(min_confirmations);
+        *confirmed = balance.confirmed;
+        *unconfirmed = balance.unconfirmed;
+        Ok(())
+    })
+}
+
+/// Parse hex string of 33 bytes color identifier.
+fn parse_color_id(color_id_hex: &str) -> Result<ColorIdentifier, FfiError> {
+    let bytes = hex::decode(color_id_hex)
+        .map_err(|_| FfiError::invalid_argument("color id should be hex string."))?;
+    ColorIdentifier::from_slice(&bytes)
+        .map_err(|e| FfiError::invalid_argument(format!("color id is invalid: {:?}", e)))
+}
+
+/// Watch colored outputs of the token and return balance of them.
+pub(crate) fn token_balance(
+    spv: &SPV,
+    color_id_hex: &str,
+    min_confirmations: u32,
+) -> Result<Balance, FfiError> {
+    let color_id = parse_color_id(color_id_hex)?;
+    spv.watch_color(color_id);
+    Ok(spv
+        .token_balances(min_confirmations)
+        .remove(&color_id)
+        .unwrap_or_default())
+}
+
+/// Get balance of the token in unspent colored outputs which pay to watched addresses.
+/// `color_id_hex` is hex string of 33 bytes color identifier. Colored outputs of the token are
+/// watched as well.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `color_id_hex` must be a valid null
+/// terminated string. `confirmed` and `unconfirmed` must be valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_token_balance(
+    spv: *const SPV,
+    color_id_hex: *const c_char,
+    min_confirmations: u32,
+    confirmed: *mut u64,
+    unconfirmed: *mut u64,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let color_id_hex = str_arg(color_id_hex, "color_id_hex")?;
+        let confirmed = mut_arg(confirmed, "confirmed")?;
+        let unconfirmed = mut_arg(unconfirmed, "unconfirmed")?;
+        let balance = token_balance(spv, color_id_hex, min_confirmations)?;
+        *confirmed = balance.confirmed;
+        *unconfirmed = balance.unconfirmed;
+        Ok(())
+    })
+}
+
+/// Write height of the active chain which the node has synced to `height`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `height` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_height(spv: *const SPV, height: *mut i32) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        *mut_arg(height, "height")? = spv.height();
+        Ok(())
+    })
+}
+
+fn not_found<S: Into<String>>(message: S) -> FfiError {
+    FfiError::new(TAPYRUS_SPV_ERROR_NOT_FOUND, message)
+}
+
+/// Return the tip of the active chain.
+pub(crate) fn tip(spv: &SPV) -> Result<BlockIndex, FfiError> {
+    spv.tip()
+        .ok_or_else(|| not_found("the node has not been started."))
+}
 
-    let params = Options {
-        remote,
-        datadir: "/tmp/tapyrus-spv".to_string(),
-        chain_params: ChainParams { network, genesis },
+/// Return the block header at the height in the active chain.
+pub(crate) fn header(spv: &SPV, height: i32) -> Result<BlockIndex, FfiError> {
+    spv.header(height)
+        .ok_or_else(|| not_found(format!("block header at height {} is not found.", height)))
+}
+
+/// Return the block header whose hash is `hash` in the active chain.
+pub(crate) fn header_by_hash(spv: &SPV, hash: &sha256d::Hash) -> Result<BlockIndex, FfiError> {
+    spv.header_by_hash(hash)
+        .ok_or_else(|| not_found(format!("block header {} is not found.", hash)))
+}
+
+/// Return hex of the serialized block header.
+pub(crate) fn header_hex(index: &BlockIndex) -> String {
+    hex::encode(serialize(&index.header))
+}
+
+/// Write hex of the tip block hash to `hash`. The string must be released with
+/// `tapyrus_spv_free_string`. TAPYRUS_SPV_ERROR_NOT_FOUND is returned before the node is started.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `hash` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_tip_hash(spv: *const SPV, hash: *mut *mut c_char) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(hash, "hash")?;
+        *out = into_c_string(tip(spv)?.header.bitcoin_hash().to_string())?;
+        Ok(())
+    })
+}
+
+/// Write hex of the serialized block header at `height` in the active chain to `header_hex`. The
+/// string must be released with `tapyrus_spv_free_string`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `header_hex` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_header(
+    spv: *const SPV,
+    height: i32,
+    header_hex: *mut *mut c_char,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(header_hex, "header_hex")?;
+        let index = header(spv, height)?;
+        *out = into_c_string(self::header_hex(&index))?;
+        Ok(())
+    })
+}
+
+/// Parse hex of block hash.
+pub(crate) fn parse_block_hash(hash_hex: &str) -> Result<sha256d::Hash, FfiError> {
+    hash_hex
+        .parse()
+        .map_err(|_| FfiError::invalid_argument("block hash should be 32 bytes hex string."))
+}
+
+/// Write height and hex of the serialized block header whose hash is `hash_hex` to `height` and
+/// `header_hex`. The header must be in the active chain. The string must be released with
+/// `tapyrus_spv_free_string`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `hash_hex` must be a valid null
+/// terminated string. `height` and `header_hex` must be valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_header_by_hash(
+    spv: *const SPV,
+    hash_hex: *const c_char,
+    height: *mut i32,
+    header_hex: *mut *mut c_char,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let hash = parse_block_hash(str_arg(hash_hex, "hash_hex")?)?;
+        let height = mut_arg(height, "height")?;
+        let out = mut_arg(header_hex, "header_hex")?;
+        let index = header_by_hash(spv, &hash)?;
+        *height = index.height;
+        *out = into_c_string(self::header_hex(&index))?;
+        Ok(())
+    })
+}
+
+/// Return the federation which signs the tip of the active chain.
+pub(crate) fn federation(spv: &SPV) -> Result<Federation, FfiError> {
+    spv.federation()
+        .ok_or_else(|| not_found("the node has not been started."))
+}
+
+/// Write hex of the aggregated public key which signs the tip of the active chain to
+/// `public_key` and the height from which the key is active to `activation_height`. The string
+/// must be released with `tapyrus_spv_free_string`. TAPYRUS_SPV_ERROR_NOT_FOUND is returned before
+/// the node is started.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new`, and `public_key` and
+/// `activation_height` must be valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_aggregated_public_key(
+    spv: *const SPV,
+    public_key: *mut *mut c_char,
+    activation_height: *mut i32,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(public_key, "public_key")?;
+        let activation_height = mut_arg(activation_height, "activation_height")?;
+        let federation = federation(spv)?;
+        *out = into_c_string(hex::encode(
+            &federation.aggregated_public_key.serialize()[..],
+        ))?;
+        *activation_height = federation.activation_height;
+        Ok(())
+    })
+}
+
+/// Progress of block header download.
+#[repr(C)]
+pub struct TapyrusSpvSyncStatus {
+    /// Height of the active chain.
+    pub height: i32,
+    /// The highest start height which connected peers reported. It is not less than `height`.
+    pub target_height: i32,
+    /// Height of the last block which has been scanned for transactions of watched addresses.
+    pub scanned_height: i32,
+    /// The number of connected peers.
+    pub peer_count: u32,
+}
+
+/// Write progress of block header download to `status`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `status` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_sync_status(
+    spv: *const SPV,
+    status: *mut TapyrusSpvSyncStatus,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(status, "status")?;
+        let status = spv.sync_status();
+        *out = TapyrusSpvSyncStatus {
+            height: status.height,
+            target_height: status.target_height,
+            scanned_height: status.scanned_height,
+            peer_count: status.peer_count as u32,
+        };
+        Ok(())
+    })
+}
+
+/// Write the number of peers which the node is connected to to `peer_count`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `peer_count` must be a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_peer_count(spv: *const SPV, peer_count: *mut u32) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        *mut_arg(peer_count, "peer_count")? = spv.peer_count() as u32;
+        Ok(())
+    })
+}
+
+/// Create HD wallet from hex of the seed and load it.
+pub(crate) fn load_wallet(spv: &SPV, seed_hex: &str, coin_type: u32) -> Result<(), FfiError> {
+    let seed = hex::decode(seed_hex)
+        .map_err(|_| FfiError::invalid_argument("seed should be hex string."))?;
+    let options = WalletOptions::new(spv.options.chain_params.network, coin_type);
+    let wallet = if spv.options.datadir.is_empty() {
+        Wallet::new(&seed, options)
+    } else {
+        Wallet::open(&seed, options, Path::new(&spv.options.datadir))
+    }
+    .map_err(|e| FfiError::invalid_argument(format!("can not create wallet: {:?}", e)))?;
+    spv.load_wallet(wallet);
+    Ok(())
+}
+
+/// Coin type of BIP44 paths of Tapyrus production network.
+pub const TAPYRUS_SPV_COIN_TYPE_TAPYRUS: u32 = 1165;
+/// Coin type of BIP44 paths of test networks.
+pub const TAPYRUS_SPV_COIN_TYPE_TESTNET: u32 = 1;
+
+/// Create HD wallet from hex of the seed and load it. Addresses of the wallet are produced for
+/// the network of the instance, and keys are derived along BIP44 path with `coin_type`, which is
+/// one of TAPYRUS_SPV_COIN_TYPE_* constants usually. If datadir is configured, indexes of used
+/// keys are saved there and keys are derived again from them when the wallet is loaded.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `seed_hex` must be a valid null
+/// terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_load_wallet(
+    spv: *const SPV,
+    seed_hex: *const c_char,
+    coin_type: u32,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        load_wallet(spv, str_arg(seed_hex, "seed_hex")?, coin_type)
+    })
+}
+
+fn no_wallet() -> FfiError {
+    FfiError::new(TAPYRUS_SPV_ERROR_NO_WALLET, "wallet is not loaded.")
+}
+
+/// Return the first unused receive address of the loaded wallet.
+pub(crate) fn receive_address(spv: &SPV) -> Result<String, FfiError> {
+    spv.receive_address()
+        .map(|address| address.to_string())
+        .ok_or_else(no_wallet)
+}
+
+/// Return comma separated addresses of all keys which the loaded wallet derived.
+pub(crate) fn addresses(spv: &SPV) -> Result<String, FfiError> {
+    let addresses = spv.addresses().ok_or_else(no_wallet)?;
+    Ok(addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<String>>()
+        .join(","))
+}
+
+/// Write the first unused receive address of the loaded wallet to `address`. The string must be
+/// released with `tapyrus_spv_free_string`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `address` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_receive_address(
+    spv: *const SPV,
+    address: *mut *mut c_char,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(address, "address")?;
+        *out = into_c_string(receive_address(spv)?)?;
+        Ok(())
+    })
+}
+
+/// Write comma separated addresses of all keys which the loaded wallet derived to `addresses`.
+/// The string must be released with `tapyrus_spv_free_string`.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new` and `addresses` must be a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_get_addresses(
+    spv: *const SPV,
+    addresses: *mut *mut c_char,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let out = mut_arg(addresses, "addresses")?;
+        *out = into_c_string(self::addresses(spv)?)?;
+        Ok(())
+    })
+}
+
+/// The tip of the active chain was updated.
+pub const TAPYRUS_SPV_EVENT_NEW_TIP: i32 = 0;
+/// Blocks were removed from the active chain by reorganization.
+pub const TAPYRUS_SPV_EVENT_REORG: i32 = 1;
+/// Connection with a peer was established.
+pub const TAPYRUS_SPV_EVENT_PEER_CONNECTED: i32 = 2;
+/// Connection with a peer was closed.
+pub const TAPYRUS_SPV_EVENT_PEER_DISCONNECTED: i32 = 3;
+/// Progress of block header download.
+pub const TAPYRUS_SPV_EVENT_SYNC_PROGRESS: i32 = 4;
+/// A watched transaction was found in a block.
+pub const TAPYRUS_SPV_EVENT_TRANSACTION_RECEIVED: i32 = 5;
+/// A found transaction reached the required confirmations.
+pub const TAPYRUS_SPV_EVENT_TRANSACTION_CONFIRMED: i32 = 6;
+
+/// Event which is passed to callbacks. Fields which the kind of event doesn't use are zero or
+/// null.
+#[repr(C)]
+pub struct TapyrusSpvEvent {
+    /// One of TAPYRUS_SPV_EVENT_* constants.
+    pub kind: i32,
+    /// Height of the tip, or height of the block which contains the transaction.
+    pub height: i32,
+    /// Fork height of reorganization, or target height of sync progress.
+    pub other_height: i32,
+    /// ID of the peer.
+    pub peer_id: u64,
+    /// Hex of block hash or txid, or address of the peer.
+    pub data: *const c_char,
+}
+
+impl TapyrusSpvEvent {
+    /// Convert the event. `data` points to the returned string, so it must be kept while the
+    /// event is used.
+    pub(crate) fn new(event: &Event) -> (TapyrusSpvEvent, CString) {
+        let (kind, height, other_height, peer_id, data) = match event {
+            Event::NewTip { height, hash } => {
+                (TAPYRUS_SPV_EVENT_NEW_TIP, *height, 0, 0, hash.to_string())
+            }
+            Event::Reorg {
+                fork_height,
+                height,
+                hash,
+            } => (
+                TAPYRUS_SPV_EVENT_REORG,
+                *height,
+                *fork_height,
+                0,
+                hash.to_string(),
+            ),
+            Event::PeerConnected { peer_id, addr } => (
+                TAPYRUS_SPV_EVENT_PEER_CONNECTED,
+                0,
+                0,
+                *peer_id,
+                addr.to_string(),
+            ),
+            Event::PeerDisconnected { peer_id, addr } => (
+                TAPYRUS_SPV_EVENT_PEER_DISCONNECTED,
+                0,
+                0,
+                *peer_id,
+                addr.to_string(),
+            ),
+            Event::SyncProgress {
+                height,
+                target_height,
+            } => (
+                TAPYRUS_SPV_EVENT_SYNC_PROGRESS,
+                *height,
+                *target_height,
+                0,
+                String::new(),
+            ),
+            Event::TransactionReceived { txid, height } => (
+                TAPYRUS_SPV_EVENT_TRANSACTION_RECEIVED,
+                *height,
+                0,
+                0,
+                txid.to_string(),
+            ),
+            Event::TransactionConfirmed { txid, height } => (
+                TAPYRUS_SPV_EVENT_TRANSACTION_CONFIRMED,
+                *height,
+                0,
+                0,
+                txid.to_string(),
+            ),
+        };
+
+        let data = CString::new(data).expect("event data should not contain null.");
+        let event = TapyrusSpvEvent {
+            kind,
+            height,
+            other_height,
+            peer_id,
+            data: if data.as_bytes().is_empty() {
+                ptr::null()
+            } else {
+                data.as_ptr()
+            },
+        };
+        (event, data)
+    }
+}
+
+/// Callback which receives events. `event` is valid only during the call.
+pub type TapyrusSpvEventCallback =
+    extern "C" fn(event: *const TapyrusSpvEvent, user_data: *mut c_void);
+
+/// Pointer which is passed back to the callback on threads of the node.
+struct UserData(*mut c_void);
+
+// The embedder is responsible for the data being usable from threads of the node.
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// Register a callback which is called with events of the node. It is called on threads which
+/// run the node, possibly at the same time, so it should return quickly. It may call functions of
+/// the instance.
+///
+/// # Safety
+///
+/// `spv` must be a pointer returned by `tapyrus_spv_new`. `user_data` is passed to `callback` as
+/// it is, and it must be valid while the node runs.
+#[no_mangle]
+pub unsafe extern "C" fn tapyrus_spv_subscribe(
+    spv: *const SPV,
+    callback: TapyrusSpvEventCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    ffi_call(|| {
+        let spv = ref_arg(spv, "spv")?;
+        let user_data = UserData(user_data);
+        spv.subscribe_fn(move |event| {
+            let (event, _data) = TapyrusSpvEvent::new(event);
+            callback(&event, user_data.0);
+        });
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{
+        get_test_genesis_block, TempDir, AGGREGATED_PUBLIC_KEY_HEX, GENESIS_BLOCK_HEX,
     };
+    use crate::{DEFAULT_GAP_LIMIT, TESTNET_COIN_TYPE};
+
+    fn last_error_message() -> String {
+        unsafe { CStr::from_ptr(tapyrus_spv_last_error_message()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn new_spv(network: &str, genesis_hex: &str) -> (i32, *mut SPV) {
+        let remote = CString::new("127.0.0.1:12383").unwrap();
+        let network = CString::new(network).unwrap();
+        let genesis_hex = CString::new(genesis_hex).unwrap();
+        let datadir = CString::new("").unwrap();
+        let mut spv = ptr::null_mut();
+        let code = unsafe {
+            tapyrus_spv_new(
+                remote.as_ptr(),
+                network.as_ptr(),
+                genesis_hex.as_ptr(),
+                datadir.as_ptr(),
+                &mut spv,
+            )
+        };
+        (code, spv)
+    }
+
+    #[test]
+    fn test_new_and_free() {
+        let (code, spv) = new_spv("regtest", GENESIS_BLOCK_HEX);
+        assert_eq!(code, TAPYRUS_SPV_OK);
+        assert!(tapyrus_spv_last_error_message().is_null());
+
+        let mut status = -1;
+        assert_eq!(
+            unsafe { tapyrus_spv_status(spv, &mut status) },
+            TAPYRUS_SPV_OK
+        );
+        assert_eq!(status, TAPYRUS_SPV_STATUS_STOPPED);
+        unsafe { tapyrus_spv_free(spv) };
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let (code, spv) = new_spv("mainnet", GENESIS_BLOCK_HEX);
+        assert_eq!(code, TAPYRUS_SPV_ERROR_INVALID_ARGUMENT);
+        assert!(spv.is_null());
+        assert_eq!(
+            last_error_message(),
+            "network should be \"prod\", \"dev\", \"bitcoin\", \"testnet\", \"regtest\" or a network id."
+        );
+
+        let (code, _) = new_spv("regtest", "00");
+        assert_eq!(code, TAPYRUS_SPV_ERROR_INVALID_ARGUMENT);
+        assert_eq!(last_error_message(), "genesis_hex is invalid block data.");
+
+        let mut status = 0;
+        let code = unsafe { tapyrus_spv_status(ptr::null(), &mut status) };
+        assert_eq!(code, TAPYRUS_SPV_ERROR_INVALID_ARGUMENT);
+        assert_eq!(last_error_message(), "spv is null.");
+
+        let (_, spv) = new_spv("regtest", GENESIS_BLOCK_HEX);
+        let address = CString::new("invalid").unwrap();
+        let code = unsafe { tapyrus_spv_watch_address(spv, address.as_ptr()) };
+        assert_eq!(code, TAPYRUS_SPV_ERROR_INVALID_ARGUMENT);
+        assert_eq!(last_error_message(), "address is invalid.");
+        unsafe { tapyrus_spv_free(spv) };
+    }
+
+    #[test]
+    fn test_run_failure() {
+        // datadir which is a file can't be opened.
+        let dir = TempDir::new("ffi_test_run_failure");
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+
+        let remote = CString::new("127.0.0.1:12383").unwrap();
+        let network = CString::new("regtest").unwrap();
+        let genesis_hex = CString::new(GENESIS_BLOCK_HEX).unwrap();
+        let datadir = CString::new(path.to_str().unwrap()).unwrap();
+        let code = unsafe {
+            tapyrus_spv_run(
+                remote.as_ptr(),
+                network.as_ptr(),
+                genesis_hex.as_ptr(),
+                datadir.as_ptr(),
+            )
+        };
+        assert_eq!(code, TAPYRUS_SPV_ERROR_NODE_FAILED);
+        assert!(last_error_message().starts_with("SPV node failed: ChainError("));
+    }
+
+    unsafe fn take_string(string: *mut c_char) -> String {
+        let result = CStr::from_ptr(string).to_str().unwrap().to_string();
+        tapyrus_spv_free_string(string);
+        result
+    }
+
+    #[test]
+    fn test_query_chain() {
+        let (_, spv) = new_spv("regtest", GENESIS_BLOCK_HEX);
+        let mut string = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                tapyrus_spv_get_tip_hash(spv, &mut string),
+                TAPYRUS_SPV_ERROR_NOT_FOUND
+            );
+
+            let mut handle = ptr::null_mut();
+            assert_eq!(tapyrus_spv_start(spv, &mut handle), TAPYRUS_SPV_OK);
+            assert_eq!(
+                tapyrus_spv_start(spv, &mut handle),
+                TAPYRUS_SPV_ERROR_ALREADY_RUNNING
+            );
+            assert_eq!(tapyrus_spv_stop(handle), TAPYRUS_SPV_OK);
+
+            let genesis = get_test_genesis_block();
+            assert_eq!(tapyrus_spv_get_tip_hash(spv, &mut string), TAPYRUS_SPV_OK);
+            let hash = take_string(string);
+            assert_eq!(hash, genesis.bitcoin_hash().to_string());
+
+            assert_eq!(tapyrus_spv_get_header(spv, 0, &mut string), TAPYRUS_SPV_OK);
+            let header = take_string(string);
+            assert_eq!(header, hex::encode(serialize(&genesis.header)));
+            assert_eq!(
+                tapyrus_spv_get_header(spv, 1, &mut string),
+                TAPYRUS_SPV_ERROR_NOT_FOUND
+            );
+
+            let hash = CString::new(hash).unwrap();
+            let mut height = -1;
+            let code = tapyrus_spv_get_header_by_hash(spv, hash.as_ptr(), &mut height, &mut string);
+            assert_eq!(code, TAPYRUS_SPV_OK);
+            assert_eq!(height, 0);
+            assert_eq!(take_string(string), header);
+
+            let mut activation_height = -1;
+            let code =
+                tapyrus_spv_get_aggregated_public_key(spv, &mut string, &mut activation_height);
+            assert_eq!(code, TAPYRUS_SPV_OK);
+            assert_eq!(take_string(string), AGGREGATED_PUBLIC_KEY_HEX);
+            assert_eq!(activation_height, 0);
+
+            let mut status = TapyrusSpvSyncStatus {
+                height: -1,
+                target_height: -1,
+                scanned_height: -1,
+                peer_count: 1,
+            };
+            assert_eq!(
+                tapyrus_spv_get_sync_status(spv, &mut status),
+                TAPYRUS_SPV_OK
+            );
+            assert_eq!(
+                (
+                    status.height,
+                    status.target_height,
+                    status.scanned_height,
+                    status.peer_count
+                ),
+                (0, 0, 0, 0)
+            );
+            tapyrus_spv_free(spv);
+        }
+    }
+
+    #[test]
+    fn test_query_wallet() {
+        let (_, spv) = new_spv("regtest", GENESIS_BLOCK_HEX);
+        let mut string = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                tapyrus_spv_get_receive_address(spv, &mut string),
+                TAPYRUS_SPV_ERROR_NO_WALLET
+            );
+
+            let seed = CString::new("000102030405060708090a0b0c0d0e0f").unwrap();
+            assert_eq!(
+                tapyrus_spv_load_wallet(spv, seed.as_ptr(), TESTNET_COIN_TYPE),
+                TAPYRUS_SPV_OK
+            );
+            assert_eq!(
+                tapyrus_spv_get_receive_address(spv, &mut string),
+                TAPYRUS_SPV_OK
+            );
+            let address = take_string(string);
+
+            assert_eq!(tapyrus_spv_get_addresses(spv, &mut string), TAPYRUS_SPV_OK);
+            let addresses = take_string(string);
+            let addresses: Vec<&str> = addresses.split(',').collect();
+            assert_eq!(addresses.len(), 2 * DEFAULT_GAP_LIMIT as usize);
+            assert_eq!(addresses[0], address);
+            tapyrus_spv_free(spv);
+        }
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let code = ffi_call(|| panic!("unexpected"));
+        assert_eq!(code, TAPYRUS_SPV_ERROR_PANIC);
+        assert_eq!(last_error_message(), "unexpected");
 
-    let spv = SPV::new(params);
-    spv.run();
+        assert_eq!(ffi_call(|| Ok(())), TAPYRUS_SPV_OK);
+        assert!(tapyrus_spv_last_error_message().is_null());
+    }
 }
@@ -2,7 +2,325 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+#ifndef TAPYRUS_SPV_H
+#define TAPYRUS_SPV_H
+
+// This file is generated by cbindgen from src/ffi/c.rs. Do not edit it manually.
+
 #include <stdint.h>
 
+typedef struct SPV SPV;
+typedef struct NodeHandle NodeHandle;
+
+// The function succeeded.
+#define TAPYRUS_SPV_OK 0
+
+// An argument is null, not a valid UTF-8 string or can not be parsed.
+#define TAPYRUS_SPV_ERROR_INVALID_ARGUMENT 1
+
+// The node is already running.
+#define TAPYRUS_SPV_ERROR_ALREADY_RUNNING 2
+
+// The library panicked. The instance may be in inconsistent state.
+#define TAPYRUS_SPV_ERROR_PANIC 3
+
+// The requested data is not found, e.g. block header above the tip.
+#define TAPYRUS_SPV_ERROR_NOT_FOUND 4
+
+// The function requires a wallet which is loaded by `tapyrus_spv_load_wallet`.
+#define TAPYRUS_SPV_ERROR_NO_WALLET 5
+
+// The node can not be started or stopped with an error, e.g. stores in datadir are broken.
+#define TAPYRUS_SPV_ERROR_NODE_FAILED 6
+
+// The node is not running.
+#define TAPYRUS_SPV_STATUS_STOPPED 0
+
+// The node is following the tip of the chain.
+#define TAPYRUS_SPV_STATUS_RUNNING 1
+
+// The node is shutting down.
+#define TAPYRUS_SPV_STATUS_STOPPING 2
+
+// Coin type of BIP44 paths of Tapyrus production network.
+#define TAPYRUS_SPV_COIN_TYPE_TAPYRUS 1165
+
+// Coin type of BIP44 paths of test networks.
+#define TAPYRUS_SPV_COIN_TYPE_TESTNET 1
+
+// The tip of the active chain was updated.
+#define TAPYRUS_SPV_EVENT_NEW_TIP 0
+
+// Blocks were removed from the active chain by reorganization.
+#define TAPYRUS_SPV_EVENT_REORG 1
+
+// Connection with a peer was established.
+#define TAPYRUS_SPV_EVENT_PEER_CONNECTED 2
+
+// Connection with a peer was closed.
+#define TAPYRUS_SPV_EVENT_PEER_DISCONNECTED 3
+
+// Progress of block header download.
+#define TAPYRUS_SPV_EVENT_SYNC_PROGRESS 4
+
+// A watched transaction was found in a block.
+#define TAPYRUS_SPV_EVENT_TRANSACTION_RECEIVED 5
+
+// A found transaction reached the required confirmations.
+#define TAPYRUS_SPV_EVENT_TRANSACTION_CONFIRMED 6
+
+// Progress of block header download.
+typedef struct {
+  // Height of the active chain.
+  int32_t height;
+  // The highest start height which connected peers reported. It is not less than `height`.
+  int32_t target_height;
+  // Height of the last block which has been scanned for transactions of watched addresses.
+  int32_t scanned_height;
+  // The number of connected peers.
+  uint32_t peer_count;
+} TapyrusSpvSyncStatus;
+
+// Event which is passed to callbacks. Fields which the kind of event doesn't use are zero or
+// null.
+typedef struct {
+  // One of TAPYRUS_SPV_EVENT_* constants.
+  int32_t kind;
+  // Height of the tip, or height of the block which contains the transaction.
+  int32_t height;
+  // Fork height of reorganization, or target height of sync progress.
+  int32_t other_height;
+  // ID of the peer.
+  uint64_t peer_id;
+  // Hex of block hash or txid, or address of the peer.
+  const char *data;
+} TapyrusSpvEvent;
+
+// Callback which receives events. `event` is valid only during the call.
+typedef void (*TapyrusSpvEventCallback)(const TapyrusSpvEvent *event, void *user_data);
+
+// Release string which was returned by functions of this library. It does nothing if `string`
+// is null.
+//
+// # Safety
+//
+// `string` must be a string returned by this library and must not be used after this call.
+void tapyrus_spv_free_string(char *string);
+
+// Return the message of the error which the last function called on this thread returned, or
+// null if it succeeded. The string is valid until the next function call on this thread.
+const char *tapyrus_spv_last_error_message(void);
+
+// initialize logger. Calling it again has no effect.
 void tapyrus_enable_log(void);
-void tapyrus_spv_run(const char* remote, const char* network, const char* genesis_hex);
+
+// Create SPV instance and write it to `spv`. It must be released with `tapyrus_spv_free`.
+//
+// `remote` is a comma separated list of remote peer addresses. The default port of the network
+// is used for addresses without a port. `network` is "prod" or "dev" for public Tapyrus
+// networks, "bitcoin", "testnet" or "regtest" for legacy networks, or the network id of a custom
+// network optionally followed by ":" and hex of its magic bytes. Block headers are stored in
+// `datadir`, or kept on memory if it is an empty string.
+//
+// # Safety
+//
+// Arguments must be valid null terminated strings and `spv` must be a valid pointer.
+int32_t tapyrus_spv_new(const char *remote,
+                        const char *network,
+                        const char *genesis_hex,
+                        const char *datadir,
+                        SPV **spv);
+
+// Release SPV instance which was created by `tapyrus_spv_new`. It does nothing if `spv` is null.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and must not be used after this call.
+void tapyrus_spv_free(SPV *spv);
+
+// Create SPV instance and run it. This function blocks while the node keeps following the tip
+// of the chain. TAPYRUS_SPV_ERROR_NODE_FAILED is returned if the node can not be started or it
+// stopped with an error.
+//
+// Arguments are the same as `tapyrus_spv_new`.
+//
+// # Safety
+//
+// Arguments must be valid null terminated strings.
+int32_t tapyrus_spv_run(const char *remote,
+                        const char *network,
+                        const char *genesis_hex,
+                        const char *datadir);
+
+// Run SPV node of the instance. This function blocks while the node keeps following the tip of
+// the chain, so other functions should be called from other threads. Errors are the same as
+// `tapyrus_spv_run`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new`.
+int32_t tapyrus_spv_run_instance(const SPV *spv);
+
+// Start SPV node of the instance on a background thread and write the handle to `handle`. The
+// node must be stopped with `tapyrus_spv_stop`. Stores in datadir are opened before this
+// function returns, and TAPYRUS_SPV_ERROR_NODE_FAILED is returned if they can not be opened.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `handle` must be a valid pointer.
+int32_t tapyrus_spv_start(const SPV *spv, NodeHandle **handle);
+
+// Stop SPV node and release the handle. This function blocks until the node stopped and block
+// headers are flushed. The node can be started again with `tapyrus_spv_start`.
+// TAPYRUS_SPV_ERROR_NODE_FAILED is returned if the node stopped with an error.
+//
+// # Safety
+//
+// `handle` must be a pointer returned by `tapyrus_spv_start` and must not be used after this
+// call.
+int32_t tapyrus_spv_stop(NodeHandle *handle);
+
+// Write one of TAPYRUS_SPV_STATUS_* constants to `status`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `status` must be a valid pointer.
+int32_t tapyrus_spv_status(const SPV *spv, int32_t *status);
+
+// Watch transactions which pay to the address.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `address` must be a valid null
+// terminated string.
+int32_t tapyrus_spv_watch_address(const SPV *spv, const char *address);
+
+// Get balance of unspent outputs which pay to watched addresses. Outputs which have
+// `min_confirmations` are counted as confirmed, and others are counted as unconfirmed.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new`. `confirmed` and `unconfirmed` must be
+// valid pointers.
+int32_t tapyrus_spv_get_balance(const SPV *spv,
+                                uint32_t min_confirmations,
+                                uint64_t *confirmed,
+                                uint64_t *unconfirmed);
+
+// Get balance of the token in unspent colored outputs which pay to watched addresses.
+// `color_id_hex` is hex string of 33 bytes color identifier. Colored outputs of the token are
+// watched as well.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `color_id_hex` must be a valid null
+// terminated string. `confirmed` and `unconfirmed` must be valid pointers.
+int32_t tapyrus_spv_get_token_balance(const SPV *spv,
+                                      const char *color_id_hex,
+                                      uint32_t min_confirmations,
+                                      uint64_t *confirmed,
+                                      uint64_t *unconfirmed);
+
+// Write height of the active chain which the node has synced to `height`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `height` must be a valid pointer.
+int32_t tapyrus_spv_get_height(const SPV *spv, int32_t *height);
+
+// Write hex of the tip block hash to `hash`. The string must be released with
+// `tapyrus_spv_free_string`. TAPYRUS_SPV_ERROR_NOT_FOUND is returned before the node is started.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `hash` must be a valid pointer.
+int32_t tapyrus_spv_get_tip_hash(const SPV *spv, char **hash);
+
+// Write hex of the serialized block header at `height` in the active chain to `header_hex`. The
+// string must be released with `tapyrus_spv_free_string`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `header_hex` must be a valid pointer.
+int32_t tapyrus_spv_get_header(const SPV *spv, int32_t height, char **header_hex);
+
+// Write height and hex of the serialized block header whose hash is `hash_hex` to `height` and
+// `header_hex`. The header must be in the active chain. The string must be released with
+// `tapyrus_spv_free_string`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `hash_hex` must be a valid null
+// terminated string. `height` and `header_hex` must be valid pointers.
+int32_t tapyrus_spv_get_header_by_hash(const SPV *spv,
+                                       const char *hash_hex,
+                                       int32_t *height,
+                                       char **header_hex);
+
+// Write hex of the aggregated public key which signs the tip of the active chain to
+// `public_key` and the height from which the key is active to `activation_height`. The string
+// must be released with `tapyrus_spv_free_string`. TAPYRUS_SPV_ERROR_NOT_FOUND is returned before
+// the node is started.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new`, and `public_key` and
+// `activation_height` must be valid pointers.
+int32_t tapyrus_spv_get_aggregated_public_key(const SPV *spv,
+                                              char **public_key,
+                                              int32_t *activation_height);
+
+// Write progress of block header download to `status`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `status` must be a valid pointer.
+int32_t tapyrus_spv_get_sync_status(const SPV *spv, TapyrusSpvSyncStatus *status);
+
+// Write the number of peers which the node is connected to to `peer_count`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `peer_count` must be a valid
+// pointer.
+int32_t tapyrus_spv_get_peer_count(const SPV *spv, uint32_t *peer_count);
+
+// Create HD wallet from hex of the seed and load it. Addresses of the wallet are produced for
+// the network of the instance, and keys are derived along BIP44 path with `coin_type`, which is
+// one of TAPYRUS_SPV_COIN_TYPE_* constants usually. If datadir is configured, indexes of used
+// keys are saved there and keys are derived again from them when the wallet is loaded.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `seed_hex` must be a valid null
+// terminated string.
+int32_t tapyrus_spv_load_wallet(const SPV *spv, const char *seed_hex, uint32_t coin_type);
+
+// Write the first unused receive address of the loaded wallet to `address`. The string must be
+// released with `tapyrus_spv_free_string`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `address` must be a valid pointer.
+int32_t tapyrus_spv_get_receive_address(const SPV *spv, char **address);
+
+// Write comma separated addresses of all keys which the loaded wallet derived to `addresses`.
+// The string must be released with `tapyrus_spv_free_string`.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new` and `addresses` must be a valid
+// pointer.
+int32_t tapyrus_spv_get_addresses(const SPV *spv, char **addresses);
+
+// Register a callback which is called with events of the node. It is called on threads which
+// run the node, possibly at the same time, so it should return quickly. It may call functions of
+// the instance.
+//
+// # Safety
+//
+// `spv` must be a pointer returned by `tapyrus_spv_new`. `user_data` is passed to `callback` as
+// it is, and it must be valid while the node runs.
+int32_t tapyrus_spv_subscribe(const SPV *spv, TapyrusSpvEventCallback callback, void *user_data);
+
+#endif /* TAPYRUS_SPV_H */
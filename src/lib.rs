@@ -20,90 +20,857 @@ extern crate tokio;
 extern crate log;
 extern crate byteorder;
 extern crate bytes;
+extern crate num_bigint;
 
-use crate::chain::store::OnMemoryChainStore;
-use crate::chain::{Chain, ChainStore};
-use crate::network::{connect, BlockHeaderDownload, Handshake};
+use crate::chain::store::{
+    FileChainStore, FileFilterHeaderStore, OnMemoryChainStore, OnMemoryFilterHeaderStore,
+};
+use crate::chain::{aggregated_public_key, Chain, ChainStore, FilterHeaderStore};
+use crate::network::{
+    connect, AddrManager, BanList, CompactFilterDownload, ConnectFuture, DnsResolver, Handshake,
+    MerkleBlockDownload, PeerManager, ScanEvent, SentNonces, TransactionBroadcast,
+    TransactionDownload, WatchList, NODE_BLOOM, NODE_COMPACT_FILTERS, NODE_NETWORK,
+};
+use crate::wallet::UtxoSet;
+use bitcoin_hashes::sha256d;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tapyrus::network::constants::Network;
-use tapyrus::Block;
-use tokio::prelude::Future;
+use std::thread::{self, JoinHandle};
+use tapyrus::{Address, OutPoint, Script, Transaction};
+use tokio::prelude::{future, Future, Stream};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 mod chain;
+mod chain_params;
+mod error;
+mod event;
 mod ffi;
 mod network;
+mod script;
+mod wallet;
 
+pub use crate::chain::Error as ChainError;
+pub use crate::chain::{
+    verify_merkle_block, BlockFilter, BlockIndex, Checkpoint, Federation, MatchedTransaction,
+    MerkleProofError, PartialMerkleTree, TrustedCheckpoint,
+};
+pub use crate::chain_params::{
+    network_magic, ChainParams, DEV_DEFAULT_PORT, DEV_NETWORK_ID, PROD_DEFAULT_PORT,
+    PROD_NETWORK_ID,
+};
+pub use crate::error::Error;
+pub use crate::event::{Event, EventBus, NOTIFY_CONFIRMATIONS};
 #[cfg(target_os = "android")]
 pub use crate::ffi::android::*;
 pub use crate::ffi::c::*;
+pub use crate::network::Error as NetworkError;
+pub use crate::network::{
+    BroadcastError, HandshakeFailure, MaliciousPeerCause, ProtocolVersion, RejectReason,
+    BITCOIN_PROTOCOL_VERSION, DEFAULT_MAX_OUTBOUND_PEERS, TAPYRUS_PROTOCOL_VERSION,
+};
+pub use crate::script::{
+    colored_script, split_colored_script, ColorError, ColorIdentifier, TokenType, OP_COLOR,
+};
+pub use crate::wallet::Error as WalletError;
+pub use crate::wallet::{
+    AddressType, Balance, DerivedKey, HistoryEntry, KeyChain, TransactionBuilder, Utxo, Wallet,
+    WalletOptions, DEFAULT_FEE_RATE, DEFAULT_GAP_LIMIT, TAPYRUS_COIN_TYPE, TESTNET_COIN_TYPE,
+};
 
 #[cfg(test)]
 mod test_helper;
 
 /// SPV
+///
+/// Shared state is locked by the node thread and by callers of the API at the same time. Locks
+/// are taken in the order of the chain state, the broadcast transactions, the watch list and the
+/// UTXO set, and the wallet is never locked together with another lock.
 #[derive(Clone)]
 pub struct SPV {
     options: Options,
+    watch_list: Arc<Mutex<WatchList>>,
+    wallet: Arc<Mutex<Option<Wallet>>>,
+    utxo_set: Arc<Mutex<UtxoSet>>,
+    broadcast: Arc<Mutex<TransactionBroadcast>>,
+    events: EventBus,
+    /// Found transactions which have not reached NOTIFY_CONFIRMATIONS and their heights.
+    unconfirmed: Arc<Mutex<HashMap<sha256d::Hash, i32>>>,
+    /// Height of the active chain which the running node has synced.
+    height: Arc<AtomicI32>,
+    /// The highest start height which peers of the running node reported.
+    target_height: Arc<AtomicI32>,
+    /// Height of the last block which the running node scanned for watched transactions.
+    scanned_height: Arc<AtomicI32>,
+    /// The active chain which the node synced last.
+    chain: Arc<Mutex<Option<Arc<dyn ActiveChain>>>>,
+    /// The number of peers which the running node is connected to.
+    peer_count: Arc<AtomicUsize>,
+    status: Arc<Mutex<NodeStatus>>,
 }
 
 impl SPV {
     /// returns SPV instance.
     pub fn new(params: Options) -> SPV {
-        SPV { options: params }
+        SPV {
+            options: params,
+            watch_list: Arc::new(Mutex::new(WatchList::new())),
+            wallet: Arc::new(Mutex::new(None)),
+            utxo_set: Arc::new(Mutex::new(UtxoSet::new())),
+            broadcast: Arc::new(Mutex::new(TransactionBroadcast::new())),
+            events: EventBus::new(),
+            unconfirmed: Arc::new(Mutex::new(HashMap::new())),
+            height: Arc::new(AtomicI32::new(0)),
+            target_height: Arc::new(AtomicI32::new(0)),
+            scanned_height: Arc::new(AtomicI32::new(0)),
+            chain: Arc::new(Mutex::new(None)),
+            peer_count: Arc::new(AtomicUsize::new(0)),
+            status: Arc::new(Mutex::new(NodeStatus::Stopped)),
+        }
     }
 
-    /// run spv node.
-    pub fn run(&self) {
+    /// Return stream of events which the node emits after this call.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Call `callback` with events which the node emits after this call. It is called on
+    /// threads of the node, so it should return quickly. No lock of the node is held during the
+    /// call, so it may call the API.
+    pub fn subscribe_fn<F>(&self, callback: F)
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.events.subscribe_fn(callback);
+    }
+
+    /// Load HD wallet whose transactions are found by the SPV node. Scripts of derived keys are
+    /// watched, and more keys are derived and watched when found transactions pay to them.
+    pub fn load_wallet(&self, wallet: Wallet) {
+        let scripts = wallet.scripts();
+        *self.wallet.lock().unwrap() = Some(wallet);
+
+        let mut watch_list = self.watch_list.lock().unwrap();
+        for script in scripts {
+            watch_list.add_script(script);
+        }
+    }
+
+    /// Return addresses of all keys which the loaded wallet derived.
+    pub fn addresses(&self) -> Option<Vec<Address>> {
+        self.wallet
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|wallet| wallet.keys().map(|key| key.address.clone()).collect())
+    }
+
+    /// Return the first unused receive address of the loaded wallet.
+    pub fn receive_address(&self) -> Option<Address> {
+        self.wallet
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|wallet| wallet.receive_address())
+    }
+
+    /// Watch transactions which pay to the script. Outputs of found transactions are watched
+    /// automatically, so that transactions spending them are also found.
+    pub fn watch_script(&self, script: Script) {
+        self.watch_list.lock().unwrap().add_script(script);
+    }
+
+    /// Watch transactions which spend the outpoint.
+    pub fn watch_outpoint(&self, outpoint: OutPoint) {
+        self.watch_list.lock().unwrap().add_outpoint(outpoint);
+    }
+
+    /// Watch colored outputs of the token which pay to watched scripts. Bloom filters match
+    /// colored outputs without this, but compact block filters contain the colored scripts.
+    pub fn watch_color(&self, color_id: ColorIdentifier) {
+        self.watch_list.lock().unwrap().add_color(color_id);
+    }
+
+    /// Stop watching all scripts and outpoints.
+    pub fn unwatch_all(&self) {
+        self.watch_list.lock().unwrap().clear();
+    }
+
+    /// Return height of the active chain which the running node has synced.
+    pub fn height(&self) -> i32 {
+        self.height.load(Ordering::SeqCst)
+    }
+
+    /// Return the tip of the active chain. The chain which the node synced last is used while it
+    /// is stopped, and None is returned before the node is started.
+    pub fn tip(&self) -> Option<BlockIndex> {
+        self.active_chain().map(|chain| chain.tip())
+    }
+
+    /// Return the block header in the active chain at the height.
+    pub fn header(&self, height: i32) -> Option<BlockIndex> {
+        self.active_chain().and_then(|chain| chain.get(height))
+    }
+
+    /// Return the block header in the active chain whose hash is `hash`.
+    pub fn header_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex> {
+        self.active_chain()
+            .and_then(|chain| chain.get_by_hash(hash))
+    }
+
+    /// Return the federation which signs the tip of the active chain. It has the current
+    /// aggregated public key and the height from which the key is active.
+    pub fn federation(&self) -> Option<Federation> {
+        self.active_chain().map(|chain| chain.federation())
+    }
+
+    /// Return progress of block header download.
+    pub fn sync_status(&self) -> SyncStatus {
+        SyncStatus {
+            height: self.height(),
+            target_height: self.target_height.load(Ordering::SeqCst),
+            scanned_height: self.scanned_height.load(Ordering::SeqCst),
+            peer_count: self.peer_count(),
+        }
+    }
+
+    fn active_chain(&self) -> Option<Arc<dyn ActiveChain>> {
+        self.chain.lock().unwrap().clone()
+    }
+
+    /// Return unspent outputs which pay to watched scripts.
+    pub fn unspent_outputs(&self) -> Vec<Utxo> {
+        self.utxo_set.lock().unwrap().unspent()
+    }
+
+    /// Return balance of unspent outputs which pay to watched scripts. Outputs which have
+    /// `min_confirmations` in the active chain are confirmed.
+    pub fn balance(&self, min_confirmations: u32) -> Balance {
+        self.utxo_set
+            .lock()
+            .unwrap()
+            .balance(self.height(), min_confirmations)
+    }
+
+    /// Return balances of tokens in unspent colored outputs by color identifier.
+    pub fn token_balances(&self, min_confirmations: u32) -> BTreeMap<ColorIdentifier, Balance> {
+        self.utxo_set
+            .lock()
+            .unwrap()
+            .token_balances(self.height(), min_confirmations)
+    }
+
+    /// Return history of transactions which changed balance of the token. `color_id` is None for
+    /// TPC.
+    pub fn history(&self, color_id: Option<&ColorIdentifier>) -> Vec<HistoryEntry> {
+        self.utxo_set.lock().unwrap().history(color_id)
+    }
+
+    /// Build and sign a transaction with the loaded wallet. `f` adds payments to the builder.
+    /// Unspent outputs which have `min_confirmations` in the active chain are spent.
+    pub fn build_transaction<F>(
+        &self,
+        min_confirmations: u32,
+        f: F,
+    ) -> Result<Transaction, WalletError>
+    where
+        F: FnOnce(&mut TransactionBuilder),
+    {
+        let tip_height = self.height();
+        let utxos: Vec<Utxo> = self
+            .unspent_outputs()
+            .into_iter()
+            .filter(|utxo| utxo.confirmations(tip_height) >= min_confirmations)
+            .collect();
+
+        let wallet = self.wallet.lock().unwrap();
+        let wallet = wallet.as_ref().ok_or(WalletError::NoWallet)?;
+        let mut builder = TransactionBuilder::new(wallet, utxos);
+        f(&mut builder);
+        builder.build()
+    }
+
+    /// Broadcast the transaction to peers of the running node. It is announced to peers again
+    /// until it is found in a block. The returned future completes when the transaction is
+    /// confirmed, or fails when the majority of peers which received it reject it.
+    ///
+    /// Outputs of the transaction should pay to watched scripts or spend watched outpoints,
+    /// otherwise the confirmation can not be found.
+    pub fn broadcast(
+        &self,
+        tx: Transaction,
+    ) -> Box<dyn Future<Item = (), Error = BroadcastError> + Send> {
+        let receiver = self.broadcast.lock().unwrap().add(tx);
+        Box::new(receiver.into_future().then(|result| match result {
+            Ok((Some(result), _)) => result,
+            _ => Err(BroadcastError::Cancelled),
+        }))
+    }
+
+    /// Return the number of peers which requested the broadcast transaction. None if the
+    /// transaction is not being broadcast. `txid` is `Transaction::txid` as in events.
+    pub fn requested_peers(&self, txid: &sha256d::Hash) -> Option<usize> {
+        self.broadcast.lock().unwrap().requested_peers(txid)
+    }
+
+    /// Return the number of peers which the running node is connected to.
+    pub fn peer_count(&self) -> usize {
+        self.peer_count.load(Ordering::SeqCst)
+    }
+
+    /// Return the lifecycle status of the node.
+    pub fn status(&self) -> NodeStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// run spv node. This function blocks while the node keeps following the tip of the chain.
+    ///
+    /// Block headers are stored in `datadir` if it is configured, otherwise they are kept on
+    /// memory.
+    pub fn run(&self) -> Result<(), Error> {
+        self.start()?.wait()
+    }
+
+    /// Start spv node on a background thread and return the handle to stop it.
+    ///
+    /// The node can be started again after it stopped. Watched scripts, the wallet and found
+    /// transactions are kept while it is stopped, so apps can stop the node when they go to
+    /// background and start it again when they come back.
+    ///
+    /// Options are checked and stores in datadir are opened before the thread starts, so errors
+    /// in them are returned from this function. Return `Error::AlreadyRunning` if the node is not
+    /// stopped.
+    pub fn start(&self) -> Result<NodeHandle, Error> {
+        {
+            // The status is checked and set at once, so only one caller can start the node.
+            let mut status = self.status.lock().unwrap();
+            if *status != NodeStatus::Stopped {
+                return Err(Error::AlreadyRunning);
+            }
+            *status = NodeStatus::Running;
+        }
+
         info!("Start SPV node.");
+        let result = if self.options.datadir.is_empty() {
+            info!("datadir is not configured. Block headers are kept on memory.");
+            self.spawn(OnMemoryChainStore::new())
+        } else {
+            let datadir_path = Path::new(&self.options.datadir);
+            info!("datadir is {}", datadir_path.display());
+            FileChainStore::open(datadir_path)
+                .map_err(Error::from)
+                .and_then(|chain_store| self.spawn(chain_store))
+        };
+        if result.is_err() {
+            *self.status.lock().unwrap() = NodeStatus::Stopped;
+        }
+        result
+    }
+
+    /// Prepare the node with the chain store and run it on a new thread.
+    fn spawn<S: ChainStore + Send + 'static>(&self, chain_store: S) -> Result<NodeHandle, Error> {
+        let node = self.prepare(chain_store)?;
+
+        let (stop_sender, stop_receiver) = unbounded_channel();
+        let spv = self.clone();
+        let thread = thread::Builder::new()
+            .name("tapyrus-spv".to_string())
+            .spawn(move || {
+                let _stopped = StoppedOnDrop(spv.status.clone());
+                spv.run_until(node, stop_receiver)
+            })?;
+
+        Ok(NodeHandle {
+            status: self.status.clone(),
+            stop: stop_sender,
+            thread: Some(thread),
+        })
+    }
+
+    /// Parse addresses of remote peers, initialize the chain and open stores in datadir.
+    fn prepare<S: ChainStore + Send + 'static>(
+        &self,
+        mut chain_store: S,
+    ) -> Result<PreparedNode<S>, Error> {
+        let remote_socket_addrs = self
+            .options
+            .remotes
+            .iter()
+            .map(|remote| {
+                self.options
+                    .chain_params
+                    .remote_addr(remote)
+                    .ok_or_else(|| Error::InvalidRemote(remote.clone()))
+            })
+            .collect::<Result<Vec<SocketAddr>, Error>>()?;
 
         // initialize chain_state
-        let datadir_path = Path::new(&self.options.datadir);
-        info!("datadir is {}", datadir_path.display());
-        let remote_socket_addr = self.options.remote.parse().expect(&format!(
-            "Can not parse remote peer address: \"{}\"",
-            self.options.remote
-        ));
-
-        let mut chain_store = OnMemoryChainStore::new();
-        chain_store.initialize(self.options.chain_params.genesis.clone());
-        let chain_active = Chain::new(chain_store);
+        let chain_params = &self.options.chain_params;
+        let genesis = &chain_params.genesis;
+        let base = match chain_params.trusted_checkpoint {
+            Some(ref trusted) => trusted.block_index(),
+            None => BlockIndex {
+                header: genesis.header.clone(),
+                height: 0,
+                next_blockhash: sha256d::Hash::default(),
+            },
+        };
+        chain_store.initialize(base)?;
+
+        // The store keeps the first block which it was initialized with.
+        let base_height = chain_store.base_height();
+        let aggregated_public_key = match chain_params.trusted_checkpoint {
+            _ if base_height == 0 => aggregated_public_key(genesis)?,
+            Some(ref trusted)
+                if chain_store.get(base_height).map(|index| index.header)
+                    == Some(trusted.header.clone()) =>
+            {
+                trusted.aggregated_public_key
+            }
+            _ => return Err(Error::UnknownChainBase(base_height)),
+        };
+        if base_height > 0 && self.options.filter_mode == FilterMode::CompactFilter {
+            return Err(Error::CompactFilterFromCheckpoint);
+        }
+
+        let mut chain_active = Chain::new(chain_store, aggregated_public_key);
+        for federation in &chain_params.federations {
+            // Federations before the first block are not used.
+            if federation.activation_height <= base_height {
+                continue;
+            }
+            chain_active.add_federation(*federation)?;
+        }
+        for checkpoint in &chain_params.checkpoints {
+            chain_active.add_checkpoint(*checkpoint)?;
+        }
+
+        let (ban_list, addr_manager) = if self.options.datadir.is_empty() {
+            (BanList::new(), AddrManager::new())
+        } else {
+            let datadir_path = Path::new(&self.options.datadir);
+            (
+                BanList::open(datadir_path)?,
+                AddrManager::open(datadir_path)?,
+            )
+        };
+
+        let filter_headers: Option<Box<dyn FilterHeaderStore + Send>> =
+            match self.options.filter_mode {
+                FilterMode::BloomFilter => None,
+                FilterMode::CompactFilter if self.options.datadir.is_empty() => {
+                    Some(Box::new(OnMemoryFilterHeaderStore::new()))
+                }
+                FilterMode::CompactFilter => Some(Box::new(FileFilterHeaderStore::open(
+                    Path::new(&self.options.datadir),
+                )?)),
+            };
+
+        self.height.store(chain_active.height(), Ordering::SeqCst);
         let chain_state = Arc::new(Mutex::new(ChainState::new(chain_active)));
+        *self.chain.lock().unwrap() = Some(chain_state.clone());
+
+        Ok(PreparedNode {
+            remote_socket_addrs,
+            chain_state,
+            ban_list,
+            addr_manager,
+            filter_headers,
+        })
+    }
 
-        let chain_state_for_block_header_download = chain_state.clone();
+    /// Run the node on the current thread until a stop signal is received or an error occurs.
+    fn run_until<S: ChainStore + Send + 'static>(
+        &self,
+        node: PreparedNode<S>,
+        stop: UnboundedReceiver<()>,
+    ) -> Result<(), Error> {
+        let PreparedNode {
+            remote_socket_addrs,
+            chain_state,
+            ban_list,
+            mut addr_manager,
+            filter_headers,
+        } = node;
 
+        let chain_params = &self.options.chain_params;
+        if addr_manager.is_empty() && !chain_params.dns_seeds.is_empty() {
+            addr_manager.add_seeds(
+                &DnsResolver,
+                &chain_params.dns_seeds,
+                chain_params.default_port,
+            );
+        }
         info!(
-            "Connect to remote peer {}. Network is {}.",
-            remote_socket_addr, self.options.chain_params.network
+            "Known peer addresses: {} ({} tried).",
+            addr_manager.len(),
+            addr_manager.tried_len()
         );
-        let connection = connect(&remote_socket_addr, self.options.chain_params.network)
-            .and_then(|peer| Handshake::new(peer))
-            .and_then(move |peer| {
-                BlockHeaderDownload::new(peer, chain_state_for_block_header_download)
-            })
-            .map(move |_peer| {
-                let chain_state = chain_state.lock().unwrap();
-                let chain_active = chain_state.borrow_chain_active();
-                info!("current block height: {}", chain_active.height());
+
+        let magic = self.options.chain_params.magic;
+        info!(
+            "Connect to remote peers {:?}. Network id is {}.",
+            remote_socket_addrs, self.options.chain_params.network_id
+        );
+        // Peers must serve filters which the node uses.
+        let required_services = match self.options.filter_mode {
+            FilterMode::BloomFilter => NODE_BLOOM,
+            FilterMode::CompactFilter => NODE_NETWORK | NODE_COMPACT_FILTERS,
+        };
+        let protocol_version = self.options.chain_params.protocol_version;
+        let height = self.height.clone();
+        // Handshakes share nonces to detect a connection between two connections of the node.
+        let sent_nonces = SentNonces::default();
+        let connector = move |id, addr: SocketAddr| -> ConnectFuture<_> {
+            let start_height = height.load(Ordering::SeqCst);
+            let sent_nonces = sent_nonces.clone();
+            Box::new(connect(&addr, magic, id).and_then(move |peer| {
+                let mut handshake =
+                    Handshake::new(peer, start_height, required_services, protocol_version);
+                handshake.set_sent_nonces(sent_nonces);
+                handshake
+            }))
+        };
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<ScanEvent>();
+        let transaction_download =
+            match filter_headers {
+                None => TransactionDownload::Bloom(MerkleBlockDownload::new(
+                    self.watch_list.clone(),
+                    sender,
+                )),
+                Some(filter_headers) => TransactionDownload::CompactFilter(
+                    CompactFilterDownload::new(self.watch_list.clone(), filter_headers, sender),
+                ),
+            };
+        let mut peer_manager = PeerManager::new(
+            connector,
+            remote_socket_addrs,
+            self.options.max_outbound_peers,
+            ban_list,
+            chain_state.clone(),
+            transaction_download,
+            self.broadcast.clone(),
+        );
+        peer_manager.set_event_bus(self.events.clone());
+        peer_manager.set_addr_manager(addr_manager);
+        let spv = self.clone();
+        let polled_chain_state = chain_state.clone();
+        let peer_manager = future::poll_fn(move || {
+            let result = peer_manager.poll();
+            let height = polled_chain_state
+                .lock()
+                .unwrap()
+                .borrow_chain_active()
+                .height();
+            spv.height.store(height, Ordering::SeqCst);
+            spv.target_height
+                .store(peer_manager.target_height(height), Ordering::SeqCst);
+            spv.scanned_height
+                .store(peer_manager.scanned_height(), Ordering::SeqCst);
+            spv.peer_count
+                .store(peer_manager.peer_count(), Ordering::SeqCst);
+            spv.notify_confirmations(height);
+            result
+        })
+        .map_err(Error::from);
+
+        let spv = self.clone();
+        let found_transactions = receiver
+            .for_each(move |event| {
+                spv.on_scan_event(event);
+                Ok(())
             })
             .map_err(|e| error!("Error: {:?}", e));
-        tokio::run(connection);
+
+        // Sender of the stop signal is dropped when the handle is dropped, so the node stops in
+        // both cases.
+        let stop = stop
+            .into_future()
+            .map(|_| info!("Stop SPV node."))
+            .map_err(|(e, _)| Error::from(network::Error::from(e)));
+
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(found_transactions);
+        let result = runtime
+            .block_on(peer_manager.select(stop))
+            .map(|_| ())
+            .map_err(|(e, _)| e);
+        // The peer manager was dropped with the sender of found transactions, so the runtime
+        // becomes idle after the remaining transactions are processed.
+        if runtime.shutdown_on_idle().wait().is_err() {
+            error!("Can not shutdown tokio runtime.");
+        }
+
+        self.peer_count.store(0, Ordering::SeqCst);
+        let flushed = chain_state
+            .lock()
+            .unwrap()
+            .borrow_mut_chain_active()
+            .flush();
+        match result {
+            Ok(()) => info!("SPV node stopped."),
+            Err(ref e) => error!("SPV node stopped with error: {:?}", e),
+        }
+        result?;
+        flushed?;
+        Ok(())
+    }
+
+    /// Update the wallet and the UTXO set with found transactions or rollback.
+    fn on_scan_event(&self, event: ScanEvent) {
+        let block = match event {
+            ScanEvent::Found(block) => block,
+            ScanEvent::Rollback(fork_height) => {
+                self.utxo_set.lock().unwrap().rollback(fork_height);
+                self.unconfirmed
+                    .lock()
+                    .unwrap()
+                    .retain(|_, height| *height <= fork_height);
+                return;
+            }
+        };
+
+        // The wallet is unlocked before the watch list is locked.
+        let scripts = match *self.wallet.lock().unwrap() {
+            Some(ref mut wallet) => match wallet.on_transactions(&block.transactions) {
+                Ok(scripts) => scripts,
+                Err(e) => {
+                    error!("Can not derive wallet keys: {:?}", e);
+                    vec![]
+                }
+            },
+            None => vec![],
+        };
+        if !scripts.is_empty() {
+            let mut watch_list = self.watch_list.lock().unwrap();
+            for script in scripts {
+                watch_list.add_script(script);
+            }
+        }
+
+        // The peer manager locks the watch list while it holds the broadcast transactions.
+        {
+            let mut broadcast = self.broadcast.lock().unwrap();
+            for tx in &block.transactions {
+                broadcast.on_confirmed(&tx.txid());
+            }
+        }
+
+        {
+            let watch_list = self.watch_list.lock().unwrap();
+            let mut utxo_set = self.utxo_set.lock().unwrap();
+            for tx in &block.transactions {
+                info!(
+                    "Transaction {} was found in block {} at height {}.",
+                    tx.txid(),
+                    block.block_hash,
+                    block.height
+                );
+                utxo_set.add_transaction(tx, Some(block.height), |script| {
+                    watch_list.contains_script(script)
+                });
+            }
+        }
+
+        let txids: Vec<sha256d::Hash> = block.transactions.iter().map(|tx| tx.txid()).collect();
+        {
+            let mut unconfirmed = self.unconfirmed.lock().unwrap();
+            for txid in &txids {
+                unconfirmed.insert(*txid, block.height);
+            }
+        }
+
+        // Subscribers may call the API, so events are emitted without locks.
+        for txid in txids {
+            self.events.emit(Event::TransactionReceived {
+                txid,
+                height: block.height,
+            });
+        }
+    }
+
+    /// Emit events for found transactions which reached NOTIFY_CONFIRMATIONS.
+    fn notify_confirmations(&self, tip_height: i32) {
+        let confirmed: Vec<(sha256d::Hash, i32)> = {
+            let mut unconfirmed = self.unconfirmed.lock().unwrap();
+            let confirmed: Vec<(sha256d::Hash, i32)> = unconfirmed
+                .iter()
+                .filter(|(_, height)| tip_height - **height + 1 >= NOTIFY_CONFIRMATIONS as i32)
+                .map(|(txid, height)| (*txid, *height))
+                .collect();
+            for (txid, _) in &confirmed {
+                unconfirmed.remove(txid);
+            }
+            confirmed
+        };
+        for (txid, height) in confirmed {
+            self.events
+                .emit(Event::TransactionConfirmed { txid, height });
+        }
+    }
+}
+
+/// Progress of block header download
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncStatus {
+    /// Height of the active chain
+    pub height: i32,
+    /// The highest start height which connected peers reported. It is not less than `height`.
+    pub target_height: i32,
+    /// Height of the last block which has been scanned for transactions of watched scripts and
+    /// outpoints. Transactions in blocks above it are not reflected to balances yet.
+    pub scanned_height: i32,
+    /// The number of connected peers
+    pub peer_count: usize,
+}
+
+impl SyncStatus {
+    /// Return whether the active chain reached heights which connected peers reported.
+    pub fn is_synced(&self) -> bool {
+        self.peer_count > 0 && self.height >= self.target_height
+    }
+
+    /// Return whether all blocks in the active chain have been scanned in addition to being
+    /// synced.
+    pub fn is_scanned(&self) -> bool {
+        self.is_synced() && self.scanned_height >= self.height
+    }
+}
+
+/// Read access to the active chain which is shared with the running node.
+trait ActiveChain: Send + Sync {
+    fn tip(&self) -> BlockIndex;
+    fn get(&self, height: i32) -> Option<BlockIndex>;
+    fn get_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex>;
+    fn federation(&self) -> Federation;
+}
+
+impl<T: ChainStore + Send> ActiveChain for Mutex<ChainState<T>> {
+    fn tip(&self) -> BlockIndex {
+        self.lock().unwrap().borrow_chain_active().tip()
+    }
+
+    fn get(&self, height: i32) -> Option<BlockIndex> {
+        self.lock().unwrap().borrow_chain_active().get(height)
+    }
+
+    fn get_by_hash(&self, hash: &sha256d::Hash) -> Option<BlockIndex> {
+        self.lock().unwrap().borrow_chain_active().get_by_hash(hash)
+    }
+
+    fn federation(&self) -> Federation {
+        self.lock().unwrap().borrow_chain_active().federation()
     }
 }
 
+/// Lifecycle status of the SPV node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeStatus {
+    /// The node is not running. It can be started.
+    Stopped,
+    /// The node is connecting to peers and following the tip of the chain.
+    Running,
+    /// The node received the stop signal and is shutting down.
+    Stopping,
+}
+
+/// Chain state and stores which are opened before the node thread starts.
+struct PreparedNode<S: ChainStore> {
+    remote_socket_addrs: Vec<SocketAddr>,
+    chain_state: Arc<Mutex<ChainState<S>>>,
+    ban_list: BanList,
+    addr_manager: AddrManager,
+    /// Store of compact filter headers. None in FilterMode::BloomFilter.
+    filter_headers: Option<Box<dyn FilterHeaderStore + Send>>,
+}
+
+/// Set the status to Stopped when the node thread exits even if it panicked.
+struct StoppedOnDrop(Arc<Mutex<NodeStatus>>);
+
+impl Drop for StoppedOnDrop {
+    fn drop(&mut self) {
+        if let Ok(mut status) = self.0.lock() {
+            *status = NodeStatus::Stopped;
+        }
+    }
+}
+
+/// Handle of the SPV node which runs on a background thread. It is returned by `SPV::start`.
+///
+/// The node is stopped when the handle is dropped.
+pub struct NodeHandle {
+    status: Arc<Mutex<NodeStatus>>,
+    stop: UnboundedSender<()>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl NodeHandle {
+    /// Return the status of the node.
+    pub fn status(&self) -> NodeStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Stop the node gracefully. This function blocks until connections are closed and the chain
+    /// store is flushed. Return the error if the node stopped with an error.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    /// Block until the node stops by itself. Return the error which stopped the node.
+    pub fn wait(mut self) -> Result<(), Error> {
+        match self.thread.take() {
+            Some(thread) => join(thread),
+            None => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        {
+            let mut status = self.status.lock().unwrap();
+            if *status == NodeStatus::Running {
+                *status = NodeStatus::Stopping;
+            }
+        }
+        let _ = self.stop.try_send(());
+        join(thread)
+    }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("SPV node stopped with error: {:?}", e);
+        }
+    }
+}
+
+/// Wait for the node thread and return its result.
+fn join(thread: JoinHandle<Result<(), Error>>) -> Result<(), Error> {
+    thread.join().unwrap_or_else(|_| {
+        error!("SPV node thread panicked.");
+        Err(Error::Panicked)
+    })
+}
+
 /// Manage blockchain status
 pub struct ChainState<T: ChainStore> {
     chain_active: Chain<T>,
 }
 
-impl ChainState<OnMemoryChainStore> {
+impl<T: ChainStore> ChainState<T> {
     /// create ChainState instance
-    pub fn new<T: ChainStore>(chain_active: Chain<T>) -> ChainState<T> {
+    pub fn new(chain_active: Chain<T>) -> ChainState<T> {
         ChainState { chain_active }
     }
-}
 
-impl<T: ChainStore> ChainState<T> {
     /// borrow chain_active
     pub fn borrow_chain_active(&self) -> &Chain<T> {
         &self.chain_active
@@ -118,19 +885,292 @@ impl<T: ChainStore> ChainState<T> {
 /// Parameters for SPV node
 #[derive(Debug, Clone)]
 pub struct Options {
-    /// Remote peer address to connect.
-    pub remote: String,
-    /// Data directory for putting database files.
+    /// Remote peer addresses to connect.
+    pub remotes: Vec<String>,
+    /// The maximum number of remote peers which the SPV node connects to at the same time.
+    pub max_outbound_peers: usize,
+    /// Data directory for putting database files. Block headers are kept on memory if it is
+    /// empty.
     pub datadir: String,
     /// Chain parameter for network type which the SPV node work on.
     pub chain_params: ChainParams,
+    /// The way to find transactions which match watched scripts and outpoints.
+    pub filter_mode: FilterMode,
 }
 
-/// Parameters for Blockchain network
-#[derive(Debug, Clone)]
-pub struct ChainParams {
-    /// Network Type
-    pub network: Network,
-    /// Genesis block for network to be connected
-    pub genesis: Block,
+/// The way to find transactions which match watched scripts and outpoints.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    /// Load bloom filters (BIP37) on peers. Peers learn which transactions the SPV node is
+    /// interested in.
+    BloomFilter,
+    /// Download compact block filters (BIP157/158) and match them locally. Only blocks whose
+    /// filter matched are downloaded. Outpoints which are watched without their script are not
+    /// found in this mode.
+    CompactFilter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::RawMessage;
+    use crate::network::{FilteredBlock, Peer, PeerID};
+    use crate::test_helper::{
+        get_chain, get_test_aggregated_public_key, get_test_block_index, get_test_genesis_block,
+        get_test_next_aggregated_public_key, get_test_transactions, get_test_wallet_seed, TempDir,
+        TwoWayChannel,
+    };
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tapyrus::network::constants::Network;
+    use tapyrus::BitcoinHash;
+
+    fn test_spv() -> SPV {
+        SPV::new(Options {
+            remotes: vec![],
+            max_outbound_peers: DEFAULT_MAX_OUTBOUND_PEERS,
+            datadir: String::new(),
+            chain_params: ChainParams::legacy(Network::Regtest, get_test_genesis_block()),
+            filter_mode: FilterMode::BloomFilter,
+        })
+    }
+
+    /// Run `f` on a thread `count` times, and return a receiver which receives when it finished.
+    fn run_repeatedly<F>(count: usize, f: F) -> mpsc::Receiver<()>
+    where
+        F: Fn(usize) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for i in 0..count {
+                f(i);
+            }
+            sender.send(()).unwrap();
+        });
+        receiver
+    }
+
+    #[test]
+    fn test_load_wallet_while_scanning() {
+        let spv = test_spv();
+        let options = WalletOptions::new(Network::Regtest, TESTNET_COIN_TYPE);
+        let wallet = Wallet::new(&get_test_wallet_seed(), options.clone()).unwrap();
+        let mut tx = get_test_transactions(1).pop().unwrap();
+        tx.output[0].script_pubkey = wallet.scripts()[0].clone();
+
+        let scanner = spv.clone();
+        let scanned = run_repeatedly(50, move |i| {
+            scanner.on_scan_event(ScanEvent::Found(FilteredBlock {
+                block_hash: sha256d::Hash::default(),
+                height: i as i32,
+                transactions: vec![tx.clone()],
+            }));
+        });
+        let loader = spv.clone();
+        let loaded = run_repeatedly(50, move |_| {
+            let wallet = Wallet::new(&get_test_wallet_seed(), options.clone()).unwrap();
+            loader.load_wallet(wallet);
+        });
+
+        let timeout = Duration::from_secs(30);
+        assert!(scanned.recv_timeout(timeout).is_ok());
+        assert!(loaded.recv_timeout(timeout).is_ok());
+        assert!(spv.receive_address().is_some());
+    }
+
+    #[test]
+    fn test_transaction_events_use_txid() {
+        let spv = test_spv();
+        let mut tx = get_test_transactions(1).pop().unwrap();
+        tx.input[0].script_sig = Script::from(vec![0x51]);
+        assert_ne!(tx.txid(), tx.malfix_txid());
+        spv.watch_script(tx.output[0].script_pubkey.clone());
+        let receiver = spv.subscribe();
+
+        let _confirmed = spv.broadcast(tx.clone());
+        assert_eq!(spv.requested_peers(&tx.txid()), Some(0));
+
+        spv.on_scan_event(ScanEvent::Found(FilteredBlock {
+            block_hash: sha256d::Hash::default(),
+            height: 1,
+            transactions: vec![tx.clone()],
+        }));
+        assert_eq!(spv.requested_peers(&tx.txid()), None);
+        spv.notify_confirmations(NOTIFY_CONFIRMATIONS as i32);
+        drop(spv);
+
+        let events: Vec<Event> = receiver.take(2).collect().wait().unwrap();
+        assert_eq!(
+            events,
+            vec![
+                Event::TransactionReceived {
+                    txid: tx.txid(),
+                    height: 1,
+                },
+                Event::TransactionConfirmed {
+                    txid: tx.txid(),
+                    height: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_while_requesting_blocks() {
+        let spv = test_spv();
+        let tx = get_test_transactions(1).pop().unwrap();
+        spv.watch_script(tx.output[0].script_pubkey.clone());
+
+        let scanner = spv.clone();
+        let scanned = run_repeatedly(50, move |i| {
+            scanner.on_scan_event(ScanEvent::Found(FilteredBlock {
+                block_hash: sha256d::Hash::default(),
+                height: i as i32,
+                transactions: vec![tx.clone()],
+            }));
+        });
+
+        // The peer manager requests blocks while it holds the broadcast transactions.
+        let requester = spv.clone();
+        let requested = run_repeatedly(50, move |_| {
+            let mut download =
+                MerkleBlockDownload::new(requester.watch_list.clone(), unbounded_channel().0);
+            let mut peers: HashMap<PeerID, Peer<TwoWayChannel<RawMessage>>> = HashMap::new();
+            let _broadcast = requester.broadcast.lock().unwrap();
+            download.request(&mut peers, &get_chain(), true);
+        });
+
+        let timeout = Duration::from_secs(30);
+        assert!(scanned.recv_timeout(timeout).is_ok());
+        assert!(requested.recv_timeout(timeout).is_ok());
+    }
+
+    #[test]
+    fn test_start_and_stop() {
+        let dir = TempDir::new("spv_test_start_and_stop");
+        let spv = SPV::new(Options {
+            remotes: vec![],
+            max_outbound_peers: DEFAULT_MAX_OUTBOUND_PEERS,
+            datadir: dir.path().to_str().unwrap().to_string(),
+            chain_params: ChainParams::legacy(Network::Regtest, get_test_genesis_block()),
+            filter_mode: FilterMode::BloomFilter,
+        });
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+        assert_eq!(spv.tip(), None);
+
+        let handle = spv.start().unwrap();
+        assert_eq!(handle.status(), NodeStatus::Running);
+        assert_eq!(spv.status(), NodeStatus::Running);
+        match spv.start() {
+            Err(Error::AlreadyRunning) => {}
+            _ => panic!("Node should not be started twice."),
+        }
+        handle.stop().unwrap();
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+        assert!(dir.path().join("headers.dat").exists());
+
+        // the chain which the node synced is available after it stopped.
+        let genesis = get_test_genesis_block();
+        let tip = spv.tip().unwrap();
+        assert_eq!(tip.height, 0);
+        assert_eq!(tip.header, genesis.header);
+        assert_eq!(spv.header(0), Some(tip.clone()));
+        assert_eq!(spv.header(1), None);
+        assert_eq!(spv.header_by_hash(&genesis.bitcoin_hash()), Some(tip));
+        assert!(!spv.sync_status().is_synced());
+
+        // the node can be started again, and dropping the handle stops it.
+        let handle = spv.start().unwrap();
+        assert_eq!(spv.status(), NodeStatus::Running);
+        drop(handle);
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+        assert_eq!(spv.peer_count(), 0);
+    }
+
+    #[test]
+    fn test_start_from_trusted_checkpoint() {
+        let dir = TempDir::new("spv_test_start_from_trusted_checkpoint");
+        let base = get_test_block_index(10);
+        let spv = SPV::new(Options {
+            remotes: vec![],
+            max_outbound_peers: DEFAULT_MAX_OUTBOUND_PEERS,
+            datadir: dir.path().to_str().unwrap().to_string(),
+            chain_params: ChainParams {
+                trusted_checkpoint: Some(TrustedCheckpoint {
+                    height: 10,
+                    header: base.header.clone(),
+                    aggregated_public_key: get_test_aggregated_public_key(),
+                }),
+                ..ChainParams::legacy(Network::Regtest, get_test_genesis_block())
+            },
+            filter_mode: FilterMode::BloomFilter,
+        });
+        spv.start().unwrap().stop().unwrap();
+
+        assert_eq!(spv.tip(), Some(base));
+        assert_eq!(spv.header(0), None);
+        assert_eq!(spv.federation().unwrap().activation_height, 10);
+
+        // the chain in datadir can't be used with compact filters.
+        let spv = SPV::new(Options {
+            filter_mode: FilterMode::CompactFilter,
+            ..spv.options.clone()
+        });
+        match spv.start() {
+            Err(Error::CompactFilterFromCheckpoint) => {}
+            _ => panic!("Compact filter mode should not be used from a trusted checkpoint."),
+        }
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+    }
+
+    #[test]
+    fn test_start_with_invalid_options() {
+        let mut options = test_spv().options;
+        options.remotes = vec!["localhost:2360".to_string()];
+        let spv = SPV::new(options.clone());
+        match spv.start() {
+            Err(Error::InvalidRemote(remote)) => assert_eq!(remote, "localhost:2360"),
+            _ => panic!("Remote address should be parsed before start."),
+        }
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+
+        // datadir which is a file can't be opened.
+        let dir = TempDir::new("spv_test_start_with_invalid_options");
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        options.remotes = vec![];
+        options.datadir = path.to_str().unwrap().to_string();
+        let spv = SPV::new(options);
+        match spv.start() {
+            Err(Error::ChainError(_)) => {}
+            _ => panic!("Chain store should be opened before start."),
+        }
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+    }
+
+    #[test]
+    fn test_start_with_conflicting_federation() {
+        let dir = TempDir::new("spv_test_start_with_conflicting_federation");
+        {
+            let mut store = FileChainStore::open(dir.path()).unwrap();
+            let saved = Federation {
+                activation_height: 10,
+                aggregated_public_key: get_test_next_aggregated_public_key(),
+            };
+            store.save_federations(&[saved]).unwrap();
+        }
+
+        let mut options = test_spv().options;
+        options.datadir = dir.path().to_str().unwrap().to_string();
+        options.chain_params.federations = vec![Federation {
+            activation_height: 10,
+            aggregated_public_key: get_test_aggregated_public_key(),
+        }];
+        let spv = SPV::new(options);
+        match spv.start() {
+            Err(Error::ChainError(ChainError::ConflictingFederation)) => {}
+            _ => panic!("Federation which conflicts with the saved one should be rejected."),
+        }
+        assert_eq!(spv.status(), NodeStatus::Stopped);
+    }
 }
@@ -0,0 +1,466 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::Error;
+use rand::seq::IteratorRandom;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of known addresses in datadir.
+pub const ADDR_MANAGER_FILE_NAME: &str = "peers.dat";
+
+/// The maximum number of addresses in the new bucket.
+pub const MAX_NEW_ADDRS: usize = 1024;
+
+/// The maximum number of addresses in the tried bucket.
+pub const MAX_TRIED_ADDRS: usize = 256;
+
+/// The maximum number of addresses in an addr or addrv2 message.
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+/// Addresses in the new bucket are dropped after this number of consecutive failures.
+const MAX_NEW_FAILURES: u32 = 3;
+
+/// Time of an announced address must not be later than this number of seconds from now.
+const MAX_FUTURE_TIME: u64 = 10 * 60;
+
+/// Addresses announced with a time in the future are regarded as seen this number of seconds ago,
+/// like Bitcoin Core does.
+const FUTURE_TIME_PENALTY: u64 = 5 * 24 * 60 * 60;
+
+/// Resolve host names of DNS seeds.
+pub trait Resolver {
+    /// Return addresses of the host. The port is used for all addresses.
+    fn resolve(&self, host: &str, port: u16) -> Vec<SocketAddr>;
+}
+
+/// Resolver which uses DNS resolution of the system. It blocks until the query finishes.
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+    fn resolve(&self, host: &str, port: u16) -> Vec<SocketAddr> {
+        match (host, port).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                warn!("Can not resolve DNS seed {}: {}", host, e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// State of a known address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AddrInfo {
+    /// Unix time when the address was announced or connected last.
+    last_seen: u64,
+    /// Unix time of the last successful connection. It is 0 if the address has never been
+    /// connected.
+    last_success: u64,
+    /// The number of consecutive failures of connection.
+    failures: u32,
+}
+
+/// Addresses of peers which the node can connect to.
+///
+/// Addresses which were learned from DNS seeds or addr messages are put in the new bucket. An
+/// address moves to the tried bucket once a connection to it succeeds. Both buckets have limited
+/// size, and the oldest address is evicted when a bucket is full.
+///
+/// If the manager is opened in datadir, changes are saved into the file by `flush` and when the
+/// manager is dropped. Each line of the file is the bucket, the address, the last seen time, the
+/// last success time and the number of consecutive failures, separated by spaces.
+pub struct AddrManager {
+    new: HashMap<SocketAddr, AddrInfo>,
+    tried: HashMap<SocketAddr, AddrInfo>,
+    path: Option<PathBuf>,
+    /// True if addresses were changed after they were saved.
+    dirty: bool,
+}
+
+impl AddrManager {
+    /// Create address manager which is kept on memory.
+    pub fn new() -> AddrManager {
+        AddrManager {
+            new: HashMap::new(),
+            tried: HashMap::new(),
+            path: None,
+            dirty: false,
+        }
+    }
+
+    /// Open address manager in `datadir`.
+    pub fn open(datadir: &Path) -> Result<AddrManager, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let path = datadir.join(ADDR_MANAGER_FILE_NAME);
+        let mut manager = AddrManager::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match parse_line(&line) {
+                        Some((true, addr, info)) => {
+                            manager.tried.insert(addr, info);
+                        }
+                        Some((false, addr, info)) => {
+                            manager.new.insert(addr, info);
+                        }
+                        None => warn!("Ignore broken line in address list: \"{}\"", line),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        manager.path = Some(path);
+        Ok(manager)
+    }
+
+    /// Return the number of known addresses.
+    pub fn len(&self) -> usize {
+        self.new.len() + self.tried.len()
+    }
+
+    /// Return the number of addresses which have been connected successfully.
+    pub fn tried_len(&self) -> usize {
+        self.tried.len()
+    }
+
+    /// Return true if no address is known.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add addresses with unix time when they were seen. Return the number of addresses which
+    /// were not known.
+    ///
+    /// Time later than MAX_FUTURE_TIME from now is replaced with FUTURE_TIME_PENALTY ago, so that
+    /// peers can't keep their addresses from being evicted.
+    pub fn add<I>(&mut self, addrs: I) -> usize
+    where
+        I: IntoIterator<Item = (SocketAddr, u64)>,
+    {
+        let now = now();
+        let mut added = 0;
+        for (addr, time) in addrs {
+            let time = if time > now + MAX_FUTURE_TIME {
+                now.saturating_sub(FUTURE_TIME_PENALTY)
+            } else {
+                time
+            };
+            self.dirty = true;
+            if let Some(info) = self.tried.get_mut(&addr) {
+                info.last_seen = std::cmp::max(info.last_seen, time);
+                continue;
+            }
+            if let Some(info) = self.new.get_mut(&addr) {
+                info.last_seen = std::cmp::max(info.last_seen, time);
+                continue;
+            }
+
+            if self.new.len() >= MAX_NEW_ADDRS {
+                evict_oldest(&mut self.new, |info| info.last_seen);
+            }
+            self.new.insert(
+                addr,
+                AddrInfo {
+                    last_seen: time,
+                    last_success: 0,
+                    failures: 0,
+                },
+            );
+            added += 1;
+        }
+
+        added
+    }
+
+    /// Resolve DNS seeds and add their addresses with the port. Return the number of addresses
+    /// which were not known.
+    pub fn add_seeds<R: Resolver>(&mut self, resolver: &R, seeds: &[String], port: u16) -> usize {
+        let now = now();
+        let addrs: Vec<(SocketAddr, u64)> = seeds
+            .iter()
+            .flat_map(|seed| {
+                let addrs = resolver.resolve(seed, port);
+                info!("DNS seed {} returned {} addresses.", seed, addrs.len());
+                addrs
+            })
+            .map(|addr| (addr, now))
+            .collect();
+        self.add(addrs)
+    }
+
+    /// Mark the address as connected successfully. It is moved to the tried bucket.
+    pub fn good(&mut self, addr: SocketAddr) {
+        let now = now();
+        self.new.remove(&addr);
+        if !self.tried.contains_key(&addr) && self.tried.len() >= MAX_TRIED_ADDRS {
+            evict_oldest(&mut self.tried, |info| info.last_success);
+        }
+        self.tried.insert(
+            addr,
+            AddrInfo {
+                last_seen: now,
+                last_success: now,
+                failures: 0,
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Mark the connection to the address as failed. Addresses in the new bucket are dropped
+    /// after MAX_NEW_FAILURES consecutive failures.
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.tried.get_mut(addr) {
+            info.failures += 1;
+            self.dirty = true;
+            return;
+        }
+        let drop = match self.new.get_mut(addr) {
+            Some(info) => {
+                info.failures += 1;
+                self.dirty = true;
+                info.failures >= MAX_NEW_FAILURES
+            }
+            None => false,
+        };
+        if drop {
+            debug!("Forget address {}.", addr);
+            self.new.remove(addr);
+        }
+    }
+
+    /// Choose an address which satisfies `filter` at random. Either bucket is chosen with equal
+    /// probability, and the other bucket is used if it has no such address.
+    pub fn select<F>(&self, filter: F) -> Option<SocketAddr>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let mut rng = thread_rng();
+        let (first, second) = if rng.gen_bool(0.5) {
+            (&self.tried, &self.new)
+        } else {
+            (&self.new, &self.tried)
+        };
+        first
+            .keys()
+            .filter(|addr| filter(addr))
+            .choose(&mut rng)
+            .or_else(|| second.keys().filter(|addr| filter(addr)).choose(&mut rng))
+            .cloned()
+    }
+
+    /// Write all addresses if they were changed after the last save. The file is replaced
+    /// atomically by renaming a temporary file.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let path = match self.path {
+            Some(ref path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            let buckets = self
+                .tried
+                .iter()
+                .map(|entry| ("tried", entry))
+                .chain(self.new.iter().map(|entry| ("new", entry)));
+            for (bucket, (addr, info)) in buckets {
+                writeln!(
+                    file,
+                    "{} {} {} {} {}",
+                    bucket, addr, info.last_seen, info.last_success, info.failures
+                )?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for AddrManager {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Can not save addresses: {:?}", e);
+        }
+    }
+}
+
+/// Remove the entry which has the smallest time.
+fn evict_oldest<F>(bucket: &mut HashMap<SocketAddr, AddrInfo>, time: F)
+where
+    F: Fn(&AddrInfo) -> u64,
+{
+    let oldest = bucket
+        .iter()
+        .min_by_key(|(_, info)| time(info))
+        .map(|(addr, _)| *addr);
+    if let Some(addr) = oldest {
+        bucket.remove(&addr);
+    }
+}
+
+/// Parse a line of the file. The first value is true if the address is in the tried bucket.
+fn parse_line(line: &str) -> Option<(bool, SocketAddr, AddrInfo)> {
+    let mut iter = line.split_whitespace();
+    let tried = match iter.next()? {
+        "tried" => true,
+        "new" => false,
+        _ => return None,
+    };
+    let addr = iter.next()?.parse().ok()?;
+    let last_seen = iter.next()?.parse().ok()?;
+    let last_success = iter.next()?.parse().ok()?;
+    let failures = iter.next()?.parse().ok()?;
+    Some((
+        tried,
+        addr,
+        AddrInfo {
+            last_seen,
+            last_success,
+            failures,
+        },
+    ))
+}
+
+/// now in unix time
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::TempDir;
+
+    /// Resolver which returns fixed addresses for each host.
+    struct StubResolver(HashMap<String, Vec<&'static str>>);
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, host: &str, port: u16) -> Vec<SocketAddr> {
+            self.0
+                .get(host)
+                .map(|ips| {
+                    ips.iter()
+                        .map(|ip| SocketAddr::new(ip.parse().unwrap(), port))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn test_add_seeds() {
+        let mut resolver = StubResolver(HashMap::new());
+        resolver
+            .0
+            .insert("seed1".to_string(), vec!["127.0.0.1", "127.0.0.2"]);
+        resolver.0.insert("seed2".to_string(), vec!["127.0.0.2"]);
+
+        let mut manager = AddrManager::new();
+        let seeds = vec![
+            "seed1".to_string(),
+            "seed2".to_string(),
+            "unknown".to_string(),
+        ];
+        assert_eq!(manager.add_seeds(&resolver, &seeds, 2357), 2);
+        assert_eq!(manager.len(), 2);
+
+        let addr: SocketAddr = "127.0.0.1:2357".parse().unwrap();
+        assert_eq!(manager.select(|a| *a == addr), Some(addr));
+        assert_eq!(manager.select(|_| false), None);
+    }
+
+    #[test]
+    fn test_good_and_failed() {
+        let mut manager = AddrManager::new();
+        let addr1: SocketAddr = "127.0.0.1:2357".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.2:2357".parse().unwrap();
+        manager.add(vec![(addr1, 1), (addr2, 1)]);
+
+        // tried address is kept after failures.
+        manager.good(addr1);
+        assert!(manager.tried.contains_key(&addr1));
+        assert!(manager.tried[&addr1].last_success > 0);
+        for _ in 0..MAX_NEW_FAILURES {
+            manager.failed(&addr1);
+            manager.failed(&addr2);
+        }
+        assert!(manager.tried.contains_key(&addr1));
+        assert_eq!(manager.len(), 1);
+
+        // known address is not added again.
+        assert_eq!(manager.add(vec![(addr1, 2)]), 0);
+        assert_eq!(manager.len(), 1);
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let mut manager = AddrManager::new();
+        let addrs: Vec<(SocketAddr, u64)> = (0..MAX_NEW_ADDRS + 1)
+            .map(|i| (SocketAddr::new([127, 0, 0, 1].into(), i as u16), i as u64))
+            .collect();
+        manager.add(addrs);
+        assert_eq!(manager.len(), MAX_NEW_ADDRS);
+        assert!(!manager.new.contains_key(&"127.0.0.1:0".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_add_future_time() {
+        let mut manager = AddrManager::new();
+        let addr1: SocketAddr = "127.0.0.1:2357".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.2:2357".parse().unwrap();
+        let now = now();
+        manager.add(vec![(addr1, now + 60), (addr2, now + 24 * 60 * 60)]);
+        assert_eq!(manager.new[&addr1].last_seen, now + 60);
+        let last_seen = manager.new[&addr2].last_seen;
+        assert!(last_seen >= now - FUTURE_TIME_PENALTY);
+        assert!(last_seen <= super::now() - FUTURE_TIME_PENALTY);
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = TempDir::new("addr_manager_test_persist");
+        let addr1: SocketAddr = "127.0.0.1:2357".parse().unwrap();
+        let addr2: SocketAddr = "[::1]:2357".parse().unwrap();
+        {
+            let mut manager = AddrManager::open(dir.path()).unwrap();
+            manager.add(vec![(addr1, 1), (addr2, 2)]);
+            manager.flush().unwrap();
+            assert!(!manager.dirty);
+
+            // changes which are not flushed are saved when the manager is dropped.
+            manager.good(addr1);
+            manager.failed(&addr1);
+            manager.failed(&addr2);
+        }
+
+        // add broken line.
+        let path = dir.path().join(ADDR_MANAGER_FILE_NAME);
+        let mut content = fs::read_to_string(&path).unwrap();
+        content.push_str("broken\nnew 127.0.0.3:2357 a 0 0\nnew 127.0.0.4:2357 3 0\n");
+        fs::write(&path, content).unwrap();
+
+        let manager = AddrManager::open(dir.path()).unwrap();
+        assert_eq!(manager.len(), 2);
+        assert!(manager.tried.contains_key(&addr1));
+        assert_eq!(manager.tried[&addr1].failures, 1);
+        assert_eq!(manager.new[&addr2].last_seen, 2);
+        assert_eq!(manager.new[&addr2].failures, 1);
+    }
+}
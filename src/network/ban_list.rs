@@ -0,0 +1,165 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::Error;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File name of ban list in datadir.
+pub const BAN_LIST_FILE_NAME: &str = "banlist.dat";
+
+/// Duration of ban for misbehaving peers.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// List of banned addresses.
+///
+/// If the list is opened in datadir, it is saved into the file whenever an address is banned.
+/// Each line of the file is an address and the unix time when the ban expires, separated by a
+/// space.
+pub struct BanList {
+    /// Unix time when the ban expires, indexed by banned address.
+    entries: HashMap<IpAddr, u64>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Create ban list which is kept on memory.
+    pub fn new() -> BanList {
+        BanList {
+            entries: HashMap::new(),
+            path: None,
+        }
+    }
+
+    /// Open ban list in `datadir`. Expired entries are dropped.
+    pub fn open(datadir: &Path) -> Result<BanList, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let path = datadir.join(BAN_LIST_FILE_NAME);
+        let mut entries = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                let now = now();
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match parse_line(&line) {
+                        Some((addr, until)) if until > now => {
+                            entries.insert(addr, until);
+                        }
+                        Some(_) => {} // expired.
+                        None => warn!("Ignore broken line in ban list: \"{}\"", line),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        Ok(BanList {
+            entries,
+            path: Some(path),
+        })
+    }
+
+    /// Ban the address for the duration.
+    pub fn ban(&mut self, addr: IpAddr, duration: Duration) -> Result<(), Error> {
+        let until = now() + duration.as_secs();
+        info!("Ban {} until {}.", addr, until);
+        self.entries.insert(addr, until);
+        self.save()
+    }
+
+    /// Return true if the address is banned and the ban has not expired.
+    pub fn is_banned(&self, addr: &IpAddr) -> bool {
+        self.entries.get(addr).map_or(false, |until| *until > now())
+    }
+
+    /// Write all entries which are not expired. The file is replaced atomically by renaming a
+    /// temporary file.
+    fn save(&mut self) -> Result<(), Error> {
+        let now = now();
+        self.entries.retain(|_, until| *until > now);
+
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for (addr, until) in &self.entries {
+                writeln!(file, "{} {}", addr, until)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Option<(IpAddr, u64)> {
+    let mut iter = line.split_whitespace();
+    let addr = iter.next()?.parse().ok()?;
+    let until = iter.next()?.parse().ok()?;
+    Some((addr, until))
+}
+
+/// now in unix time
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::TempDir;
+
+    #[test]
+    fn test_ban() {
+        let mut ban_list = BanList::new();
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+
+        assert!(!ban_list.is_banned(&addr));
+
+        ban_list.ban(addr, DEFAULT_BAN_DURATION).unwrap();
+        assert!(ban_list.is_banned(&addr));
+        assert!(!ban_list.is_banned(&other));
+
+        // expired ban.
+        ban_list.ban(other, Duration::from_secs(0)).unwrap();
+        assert!(!ban_list.is_banned(&other));
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = TempDir::new("ban_list_test_persist");
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "::1".parse().unwrap();
+        {
+            let mut ban_list = BanList::open(dir.path()).unwrap();
+            ban_list.ban(addr, DEFAULT_BAN_DURATION).unwrap();
+            ban_list.ban(other, DEFAULT_BAN_DURATION).unwrap();
+        }
+
+        // add broken line and expired entry.
+        let path = dir.path().join(BAN_LIST_FILE_NAME);
+        let mut content = fs::read_to_string(&path).unwrap();
+        content.push_str("broken\n127.0.0.3 1\n");
+        fs::write(&path, content).unwrap();
+
+        let ban_list = BanList::open(dir.path()).unwrap();
+        assert!(ban_list.is_banned(&addr));
+        assert!(ban_list.is_banned(&other));
+        assert_eq!(ban_list.entries.len(), 2);
+    }
+}
@@ -2,47 +2,193 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
-use crate::chain::{Chain, ChainStore};
-use crate::network::{error::MaliciousPeerCause, Error, Peer};
-use crate::ChainState;
-use std::cell::RefCell;
-use std::sync::{Arc, Mutex};
-use tapyrus::network::message::NetworkMessage;
-use tapyrus::network::message::RawNetworkMessage;
+use crate::chain::{self, Chain, ChainStore, ConnectResult};
+use crate::network::message::RawMessage;
+use crate::network::peer::next_peer_id;
+use crate::network::{error::MaliciousPeerCause, Error, Peer, PeerID};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tapyrus::network::message_blockdata::{InvType, Inventory};
 use tapyrus::BlockHeader;
-use tokio::prelude::{Async, Future, Sink, Stream};
+use tokio::prelude::{Sink, Stream};
 
 /// The maximum number of block headers that can be in a single headers message.
 pub const MAX_HEADERS_RESULTS: usize = 2_000;
 
-pub struct BlockHeaderDownload<T, S>
-where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
-    S: ChainStore,
-{
-    peer: Option<RefCell<Peer<T>>>,
-    started: bool,
-    chain_state: Arc<Mutex<ChainState<S>>>,
+/// If a peer doesn't respond to getheaders message in this duration, the request is abandoned.
+/// During the initial download, the next request is sent to another peer.
+pub const HEADERS_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The peer is regarded as misbehaving each time it announces this number of headers which don't
+/// connect to known block.
+pub const MAX_UNCONNECTING_HEADERS: u32 = 10;
+
+/// Download block headers from connected peers and follow the tip.
+///
+/// During the initial download, only one getheaders request is in flight at a time. Each request
+/// is sent to the next peer in order of PeerID, so that the download is spread across peers and is
+/// not stalled by a single peer.
+///
+/// After the initial download, new blocks are found by `inv` and `headers` announcements. When
+/// an announced block is unknown, getheaders is sent to the peer which announced it.
+pub struct BlockHeaderDownload {
+    /// Peers which getheaders message was sent to and when it was sent.
+    requests: HashMap<PeerID, Instant>,
+    /// The peer which the last request was sent to.
+    last_peer: Option<PeerID>,
+    /// The number of announcements which don't connect to known block, indexed by peer.
+    unconnecting_headers: HashMap<PeerID, u32>,
+    synced: bool,
     max_headers_results: usize,
 }
 
-impl<T, S> BlockHeaderDownload<T, S>
-where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
-    S: ChainStore,
-{
-    pub fn new(peer: Peer<T>, chain_state: Arc<Mutex<ChainState<S>>>) -> BlockHeaderDownload<T, S> {
+impl BlockHeaderDownload {
+    pub fn new() -> BlockHeaderDownload {
+        BlockHeaderDownload::with_max_headers_results(MAX_HEADERS_RESULTS)
+    }
+
+    /// Return true if the initial download has been finished.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    pub fn with_max_headers_results(max_headers_results: usize) -> BlockHeaderDownload {
         BlockHeaderDownload {
-            peer: Some(RefCell::new(peer)),
-            started: false,
-            chain_state,
-            max_headers_results: MAX_HEADERS_RESULTS,
+            requests: HashMap::new(),
+            last_peer: None,
+            unconnecting_headers: HashMap::new(),
+            synced: false,
+            max_headers_results,
+        }
+    }
+
+    /// Abandon requests to peers which have been disconnected or timed out. Then, during the
+    /// initial download, send getheaders message to the next peer if there is no request in
+    /// flight.
+    pub fn request<T, S>(&mut self, peers: &mut HashMap<PeerID, Peer<T>>, chain_active: &Chain<S>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        self.requests.retain(|peer_id, sent_at| {
+            if !peers.contains_key(peer_id) {
+                false
+            } else if sent_at.elapsed() >= HEADERS_REQUEST_TIMEOUT {
+                warn!("getheaders request to peer {} timed out.", peer_id);
+                false
+            } else {
+                true
+            }
+        });
+        self.unconnecting_headers
+            .retain(|peer_id, _| peers.contains_key(peer_id));
+
+        if self.synced || !self.requests.is_empty() {
+            return;
+        }
+
+        if let Some(peer_id) = next_peer_id(peers.keys().cloned(), self.last_peer) {
+            let peer = peers.get_mut(&peer_id).unwrap();
+            self.send_getheaders(peer, chain_active);
         }
     }
+
+    /// Process headers message received from the peer.
+    pub fn on_headers<T, S>(
+        &mut self,
+        peer: &mut Peer<T>,
+        chain_active: &mut Chain<S>,
+        headers: Vec<BlockHeader>,
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let requested = self.requests.remove(&peer.id).is_some();
+
+        // Announced headers don't connect to our chain when some announcements were missed.
+        // Request the missing headers instead of rejecting them. Headers which are sent as a
+        // response to getheaders message must connect.
+        if !requested {
+            if let Some(first) = headers.first() {
+                if !chain_active.is_known(&first.prev_blockhash) {
+                    debug!(
+                        "Headers announced by peer {} don't connect. Request missing headers.",
+                        peer.id
+                    );
+                    self.send_getheaders(peer, chain_active);
+
+                    let count = self.unconnecting_headers.entry(peer.id).or_insert(0);
+                    *count += 1;
+                    if *count % MAX_UNCONNECTING_HEADERS == 0 {
+                        return Err(Error::MaliciousPeer(
+                            peer.id,
+                            MaliciousPeerCause::UnconnectingHeaders,
+                        ));
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        let all_headers_downloaded =
+            process_headers(peer, chain_active, headers, self.max_headers_results)?;
+        self.unconnecting_headers.remove(&peer.id);
+
+        if !self.synced {
+            if requested && all_headers_downloaded {
+                info!(
+                    "Block headers download finished. height: {}",
+                    chain_active.height()
+                );
+                self.synced = true;
+            }
+        } else if !all_headers_downloaded {
+            // The peer has more headers.
+            self.send_getheaders(peer, chain_active);
+        }
+
+        Ok(())
+    }
+
+    /// Process inv message received from the peer. Send getheaders message when the peer
+    /// announces unknown blocks.
+    pub fn on_inv<T, S>(
+        &mut self,
+        peer: &mut Peer<T>,
+        chain_active: &Chain<S>,
+        inventory: &[Inventory],
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        let has_unknown_block = inventory
+            .iter()
+            .any(|inv| inv.inv_type == InvType::Block && !chain_active.is_known(&inv.hash));
+
+        if has_unknown_block && !self.requests.contains_key(&peer.id) {
+            debug!("Peer {} announced new block.", peer.id);
+            self.send_getheaders(peer, chain_active);
+        }
+    }
+
+    fn send_getheaders<T, S>(&mut self, peer: &mut Peer<T>, chain_active: &Chain<S>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        peer.send_getheaders(chain_active);
+        self.requests.insert(peer.id, Instant::now());
+        self.last_peer = Some(peer.id);
+    }
 }
 
 /// Process received headers message.
 /// Return flag for whether all block headers received.
+///
+/// Headers after the tip which are not signed by the known federations don't add ban score,
+/// because the federation may have been replaced without being added to the chain. The peer is
+/// disconnected, and the chain doesn't grow until the federation is added.
 fn process_headers<T, S: ChainStore>(
     peer: &mut Peer<T>,
     chain_active: &mut Chain<S>,
@@ -50,7 +196,7 @@ fn process_headers<T, S: ChainStore>(
     max_headers_results: usize,
 ) -> Result<bool, Error>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     if headers.len() > max_headers_results {
         return Err(Error::MaliciousPeer(
@@ -59,84 +205,70 @@ where
         ));
     }
 
-    let all_headers_downloaded = headers.len() < max_headers_results;
-
-    for header in headers {
-        let _ = chain_active.connect_block_header(header);
-    }
-
-    if !all_headers_downloaded {
-        peer.send_getheaders(chain_active);
+    // Headers must be connected to known block.
+    if let Some(first) = headers.first() {
+        if !chain_active.is_known(&first.prev_blockhash) {
+            return Err(Error::MaliciousPeer(
+                peer.id,
+                MaliciousPeerCause::UnconnectingHeaders,
+            ));
+        }
     }
 
-    Ok(all_headers_downloaded)
-}
-
-impl<T, S> Future for BlockHeaderDownload<T, S>
-where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
-    S: ChainStore,
-    Error: From<T::Error>,
-{
-    type Item = Peer<T>;
-    type Error = Error;
-
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        let mut done = false;
-
-        let mut chain_state = self.chain_state.lock().unwrap();
-        let chain_active = chain_state.borrow_mut_chain_active();
-
-        if let Some(ref peer) = self.peer {
-            let mut peer = peer.borrow_mut();
-
-            if !self.started {
-                peer.send_getheaders(chain_active);
-                self.started = true;
-            }
+    let all_headers_downloaded = headers.len() < max_headers_results;
 
-            loop {
-                match peer.poll()? {
-                    Async::Ready(Some(NetworkMessage::Headers(headers))) => {
-                        done = process_headers(
-                            &mut peer,
-                            chain_active,
-                            headers,
-                            self.max_headers_results,
-                        )?;
-                    }
-                    Async::Ready(None) | Async::NotReady => break,
-                    Async::Ready(_) => {} // ignore other messages.
+    for header in headers {
+        let result = chain_active
+            .connect_block_header(header)
+            .map_err(|e| match e {
+                chain::Error::InvalidProof => {
+                    Error::MaliciousPeer(peer.id, MaliciousPeerCause::InvalidBlockProof)
                 }
-            }
-            peer.flush();
-        } else {
-            panic!("BlockHeaderDownload should have peer instance when call poll.");
-        }
-
-        if done {
-            let peer = self.peer.take().unwrap();
-            Ok(Async::Ready(peer.into_inner()))
-        } else {
-            Ok(Async::NotReady)
+                chain::Error::UnknownFederation(height) => {
+                    error!(
+                        "Block at height {} from peer {} is not signed by known federations. Add the federation which signs it to continue sync.",
+                        height, peer.id
+                    );
+                    Error::from(e)
+                }
+                e => Error::from(e),
+            })?;
+
+        if let ConnectResult::Reorganized(reorg) = result {
+            info!(
+                "Chain was reorganized by headers from peer {}. disconnected: {}, connected: {}",
+                peer.id,
+                reorg.disconnected.len(),
+                reorg.connected.len()
+            );
         }
     }
+
+    Ok(all_headers_downloaded)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_helper::{
-        channel, get_chain, get_test_genesis_block, get_test_headers, TwoWayChannel,
-    };
-    use bitcoin_hashes::sha256d;
-    use tapyrus::network::message_blockdata::GetHeadersMessage;
+    use crate::test_helper::{channel, create_signed_header, get_chain, get_test_headers};
+    use crate::ChainState;
     use tapyrus::{BitcoinHash, Network};
 
+    fn requested_peers(download: &BlockHeaderDownload) -> Vec<PeerID> {
+        let mut peers: Vec<PeerID> = download.requests.keys().cloned().collect();
+        peers.sort();
+        peers
+    }
+
     #[test]
     fn test_process_headers_fails_when_passed_over_max_headers_results() {
-        let (_here, there) = channel::<RawNetworkMessage>();
-        let mut peer = Peer::new(0, there, "0.0.0.0:0".parse().unwrap(), Network::Regtest);
+        let (_here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
 
         let mut chain_state = ChainState::new(get_chain());
         let mut chain_active = chain_state.borrow_mut_chain_active();
@@ -150,153 +282,202 @@ mod tests {
         }
     }
 
-    /// Build remote peer for testing BlockHeaderDownload future.
-    /// Remote peer checks and responds messages from local peer.
-    ///
-    /// ## Situation
-    /// Local peer has only genesis block. Remote peer has 24 blocks includes genesis block.
-    ///
-    /// ## Flow
-    /// 1st message round trip, local peer send getheaders message and get 10 blocks from remote.
-    /// 2nd message round trip, local peer send getheaders message and get 10 blocks from remote.
-    /// 3rd message round trip, local peer send getheaders message and get 3 blocks from remote.
-    /// And finish sending getheaders message.
-    fn remote_peer(stream: TwoWayChannel<RawNetworkMessage>) -> impl Future<Item = (), Error = ()> {
-        stream
-            .into_future()
-            .and_then(|(msg, mut here)| {
-                // 1st message round trip.
-                // local peer request block headers with `getheaders` message. And remote peer sends
-                // MAX_HEADERS_RESULTS headers.
-                if let Some(RawNetworkMessage {
-                    payload: NetworkMessage::GetHeaders(getheaders_msg),
-                    ..
-                }) = msg
-                {
-                    match getheaders_msg {
-                        GetHeadersMessage {
-                            locator_hashes,
-                            stop_hash,
-                            ..
-                        } => {
-                            // test BlockHeaderDownload future send collect message.
-                            assert_eq!(
-                                locator_hashes,
-                                vec![get_test_genesis_block().header.bitcoin_hash()]
-                            );
-                            assert_eq!(stop_hash, sha256d::Hash::default());
-                        }
-                    }
-                } else {
-                    assert!(false, "Peer should send 1st getheaders message.");
-                }
+    #[test]
+    fn test_process_headers_fails_when_passed_unknown_prev_blockhash() {
+        let (_here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
 
-                let headers_message = RawNetworkMessage {
-                    magic: Network::Regtest.magic(),
-                    payload: NetworkMessage::Headers(get_test_headers(1, 10)),
-                };
-
-                let _ = here.start_send(headers_message);
-
-                here.into_future()
-            })
-            .and_then(|(msg, mut here)| {
-                // 2nd message round trip.
-                // Remote peer send next 10 headers.
-                if let Some(RawNetworkMessage {
-                    payload: NetworkMessage::GetHeaders(getheaders_msg),
-                    ..
-                }) = msg
-                {
-                    match getheaders_msg {
-                        GetHeadersMessage {
-                            locator_hashes,
-                            stop_hash,
-                            ..
-                        } => {
-                            // test BlockHeaderDownload future send collect message.
-                            let expected: Vec<sha256d::Hash> = get_test_headers(0, 11)
-                                .into_iter()
-                                .rev()
-                                .map(|v| v.bitcoin_hash())
-                                .collect();
-                            assert_eq!(locator_hashes, expected);
-                            assert_eq!(stop_hash, sha256d::Hash::default());
-                        }
-                    }
-                } else {
-                    assert!(false, "Peer should send 2nd getheaders message.");
-                }
+        let mut chain_state = ChainState::new(get_chain());
+        let mut chain_active = chain_state.borrow_mut_chain_active();
+        let headers = get_test_headers(2, 5);
+        let result = process_headers(&mut peer, &mut chain_active, headers, 10);
 
-                let headers_message = RawNetworkMessage {
-                    magic: Network::Regtest.magic(),
-                    payload: NetworkMessage::Headers(get_test_headers(10, 10)),
-                };
-
-                let _ = here.start_send(headers_message);
-
-                here.into_future()
-            })
-            .map(|(msg, mut here)| {
-                // 3rd message round trip.
-                // Remote peer send 3 headers as latest headers.
-                if let Some(RawNetworkMessage {
-                    payload: NetworkMessage::GetHeaders(getheaders_msg),
-                    ..
-                }) = msg
-                {
-                    match getheaders_msg {
-                        GetHeadersMessage { stop_hash, .. } => {
-                            // test BlockHeaderDownload future send collect message.
-                            assert_eq!(stop_hash, sha256d::Hash::default());
-                        }
-                    }
-                } else {
-                    assert!(false, "Peer should send 3rd getheaders message.");
-                }
+        match result {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::UnconnectingHeaders)) => {}
+            _ => assert!(false, "process_headers should fail."),
+        }
+        assert_eq!(chain_active.height(), 0);
+    }
+
+    #[test]
+    fn test_process_headers_fails_when_passed_invalid_proof() {
+        let (_here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
 
-                let headers_message = RawNetworkMessage {
-                    magic: Network::Regtest.magic(),
-                    payload: NetworkMessage::Headers(get_test_headers(20, 3)),
-                };
+        let mut chain_state = ChainState::new(get_chain());
+        let mut chain_active = chain_state.borrow_mut_chain_active();
+        let headers = get_test_headers(1, 3);
+        process_headers(&mut peer, &mut chain_active, headers.clone(), 10).unwrap();
 
-                let _ = here.start_send(headers_message);
+        // fork at the height which the active chain has.
+        let mut fork2 = create_signed_header(&headers[0], 1);
+        fork2.proof = headers[2].proof.clone();
+        let result = process_headers(&mut peer, &mut chain_active, vec![fork2], 10);
 
-                ()
-            })
-            .map_err(|_| {})
+        match result {
+            Err(Error::MaliciousPeer(_, MaliciousPeerCause::InvalidBlockProof)) => {}
+            _ => assert!(false, "process_headers should fail."),
+        }
+        assert_eq!(chain_active.height(), 3);
     }
 
     #[test]
-    fn test_block_header_download() {
-        let (here, there) = channel::<RawNetworkMessage>();
-        let peer = Peer::new(0, there, "0.0.0.0:0".parse().unwrap(), Network::Regtest);
+    fn test_process_headers_fails_when_federation_is_unknown() {
+        let (_here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            0,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
 
-        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
-        let chain_state_for_block_header_download = chain_state.clone();
+        let mut chain_state = ChainState::new(get_chain());
+        let mut chain_active = chain_state.borrow_mut_chain_active();
+        let mut headers = get_test_headers(1, 3);
+        headers[1].proof = headers[2].proof.clone();
+        let result = process_headers(&mut peer, &mut chain_active, headers, 10);
 
-        let future = tokio::prelude::future::lazy(move || {
-            tokio::spawn(remote_peer(here));
+        match result {
+            Err(Error::ChainError(chain::Error::UnknownFederation(2))) => {}
+            _ => assert!(false, "process_headers should fail."),
+        }
+        assert_eq!(chain_active.height(), 1);
+        assert_eq!(peer.ban_score, 0);
+    }
 
-            let blockheaderdownload = BlockHeaderDownload {
-                peer: Some(RefCell::new(peer)),
-                started: false,
-                chain_state: chain_state_for_block_header_download,
-                max_headers_results: 10,
-            }
-            .map(move |_| {
-                // test after BlockHeaderDownload future finished
-                let chain_state = chain_state.lock().unwrap();
-                let chain_active = chain_state.borrow_chain_active();
-                assert_eq!(chain_active.height(), 23);
-            })
-            .map_err(|_| {});
+    #[test]
+    fn test_request_spreads_across_peers() {
+        let mut peers = HashMap::new();
+        let mut remotes = vec![];
+        for id in &[1, 2, 5] {
+            let (here, there) = channel::<RawMessage>();
+            let peer = Peer::new(
+                *id,
+                there,
+                "0.0.0.0:0".parse().unwrap(),
+                Network::Regtest.magic(),
+            );
+            peers.insert(*id, peer);
+            remotes.push(here);
+        }
 
-            tokio::spawn(blockheaderdownload);
+        let mut chain_state = ChainState::new(get_chain());
+        let chain_active = chain_state.borrow_mut_chain_active();
+        let mut download = BlockHeaderDownload::with_max_headers_results(10);
+
+        download.request(&mut peers, chain_active);
+        assert_eq!(requested_peers(&download), vec![1]);
+
+        // request is not sent while the request is in flight.
+        download.request(&mut peers, chain_active);
+        assert_eq!(requested_peers(&download), vec![1]);
+
+        let headers = get_test_headers(1, 10);
+        download
+            .on_headers(peers.get_mut(&1).unwrap(), chain_active, headers)
+            .unwrap();
+        assert!(requested_peers(&download).is_empty());
+        assert!(!download.synced);
+
+        download.request(&mut peers, chain_active);
+        assert_eq!(requested_peers(&download), vec![2]);
+
+        // the request is sent to another peer when the peer was disconnected.
+        peers.remove(&2);
+        download.request(&mut peers, chain_active);
+        assert_eq!(requested_peers(&download), vec![5]);
+
+        let headers = get_test_headers(11, 3);
+        download
+            .on_headers(peers.get_mut(&5).unwrap(), chain_active, headers)
+            .unwrap();
+        assert!(download.synced);
+        assert_eq!(chain_active.height(), 13);
+
+        // no request is sent after the initial download.
+        download.request(&mut peers, chain_active);
+        assert!(requested_peers(&download).is_empty());
+    }
 
-            Ok(())
-        });
+    #[test]
+    fn test_follow_announcements() {
+        let (_here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            1,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
+
+        let mut chain_state = ChainState::new(get_chain());
+        let chain_active = chain_state.borrow_mut_chain_active();
+        let mut download = BlockHeaderDownload::with_max_headers_results(10);
+        download.synced = true;
+
+        // announced header which connects to the tip is connected.
+        download
+            .on_headers(&mut peer, chain_active, get_test_headers(1, 1))
+            .unwrap();
+        assert_eq!(chain_active.height(), 1);
+        assert!(requested_peers(&download).is_empty());
+
+        // announced header which doesn't connect causes getheaders request.
+        download
+            .on_headers(&mut peer, chain_active, get_test_headers(3, 1))
+            .unwrap();
+        assert_eq!(chain_active.height(), 1);
+        assert_eq!(requested_peers(&download), vec![1]);
+
+        // the response must connect.
+        let result = download.on_headers(&mut peer, chain_active, get_test_headers(3, 1));
+        assert!(result.is_err());
 
-        tokio::runtime::current_thread::run(future);
+        // too many unconnecting announcements are regarded as misbehavior.
+        download.unconnecting_headers.clear();
+        for _ in 0..MAX_UNCONNECTING_HEADERS - 1 {
+            download.requests.clear();
+            download
+                .on_headers(&mut peer, chain_active, get_test_headers(3, 1))
+                .unwrap();
+        }
+        download.requests.clear();
+        let result = download.on_headers(&mut peer, chain_active, get_test_headers(3, 1));
+        match result {
+            Err(Error::MaliciousPeer(1, MaliciousPeerCause::UnconnectingHeaders)) => {}
+            _ => assert!(false, "on_headers should fail."),
+        }
+        download.requests.clear();
+
+        // inv of known block is ignored.
+        let known = Inventory {
+            inv_type: InvType::Block,
+            hash: get_test_headers(1, 1)[0].bitcoin_hash(),
+        };
+        download.on_inv(&mut peer, chain_active, &[known]);
+        assert!(requested_peers(&download).is_empty());
+
+        // inv of unknown block causes getheaders request.
+        let unknown = Inventory {
+            inv_type: InvType::Block,
+            hash: get_test_headers(2, 1)[0].bitcoin_hash(),
+        };
+        download.on_inv(&mut peer, chain_active, &[unknown]);
+        assert_eq!(requested_peers(&download), vec![1]);
+
+        download
+            .on_headers(&mut peer, chain_active, get_test_headers(2, 2))
+            .unwrap();
+        assert_eq!(chain_active.height(), 3);
+        assert!(requested_peers(&download).is_empty());
     }
 }
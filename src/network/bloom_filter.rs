@@ -0,0 +1,194 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::message::FilterLoadMessage;
+use std::cmp;
+
+/// The maximum size of bloom filter in bytes.
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// The maximum number of hash functions.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Peer adds outpoint to the filter when any data element in output script matches.
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+
+const LN2_SQUARED: f64 = 0.480_453_013_918_201_4;
+const LN2: f64 = 0.693_147_180_559_945_3;
+
+/// Bloom filter which is described in BIP37.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+    flags: u8,
+}
+
+impl BloomFilter {
+    /// Create filter which has false positive rate `fp_rate` when `elements` data elements are
+    /// inserted. The size of filter is limited to MAX_BLOOM_FILTER_SIZE.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: u8) -> BloomFilter {
+        let elements = cmp::max(elements, 1) as f64;
+        let size = (-1.0 / LN2_SQUARED * elements * fp_rate.ln() / 8.0) as usize;
+        let size = cmp::max(cmp::min(size, MAX_BLOOM_FILTER_SIZE), 1);
+        let hash_funcs = (size as f64 * 8.0 / elements * LN2) as u32;
+        let hash_funcs = cmp::max(cmp::min(hash_funcs, MAX_HASH_FUNCS), 1);
+
+        BloomFilter {
+            data: vec![0u8; size],
+            hash_funcs,
+            tweak,
+            flags,
+        }
+    }
+
+    /// Insert data element into the filter.
+    pub fn insert(&mut self, data: &[u8]) {
+        for i in 0..self.hash_funcs {
+            let index = self.hash(i, data);
+            self.data[index >> 3] |= 1 << (7 & index);
+        }
+    }
+
+    /// Return true if the data element may be in the filter.
+    #[cfg(test)]
+    pub fn contains(&self, data: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|i| {
+            let index = self.hash(i, data);
+            self.data[index >> 3] & (1 << (7 & index)) != 0
+        })
+    }
+
+    /// Return filterload message which loads this filter.
+    pub fn to_message(&self) -> FilterLoadMessage {
+        FilterLoadMessage {
+            filter: self.data.clone(),
+            hash_funcs: self.hash_funcs,
+            tweak: self.tweak,
+            flags: self.flags,
+        }
+    }
+
+    fn hash(&self, n: u32, data: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xFBA4_C795).wrapping_add(self.tweak);
+        murmur3(seed, data) as usize % (self.data.len() * 8)
+    }
+}
+
+/// 32 bits MurmurHash3
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h1 = seed;
+    let blocks = data.len() / 4;
+
+    for i in 0..blocks {
+        let mut k1 = u32::from_le_bytes([
+            data[i * 4],
+            data[i * 4 + 1],
+            data[i * 4 + 2],
+            data[i * 4 + 3],
+        ]);
+        k1 = k1.wrapping_mul(C1);
+        k1 = k1.rotate_left(15);
+        k1 = k1.wrapping_mul(C2);
+
+        h1 ^= k1;
+        h1 = h1.rotate_left(13);
+        h1 = h1.wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let tail = &data[blocks * 4..];
+    let mut k1 = 0u32;
+    if tail.len() >= 3 {
+        k1 ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        k1 ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        k1 ^= tail[0] as u32;
+        k1 = k1.wrapping_mul(C1);
+        k1 = k1.rotate_left(15);
+        k1 = k1.wrapping_mul(C2);
+        h1 ^= k1;
+    }
+
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 = h1.wrapping_mul(0x85eb_ca6b);
+    h1 ^= h1 >> 13;
+    h1 = h1.wrapping_mul(0xc2b2_ae35);
+    h1 ^= h1 >> 16;
+    h1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur3() {
+        // test vectors from Bitcoin Core
+        assert_eq!(murmur3(0x00000000, &[]), 0x00000000);
+        assert_eq!(murmur3(0xFBA4C795, &[]), 0x6a396f08);
+        assert_eq!(murmur3(0xffffffff, &[]), 0x81f16f39);
+        assert_eq!(murmur3(0x00000000, &[0x00]), 0x514E28B7);
+        assert_eq!(murmur3(0xFBA4C795, &[0x00]), 0xea3f0b17);
+        assert_eq!(murmur3(0x00000000, &[0xff]), 0xfd6cf10d);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11]), 0x16c6b7ab);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22]), 0x8eb51c3d);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33]), 0xb4471bf8);
+        assert_eq!(
+            murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33, 0x44]),
+            0xe2301fa8
+        );
+    }
+
+    fn filter_with_test_elements(tweak: u32) -> BloomFilter {
+        let mut filter = BloomFilter::new(3, 0.01, tweak, BLOOM_UPDATE_ALL);
+
+        let element = hex::decode("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        filter.insert(&element);
+        assert!(filter.contains(&element));
+
+        // one bit different in first byte
+        let other = hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        assert!(!filter.contains(&other));
+
+        filter.insert(&hex::decode("b5a2c786d9ef4658287ced5914b37a1b4aa32eee").unwrap());
+        filter.insert(&hex::decode("b9300670b4c5366e95b2699e8b18bc75e5f729c5").unwrap());
+        filter
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        // test vectors from Bitcoin Core
+        let filter = filter_with_test_elements(0);
+        let message = filter.to_message();
+        assert_eq!(message.filter, vec![0x61, 0x4e, 0x9b]);
+        assert_eq!(message.hash_funcs, 5);
+        assert_eq!(message.tweak, 0);
+        assert_eq!(message.flags, BLOOM_UPDATE_ALL);
+    }
+
+    #[test]
+    fn test_bloom_filter_with_tweak() {
+        let filter = filter_with_test_elements(2147483649);
+        let message = filter.to_message();
+        assert_eq!(message.filter, vec![0xce, 0x42, 0x99]);
+        assert_eq!(message.hash_funcs, 5);
+        assert_eq!(message.tweak, 2147483649);
+    }
+
+    #[test]
+    fn test_filter_size_is_limited() {
+        let filter = BloomFilter::new(1_000_000, 0.000_001, 0, BLOOM_UPDATE_ALL);
+        let message = filter.to_message();
+        assert_eq!(message.filter.len(), MAX_BLOOM_FILTER_SIZE);
+        assert!(message.hash_funcs <= MAX_HASH_FUNCS);
+    }
+}
@@ -0,0 +1,1082 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::block_filter::{filter_header, BASIC_FILTER_TYPE};
+use crate::chain::{BlockFilter, Chain, ChainStore, FilterHeaderIndex, FilterHeaderStore};
+use crate::network::message::{
+    CFHeadersMessage, CFilterMessage, GetCFHeadersMessage, GetCFiltersMessage, Message, RawMessage,
+};
+use crate::network::peer::next_peer_id;
+use crate::network::scan_progress::ScanProgress;
+use crate::network::{
+    Error, FilteredBlock, MaliciousPeerCause, Peer, PeerID, ScanEvent, WatchList,
+};
+use bitcoin_hashes::sha256d;
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::{InvType, Inventory};
+use tapyrus::util::hash::bitcoin_merkle_root;
+use tapyrus::{BitcoinHash, Block};
+use tokio::prelude::{Sink, Stream};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Service bit of peers which serve compact block filters (BIP157).
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+
+/// The maximum number of filter headers which are requested by a single getcfheaders message.
+pub const MAX_GETCFHEADERS_SIZE: i32 = 2_000;
+
+/// The maximum number of filters which are requested by a single getcfilters message.
+pub const MAX_GETCFILTERS_SIZE: i32 = 1_000;
+
+/// The number of peers which filter headers are requested to and compared between.
+pub const FILTER_HEADERS_PEERS: usize = 3;
+
+/// If peers don't respond to a request in this duration, the request is abandoned.
+pub const COMPACT_FILTERS_REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// getcfheaders request which is sent to several peers.
+struct FilterHeadersRequest {
+    start_height: i32,
+    block_hashes: Vec<sha256d::Hash>,
+    /// Filter header of the block before `start_height`.
+    previous: sha256d::Hash,
+    /// Filter hashes in the response of each peer. It is None until the peer responds.
+    responses: HashMap<PeerID, Option<Vec<sha256d::Hash>>>,
+    sent_at: Instant,
+}
+
+/// Block and filters which are requested to find peers which sent wrong filter headers. When
+/// peers disagree, the filter of each peer at the first differing height is checked against the
+/// block as described in BIP157.
+struct FilterHeadersConflict {
+    /// The request whose responses conflict.
+    request: FilterHeadersRequest,
+    /// Height of the first block whose filter hash differs between peers.
+    height: i32,
+    /// The peer which the block is requested to.
+    block_peer: PeerID,
+    block: Option<Block>,
+    /// Filters received from the peers which responded to the request.
+    filters: HashMap<PeerID, Option<BlockFilter>>,
+    /// It is None until the block and filters are requested.
+    sent_at: Option<Instant>,
+}
+
+impl FilterHeadersConflict {
+    fn block_hash(&self) -> sha256d::Hash {
+        self.request.block_hashes[(self.height - self.request.start_height) as usize]
+    }
+}
+
+/// getcfilters request which is in flight.
+struct FiltersRequest {
+    peer_id: PeerID,
+    start_height: i32,
+    block_hashes: Vec<sha256d::Hash>,
+    /// The number of received filters.
+    received: usize,
+    /// Blocks whose filter matched the watch list.
+    matched: Vec<sha256d::Hash>,
+    blocks: HashMap<sha256d::Hash, Block>,
+    sent_at: Instant,
+}
+
+/// Download compact block filters (BIP157/158) and find transactions which match the watch list.
+///
+/// After the initial header download, filter headers of the active chain are requested from up
+/// to FILTER_HEADERS_PEERS peers which signal NODE_COMPACT_FILTERS, and they are stored only when
+/// all responding peers agree. If they disagree, the block and the filters at the first differing
+/// height are downloaded, and peers whose filter doesn't have all output scripts of the block are
+/// banned. Scripts of spent outputs can not be checked without previous transactions, so the
+/// request is abandoned if no peer is found lying. Then filters are downloaded and checked
+/// against the stored filter
+/// headers. Filters are matched against watched scripts locally, and only the blocks whose filter
+/// matched are downloaded. Blocks which have matched transactions are sent to `sender`. When
+/// scanned blocks are removed from the active chain, the rollback is sent and blocks are scanned
+/// again from the fork point.
+///
+/// Basic filters contain scriptPubKeys of outputs and spent outputs, so outpoints which are
+/// watched without the script are not found in this mode.
+pub struct CompactFilterDownload {
+    watch_list: Arc<Mutex<WatchList>>,
+    filter_headers: Box<dyn FilterHeaderStore + Send>,
+    scanned: ScanProgress,
+    headers_request: Option<FilterHeadersRequest>,
+    headers_conflict: Option<FilterHeadersConflict>,
+    filters_request: Option<FiltersRequest>,
+    /// The peer which the last request was sent to.
+    last_peer: Option<PeerID>,
+    /// Peers which were found lying while responses of other peers were processed.
+    penalties: Vec<(PeerID, MaliciousPeerCause)>,
+    sender: UnboundedSender<ScanEvent>,
+}
+
+impl CompactFilterDownload {
+    pub fn new(
+        watch_list: Arc<Mutex<WatchList>>,
+        filter_headers: Box<dyn FilterHeaderStore + Send>,
+        sender: UnboundedSender<ScanEvent>,
+    ) -> CompactFilterDownload {
+        CompactFilterDownload {
+            watch_list,
+            filter_headers,
+            scanned: ScanProgress::new(),
+            headers_request: None,
+            headers_conflict: None,
+            filters_request: None,
+            last_peer: None,
+            penalties: vec![],
+            sender,
+        }
+    }
+
+    /// Return height of the last block which has been scanned for transactions.
+    pub fn scanned_height(&self) -> i32 {
+        self.scanned.height()
+    }
+
+    /// Penalize peers which were found lying, and abandon requests to peers which have been
+    /// disconnected or timed out. Then, after the initial header download, request filter
+    /// headers and filters which have not been downloaded.
+    pub fn request<T, S>(
+        &mut self,
+        peers: &mut HashMap<PeerID, Peer<T>>,
+        chain_active: &Chain<S>,
+        synced: bool,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        for (peer_id, cause) in self.penalties.drain(..) {
+            if let Some(peer) = peers.get_mut(&peer_id) {
+                peer.misbehaving(&cause);
+            }
+        }
+        self.expire_requests(peers);
+
+        if let Some(fork_height) = self.scanned.rewind(chain_active) {
+            self.filters_request = None;
+            self.send(ScanEvent::Rollback(fork_height));
+        }
+
+        if !synced {
+            return;
+        }
+        self.rollback(chain_active);
+
+        let mut filter_peers: Vec<PeerID> = peers
+            .values()
+            .filter(|peer| supports_compact_filters(peer))
+            .map(|peer| peer.id)
+            .collect();
+        filter_peers.sort();
+
+        self.request_conflict(peers);
+        if self.headers_request.is_none()
+            && self.headers_conflict.is_none()
+            && self.filter_headers.height() < chain_active.height()
+        {
+            self.request_filter_headers(peers, &filter_peers, chain_active);
+        }
+
+        if self.filters_request.is_none()
+            && self.scanned.height() < self.filter_headers.height()
+            && !self.watch_list.lock().unwrap().is_empty()
+        {
+            self.request_filters(peers, &filter_peers);
+        }
+    }
+
+    fn expire_requests<T>(&mut self, peers: &HashMap<PeerID, Peer<T>>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        if let Some(ref mut request) = self.headers_request {
+            request
+                .responses
+                .retain(|peer_id, _| peers.contains_key(peer_id));
+            if request.sent_at.elapsed() >= COMPACT_FILTERS_REQUEST_TIMEOUT {
+                warn!("getcfheaders request timed out.");
+                self.headers_request = None;
+            } else if request.responses.is_empty() {
+                self.headers_request = None;
+            }
+        }
+        self.complete_filter_headers();
+
+        let mut abandon = false;
+        if let Some(ref mut conflict) = self.headers_conflict {
+            conflict
+                .request
+                .responses
+                .retain(|peer_id, _| peers.contains_key(peer_id));
+            conflict
+                .filters
+                .retain(|peer_id, _| peers.contains_key(peer_id));
+            if conflict
+                .sent_at
+                .map_or(false, |t| t.elapsed() >= COMPACT_FILTERS_REQUEST_TIMEOUT)
+            {
+                warn!("Block and filters to resolve conflicting filter headers timed out.");
+                abandon = true;
+            } else if conflict.block.is_none() && !peers.contains_key(&conflict.block_peer) {
+                abandon = true;
+            }
+        }
+        if abandon {
+            self.headers_conflict = None;
+        }
+        self.resolve_conflict();
+
+        if let Some(ref request) = self.filters_request {
+            if !peers.contains_key(&request.peer_id) {
+                self.filters_request = None;
+            } else if request.sent_at.elapsed() >= COMPACT_FILTERS_REQUEST_TIMEOUT {
+                warn!("getcfilters request to peer {} timed out.", request.peer_id);
+                self.filters_request = None;
+            }
+        }
+    }
+
+    /// Remove filter headers of blocks which are no longer in the active chain.
+    fn rollback<S: ChainStore>(&mut self, chain_active: &Chain<S>) {
+        let mut height = self.filter_headers.height();
+        while height >= 0 {
+            let stored = self
+                .filter_headers
+                .get(height)
+                .map(|index| index.block_hash);
+            let active = chain_active
+                .get(height)
+                .map(|index| index.header.bitcoin_hash());
+            if stored.is_some() && stored == active {
+                break;
+            }
+            height -= 1;
+        }
+
+        if height < self.filter_headers.height() {
+            info!("Roll back filter headers to height {}.", height);
+            if let Err(e) = self.filter_headers.truncate(height) {
+                error!("Can not truncate filter headers: {:?}", e);
+            }
+            self.headers_request = None;
+            self.headers_conflict = None;
+            self.filters_request = None;
+        }
+    }
+
+    fn request_filter_headers<T, S>(
+        &mut self,
+        peers: &mut HashMap<PeerID, Peer<T>>,
+        filter_peers: &[PeerID],
+        chain_active: &Chain<S>,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        if filter_peers.is_empty() {
+            return;
+        }
+
+        let start_height = self.filter_headers.height() + 1;
+        let stop_height = cmp::min(
+            start_height + MAX_GETCFHEADERS_SIZE - 1,
+            chain_active.height(),
+        );
+        let block_hashes: Vec<sha256d::Hash> = (start_height..=stop_height)
+            .map(|height| chain_active.get(height).unwrap().header.bitcoin_hash())
+            .collect();
+        let previous = match start_height {
+            0 => sha256d::Hash::default(),
+            _ => match self.filter_headers.get(start_height - 1) {
+                Some(index) => index.filter_header,
+                None => return,
+            },
+        };
+
+        let message = GetCFHeadersMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: start_height as u32,
+            stop_hash: *block_hashes.last().unwrap(),
+        };
+
+        let mut responses = HashMap::new();
+        while responses.len() < cmp::min(FILTER_HEADERS_PEERS, filter_peers.len()) {
+            let peer_id = next_peer_id(filter_peers.iter().cloned(), self.last_peer).unwrap();
+            debug!(
+                "Request filter headers from {} to {} to peer {}.",
+                start_height, stop_height, peer_id
+            );
+            let peer = peers.get_mut(&peer_id).unwrap();
+            peer.start_send(Message::GetCFHeaders(message.clone()));
+            responses.insert(peer_id, None);
+            self.last_peer = Some(peer_id);
+        }
+
+        self.headers_request = Some(FilterHeadersRequest {
+            start_height,
+            block_hashes,
+            previous,
+            responses,
+            sent_at: Instant::now(),
+        });
+    }
+
+    fn request_filters<T>(&mut self, peers: &mut HashMap<PeerID, Peer<T>>, filter_peers: &[PeerID])
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        let peer_id = match next_peer_id(filter_peers.iter().cloned(), self.last_peer) {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+
+        let start_height = self.scanned.height() + 1;
+        let stop_height = cmp::min(
+            start_height + MAX_GETCFILTERS_SIZE - 1,
+            self.filter_headers.height(),
+        );
+        let block_hashes: Option<Vec<sha256d::Hash>> = (start_height..=stop_height)
+            .map(|height| {
+                self.filter_headers
+                    .get(height)
+                    .map(|index| index.block_hash)
+            })
+            .collect();
+        let block_hashes = match block_hashes {
+            Some(block_hashes) => block_hashes,
+            None => return,
+        };
+
+        debug!(
+            "Request filters from {} to {} to peer {}.",
+            start_height, stop_height, peer_id
+        );
+        let peer = peers.get_mut(&peer_id).unwrap();
+        peer.start_send(Message::GetCFilters(GetCFiltersMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: start_height as u32,
+            stop_hash: *block_hashes.last().unwrap(),
+        }));
+
+        self.filters_request = Some(FiltersRequest {
+            peer_id,
+            start_height,
+            block_hashes,
+            received: 0,
+            matched: vec![],
+            blocks: HashMap::new(),
+            sent_at: Instant::now(),
+        });
+        self.last_peer = Some(peer_id);
+    }
+
+    /// Process cfheaders message received from the peer.
+    pub fn on_cfheaders(
+        &mut self,
+        peer_id: PeerID,
+        message: CFHeadersMessage,
+    ) -> Result<(), Error> {
+        let request = match self.headers_request {
+            Some(ref mut request) if request.responses.get(&peer_id) == Some(&None) => request,
+            _ => return Ok(()),
+        };
+
+        let result = if message.filter_type != BASIC_FILTER_TYPE
+            || Some(&message.stop_hash) != request.block_hashes.last()
+            || message.filter_hashes.len() != request.block_hashes.len()
+        {
+            request.responses.remove(&peer_id);
+            Err(Error::MaliciousPeer(
+                peer_id,
+                MaliciousPeerCause::InvalidFilterHeaders,
+            ))
+        } else if message.previous_filter_header != request.previous {
+            // The previous filter header was agreed by peers or verified, so the peer lies.
+            request.responses.remove(&peer_id);
+            Err(Error::MaliciousPeer(
+                peer_id,
+                MaliciousPeerCause::InvalidFilterHeaders,
+            ))
+        } else {
+            request
+                .responses
+                .insert(peer_id, Some(message.filter_hashes));
+            Ok(())
+        };
+
+        self.complete_filter_headers();
+        result
+    }
+
+    /// Store filter headers if all peers have responded and their responses agree. If they
+    /// disagree, the block and filters at the first differing height are going to be requested.
+    fn complete_filter_headers(&mut self) {
+        match self.headers_request {
+            Some(ref request) if request.responses.values().all(|r| r.is_some()) => {}
+            _ => return,
+        }
+        let request = self.headers_request.take().unwrap();
+
+        let responses: Vec<&Vec<sha256d::Hash>> = request
+            .responses
+            .values()
+            .filter_map(|r| r.as_ref())
+            .collect();
+        let filter_hashes = match responses.first() {
+            Some(filter_hashes) => (*filter_hashes).clone(),
+            None => return,
+        };
+        let difference = (0..filter_hashes.len())
+            .find(|i| responses.iter().any(|other| other[*i] != filter_hashes[*i]));
+        if let Some(i) = difference {
+            let height = request.start_height + i as i32;
+            warn!(
+                "Peers sent conflicting filter headers at height {}.",
+                height
+            );
+            let filters = request.responses.keys().map(|id| (*id, None)).collect();
+            let block_peer = *request.responses.keys().min().unwrap();
+            self.headers_conflict = Some(FilterHeadersConflict {
+                request,
+                height,
+                block_peer,
+                block: None,
+                filters,
+                sent_at: None,
+            });
+            return;
+        }
+
+        let mut previous = request.previous;
+        for (block_hash, filter_hash) in request.block_hashes.iter().zip(&filter_hashes) {
+            previous = filter_header(filter_hash, &previous);
+            let index = FilterHeaderIndex {
+                block_hash: *block_hash,
+                filter_header: previous,
+            };
+            if let Err(e) = self.filter_headers.push(index) {
+                error!("Can not store filter headers: {:?}", e);
+                return;
+            }
+        }
+        debug!(
+            "Filter headers are stored. height: {}",
+            self.filter_headers.height()
+        );
+    }
+
+    /// Request the block and filters at the height where peers disagree.
+    fn request_conflict<T>(&mut self, peers: &mut HashMap<PeerID, Peer<T>>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        let conflict = match self.headers_conflict {
+            Some(ref mut conflict) if conflict.sent_at.is_none() => conflict,
+            _ => return,
+        };
+
+        let block_hash = conflict.block_hash();
+        for peer_id in conflict.filters.keys() {
+            debug!(
+                "Request filter at height {} to peer {} to resolve conflict.",
+                conflict.height, peer_id
+            );
+            let peer = peers.get_mut(peer_id).unwrap();
+            peer.start_send(Message::GetCFilters(GetCFiltersMessage {
+                filter_type: BASIC_FILTER_TYPE,
+                start_height: conflict.height as u32,
+                stop_hash: block_hash,
+            }));
+        }
+
+        // The block is verified against the header, so it may come from any of the peers.
+        let peer = peers.get_mut(&conflict.block_peer).unwrap();
+        peer.start_send(NetworkMessage::GetData(vec![Inventory {
+            inv_type: InvType::Block,
+            hash: block_hash,
+        }]));
+        conflict.sent_at = Some(Instant::now());
+    }
+
+    /// Check filters against the block once all of them have been received. Peers whose filter
+    /// doesn't match their filter headers or the block are penalized, and the responses of the
+    /// rest of peers are compared again.
+    fn resolve_conflict(&mut self) {
+        match self.headers_conflict {
+            Some(ref conflict)
+                if conflict.block.is_some() && conflict.filters.values().all(|f| f.is_some()) => {}
+            _ => return,
+        }
+        let conflict = self.headers_conflict.take().unwrap();
+        let mut request = conflict.request;
+        let block = conflict.block.unwrap();
+        let position = (conflict.height - request.start_height) as usize;
+
+        let mut liars = vec![];
+        for (peer_id, filter) in conflict.filters {
+            let filter = filter.unwrap();
+            let committed = request
+                .responses
+                .get(&peer_id)
+                .and_then(|r| r.as_ref())
+                .map(|filter_hashes| filter_hashes[position]);
+            if committed != Some(filter.filter_hash()) || !has_all_outputs(&filter, &block) {
+                liars.push(peer_id);
+            }
+        }
+
+        if liars.is_empty() {
+            warn!(
+                "Can not find the peer which sent wrong filter headers at height {}.",
+                conflict.height
+            );
+            return;
+        }
+        for peer_id in liars {
+            request.responses.remove(&peer_id);
+            self.penalties
+                .push((peer_id, MaliciousPeerCause::InvalidFilterHeaders));
+        }
+        self.headers_request = Some(request);
+        self.complete_filter_headers();
+    }
+
+    /// Process cfilter message received from the peer. Return error if the filter doesn't match
+    /// the filter header. When the filter matches the watch list, the block is requested.
+    pub fn on_cfilter<T>(
+        &mut self,
+        peer: &mut Peer<T>,
+        message: CFilterMessage,
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        if let Some(ref mut conflict) = self.headers_conflict {
+            if conflict.filters.get(&peer.id) == Some(&None)
+                && message.block_hash == conflict.block_hash()
+            {
+                if message.filter_type != BASIC_FILTER_TYPE {
+                    return Err(Error::MaliciousPeer(
+                        peer.id,
+                        MaliciousPeerCause::InvalidCompactFilter,
+                    ));
+                }
+                conflict
+                    .filters
+                    .insert(peer.id, Some(BlockFilter::new(message.filter)));
+                self.resolve_conflict();
+                return Ok(());
+            }
+        }
+
+        let request = match self.filters_request {
+            Some(ref mut request) if request.peer_id == peer.id => request,
+            _ => return Ok(()),
+        };
+
+        let invalid = Error::MaliciousPeer(peer.id, MaliciousPeerCause::InvalidCompactFilter);
+        if message.filter_type != BASIC_FILTER_TYPE
+            || request.block_hashes.get(request.received) != Some(&message.block_hash)
+        {
+            return Err(invalid);
+        }
+
+        let height = request.start_height + request.received as i32;
+        let previous = match height {
+            0 => Some(sha256d::Hash::default()),
+            _ => self
+                .filter_headers
+                .get(height - 1)
+                .map(|index| index.filter_header),
+        };
+        let expected = self
+            .filter_headers
+            .get(height)
+            .map(|index| index.filter_header);
+        let (previous, expected) = match (previous, expected) {
+            (Some(previous), Some(expected)) => (previous, expected),
+            _ => {
+                self.filters_request = None;
+                return Ok(());
+            }
+        };
+
+        let filter = BlockFilter::new(message.filter);
+        if filter.filter_header(&previous) != expected {
+            return Err(invalid);
+        }
+        request.received += 1;
+
+        let watch_list = self.watch_list.lock().unwrap();
+        let watched = watch_list.scripts();
+        let scripts: Vec<&[u8]> = watched.iter().map(|s| s.as_bytes()).collect();
+        // A filter which can not be decoded is committed by the filter header anyway, so the
+        // block is checked to be safe.
+        if filter
+            .match_any(&message.block_hash, &scripts)
+            .unwrap_or(true)
+        {
+            debug!("Filter of block {} matched.", message.block_hash);
+            request.matched.push(message.block_hash);
+            peer.start_send(NetworkMessage::GetData(vec![Inventory {
+                inv_type: InvType::Block,
+                hash: message.block_hash,
+            }]));
+        }
+        drop(watch_list);
+
+        self.complete_filters();
+        Ok(())
+    }
+
+    /// Process block message received from the peer. Return error if transactions in the block
+    /// don't match merkle roots of the header.
+    pub fn on_block(&mut self, peer_id: PeerID, block: Block) -> Result<(), Error> {
+        let hash = block.bitcoin_hash();
+        let invalid = Error::MaliciousPeer(peer_id, MaliciousPeerCause::InvalidBlock);
+
+        if let Some(ref mut conflict) = self.headers_conflict {
+            if conflict.block.is_none() && conflict.block_hash() == hash {
+                if !has_valid_merkle_roots(&block) {
+                    return Err(invalid);
+                }
+                conflict.block = Some(block);
+                self.resolve_conflict();
+                return Ok(());
+            }
+        }
+
+        let request = match self.filters_request {
+            Some(ref mut request) if request.peer_id == peer_id => request,
+            _ => return Ok(()),
+        };
+
+        if !request.matched.contains(&hash) || request.blocks.contains_key(&hash) {
+            return Ok(());
+        }
+        if !has_valid_merkle_roots(&block) {
+            return Err(invalid);
+        }
+
+        request.blocks.insert(hash, block);
+        self.complete_filters();
+        Ok(())
+    }
+
+    /// If all filters and matched blocks have been received, find transactions in order of height
+    /// and complete the request.
+    fn complete_filters(&mut self) {
+        match self.filters_request {
+            Some(ref request)
+                if request.received == request.block_hashes.len()
+                    && request.matched.len() == request.blocks.len() => {}
+            _ => return,
+        }
+        let mut request = self.filters_request.take().unwrap();
+
+        let watch_list = self.watch_list.clone();
+        let mut watch_list = watch_list.lock().unwrap();
+        for hash in &request.matched {
+            let block = request.blocks.remove(hash).unwrap();
+            let position = request.block_hashes.iter().position(|h| h == hash).unwrap();
+            let height = request.start_height + position as i32;
+
+            let matched = watch_list.filter_transactions(&block.txdata);
+            if !matched.is_empty() {
+                info!(
+                    "Found {} transactions in block {} at height {}.",
+                    matched.len(),
+                    hash,
+                    height
+                );
+                self.send(ScanEvent::Found(FilteredBlock {
+                    block_hash: *hash,
+                    height,
+                    transactions: matched,
+                }));
+            }
+        }
+        self.scanned.advance(
+            request.start_height + request.block_hashes.len() as i32 - 1,
+            *request.block_hashes.last().unwrap(),
+        );
+    }
+
+    fn send(&mut self, event: ScanEvent) {
+        if self.sender.try_send(event).is_err() {
+            warn!("Receiver of scan events was dropped.");
+        }
+    }
+}
+
+/// Return true if transactions in the block match merkle roots of the header.
+fn has_valid_merkle_roots(block: &Block) -> bool {
+    let merkle_root = bitcoin_merkle_root(block.txdata.iter().map(|tx| tx.txid()).collect());
+    let im_merkle_root =
+        bitcoin_merkle_root(block.txdata.iter().map(|tx| tx.malfix_txid()).collect());
+    !block.txdata.is_empty()
+        && merkle_root == block.header.merkle_root
+        && im_merkle_root == block.header.im_merkle_root
+}
+
+/// Return true if the basic filter has all output scripts of the block except OP_RETURN
+/// outputs, which are not included in basic filters.
+fn has_all_outputs(filter: &BlockFilter, block: &Block) -> bool {
+    let scripts: Vec<&[u8]> = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .map(|output| &output.script_pubkey)
+        .filter(|script| !script.is_empty() && !script.is_op_return())
+        .map(|script| script.as_bytes())
+        .collect();
+    filter
+        .match_all(&block.bitcoin_hash(), &scripts)
+        .unwrap_or(false)
+}
+
+/// Return true if the peer signals NODE_COMPACT_FILTERS.
+fn supports_compact_filters<T>(peer: &Peer<T>) -> bool
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+{
+    peer.version.as_ref().map_or(false, |version| {
+        version.services & NODE_COMPACT_FILTERS != 0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::store::OnMemoryFilterHeaderStore;
+    use crate::network::peer::{version_message, BAN_SCORE_THRESHOLD};
+    use crate::network::BITCOIN_PROTOCOL_VERSION;
+    use crate::test_helper::{
+        channel, create_signed_block, get_chain, get_test_genesis_block, get_test_transactions,
+        TwoWayChannel,
+    };
+    use bitcoin_hashes::Hash;
+    use tapyrus::{Network, Script};
+    use tokio::prelude::Future;
+
+    fn next_message(stream: TwoWayChannel<RawMessage>) -> (Message, TwoWayChannel<RawMessage>) {
+        match stream.into_future().wait() {
+            Ok((Some(message), stream)) => (message.payload, stream),
+            _ => panic!("Peer should send message."),
+        }
+    }
+
+    fn filter_peer(id: PeerID) -> (TwoWayChannel<RawMessage>, Peer<TwoWayChannel<RawMessage>>) {
+        let (here, there) = channel::<RawMessage>();
+        let mut peer = Peer::new(
+            id,
+            there,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
+        let mut version = version_message(BITCOIN_PROTOCOL_VERSION.version, 0);
+        version.services = NODE_COMPACT_FILTERS;
+        peer.version = Some(version);
+        (here, peer)
+    }
+
+    /// Build basic filter from output scripts.
+    fn basic_filter(block: &Block) -> BlockFilter {
+        let scripts: Vec<&[u8]> = block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter().map(|o| o.script_pubkey.as_bytes()))
+            .collect();
+        BlockFilter::build(&block.bitcoin_hash(), &scripts)
+    }
+
+    fn cfilter(block: &Block, filter: &BlockFilter) -> CFilterMessage {
+        CFilterMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            block_hash: block.bitcoin_hash(),
+            filter: filter.content.clone(),
+        }
+    }
+
+    #[test]
+    fn test_download_compact_filters() {
+        let (here1, peer1) = filter_peer(1);
+        let (here2, peer2) = filter_peer(2);
+        // peer which doesn't serve filters.
+        let (_here3, there3) = channel::<RawMessage>();
+        let peer3 = Peer::new(
+            3,
+            there3,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        );
+        let mut peers = HashMap::new();
+        peers.insert(1, peer1);
+        peers.insert(2, peer2);
+        peers.insert(3, peer3);
+
+        // Only coinbase pays to the watched script in block 1.
+        let genesis = get_test_genesis_block();
+        let mut txs = get_test_transactions(2);
+        txs[1].output[0].script_pubkey = Script::new();
+        let block1 = create_signed_block(&genesis.header, txs.clone());
+        let block2 = create_signed_block(&block1.header, get_test_transactions(2));
+        let mut chain_active = get_chain();
+        chain_active
+            .connect_block_header(block1.header.clone())
+            .unwrap();
+        chain_active
+            .connect_block_header(block2.header.clone())
+            .unwrap();
+
+        let watch_list = Arc::new(Mutex::new(WatchList::new()));
+        watch_list
+            .lock()
+            .unwrap()
+            .add_script(genesis.txdata[0].output[0].script_pubkey.clone());
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut download = CompactFilterDownload::new(
+            watch_list,
+            Box::new(OnMemoryFilterHeaderStore::new()),
+            sender,
+        );
+
+        // nothing is requested until header download finishes.
+        download.request(&mut peers, &chain_active, false);
+        assert!(download.headers_request.is_none());
+
+        // filter headers are requested to peers which serve filters.
+        download.request(&mut peers, &chain_active, true);
+        let expected = Message::GetCFHeaders(GetCFHeadersMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: 0,
+            stop_hash: block2.bitcoin_hash(),
+        });
+        let (message, here1) = next_message(here1);
+        assert_eq!(message, expected);
+        let (message, _here2) = next_message(here2);
+        assert_eq!(message, expected);
+
+        let filters: Vec<BlockFilter> = [&genesis, &block1, &block2]
+            .iter()
+            .map(|block| basic_filter(block))
+            .collect();
+        let response = CFHeadersMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash: block2.bitcoin_hash(),
+            previous_filter_header: sha256d::Hash::default(),
+            filter_hashes: filters.iter().map(|f| f.filter_hash()).collect(),
+        };
+
+        // response from peer which was not requested is ignored.
+        download.on_cfheaders(3, response.clone()).unwrap();
+
+        // response which has wrong number of headers is rejected.
+        download.on_cfheaders(1, response.clone()).unwrap();
+        let mut invalid = response.clone();
+        invalid.filter_hashes.pop();
+        match download.on_cfheaders(2, invalid) {
+            Err(Error::MaliciousPeer(2, MaliciousPeerCause::InvalidFilterHeaders)) => {}
+            _ => panic!("on_cfheaders should fail."),
+        }
+        assert_eq!(download.filter_headers.height(), 2);
+
+        // filters are requested after filter headers are stored.
+        download.request(&mut peers, &chain_active, true);
+        match next_message(here1) {
+            (Message::GetCFilters(message), _) => {
+                assert_eq!(message.start_height, 1);
+                assert_eq!(message.stop_hash, block2.bitcoin_hash());
+            }
+            _ => panic!("Peer should send getcfilters."),
+        }
+
+        // filter which doesn't match the filter header is rejected.
+        let peer = peers.get_mut(&1).unwrap();
+        match download.on_cfilter(peer, cfilter(&block1, &filters[2])) {
+            Err(Error::MaliciousPeer(1, MaliciousPeerCause::InvalidCompactFilter)) => {}
+            _ => panic!("on_cfilter should fail."),
+        }
+        download
+            .on_cfilter(peer, cfilter(&block1, &filters[1]))
+            .unwrap();
+        download
+            .on_cfilter(peer, cfilter(&block2, &filters[2]))
+            .unwrap();
+        assert_eq!(
+            download.filters_request.as_ref().unwrap().matched,
+            vec![block1.bitcoin_hash(), block2.bitcoin_hash()]
+        );
+
+        // block whose transactions don't match the header is rejected.
+        let mut invalid = block2.clone();
+        invalid.txdata.pop();
+        match download.on_block(1, invalid) {
+            Err(Error::MaliciousPeer(1, MaliciousPeerCause::InvalidBlock)) => {}
+            _ => panic!("on_block should fail."),
+        }
+
+        // blocks are processed in order of height.
+        download.on_block(1, block2.clone()).unwrap();
+        download.on_block(1, block1.clone()).unwrap();
+        assert!(download.filters_request.is_none());
+        assert_eq!(download.scanned.height(), 2);
+
+        let mut receiver = receiver.wait();
+        assert_eq!(
+            receiver.next().unwrap().unwrap(),
+            ScanEvent::Found(FilteredBlock {
+                block_hash: block1.bitcoin_hash(),
+                height: 1,
+                transactions: vec![txs[0].clone()],
+            })
+        );
+        assert_eq!(
+            receiver.next().unwrap().unwrap(),
+            ScanEvent::Found(FilteredBlock {
+                block_hash: block2.bitcoin_hash(),
+                height: 2,
+                transactions: block2.txdata.clone(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_resolve_conflicting_filter_headers() {
+        let (here1, peer1) = filter_peer(1);
+        let (here2, peer2) = filter_peer(2);
+        let (here3, peer3) = filter_peer(3);
+        let mut peers = HashMap::new();
+        peers.insert(1, peer1);
+        peers.insert(2, peer2);
+        peers.insert(3, peer3);
+
+        let genesis = get_test_genesis_block();
+        let block1 = create_signed_block(&genesis.header, get_test_transactions(2));
+        let mut chain_active = get_chain();
+        chain_active
+            .connect_block_header(block1.header.clone())
+            .unwrap();
+
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut download = CompactFilterDownload::new(
+            Arc::new(Mutex::new(WatchList::new())),
+            Box::new(OnMemoryFilterHeaderStore::new()),
+            sender,
+        );
+        download.request(&mut peers, &chain_active, true);
+        let (_, here1) = next_message(here1);
+        let (_, here2) = next_message(here2);
+        let (_, _here3) = next_message(here3);
+
+        let filters: Vec<BlockFilter> = [&genesis, &block1]
+            .iter()
+            .map(|block| basic_filter(block))
+            .collect();
+        let response = CFHeadersMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash: block1.bitcoin_hash(),
+            previous_filter_header: sha256d::Hash::default(),
+            filter_hashes: filters.iter().map(|f| f.filter_hash()).collect(),
+        };
+
+        // response which doesn't connect to known filter headers is rejected.
+        let mut unconnected = response.clone();
+        unconnected.previous_filter_header = sha256d::Hash::hash(&[1]);
+        match download.on_cfheaders(3, unconnected) {
+            Err(Error::MaliciousPeer(3, MaliciousPeerCause::InvalidFilterHeaders)) => {}
+            _ => panic!("on_cfheaders should fail."),
+        }
+
+        // peer 2 commits to a filter which doesn't have outputs of block 1.
+        let fake = BlockFilter::build(&block1.bitcoin_hash(), &[]);
+        let mut conflicting = response.clone();
+        conflicting.filter_hashes[1] = fake.filter_hash();
+        download.on_cfheaders(1, response).unwrap();
+        download.on_cfheaders(2, conflicting).unwrap();
+        assert!(download.headers_conflict.is_some());
+        assert_eq!(download.filter_headers.height(), -1);
+
+        // the block and filters at the first conflicting height are requested.
+        download.request(&mut peers, &chain_active, true);
+        let expected = Message::GetCFilters(GetCFiltersMessage {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: 1,
+            stop_hash: block1.bitcoin_hash(),
+        });
+        let (message, here1) = next_message(here1);
+        assert_eq!(message, expected);
+        let (message, _here1) = next_message(here1);
+        assert_eq!(
+            message,
+            Message::Network(NetworkMessage::GetData(vec![Inventory {
+                inv_type: InvType::Block,
+                hash: block1.bitcoin_hash(),
+            }]))
+        );
+        let (message, _here2) = next_message(here2);
+        assert_eq!(message, expected);
+
+        download
+            .on_cfilter(peers.get_mut(&1).unwrap(), cfilter(&block1, &filters[1]))
+            .unwrap();
+        download
+            .on_cfilter(peers.get_mut(&2).unwrap(), cfilter(&block1, &fake))
+            .unwrap();
+        download.on_block(1, block1.clone()).unwrap();
+
+        // filter headers of the honest peer are stored, and the lying peer is penalized.
+        assert!(download.headers_conflict.is_none());
+        assert_eq!(download.filter_headers.height(), 1);
+        let expected = filters[1].filter_header(&filters[0].filter_header(&Default::default()));
+        assert_eq!(
+            download.filter_headers.get(1).unwrap().filter_header,
+            expected
+        );
+        download.request(&mut peers, &chain_active, true);
+        assert_eq!(peers[&1].ban_score, 0);
+        assert!(peers[&2].ban_score >= BAN_SCORE_THRESHOLD);
+    }
+
+    #[test]
+    fn test_rollback_filter_headers() {
+        let genesis = get_test_genesis_block();
+        let block1 = create_signed_block(&genesis.header, get_test_transactions(1));
+        let mut chain_active = get_chain();
+        chain_active
+            .connect_block_header(block1.header.clone())
+            .unwrap();
+
+        let mut store = OnMemoryFilterHeaderStore::new();
+        // the last filter header is of a block which is not in the active chain.
+        let block_hashes = [
+            genesis.bitcoin_hash(),
+            block1.bitcoin_hash(),
+            sha256d::Hash::hash(&[3]),
+        ];
+        for (i, block_hash) in block_hashes.iter().enumerate() {
+            store
+                .push(FilterHeaderIndex {
+                    block_hash: *block_hash,
+                    filter_header: sha256d::Hash::hash(&[i as u8]),
+                })
+                .unwrap();
+        }
+
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut download = CompactFilterDownload::new(
+            Arc::new(Mutex::new(WatchList::new())),
+            Box::new(store),
+            sender,
+        );
+        download.rollback(&chain_active);
+        assert_eq!(download.filter_headers.height(), 1);
+    }
+}
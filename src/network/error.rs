@@ -2,24 +2,88 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+use crate::chain;
 use crate::network::peer::PeerID;
 use crate::network::utils::codec;
 
+/// Errors in network module
 #[derive(Debug)]
 pub enum Error {
+    /// Connection or a store of peers failed.
     IoError(std::io::Error),
+    /// The message can not be encoded or decoded.
     CodecError(codec::Error),
+    /// Internal channel was closed.
     UnboundedSendError(tokio::sync::mpsc::error::UnboundedSendError),
+    /// Internal channel was closed.
     UnboundedRecvError(tokio::sync::mpsc::error::UnboundedRecvError),
+    /// The peer sent invalid data.
     MaliciousPeer(PeerID, MaliciousPeerCause),
-    WrongMagicBytes,
+    /// Handshake with the peer failed.
+    HandshakeFailed(PeerID, HandshakeFailure),
+    /// The chain can not be updated.
+    ChainError(chain::Error),
+    /// Timer of the runtime failed.
+    TimerError(tokio::timer::Error),
 }
 
+/// The reason why the peer is regarded as malicious
 #[derive(Debug)]
 pub enum MaliciousPeerCause {
     /// The peer send over maximum number which is MAX_HEADERS_RESULTS of headers in single
     /// headers message.
     SendOverMaxHeadersResults,
+    /// The peer send block header which has invalid proof.
+    InvalidBlockProof,
+    /// The peer send headers which don't connect to known block as a response to getheaders
+    /// message, or announced such headers too many times.
+    UnconnectingHeaders,
+    /// The peer send message which has magic bytes of other network.
+    WrongMagicBytes,
+    /// The peer send message which is larger than MAX_PROTOCOL_MESSAGE_LENGTH.
+    OversizedMessage,
+    /// The peer send merkleblock message whose partial merkle tree is invalid.
+    InvalidMerkleBlock,
+    /// The peer send cfheaders message which doesn't match the request.
+    InvalidFilterHeaders,
+    /// The peer send cfilter message which doesn't match the filter header.
+    InvalidCompactFilter,
+    /// The peer send block message whose transactions don't match the header.
+    InvalidBlock,
+    /// The peer send addr or addrv2 message which has more than MAX_ADDR_TO_SEND addresses.
+    OversizedAddr,
+}
+
+/// The reason why handshake with the peer failed
+#[derive(Debug)]
+pub enum HandshakeFailure {
+    /// The peer didn't complete the handshake in time.
+    Timeout,
+    /// The peer uses protocol version which is lower than the minimum version of the network.
+    ObsoleteVersion(u32),
+    /// The peer doesn't signal service bits which the node needs.
+    MissingServices(u64),
+    /// The connection is to the node itself. The peer sent the nonce which the node sent.
+    SelfConnection,
+}
+
+impl MaliciousPeerCause {
+    /// Return ban score which is added to the peer. The peer is banned when the total score
+    /// reaches BAN_SCORE_THRESHOLD.
+    pub fn score(&self) -> u32 {
+        match self {
+            MaliciousPeerCause::SendOverMaxHeadersResults => 20,
+            MaliciousPeerCause::InvalidBlockProof => 100,
+            MaliciousPeerCause::UnconnectingHeaders => 20,
+            MaliciousPeerCause::WrongMagicBytes => 100,
+            MaliciousPeerCause::OversizedMessage => 100,
+            MaliciousPeerCause::InvalidMerkleBlock => 100,
+            MaliciousPeerCause::InvalidFilterHeaders => 100,
+            MaliciousPeerCause::InvalidCompactFilter => 100,
+            MaliciousPeerCause::InvalidBlock => 100,
+            MaliciousPeerCause::OversizedAddr => 20,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -28,6 +92,18 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<chain::Error> for Error {
+    fn from(e: chain::Error) -> Error {
+        Error::ChainError(e)
+    }
+}
+
+impl From<tokio::timer::Error> for Error {
+    fn from(e: tokio::timer::Error) -> Error {
+        Error::TimerError(e)
+    }
+}
+
 impl From<codec::Error> for Error {
     fn from(e: codec::Error) -> Error {
         Error::CodecError(e)
@@ -2,80 +2,197 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+use crate::network::message::{Message, RawMessage};
 use crate::network::peer::version_message;
-use crate::network::{Error, Peer};
-use tapyrus::network::message::{NetworkMessage, RawNetworkMessage};
+use crate::network::{Error, HandshakeFailure, Peer, ProtocolVersion};
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tapyrus::network::message::NetworkMessage;
 use tokio::prelude::*;
+use tokio::timer::Delay;
 
+/// The peer is disconnected if the handshake is not completed in this duration.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Nonces of version messages which handshakes in progress have sent. Handshakes which share it
+/// detect connections between themselves.
+pub type SentNonces = Arc<Mutex<HashSet<u64>>>;
+
+/// Exchange version and verack messages with the peer.
+///
+/// The peer is rejected if its protocol version is lower than the minimum version of the
+/// protocol, it doesn't signal `required_services`, or it sent the nonce of a version message
+/// which the node sent, which means the node connected to itself. Feature negotiation messages
+/// which the peer sent before verack are recorded in the peer. After the handshake, `sendheaders`
+/// and `getaddr` messages are sent.
 pub struct Handshake<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     peer: Option<Peer<T>>,
-    sent_version: bool,
+    start_height: i32,
+    required_services: u64,
+    protocol_version: ProtocolVersion,
+    /// Nonce of the version message which was sent to the peer.
+    nonce: Option<u64>,
+    sent_nonces: SentNonces,
     received_version: bool,
     received_verack: bool,
+    timeout: Delay,
 }
 
 impl<T> Handshake<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
-    pub fn new(peer: Peer<T>) -> Handshake<T> {
+    /// Start handshake which reports `start_height` as the height of the active chain.
+    pub fn new(
+        peer: Peer<T>,
+        start_height: i32,
+        required_services: u64,
+        protocol_version: ProtocolVersion,
+    ) -> Handshake<T> {
+        Handshake::with_timeout(
+            peer,
+            start_height,
+            required_services,
+            protocol_version,
+            HANDSHAKE_TIMEOUT,
+        )
+    }
+
+    /// Start handshake which fails if it is not completed in `timeout`.
+    pub fn with_timeout(
+        peer: Peer<T>,
+        start_height: i32,
+        required_services: u64,
+        protocol_version: ProtocolVersion,
+        timeout: Duration,
+    ) -> Handshake<T> {
         Handshake {
             peer: Some(peer),
-            sent_version: false,
+            start_height,
+            required_services,
+            protocol_version,
+            nonce: None,
+            sent_nonces: SentNonces::default(),
             received_version: false,
             received_verack: false,
+            timeout: Delay::new(Instant::now() + timeout),
         }
     }
+
+    /// Share nonces with other handshakes of the node instead of the set which only this
+    /// handshake uses.
+    pub fn set_sent_nonces(&mut self, sent_nonces: SentNonces) {
+        self.sent_nonces = sent_nonces;
+    }
+
+    /// Check the version message of the peer.
+    fn check_version(
+        &self,
+        peer: &Peer<T>,
+        version: &tapyrus::network::message_network::VersionMessage,
+    ) -> Result<(), Error> {
+        let failure = if self.sent_nonces.lock().unwrap().contains(&version.nonce) {
+            HandshakeFailure::SelfConnection
+        } else if version.version < self.protocol_version.min_peer_version {
+            HandshakeFailure::ObsoleteVersion(version.version)
+        } else if version.services & self.required_services != self.required_services {
+            HandshakeFailure::MissingServices(version.services)
+        } else {
+            return Ok(());
+        };
+        info!("Reject peer {}: {:?}", peer.id, failure);
+        Err(Error::HandshakeFailed(peer.id, failure))
+    }
 }
 
 impl<T> Future for Handshake<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
     Error: From<T::Error>,
 {
     type Item = Peer<T>;
     type Error = Error;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        if let Some(ref mut peer) = self.peer {
-            if !self.sent_version {
-                peer.start_send(NetworkMessage::Version(version_message()));
-                self.sent_version = true;
-            }
+        let mut peer = self
+            .peer
+            .take()
+            .expect("Handshake should have peer instance when call poll.");
+
+        if self.nonce.is_none() {
+            let version = version_message(self.protocol_version.version, self.start_height);
+            self.nonce = Some(version.nonce);
+            self.sent_nonces.lock().unwrap().insert(version.nonce);
+            peer.start_send(NetworkMessage::Version(version));
+            // sendaddrv2 must be sent before verack.
+            peer.start_send(Message::SendAddrV2);
+        }
 
-            loop {
-                match peer.poll()? {
-                    Async::Ready(Some(NetworkMessage::Version(version))) => {
-                        peer.version = Some(version);
-
-                        // send verack message
-                        let _ = peer.start_send(NetworkMessage::Verack);
-                        self.received_version = true;
-                    }
-                    Async::Ready(Some(NetworkMessage::Verack)) => {
-                        self.received_verack = true;
-                    }
-                    Async::Ready(None) => break,
-                    Async::Ready(_) => {} // ignore other messages.
-                    Async::NotReady => break,
+        loop {
+            match peer.poll()? {
+                Async::Ready(Some(Message::Network(NetworkMessage::Version(version)))) => {
+                    self.check_version(&peer, &version)?;
+                    peer.version = Some(version);
+
+                    // send verack message
+                    peer.start_send(NetworkMessage::Verack);
+                    self.received_version = true;
+                }
+                Async::Ready(Some(Message::Network(NetworkMessage::Verack))) => {
+                    self.received_verack = true;
                 }
+                Async::Ready(Some(Message::SendHeaders)) => {
+                    peer.send_headers = true;
+                }
+                Async::Ready(Some(Message::WtxidRelay)) if !self.received_verack => {
+                    peer.wtxid_relay = true;
+                }
+                Async::Ready(None) => {
+                    return Err(Error::IoError(io::Error::from(
+                        io::ErrorKind::UnexpectedEof,
+                    )));
+                }
+                Async::Ready(_) => {} // ignore other messages.
+                Async::NotReady => break,
             }
-
-            peer.flush();
-        } else {
-            panic!("Handshake should have peer instance when call poll.");
         }
 
+        peer.flush();
+
         // check either handshake finished
-        if self.sent_version && self.received_version && self.received_verack {
-            let peer = self.peer.take().unwrap();
+        if self.received_version && self.received_verack {
             trace!("Handshake complete. peer: {}", peer.id);
-            Ok(Async::Ready(peer))
-        } else {
-            Ok(Async::NotReady)
+
+            // ask the peer to announce new blocks by headers.
+            peer.start_send(Message::SendHeaders);
+            // ask addresses which the peer knows.
+            peer.start_send(NetworkMessage::GetAddr);
+            peer.flush();
+            return Ok(Async::Ready(peer));
+        }
+
+        if let Async::Ready(()) = self.timeout.poll()? {
+            info!("Handshake with peer {} timed out.", peer.id);
+            return Err(Error::HandshakeFailed(peer.id, HandshakeFailure::Timeout));
+        }
+
+        self.peer = Some(peer);
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> Drop for Handshake<T>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+{
+    fn drop(&mut self) {
+        if let Some(nonce) = self.nonce {
+            self.sent_nonces.lock().unwrap().remove(&nonce);
         }
     }
 }
@@ -83,74 +200,177 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_helper::channel;
+    use crate::network::{NODE_BLOOM, TAPYRUS_PROTOCOL_VERSION};
+    use crate::test_helper::{channel, TwoWayChannel};
     use tapyrus::network::constants::Network;
+    use tapyrus::network::message_network::VersionMessage;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn raw(message: Message) -> RawMessage {
+        RawMessage {
+            magic: Network::Regtest.magic(),
+            payload: message,
+        }
+    }
+
+    fn new_peer(stream: TwoWayChannel<RawMessage>) -> Peer<TwoWayChannel<RawMessage>> {
+        Peer::new(
+            0,
+            stream,
+            "0.0.0.0:0".parse().unwrap(),
+            Network::Regtest.magic(),
+        )
+    }
+
+    /// Run handshake with the remote which has already sent `messages`.
+    fn run_handshake(
+        messages: Vec<Message>,
+        required_services: u64,
+    ) -> (
+        Result<Peer<TwoWayChannel<RawMessage>>, Error>,
+        TwoWayChannel<RawMessage>,
+    ) {
+        let (mut here, there) = channel::<RawMessage>();
+        for message in messages {
+            let _ = here.start_send(raw(message));
+        }
+        let handshake = Handshake::new(
+            new_peer(there),
+            10,
+            required_services,
+            TAPYRUS_PROTOCOL_VERSION,
+        );
+        let result = Runtime::new().unwrap().block_on(handshake);
+        (result, here)
+    }
+
+    fn remote_version(version: u32, services: u64) -> Message {
+        let mut message = version_message(version, 0);
+        message.services = services;
+        Message::Network(NetworkMessage::Version(message))
+    }
 
     #[test]
     fn test_handshake() {
-        let (here, there) = channel::<RawNetworkMessage>();
-
-        let addr = "0.0.0.0:0".parse().unwrap();
-        let peer = Peer::new(0, there, addr, Network::Regtest);
-
-        let future = tokio::prelude::future::lazy(move || {
-            let handshake = Handshake::new(peer).map(|_| {}).map_err(|_| {});
-
-            tokio::spawn(handshake);
-
-            let test_future = here
-                .into_future()
-                .and_then(|(msg, mut here)| {
-                    // check version message received.
-                    match msg {
-                        Some(RawNetworkMessage {
-                            payload: NetworkMessage::Version(_),
-                            ..
-                        }) => {
-                            assert!(true);
-                        }
-                        _ => assert!(false),
-                    }
-
-                    // send version message.
-                    let version = RawNetworkMessage {
-                        magic: Network::Regtest.magic(),
-                        payload: NetworkMessage::Version(version_message()),
-                    };
-
-                    let _ = here.start_send(version);
-
-                    // send verack message.
-                    let verack = RawNetworkMessage {
-                        magic: Network::Regtest.magic(),
-                        payload: NetworkMessage::Verack,
-                    };
-                    let _ = here.start_send(verack);
-
-                    // flush sending message.
-                    let _ = here.poll_complete();
-
-                    here.into_future()
-                })
-                .map(|(msg, _here)| {
-                    // check verack message received.
-                    match msg {
-                        Some(RawNetworkMessage {
-                            payload: NetworkMessage::Verack,
-                            ..
-                        }) => {
-                            assert!(true);
-                        }
-                        _ => assert!(false),
-                    }
-                })
-                .map_err(|_| {});
-
-            tokio::spawn(test_future);
-
-            Ok(())
-        });
-
-        tokio::runtime::current_thread::run(future);
+        let (result, here) = run_handshake(
+            vec![
+                remote_version(TAPYRUS_PROTOCOL_VERSION.version, NODE_BLOOM),
+                Message::WtxidRelay,
+                Message::Network(NetworkMessage::Verack),
+                Message::SendHeaders,
+            ],
+            NODE_BLOOM,
+        );
+        let peer = result.unwrap();
+        assert!(peer.send_headers);
+        assert!(peer.wtxid_relay);
+        assert_eq!(peer.version.as_ref().unwrap().services, NODE_BLOOM);
+
+        drop(peer);
+        let sent: Vec<Message> = Runtime::new()
+            .unwrap()
+            .block_on(here.map(|raw| raw.payload).collect())
+            .unwrap();
+        assert_eq!(sent.len(), 5);
+        match sent[0] {
+            Message::Network(NetworkMessage::Version(VersionMessage { start_height, .. })) => {
+                assert_eq!(start_height, 10)
+            }
+            ref message => assert!(false, "unexpected message: {:?}", message),
+        }
+        assert_eq!(
+            sent[1..].to_vec(),
+            vec![
+                Message::SendAddrV2,
+                Message::Network(NetworkMessage::Verack),
+                Message::SendHeaders,
+                Message::Network(NetworkMessage::GetAddr),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reject_peer() {
+        let verack = Message::Network(NetworkMessage::Verack);
+
+        let obsolete = TAPYRUS_PROTOCOL_VERSION.min_peer_version - 1;
+        let (result, _) = run_handshake(vec![remote_version(obsolete, 0), verack.clone()], 0);
+        match result {
+            Err(Error::HandshakeFailed(0, HandshakeFailure::ObsoleteVersion(v))) => {
+                assert_eq!(v, obsolete)
+            }
+            r => assert!(false, "unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let version = TAPYRUS_PROTOCOL_VERSION.version;
+        let (result, _) = run_handshake(vec![remote_version(version, 0), verack], NODE_BLOOM);
+        match result {
+            Err(Error::HandshakeFailed(0, HandshakeFailure::MissingServices(0))) => {}
+            r => assert!(false, "unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_self_connection() {
+        // the peer receives messages which it sent.
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let handshake = Handshake::new(
+            new_peer(TwoWayChannel::new(sender, receiver)),
+            0,
+            0,
+            TAPYRUS_PROTOCOL_VERSION,
+        );
+        match Runtime::new().unwrap().block_on(handshake) {
+            Err(Error::HandshakeFailed(0, HandshakeFailure::SelfConnection)) => {}
+            r => assert!(false, "unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    /// Run handshakes on both ends of a connection. They share nonces if `sent_nonces` is given.
+    fn run_connected_handshakes(sent_nonces: Option<SentNonces>) -> Result<(), Error> {
+        let (here, there) = channel::<RawMessage>();
+        let mut outbound = Handshake::new(new_peer(here), 0, 0, TAPYRUS_PROTOCOL_VERSION);
+        let mut inbound = Handshake::new(new_peer(there), 0, 0, TAPYRUS_PROTOCOL_VERSION);
+        if let Some(sent_nonces) = sent_nonces {
+            outbound.set_sent_nonces(sent_nonces.clone());
+            inbound.set_sent_nonces(sent_nonces);
+        }
+        Runtime::new()
+            .unwrap()
+            .block_on(outbound.join(inbound))
+            .map(|_| ())
+    }
+
+    #[test]
+    fn test_handshake_with_own_version() {
+        // both ends send version message which the node builds.
+        run_connected_handshakes(None).unwrap();
+    }
+
+    #[test]
+    fn test_self_connection_between_handshakes() {
+        // the node connected to itself through two connections.
+        let sent_nonces = SentNonces::default();
+        match run_connected_handshakes(Some(sent_nonces.clone())) {
+            Err(Error::HandshakeFailed(0, HandshakeFailure::SelfConnection)) => {}
+            r => assert!(false, "unexpected result: {:?}", r),
+        }
+        assert!(sent_nonces.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (_here, there) = channel::<RawMessage>();
+        let handshake = Handshake::with_timeout(
+            new_peer(there),
+            0,
+            0,
+            TAPYRUS_PROTOCOL_VERSION,
+            Duration::from_millis(10),
+        );
+        match Runtime::new().unwrap().block_on(handshake) {
+            Err(Error::HandshakeFailed(0, HandshakeFailure::Timeout)) => {}
+            r => assert!(false, "unexpected result: {:?}", r.map(|_| ())),
+        }
     }
 }
@@ -0,0 +1,519 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{verify_merkle_block, Chain, ChainStore, MatchedTransaction, PartialMerkleTree};
+use crate::network::bloom_filter::{BloomFilter, BLOOM_UPDATE_ALL};
+use crate::network::message::{MerkleBlockMessage, Message, RawMessage};
+use crate::network::peer::next_peer_id;
+use crate::network::scan_progress::ScanProgress;
+use crate::network::{Error, MaliciousPeerCause, Peer, PeerID, ScanEvent, WatchList};
+use bitcoin_hashes::sha256d;
+use rand::{thread_rng, RngCore};
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tapyrus::blockdata::transaction::Transaction;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::BitcoinHash;
+use tokio::prelude::{Sink, Stream};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The maximum number of filtered blocks which are requested by a single getdata message.
+pub const MAX_FILTERED_BLOCKS_PER_REQUEST: i32 = 500;
+
+/// If a peer doesn't finish sending requested filtered blocks in this duration, the request is
+/// abandoned and sent to another peer.
+pub const FILTERED_BLOCKS_REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// False positive rate of bloom filters which are loaded to peers.
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.0005;
+
+/// Transactions in a block which match the watch list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilteredBlock {
+    /// Hash of the block
+    pub block_hash: sha256d::Hash,
+    /// Height of the block
+    pub height: i32,
+    /// Matched transactions in order of the block
+    pub transactions: Vec<Transaction>,
+}
+
+/// Bloom filter which was loaded to a peer.
+struct LoadedFilter {
+    /// Generation of the watch list when the filter was loaded.
+    generation: u64,
+    /// The number of elements of the watch list which were sent to the peer.
+    elements: usize,
+    /// The number of elements which the filter was made for.
+    capacity: usize,
+}
+
+/// getdata request which is in flight.
+struct Request {
+    peer_id: PeerID,
+    /// Nonce of ping message which is sent after getdata. Peer responds to messages in order, so
+    /// pong means all filtered blocks were sent.
+    nonce: u64,
+    hashes: Vec<sha256d::Hash>,
+    blocks: Vec<ReceivedBlock>,
+    sent_at: Instant,
+}
+
+/// Verified merkleblock and transactions which were sent after it.
+struct ReceivedBlock {
+    hash: sha256d::Hash,
+    matches: Vec<MatchedTransaction>,
+    transactions: Vec<Transaction>,
+}
+
+/// Download filtered blocks (BIP37) and find transactions which match the watch list.
+///
+/// Bloom filter made from the watch list is loaded to each peer, and it is kept up to date with
+/// filteradd messages. When the watch list is cleared, the filter is reloaded.
+///
+/// After the initial header download, blocks in the active chain are scanned in order of height.
+/// Only one getdata request is in flight at a time. Partial merkle tree in each merkleblock is
+/// verified against the block header in the chain. Since a peer sends tx messages for matched
+/// transactions right after each merkleblock message, received transactions are attached to the
+/// preceding merkleblock if the tree proves them. Blocks which have matched transactions are sent
+/// to `sender`. When scanned blocks are removed from the active chain, the rollback is sent and
+/// blocks are scanned again from the fork point.
+pub struct MerkleBlockDownload {
+    watch_list: Arc<Mutex<WatchList>>,
+    filters: HashMap<PeerID, LoadedFilter>,
+    scanned: ScanProgress,
+    request: Option<Request>,
+    /// The peer which the last request was sent to.
+    last_peer: Option<PeerID>,
+    sender: UnboundedSender<ScanEvent>,
+}
+
+impl MerkleBlockDownload {
+    pub fn new(
+        watch_list: Arc<Mutex<WatchList>>,
+        sender: UnboundedSender<ScanEvent>,
+    ) -> MerkleBlockDownload {
+        MerkleBlockDownload {
+            watch_list,
+            filters: HashMap::new(),
+            scanned: ScanProgress::new(),
+            request: None,
+            last_peer: None,
+            sender,
+        }
+    }
+
+    /// Return height of the last block which has been scanned for transactions.
+    pub fn scanned_height(&self) -> i32 {
+        self.scanned.height()
+    }
+
+    /// Update bloom filters of peers. Then, after the initial header download, request filtered
+    /// blocks which have not been scanned if there is no request in flight.
+    pub fn request<T, S>(
+        &mut self,
+        peers: &mut HashMap<PeerID, Peer<T>>,
+        chain_active: &Chain<S>,
+        synced: bool,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        self.update_filters(peers);
+
+        if let Some(ref request) = self.request {
+            if !peers.contains_key(&request.peer_id) {
+                self.request = None;
+            } else if request.sent_at.elapsed() >= FILTERED_BLOCKS_REQUEST_TIMEOUT {
+                warn!("getdata request to peer {} timed out.", request.peer_id);
+                self.request = None;
+            }
+        }
+
+        if let Some(fork_height) = self.scanned.rewind(chain_active) {
+            self.request = None;
+            self.send(ScanEvent::Rollback(fork_height));
+        }
+
+        let scanned_height = self.scanned.height();
+        if !synced || self.request.is_some() || scanned_height >= chain_active.height() {
+            return;
+        }
+        if self.watch_list.lock().unwrap().is_empty() {
+            return;
+        }
+
+        let candidates: Vec<PeerID> = peers
+            .keys()
+            .filter(|id| self.filters.contains_key(id))
+            .cloned()
+            .collect();
+        let peer_id = match next_peer_id(candidates.into_iter(), self.last_peer) {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+
+        let end = cmp::min(
+            chain_active.height(),
+            scanned_height + MAX_FILTERED_BLOCKS_PER_REQUEST,
+        );
+        let hashes: Vec<sha256d::Hash> = (scanned_height + 1..=end)
+            .map(|height| chain_active.get(height).unwrap().header.bitcoin_hash())
+            .collect();
+        let nonce = thread_rng().next_u64();
+
+        debug!(
+            "Request filtered blocks from {} to {} to peer {}.",
+            scanned_height + 1,
+            end,
+            peer_id
+        );
+        let peer = peers.get_mut(&peer_id).unwrap();
+        peer.start_send(Message::GetFilteredBlocks(hashes.clone()));
+        peer.start_send(NetworkMessage::Ping(nonce));
+
+        self.request = Some(Request {
+            peer_id,
+            nonce,
+            hashes,
+            blocks: vec![],
+            sent_at: Instant::now(),
+        });
+        self.last_peer = Some(peer_id);
+    }
+
+    /// Load, add to or clear bloom filters of peers so that they match the watch list.
+    fn update_filters<T>(&mut self, peers: &mut HashMap<PeerID, Peer<T>>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        self.filters
+            .retain(|peer_id, _| peers.contains_key(peer_id));
+
+        let watch_list = self.watch_list.lock().unwrap();
+        let elements = watch_list.elements();
+
+        for (peer_id, peer) in peers.iter_mut() {
+            if let Some(filter) = self.filters.get_mut(peer_id) {
+                if filter.generation == watch_list.generation() && elements.len() <= filter.capacity
+                {
+                    for element in &elements[filter.elements..] {
+                        peer.start_send(Message::FilterAdd(element.clone()));
+                    }
+                    filter.elements = elements.len();
+                    continue;
+                }
+            }
+
+            if watch_list.is_empty() {
+                if self.filters.remove(peer_id).is_some() {
+                    peer.start_send(Message::FilterClear);
+                }
+                continue;
+            }
+
+            // Leave room for elements which will be added later.
+            let capacity = elements.len() * 2;
+            let mut filter = BloomFilter::new(
+                capacity,
+                DEFAULT_FALSE_POSITIVE_RATE,
+                thread_rng().next_u32(),
+                BLOOM_UPDATE_ALL,
+            );
+            for element in elements {
+                filter.insert(element);
+            }
+            peer.start_send(Message::FilterLoad(filter.to_message()));
+
+            self.filters.insert(
+                *peer_id,
+                LoadedFilter {
+                    generation: watch_list.generation(),
+                    elements: elements.len(),
+                    capacity,
+                },
+            );
+        }
+    }
+
+    /// Process merkleblock message received from the peer. Return error if the partial merkle
+    /// tree doesn't match the block header.
+    pub fn on_merkle_block<S: ChainStore>(
+        &mut self,
+        peer_id: PeerID,
+        message: MerkleBlockMessage,
+        chain_active: &Chain<S>,
+    ) -> Result<(), Error> {
+        let request = match self.request {
+            Some(ref mut request) if request.peer_id == peer_id => request,
+            _ => return Ok(()),
+        };
+
+        let hash = message.header.bitcoin_hash();
+        if !request.hashes.contains(&hash) {
+            return Ok(());
+        }
+        let index = match chain_active.get_by_hash(&hash) {
+            Some(index) => index,
+            None => return Ok(()), // the block was removed by reorg.
+        };
+
+        let tree =
+            PartialMerkleTree::new(message.total_transactions, message.hashes, &message.flags);
+        let matches = verify_merkle_block(&index, &tree).map_err(|e| {
+            debug!(
+                "Invalid merkleblock {} from peer {}: {:?}",
+                hash, peer_id, e
+            );
+            Error::MaliciousPeer(peer_id, MaliciousPeerCause::InvalidMerkleBlock)
+        })?;
+
+        request.blocks.push(ReceivedBlock {
+            hash,
+            matches,
+            transactions: vec![],
+        });
+        Ok(())
+    }
+
+    /// Process tx message received from the peer. The transaction is attached to the last
+    /// received merkleblock if it is proved by the merkleblock.
+    pub fn on_tx(&mut self, peer_id: PeerID, tx: Transaction) {
+        if let Some(ref mut request) = self.request {
+            if request.peer_id != peer_id {
+                return;
+            }
+            if let Some(block) = request.blocks.last_mut() {
+                let txid = tx.malfix_txid();
+                if block.matches.iter().any(|m| m.txid == txid) {
+                    block.transactions.push(tx);
+                }
+            }
+        }
+    }
+
+    /// Process pong message received from the peer. If it is the response to the ping which
+    /// follows getdata, complete the request.
+    ///
+    /// Blocks are processed in order of height until a block which was not received, so that
+    /// missing blocks are requested again.
+    pub fn on_pong<S: ChainStore>(&mut self, peer_id: PeerID, nonce: u64, chain_active: &Chain<S>) {
+        match self.request {
+            Some(ref request) if request.peer_id == peer_id && request.nonce == nonce => {}
+            _ => return,
+        }
+        let request = self.request.take().unwrap();
+
+        let watch_list = self.watch_list.clone();
+        let mut watch_list = watch_list.lock().unwrap();
+        for hash in &request.hashes {
+            let transactions = match request.blocks.iter().find(|b| b.hash == *hash) {
+                Some(block) => &block.transactions,
+                None => break,
+            };
+            let height = match chain_active.get_by_hash(hash) {
+                Some(index) => index.height,
+                None => break, // the block was removed by reorg.
+            };
+
+            // Peer sends false positive transactions too.
+            let matched = watch_list.filter_transactions(transactions);
+
+            if !matched.is_empty() {
+                info!(
+                    "Found {} transactions in block {} at height {}.",
+                    matched.len(),
+                    hash,
+                    height
+                );
+                self.send(ScanEvent::Found(FilteredBlock {
+                    block_hash: *hash,
+                    height,
+                    transactions: matched,
+                }));
+            }
+            self.scanned.advance(height, *hash);
+        }
+    }
+
+    fn send(&mut self, event: ScanEvent) {
+        if self.sender.try_send(event).is_err() {
+            warn!("Receiver of scan events was dropped.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{
+        channel, create_signed_block, get_chain, get_test_genesis_block, get_test_transactions,
+    };
+    use tapyrus::consensus::serialize;
+    use tapyrus::{Block, Network, OutPoint, Script};
+    use tokio::prelude::Future;
+
+    fn next_message<S: Stream<Item = RawMessage>>(stream: S) -> (Message, S) {
+        match stream.into_future().wait() {
+            Ok((Some(message), stream)) => (message.payload, stream),
+            _ => panic!("Peer should send message."),
+        }
+    }
+
+    fn merkle_block(block: &Block, matches: &[bool]) -> MerkleBlockMessage {
+        let txids: Vec<sha256d::Hash> = block.txdata.iter().map(|tx| tx.malfix_txid()).collect();
+        let tree = PartialMerkleTree::from_txids(&txids, matches).unwrap();
+        MerkleBlockMessage {
+            header: block.header.clone(),
+            total_transactions: tree.total_transactions(),
+            hashes: tree.hashes().to_vec(),
+            flags: tree.flags(),
+        }
+    }
+
+    #[test]
+    fn test_download_filtered_blocks() {
+        let (here, there) = channel::<RawMessage>();
+        let mut peers = HashMap::new();
+        peers.insert(
+            1,
+            Peer::new(
+                1,
+                there,
+                "0.0.0.0:0".parse().unwrap(),
+                Network::Regtest.magic(),
+            ),
+        );
+
+        let script = get_test_genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+
+        // block 2 has a transaction paying to the watched script and a false positive.
+        let tx = get_test_transactions(2).pop().unwrap();
+        let mut false_positive = tx.clone();
+        false_positive.output[0].script_pubkey = Script::new();
+        let mut txdata = get_test_transactions(1);
+        txdata.push(tx.clone());
+        txdata.push(false_positive.clone());
+
+        let genesis = get_test_genesis_block();
+        let block1 = create_signed_block(&genesis.header, get_test_transactions(1));
+        let block2 = create_signed_block(&block1.header, txdata);
+        let block3 = create_signed_block(&block2.header, get_test_transactions(1));
+        let mut chain_active = get_chain();
+        for block in &[&block1, &block2, &block3] {
+            chain_active
+                .connect_block_header(block.header.clone())
+                .unwrap();
+        }
+
+        let watch_list = Arc::new(Mutex::new(WatchList::new()));
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut download = MerkleBlockDownload::new(watch_list.clone(), sender);
+
+        // no filter is loaded while the watch list is empty.
+        download.request(&mut peers, &chain_active, true);
+        assert!(download.filters.is_empty());
+
+        // filter is loaded, but blocks are not requested until header download finishes.
+        watch_list.lock().unwrap().add_script(script.clone());
+        download.request(&mut peers, &chain_active, false);
+        let (message, here) = next_message(here);
+        match message {
+            Message::FilterLoad(filterload) => assert_eq!(filterload.flags, BLOOM_UPDATE_ALL),
+            _ => panic!("Peer should send filterload."),
+        }
+        assert!(download.request.is_none());
+
+        download.request(&mut peers, &chain_active, true);
+        let (message, here) = next_message(here);
+        let hashes: Vec<sha256d::Hash> = [&block1, &block2, &block3]
+            .iter()
+            .map(|block| block.bitcoin_hash())
+            .collect();
+        assert_eq!(message, Message::GetFilteredBlocks(hashes.clone()));
+        let (message, here) = next_message(here);
+        let nonce = match message {
+            Message::Network(NetworkMessage::Ping(nonce)) => nonce,
+            _ => panic!("Peer should send ping."),
+        };
+
+        download
+            .on_merkle_block(1, merkle_block(&block1, &[false]), &chain_active)
+            .unwrap();
+        download
+            .on_merkle_block(
+                1,
+                merkle_block(&block2, &[false, true, true]),
+                &chain_active,
+            )
+            .unwrap();
+        download.on_tx(1, tx.clone());
+        download.on_tx(1, false_positive.clone());
+        // transaction which is not proved by the merkleblock is ignored.
+        download.on_tx(1, get_test_transactions(3).pop().unwrap());
+
+        // merkleblock which doesn't match the header is rejected.
+        let mut invalid = merkle_block(&block3, &[true]);
+        invalid.hashes[0] = tx.malfix_txid();
+        match download.on_merkle_block(1, invalid, &chain_active) {
+            Err(Error::MaliciousPeer(1, MaliciousPeerCause::InvalidMerkleBlock)) => {}
+            _ => panic!("on_merkle_block should fail."),
+        }
+        download
+            .on_merkle_block(1, merkle_block(&block3, &[false]), &chain_active)
+            .unwrap();
+
+        // pong of other nonce is ignored.
+        download.on_pong(1, nonce.wrapping_add(1), &chain_active);
+        assert!(download.request.is_some());
+
+        download.on_pong(1, nonce, &chain_active);
+        assert!(download.request.is_none());
+        assert_eq!(download.scanned.height(), 3);
+
+        let (event, receiver) = receiver.into_future().wait().ok().unwrap();
+        assert_eq!(
+            event,
+            Some(ScanEvent::Found(FilteredBlock {
+                block_hash: hashes[1],
+                height: 2,
+                transactions: vec![tx.clone()],
+            }))
+        );
+
+        // the output of found transaction is watched.
+        let spending = OutPoint {
+            txid: tx.malfix_txid(),
+            vout: 0,
+        };
+        let mut spending_tx = false_positive.clone();
+        spending_tx.input[0].previous_output = spending;
+        assert!(watch_list.lock().unwrap().matches(&spending_tx));
+
+        // blocks are scanned again from the fork point after reorganization.
+        let fork2 = create_signed_block(&block1.header, get_test_transactions(2));
+        let fork3 = create_signed_block(&fork2.header, get_test_transactions(1));
+        let fork4 = create_signed_block(&fork3.header, get_test_transactions(1));
+        for block in &[&fork2, &fork3, &fork4] {
+            chain_active
+                .connect_block_header(block.header.clone())
+                .unwrap();
+        }
+        download.request(&mut peers, &chain_active, true);
+        let (event, _) = receiver.into_future().wait().ok().unwrap();
+        assert_eq!(event, Some(ScanEvent::Rollback(1)));
+        let (message, here) = next_message(here);
+        assert_eq!(message, Message::FilterAdd(serialize(&spending)));
+        let (message, _) = next_message(here);
+        let hashes: Vec<sha256d::Hash> = [&fork2, &fork3, &fork4]
+            .iter()
+            .map(|block| block.bitcoin_hash())
+            .collect();
+        assert_eq!(message, Message::GetFilteredBlocks(hashes));
+    }
+}
@@ -0,0 +1,625 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! Messages which are exchanged with peers.
+//!
+//! rust-tapyrus doesn't support some messages which SPV node needs, such as BIP37, BIP155 and
+//! BIP157 messages and `reject` message.
+//! `Message` wraps `NetworkMessage` of rust-tapyrus and adds those messages.
+
+use bitcoin_hashes::{sha256d, Hash};
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tapyrus::consensus::encode::{self, Decodable, Encodable, VarInt};
+use tapyrus::network::message::{NetworkMessage, RawNetworkMessage};
+use tapyrus::BlockHeader;
+
+/// Inventory type for filtered block (MSG_FILTERED_BLOCK) in BIP37.
+const INV_TYPE_FILTERED_BLOCK: u32 = 3;
+
+/// Network id of IPv4 addresses in BIP155.
+const ADDRV2_NETWORK_IPV4: u8 = 1;
+
+/// Network id of IPv6 addresses in BIP155.
+const ADDRV2_NETWORK_IPV6: u8 = 2;
+
+/// The maximum length of an address in `addrv2` message.
+const MAX_ADDRV2_SIZE: usize = 512;
+
+/// Message which is exchanged with peers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Message which is supported by rust-tapyrus.
+    Network(NetworkMessage),
+    /// `filterload` message in BIP37.
+    FilterLoad(FilterLoadMessage),
+    /// `filteradd` message in BIP37. It has a data element which is added to the filter.
+    FilterAdd(Vec<u8>),
+    /// `filterclear` message in BIP37.
+    FilterClear,
+    /// `getdata` message which requests filtered blocks. rust-tapyrus can not encode inventory
+    /// type MSG_FILTERED_BLOCK.
+    GetFilteredBlocks(Vec<sha256d::Hash>),
+    /// `merkleblock` message in BIP37.
+    MerkleBlock(MerkleBlockMessage),
+    /// `getcfilters` message in BIP157.
+    GetCFilters(GetCFiltersMessage),
+    /// `cfilter` message in BIP157.
+    CFilter(CFilterMessage),
+    /// `getcfheaders` message in BIP157.
+    GetCFHeaders(GetCFHeadersMessage),
+    /// `cfheaders` message in BIP157.
+    CFHeaders(CFHeadersMessage),
+    /// `reject` message in BIP61.
+    Reject(RejectMessage),
+    /// `addrv2` message in BIP155.
+    AddrV2(Vec<AddrV2Message>),
+    /// `sendaddrv2` message in BIP155. It requests the peer to send `addrv2` instead of `addr`.
+    SendAddrV2,
+    /// `sendheaders` message in BIP130. It requests the peer to announce new blocks by headers.
+    SendHeaders,
+    /// `wtxidrelay` message in BIP339. It requests the peer to announce transactions by wtxid.
+    WtxidRelay,
+}
+
+/// `filterload` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterLoadMessage {
+    /// The filter itself
+    pub filter: Vec<u8>,
+    /// The number of hash functions to use
+    pub hash_funcs: u32,
+    /// A random value to add to the seed value in the hash function
+    pub tweak: u32,
+    /// Flags that control how matched items are added to the filter
+    pub flags: u8,
+}
+
+/// `merkleblock` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleBlockMessage {
+    /// The header of the block
+    pub header: BlockHeader,
+    /// The number of transactions in the block
+    pub total_transactions: u32,
+    /// Hashes in depth-first order
+    pub hashes: Vec<sha256d::Hash>,
+    /// Flag bits, packed per 8 in a byte, least significant bit first
+    pub flags: Vec<u8>,
+}
+
+/// `getcfilters` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetCFiltersMessage {
+    /// Filter type for which filters are requested
+    pub filter_type: u8,
+    /// The height of the first block in the requested range
+    pub start_height: u32,
+    /// The hash of the last block in the requested range
+    pub stop_hash: sha256d::Hash,
+}
+
+/// `cfilter` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct CFilterMessage {
+    /// Filter type of the filter
+    pub filter_type: u8,
+    /// The hash of the block which the filter is for
+    pub block_hash: sha256d::Hash,
+    /// Serialized compact filter
+    pub filter: Vec<u8>,
+}
+
+/// `getcfheaders` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetCFHeadersMessage {
+    /// Filter type for which headers are requested
+    pub filter_type: u8,
+    /// The height of the first block in the requested range
+    pub start_height: u32,
+    /// The hash of the last block in the requested range
+    pub stop_hash: sha256d::Hash,
+}
+
+/// `cfheaders` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct CFHeadersMessage {
+    /// Filter type of the headers
+    pub filter_type: u8,
+    /// The hash of the last block in the requested range
+    pub stop_hash: sha256d::Hash,
+    /// The filter header preceding the first block in the requested range
+    pub previous_filter_header: sha256d::Hash,
+    /// Filter hashes of each block in the requested range
+    pub filter_hashes: Vec<sha256d::Hash>,
+}
+
+/// `reject` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct RejectMessage {
+    /// Command of the rejected message
+    pub message: String,
+    /// Code which represents the reason of rejection
+    pub ccode: u8,
+    /// Human readable reason of rejection
+    pub reason: String,
+    /// Hash of the rejected transaction or block
+    pub hash: Option<sha256d::Hash>,
+}
+
+/// Address in `addrv2` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddrV2Message {
+    /// Unix time when the address was seen last
+    pub time: u32,
+    /// Service bits of the node
+    pub services: u64,
+    /// Network id of the address
+    pub network: u8,
+    /// The address which is encoded for the network
+    pub addr: Vec<u8>,
+    /// Port of the node
+    pub port: u16,
+}
+
+impl AddrV2Message {
+    /// Return the socket address if it is IPv4 or IPv6. Addresses of other networks such as
+    /// Tor can't be connected.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let ip = match (self.network, self.addr.len()) {
+            (ADDRV2_NETWORK_IPV4, 4) => {
+                let a = &self.addr;
+                IpAddr::V4(Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+            }
+            (ADDRV2_NETWORK_IPV6, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.addr);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, self.port))
+    }
+}
+
+impl From<NetworkMessage> for Message {
+    fn from(message: NetworkMessage) -> Message {
+        Message::Network(message)
+    }
+}
+
+impl Message {
+    /// Return command string of the message.
+    pub fn cmd(&self) -> &'static str {
+        match self {
+            Message::Network(message) => message.cmd(),
+            Message::FilterLoad(_) => "filterload",
+            Message::FilterAdd(_) => "filteradd",
+            Message::FilterClear => "filterclear",
+            Message::GetFilteredBlocks(_) => "getdata",
+            Message::MerkleBlock(_) => "merkleblock",
+            Message::GetCFilters(_) => "getcfilters",
+            Message::CFilter(_) => "cfilter",
+            Message::GetCFHeaders(_) => "getcfheaders",
+            Message::CFHeaders(_) => "cfheaders",
+            Message::Reject(_) => "reject",
+            Message::AddrV2(_) => "addrv2",
+            Message::SendAddrV2 => "sendaddrv2",
+            Message::SendHeaders => "sendheaders",
+            Message::WtxidRelay => "wtxidrelay",
+        }
+    }
+
+    /// Return true if the message of the command is decoded by `Message::decode` instead of
+    /// rust-tapyrus.
+    pub fn is_extension_command(cmd: &str) -> bool {
+        match cmd {
+            "filterload" | "filteradd" | "filterclear" | "merkleblock" | "getcfilters"
+            | "cfilter" | "getcfheaders" | "cfheaders" | "reject" | "addrv2" | "sendaddrv2"
+            | "sendheaders" | "wtxidrelay" => true,
+            _ => false,
+        }
+    }
+
+    /// Decode payload of the message which is not supported by rust-tapyrus.
+    pub fn decode(cmd: &str, payload: &[u8]) -> Result<Message, encode::Error> {
+        let mut d = Cursor::new(payload);
+        let message = match cmd {
+            "filterload" => Message::FilterLoad(FilterLoadMessage {
+                filter: Decodable::consensus_decode(&mut d)?,
+                hash_funcs: Decodable::consensus_decode(&mut d)?,
+                tweak: Decodable::consensus_decode(&mut d)?,
+                flags: Decodable::consensus_decode(&mut d)?,
+            }),
+            "filteradd" => Message::FilterAdd(Decodable::consensus_decode(&mut d)?),
+            "filterclear" => Message::FilterClear,
+            "merkleblock" => Message::MerkleBlock(MerkleBlockMessage {
+                header: Decodable::consensus_decode(&mut d)?,
+                total_transactions: Decodable::consensus_decode(&mut d)?,
+                hashes: Decodable::consensus_decode(&mut d)?,
+                flags: Decodable::consensus_decode(&mut d)?,
+            }),
+            "getcfilters" => Message::GetCFilters(GetCFiltersMessage {
+                filter_type: Decodable::consensus_decode(&mut d)?,
+                start_height: Decodable::consensus_decode(&mut d)?,
+                stop_hash: Decodable::consensus_decode(&mut d)?,
+            }),
+            "cfilter" => Message::CFilter(CFilterMessage {
+                filter_type: Decodable::consensus_decode(&mut d)?,
+                block_hash: Decodable::consensus_decode(&mut d)?,
+                filter: Decodable::consensus_decode(&mut d)?,
+            }),
+            "getcfheaders" => Message::GetCFHeaders(GetCFHeadersMessage {
+                filter_type: Decodable::consensus_decode(&mut d)?,
+                start_height: Decodable::consensus_decode(&mut d)?,
+                stop_hash: Decodable::consensus_decode(&mut d)?,
+            }),
+            "cfheaders" => Message::CFHeaders(CFHeadersMessage {
+                filter_type: Decodable::consensus_decode(&mut d)?,
+                stop_hash: Decodable::consensus_decode(&mut d)?,
+                previous_filter_header: Decodable::consensus_decode(&mut d)?,
+                filter_hashes: Decodable::consensus_decode(&mut d)?,
+            }),
+            "reject" => {
+                let message = Decodable::consensus_decode(&mut d)?;
+                let ccode = Decodable::consensus_decode(&mut d)?;
+                let reason = Decodable::consensus_decode(&mut d)?;
+                // Only reject messages for tx and block have hash.
+                let hash = if (d.position() as usize) < payload.len() {
+                    Some(Decodable::consensus_decode(&mut d)?)
+                } else {
+                    None
+                };
+                Message::Reject(RejectMessage {
+                    message,
+                    ccode,
+                    reason,
+                    hash,
+                })
+            }
+            "addrv2" => {
+                let VarInt(count) = Decodable::consensus_decode(&mut d)?;
+                let mut addrs = vec![];
+                for _ in 0..count {
+                    let time = Decodable::consensus_decode(&mut d)?;
+                    let VarInt(services) = Decodable::consensus_decode(&mut d)?;
+                    let network = Decodable::consensus_decode(&mut d)?;
+                    let addr: Vec<u8> = Decodable::consensus_decode(&mut d)?;
+                    if addr.len() > MAX_ADDRV2_SIZE {
+                        return Err(encode::Error::ParseFailed("addrv2 address is too long"));
+                    }
+                    // port is big endian.
+                    let port: u16 = Decodable::consensus_decode(&mut d)?;
+                    addrs.push(AddrV2Message {
+                        time,
+                        services,
+                        network,
+                        addr,
+                        port: port.swap_bytes(),
+                    });
+                }
+                Message::AddrV2(addrs)
+            }
+            "sendaddrv2" => Message::SendAddrV2,
+            "sendheaders" => Message::SendHeaders,
+            "wtxidrelay" => Message::WtxidRelay,
+            _ => return Err(encode::Error::UnrecognizedNetworkCommand(cmd.to_string())),
+        };
+
+        if d.position() as usize != payload.len() {
+            return Err(encode::Error::ParseFailed(
+                "data not consumed entirely when decoding message",
+            ));
+        }
+        Ok(message)
+    }
+
+    /// Encode payload of the message which is not supported by rust-tapyrus.
+    fn encode_payload(&self) -> Result<Vec<u8>, encode::Error> {
+        let mut s = vec![];
+        match self {
+            Message::Network(_) => unreachable!("NetworkMessage is encoded by rust-tapyrus."),
+            Message::FilterLoad(message) => {
+                message.filter.consensus_encode(&mut s)?;
+                message.hash_funcs.consensus_encode(&mut s)?;
+                message.tweak.consensus_encode(&mut s)?;
+                message.flags.consensus_encode(&mut s)?;
+            }
+            Message::FilterAdd(data) => {
+                data.consensus_encode(&mut s)?;
+            }
+            Message::FilterClear => {}
+            Message::GetFilteredBlocks(hashes) => {
+                VarInt(hashes.len() as u64).consensus_encode(&mut s)?;
+                for hash in hashes {
+                    INV_TYPE_FILTERED_BLOCK.consensus_encode(&mut s)?;
+                    hash.consensus_encode(&mut s)?;
+                }
+            }
+            Message::MerkleBlock(message) => {
+                message.header.consensus_encode(&mut s)?;
+                message.total_transactions.consensus_encode(&mut s)?;
+                message.hashes.consensus_encode(&mut s)?;
+                message.flags.consensus_encode(&mut s)?;
+            }
+            Message::GetCFilters(message) => {
+                message.filter_type.consensus_encode(&mut s)?;
+                message.start_height.consensus_encode(&mut s)?;
+                message.stop_hash.consensus_encode(&mut s)?;
+            }
+            Message::CFilter(message) => {
+                message.filter_type.consensus_encode(&mut s)?;
+                message.block_hash.consensus_encode(&mut s)?;
+                message.filter.consensus_encode(&mut s)?;
+            }
+            Message::GetCFHeaders(message) => {
+                message.filter_type.consensus_encode(&mut s)?;
+                message.start_height.consensus_encode(&mut s)?;
+                message.stop_hash.consensus_encode(&mut s)?;
+            }
+            Message::CFHeaders(message) => {
+                message.filter_type.consensus_encode(&mut s)?;
+                message.stop_hash.consensus_encode(&mut s)?;
+                message.previous_filter_header.consensus_encode(&mut s)?;
+                message.filter_hashes.consensus_encode(&mut s)?;
+            }
+            Message::Reject(message) => {
+                message.message.consensus_encode(&mut s)?;
+                message.ccode.consensus_encode(&mut s)?;
+                message.reason.consensus_encode(&mut s)?;
+                if let Some(hash) = message.hash {
+                    hash.consensus_encode(&mut s)?;
+                }
+            }
+            Message::AddrV2(addrs) => {
+                VarInt(addrs.len() as u64).consensus_encode(&mut s)?;
+                for addr in addrs {
+                    addr.time.consensus_encode(&mut s)?;
+                    VarInt(addr.services).consensus_encode(&mut s)?;
+                    addr.network.consensus_encode(&mut s)?;
+                    addr.addr.consensus_encode(&mut s)?;
+                    addr.port.swap_bytes().consensus_encode(&mut s)?;
+                }
+            }
+            Message::SendAddrV2 | Message::SendHeaders | Message::WtxidRelay => {}
+        }
+        Ok(s)
+    }
+}
+
+/// Message with magic bytes of the network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawMessage {
+    /// Magic bytes to identify the network
+    pub magic: u32,
+    /// The actual message data
+    pub payload: Message,
+}
+
+impl RawMessage {
+    /// Serialize the message.
+    pub fn into_bytes(self) -> Result<Vec<u8>, encode::Error> {
+        let mut s = vec![];
+        match self.payload {
+            Message::Network(payload) => {
+                RawNetworkMessage {
+                    magic: self.magic,
+                    payload,
+                }
+                .consensus_encode(&mut s)?;
+            }
+            payload => {
+                let mut command = [0u8; 12];
+                let cmd = payload.cmd().as_bytes();
+                command[..cmd.len()].copy_from_slice(cmd);
+
+                let data = payload.encode_payload()?;
+                let checksum = sha256d::Hash::hash(&data);
+
+                self.magic.consensus_encode(&mut s)?;
+                command.consensus_encode(&mut s)?;
+                (data.len() as u32).consensus_encode(&mut s)?;
+                s.extend_from_slice(&checksum[0..4]);
+                s.extend_from_slice(&data);
+            }
+        }
+        Ok(s)
+    }
+}
+
+impl From<RawNetworkMessage> for RawMessage {
+    fn from(message: RawNetworkMessage) -> RawMessage {
+        RawMessage {
+            magic: message.magic,
+            payload: Message::Network(message.payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_headers;
+    use tapyrus::Network;
+
+    #[test]
+    fn test_encode_filterload() {
+        let message = RawMessage {
+            magic: Network::Regtest.magic(),
+            payload: Message::FilterLoad(FilterLoadMessage {
+                filter: vec![0x61, 0x4e, 0x9b],
+                hash_funcs: 5,
+                tweak: 0,
+                flags: 1,
+            }),
+        };
+
+        let bytes = message.into_bytes().unwrap();
+        assert_eq!(&bytes[4..16], b"filterload\0\0");
+        assert_eq!(&bytes[16..20], &[13, 0, 0, 0]);
+        assert_eq!(
+            hex::encode(&bytes[24..]),
+            "03614e9b050000000000000001".to_string()
+        );
+    }
+
+    #[test]
+    fn test_encode_get_filtered_blocks() {
+        let hash = sha256d::Hash::hash(&[1]);
+        let message = RawMessage {
+            magic: Network::Regtest.magic(),
+            payload: Message::GetFilteredBlocks(vec![hash]),
+        };
+
+        let bytes = message.into_bytes().unwrap();
+        assert_eq!(&bytes[4..16], b"getdata\0\0\0\0\0");
+        assert_eq!(&bytes[24..29], &[1, 3, 0, 0, 0]);
+        assert_eq!(&bytes[29..], &hash[..]);
+    }
+
+    #[test]
+    fn test_decode_merkleblock() {
+        let message = Message::MerkleBlock(MerkleBlockMessage {
+            header: get_test_headers(1, 1).pop().unwrap(),
+            total_transactions: 3,
+            hashes: vec![sha256d::Hash::hash(&[1]), sha256d::Hash::hash(&[2])],
+            flags: vec![0x1d],
+        });
+
+        let payload = message.encode_payload().unwrap();
+        assert_eq!(Message::decode("merkleblock", &payload).unwrap(), message);
+
+        // trailing bytes are not allowed.
+        let mut payload = payload;
+        payload.push(0);
+        assert!(Message::decode("merkleblock", &payload).is_err());
+    }
+
+    #[test]
+    fn test_compact_filter_messages() {
+        let messages = vec![
+            Message::GetCFilters(GetCFiltersMessage {
+                filter_type: 0,
+                start_height: 1,
+                stop_hash: sha256d::Hash::hash(&[1]),
+            }),
+            Message::CFilter(CFilterMessage {
+                filter_type: 0,
+                block_hash: sha256d::Hash::hash(&[1]),
+                filter: vec![0x01, 0x9d, 0xfc, 0xa8],
+            }),
+            Message::GetCFHeaders(GetCFHeadersMessage {
+                filter_type: 0,
+                start_height: 1,
+                stop_hash: sha256d::Hash::hash(&[1]),
+            }),
+            Message::CFHeaders(CFHeadersMessage {
+                filter_type: 0,
+                stop_hash: sha256d::Hash::hash(&[1]),
+                previous_filter_header: sha256d::Hash::hash(&[2]),
+                filter_hashes: vec![sha256d::Hash::hash(&[3]), sha256d::Hash::hash(&[4])],
+            }),
+        ];
+
+        for message in messages {
+            assert!(Message::is_extension_command(message.cmd()));
+            let payload = message.encode_payload().unwrap();
+            assert_eq!(Message::decode(message.cmd(), &payload).unwrap(), message);
+        }
+
+        let payload = Message::GetCFilters(GetCFiltersMessage {
+            filter_type: 0,
+            start_height: 1,
+            stop_hash: sha256d::Hash::default(),
+        })
+        .encode_payload()
+        .unwrap();
+        assert_eq!(payload.len(), 37);
+        assert_eq!(&payload[..5], &[0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_decode_reject() {
+        let txid = sha256d::Hash::hash(&[1]);
+        let message = Message::Reject(RejectMessage {
+            message: "tx".to_string(),
+            ccode: 0x42,
+            reason: "insufficient fee".to_string(),
+            hash: Some(txid),
+        });
+        let payload = message.encode_payload().unwrap();
+        assert_eq!(&payload[..4], &[2, b't', b'x', 0x42]);
+        assert!(Message::is_extension_command("reject"));
+        assert_eq!(Message::decode("reject", &payload).unwrap(), message);
+
+        // reject of other messages doesn't have hash.
+        let message = Message::Reject(RejectMessage {
+            message: "version".to_string(),
+            ccode: 0x11,
+            reason: "obsolete".to_string(),
+            hash: None,
+        });
+        let payload = message.encode_payload().unwrap();
+        assert_eq!(Message::decode("reject", &payload).unwrap(), message);
+    }
+
+    #[test]
+    fn test_addrv2() {
+        let ipv4 = "127.0.0.1:2357".parse().unwrap();
+        let ipv6 = "[::1]:2357".parse().unwrap();
+        let tor = AddrV2Message {
+            time: 1,
+            services: 0,
+            network: 4,
+            addr: vec![0; 32],
+            port: 2357,
+        };
+        let message = Message::AddrV2(vec![
+            AddrV2Message {
+                time: 1,
+                services: 1,
+                network: ADDRV2_NETWORK_IPV4,
+                addr: vec![127, 0, 0, 1],
+                port: 2357,
+            },
+            AddrV2Message {
+                time: 1,
+                services: 1,
+                network: ADDRV2_NETWORK_IPV6,
+                addr: "::1".parse::<Ipv6Addr>().unwrap().octets().to_vec(),
+                port: 2357,
+            },
+            tor,
+        ]);
+        let payload = message.encode_payload().unwrap();
+        assert_eq!(hex::encode(&payload[..14]), "03010000000101047f0000010935");
+        assert!(Message::is_extension_command("addrv2"));
+        let decoded = Message::decode("addrv2", &payload).unwrap();
+        assert_eq!(decoded, message);
+
+        match decoded {
+            Message::AddrV2(addrs) => {
+                assert_eq!(addrs[0].socket_addr(), Some(ipv4));
+                assert_eq!(addrs[1].socket_addr(), Some(ipv6));
+                assert_eq!(addrs[2].socket_addr(), None);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            Message::decode("sendaddrv2", &[]).unwrap(),
+            Message::SendAddrV2
+        );
+    }
+
+    #[test]
+    fn test_feature_negotiation_messages() {
+        for message in vec![Message::SendHeaders, Message::WtxidRelay] {
+            assert!(Message::is_extension_command(message.cmd()));
+            let payload = message.encode_payload().unwrap();
+            assert!(payload.is_empty());
+            assert_eq!(Message::decode(message.cmd(), &payload).unwrap(), message);
+        }
+    }
+}
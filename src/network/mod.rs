@@ -10,15 +10,60 @@
 mod peer;
 pub use self::peer::connect;
 pub use self::peer::Peer;
+pub use self::peer::PeerID;
+pub use self::peer::ProtocolVersion;
+pub use self::peer::BAN_SCORE_THRESHOLD;
+pub use self::peer::BITCOIN_PROTOCOL_VERSION;
+pub use self::peer::NODE_BLOOM;
+pub use self::peer::NODE_NETWORK;
+pub use self::peer::TAPYRUS_PROTOCOL_VERSION;
+
+mod ban_list;
+pub use self::ban_list::BanList;
+
+mod addr_manager;
+pub use self::addr_manager::AddrManager;
+pub use self::addr_manager::DnsResolver;
 
 mod handshake;
 pub use self::handshake::Handshake;
+pub use self::handshake::SentNonces;
 
 mod block_header_download;
 pub use self::block_header_download::BlockHeaderDownload;
 
+mod bloom_filter;
+
+mod watch_list;
+pub use self::watch_list::WatchList;
+
+mod merkle_block_download;
+pub use self::merkle_block_download::FilteredBlock;
+pub use self::merkle_block_download::MerkleBlockDownload;
+
+mod scan_progress;
+pub use self::scan_progress::ScanEvent;
+
+mod compact_filter_download;
+pub use self::compact_filter_download::CompactFilterDownload;
+pub use self::compact_filter_download::NODE_COMPACT_FILTERS;
+
+mod transaction_broadcast;
+pub use self::transaction_broadcast::BroadcastError;
+pub use self::transaction_broadcast::RejectReason;
+pub use self::transaction_broadcast::TransactionBroadcast;
+
+mod peer_manager;
+pub use self::peer_manager::ConnectFuture;
+pub use self::peer_manager::PeerManager;
+pub use self::peer_manager::TransactionDownload;
+pub use self::peer_manager::DEFAULT_MAX_OUTBOUND_PEERS;
+
+pub mod message;
+
 pub mod utils;
 
 mod error;
 pub use self::error::Error;
+pub use self::error::HandshakeFailure;
 pub use self::error::MaliciousPeerCause;
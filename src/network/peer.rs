@@ -3,58 +3,116 @@
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
 use crate::chain::{Chain, ChainStore};
-use crate::network::{utils::codec::NetworkMessagesCodec, Error};
+use crate::network::message::{Message, RawMessage};
+use crate::network::utils::codec::{self, NetworkMessagesCodec};
+use crate::network::{Error, MaliciousPeerCause};
 use bitcoin_hashes::sha256d;
 use rand::{thread_rng, RngCore};
 use std::{
     borrow::BorrowMut,
+    io,
     net::SocketAddr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tapyrus::network::message_blockdata::GetHeadersMessage;
 use tapyrus::network::{
-    address::Address,
-    constants::Network,
-    message::{NetworkMessage, RawNetworkMessage},
-    message_network::VersionMessage,
+    address::Address, message::NetworkMessage, message_network::VersionMessage,
 };
-use tokio::{codec::Framed, net::TcpStream, prelude::*};
+use tokio::{codec::Framed, net::TcpStream, prelude::*, timer::Timeout};
 
 pub type PeerID = u64;
 
+/// The peer is banned when its ban score reaches this value.
+pub const BAN_SCORE_THRESHOLD: u32 = 100;
+
+/// Service bit of peers which serve full blocks.
+pub const NODE_NETWORK: u64 = 1;
+
+/// Service bit of peers which support bloom filters (BIP111).
+pub const NODE_BLOOM: u64 = 1 << 2;
+
+/// Protocol version which the node sends in version message, and the lowest version of peers
+/// which it accepts. Headers announcements (BIP130) must be available from `min_peer_version`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProtocolVersion {
+    /// Version which the node sends in version message
+    pub version: u32,
+    /// Peers whose protocol version is lower than this are disconnected.
+    pub min_peer_version: u32,
+}
+
+/// Protocol version of Tapyrus networks. Tapyrus Core numbers protocol versions from 10000, and
+/// every message which the node uses is available from it.
+pub const TAPYRUS_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion {
+    version: 10_000,
+    min_peer_version: 10_000,
+};
+
+/// Protocol version of legacy networks which use Bitcoin numbering. Headers announcements are
+/// available from 70012.
+pub const BITCOIN_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion {
+    version: 70_015,
+    min_peer_version: 70_012,
+};
+
+/// Give up TCP connection if it is not established in this duration.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Peer<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
     pub id: PeerID,
     pub addr: SocketAddr,
-    pub network: Network,
+    /// Magic bytes of messages on the network
+    pub magic: u32,
     pub stream: T,
     pub version: Option<VersionMessage>,
+    /// Accumulated score of misbehavior.
+    pub ban_score: u32,
+    /// True if the peer sent `sendheaders` message. It prefers headers announcements to inv.
+    pub send_headers: bool,
+    /// True if the peer sent `wtxidrelay` message during the handshake.
+    pub wtxid_relay: bool,
 }
 
 impl<T> Peer<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
 {
-    pub fn new(id: u64, stream: T, addr: SocketAddr, network: Network) -> Peer<T> {
+    pub fn new(id: PeerID, stream: T, addr: SocketAddr, magic: u32) -> Peer<T> {
         Peer {
             id,
             addr,
-            network,
+            magic,
             stream,
             version: None,
+            ban_score: 0,
+            send_headers: false,
+            wtxid_relay: false,
         }
     }
 
+    /// Increase ban score of the peer for the misbehavior.
+    /// Return true if the score reached BAN_SCORE_THRESHOLD.
+    pub fn misbehaving(&mut self, cause: &MaliciousPeerCause) -> bool {
+        self.ban_score += cause.score();
+        warn!(
+            "Peer {} misbehaved: {:?}. ban score: {}",
+            self.id, cause, self.ban_score
+        );
+        self.ban_score >= BAN_SCORE_THRESHOLD
+    }
+
     /// Start to send message.
     /// This function just put message into buffer on sink. So call stream.poll_complete() to  send
     /// to remote.
-    pub fn start_send(&mut self, message: NetworkMessage) {
+    pub fn start_send<M: Into<Message>>(&mut self, message: M) {
+        let message = message.into();
         trace!("Sending message: {:?}", message);
 
-        let raw_msg = RawNetworkMessage {
-            magic: self.network.magic(),
+        let raw_msg = RawMessage {
+            magic: self.magic,
             payload: message,
         };
 
@@ -77,18 +135,32 @@ where
 
 impl<T> Stream for Peer<T>
 where
-    T: Sink<SinkItem = RawNetworkMessage> + Stream<Item = RawNetworkMessage>,
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
     Error: From<T::Error>,
 {
-    type Item = NetworkMessage;
+    type Item = Message;
     type Error = Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        match self.stream.poll()? {
+        let result = match self.stream.poll().map_err(Error::from) {
+            Err(Error::CodecError(codec::Error::OversizedMessage(size))) => {
+                info!("Oversized message. size: {}", size);
+                return Err(Error::MaliciousPeer(
+                    self.id,
+                    MaliciousPeerCause::OversizedMessage,
+                ));
+            }
+            result => result?,
+        };
+
+        match result {
             Async::Ready(Some(message)) => {
-                if message.magic != self.network.magic() {
+                if message.magic != self.magic {
                     info!("Wrong magic bytes.");
-                    return Err(Error::WrongMagicBytes);
+                    return Err(Error::MaliciousPeer(
+                        self.id,
+                        MaliciousPeerCause::WrongMagicBytes,
+                    ));
                 }
 
                 trace!("Receive message: {:?}", message);
@@ -100,22 +172,46 @@ where
     }
 }
 
+/// Choose the smallest PeerID greater than `last` from `ids`. If there is no such id, choose the
+/// smallest one. It is used to spread requests across peers.
+pub fn next_peer_id<I>(ids: I, last: Option<PeerID>) -> Option<PeerID>
+where
+    I: Iterator<Item = PeerID> + Clone,
+{
+    let first = ids.clone().min();
+    match last {
+        Some(last) => ids.filter(|id| *id > last).min().or(first),
+        None => first,
+    }
+}
+
 pub fn connect(
     address: &SocketAddr,
-    network: Network,
+    magic: u32,
+    id: PeerID,
 ) -> impl Future<Item = Peer<Framed<TcpStream, NetworkMessagesCodec>>, Error = Error> {
     trace!("Try to create TCP connection to {}", address);
-    TcpStream::connect(address)
+    Timeout::new(TcpStream::connect(address), CONNECT_TIMEOUT)
         .map(move |stream| {
             let addr = stream.peer_addr().unwrap();
             trace!("Success to create TCP connection to {}", addr);
             let stream = Framed::new(stream, NetworkMessagesCodec::new());
-            Peer::new(0, stream, addr, network)
+            Peer::new(id, stream, addr, magic)
+        })
+        .map_err(|e| {
+            if e.is_elapsed() {
+                Error::from(io::Error::from(io::ErrorKind::TimedOut))
+            } else if e.is_timer() {
+                Error::from(e.into_timer().unwrap())
+            } else {
+                Error::from(e.into_inner().unwrap())
+            }
         })
-        .map_err(|e| Error::from(e))
 }
 
-pub fn version_message() -> VersionMessage {
+/// Build version message of the protocol version which reports `start_height` as the height of
+/// the active chain.
+pub fn version_message(protocol_version: u32, start_height: i32) -> VersionMessage {
     let blank_addr = "[0:0:0:0:0:0:0:0]:0".parse().unwrap();
 
     // now in unix time
@@ -129,13 +225,10 @@ pub fn version_message() -> VersionMessage {
     // generate random value
     let nonce = thread_rng().borrow_mut().next_u64();
 
-    // TODO: after block database is constructed, set actual latest block height.
-    let start_height = 0;
-
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
     // build message
-    VersionMessage::new(
+    let mut message = VersionMessage::new(
         services,
         timestamp,
         Address::new(&blank_addr, 0),
@@ -143,5 +236,8 @@ pub fn version_message() -> VersionMessage {
         nonce,
         format!("/tapyrus-spv:{}/", VERSION),
         start_height,
-    )
+    );
+    // The version of the tapyrus crate is for Bitcoin network.
+    message.version = protocol_version;
+    message
 }
@@ -0,0 +1,1273 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Chain, ChainStore};
+use crate::event::{Event, EventBus};
+use crate::network::addr_manager::MAX_ADDR_TO_SEND;
+use crate::network::ban_list::DEFAULT_BAN_DURATION;
+use crate::network::message::{Message, RawMessage};
+use crate::network::{
+    AddrManager, BanList, BlockHeaderDownload, CompactFilterDownload, Error, MaliciousPeerCause,
+    MerkleBlockDownload, Peer, PeerID, TransactionBroadcast, BAN_SCORE_THRESHOLD,
+};
+use crate::ChainState;
+use bitcoin_hashes::sha256d;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::BitcoinHash;
+use tokio::prelude::{Async, Future, Sink, Stream};
+use tokio::timer::Interval;
+
+/// The default number of outbound peers which PeerManager keeps connections with.
+pub const DEFAULT_MAX_OUTBOUND_PEERS: usize = 8;
+
+/// Interval to check connections and requests.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before reconnecting to an address after the first failure. The delay is doubled for
+/// each consecutive failure up to MAX_RECONNECT_DELAY.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay before reconnecting to an address.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// Interval to save changes of known addresses.
+const ADDR_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Future which establishes connection and completes handshake with a peer.
+pub type ConnectFuture<T> = Box<dyn Future<Item = Peer<T>, Error = Error> + Send>;
+
+/// The way to find transactions which match the watch list.
+pub enum TransactionDownload {
+    /// Load bloom filters (BIP37) on peers and download merkle blocks.
+    Bloom(MerkleBlockDownload),
+    /// Download compact block filters (BIP157/158) and match them locally.
+    CompactFilter(CompactFilterDownload),
+}
+
+impl TransactionDownload {
+    /// Return height of the last block which has been scanned for transactions.
+    fn scanned_height(&self) -> i32 {
+        match self {
+            TransactionDownload::Bloom(download) => download.scanned_height(),
+            TransactionDownload::CompactFilter(download) => download.scanned_height(),
+        }
+    }
+
+    fn request<T, S>(
+        &mut self,
+        peers: &mut HashMap<PeerID, Peer<T>>,
+        chain_active: &Chain<S>,
+        synced: bool,
+    ) where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        match self {
+            TransactionDownload::Bloom(download) => download.request(peers, chain_active, synced),
+            TransactionDownload::CompactFilter(download) => {
+                download.request(peers, chain_active, synced)
+            }
+        }
+    }
+
+    /// Process messages which are related to the transaction download.
+    fn on_message<T, S>(
+        &mut self,
+        peer: &mut Peer<T>,
+        chain_active: &Chain<S>,
+        message: Message,
+    ) -> Result<(), Error>
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+        S: ChainStore,
+    {
+        match (self, message) {
+            (
+                TransactionDownload::Bloom(download),
+                Message::Network(NetworkMessage::Pong(nonce)),
+            ) => {
+                download.on_pong(peer.id, nonce, chain_active);
+            }
+            (TransactionDownload::Bloom(download), Message::Network(NetworkMessage::Tx(tx))) => {
+                download.on_tx(peer.id, tx);
+            }
+            (TransactionDownload::Bloom(download), Message::MerkleBlock(merkle_block)) => {
+                download.on_merkle_block(peer.id, merkle_block, chain_active)?;
+            }
+            (TransactionDownload::CompactFilter(download), Message::CFHeaders(cfheaders)) => {
+                download.on_cfheaders(peer.id, cfheaders)?;
+            }
+            (TransactionDownload::CompactFilter(download), Message::CFilter(cfilter)) => {
+                download.on_cfilter(peer, cfilter)?;
+            }
+            (
+                TransactionDownload::CompactFilter(download),
+                Message::Network(NetworkMessage::Block(block)),
+            ) => {
+                download.on_block(peer.id, block)?;
+            }
+            _ => {} // ignore other messages.
+        }
+        Ok(())
+    }
+}
+
+/// Reconnection state for an address.
+struct Backoff {
+    /// The number of consecutive failures.
+    failures: u32,
+    /// Don't try to connect until this time.
+    retry_at: Instant,
+}
+
+/// PeerManager maintains connections with outbound peers, downloads block headers from them and
+/// keeps following the tip. It also finds transactions which match the watch list with
+/// `transaction_download`, and relays transactions in `broadcast` to peers. This future never
+/// completes.
+///
+/// `connector` is called with a unique PeerID and an address to establish a new connection.
+/// Given addresses are connected first, and the rest of connections are made to addresses in the
+/// address manager, which learns addresses from peers. When a connection fails or is closed, the
+/// address is retried after a delay which grows exponentially. A peer which misbehaves
+/// accumulates ban score, and it is disconnected and its address is banned when the score reaches
+/// BAN_SCORE_THRESHOLD. Banned addresses are not connected until the ban expires.
+///
+/// Changes of connections and the active chain are emitted to the event bus.
+pub struct PeerManager<T, S, C>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+    C: Fn(PeerID, SocketAddr) -> ConnectFuture<T>,
+{
+    connector: C,
+    addrs: Vec<SocketAddr>,
+    max_outbound_peers: usize,
+    next_peer_id: PeerID,
+    peers: HashMap<PeerID, Peer<T>>,
+    connecting: HashMap<PeerID, (SocketAddr, ConnectFuture<T>)>,
+    backoffs: HashMap<SocketAddr, Backoff>,
+    ban_list: BanList,
+    addr_manager: AddrManager,
+    header_download: BlockHeaderDownload,
+    transaction_download: TransactionDownload,
+    broadcast: Arc<Mutex<TransactionBroadcast>>,
+    chain_state: Arc<Mutex<ChainState<S>>>,
+    events: EventBus,
+    /// The tip which was notified last.
+    notified_tip: Option<sha256d::Hash>,
+    interval: Interval,
+    /// Time when known addresses were saved last.
+    addrs_saved_at: Instant,
+}
+
+impl<T, S, C> PeerManager<T, S, C>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+    C: Fn(PeerID, SocketAddr) -> ConnectFuture<T>,
+    Error: From<T::Error>,
+{
+    pub fn new(
+        connector: C,
+        addrs: Vec<SocketAddr>,
+        max_outbound_peers: usize,
+        ban_list: BanList,
+        chain_state: Arc<Mutex<ChainState<S>>>,
+        transaction_download: TransactionDownload,
+        broadcast: Arc<Mutex<TransactionBroadcast>>,
+    ) -> PeerManager<T, S, C> {
+        PeerManager {
+            connector,
+            addrs,
+            max_outbound_peers,
+            next_peer_id: 1,
+            peers: HashMap::new(),
+            connecting: HashMap::new(),
+            backoffs: HashMap::new(),
+            ban_list,
+            addr_manager: AddrManager::new(),
+            header_download: BlockHeaderDownload::new(),
+            transaction_download,
+            broadcast,
+            chain_state,
+            events: EventBus::new(),
+            notified_tip: None,
+            interval: Interval::new_interval(TICK_INTERVAL),
+            addrs_saved_at: Instant::now(),
+        }
+    }
+
+    /// Emit events to the bus instead of the bus without subscribers.
+    pub fn set_event_bus(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    /// Use the address manager instead of the empty one which is kept on memory.
+    pub fn set_addr_manager(&mut self, addr_manager: AddrManager) {
+        self.addr_manager = addr_manager;
+    }
+
+    /// Return the number of peers which completed handshake.
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Return height of the last block which has been scanned for transactions of the watch
+    /// list.
+    pub fn scanned_height(&self) -> i32 {
+        self.transaction_download.scanned_height()
+    }
+
+    /// Return the highest start height which connected peers reported, or `height` if it is
+    /// higher.
+    pub fn target_height(&self, height: i32) -> i32 {
+        self.peers
+            .values()
+            .filter_map(|peer| peer.version.as_ref())
+            .map(|version| version.start_height)
+            .fold(height, cmp::max)
+    }
+
+    /// Start connecting to addresses which are neither connected, banned nor waiting for
+    /// reconnection until the number of peers reaches max_outbound_peers. Given addresses are
+    /// preferred to addresses in the address manager.
+    fn connect_peers(&mut self) {
+        let candidates: Vec<SocketAddr> = self
+            .addrs
+            .iter()
+            .filter(|addr| self.is_connectable(addr))
+            .cloned()
+            .collect();
+
+        for addr in candidates {
+            if self.peers.len() + self.connecting.len() >= self.max_outbound_peers {
+                return;
+            }
+            self.start_connect(addr);
+        }
+
+        while self.peers.len() + self.connecting.len() < self.max_outbound_peers {
+            match self.addr_manager.select(|addr| self.is_connectable(addr)) {
+                Some(addr) => self.start_connect(addr),
+                None => break,
+            }
+        }
+    }
+
+    fn start_connect(&mut self, addr: SocketAddr) {
+        let id = self.next_peer_id;
+        self.next_peer_id += 1;
+
+        info!("Connect to peer {}({}).", id, addr);
+        let future = (self.connector)(id, addr);
+        self.connecting.insert(id, (addr, future));
+    }
+
+    /// Return true if the address is neither connected, banned nor waiting for reconnection.
+    fn is_connectable(&self, addr: &SocketAddr) -> bool {
+        !self.is_connected(addr)
+            && !self.ban_list.is_banned(&addr.ip())
+            && self
+                .backoffs
+                .get(addr)
+                .map_or(true, |b| b.retry_at <= Instant::now())
+    }
+
+    fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.peers.values().any(|peer| peer.addr == *addr)
+            || self.connecting.values().any(|(a, _)| a == addr)
+    }
+
+    /// Poll connecting futures and move established peers into `peers`.
+    fn poll_connecting(&mut self) {
+        let mut finished = vec![];
+        for (id, (_, future)) in self.connecting.iter_mut() {
+            match future.poll() {
+                Ok(Async::Ready(peer)) => finished.push((*id, Ok(peer))),
+                Ok(Async::NotReady) => {}
+                Err(e) => finished.push((*id, Err(e))),
+            }
+        }
+
+        for (id, result) in finished {
+            let (addr, _) = self.connecting.remove(&id).unwrap();
+            match result {
+                Ok(peer) => {
+                    info!("Connected to peer {}({}).", id, addr);
+                    self.events.emit(Event::PeerConnected { peer_id: id, addr });
+                    self.backoffs.remove(&addr);
+                    self.addr_manager.good(addr);
+                    self.peers.insert(id, peer);
+                }
+                Err(e) => {
+                    warn!("Failed to connect to peer {}({}): {:?}", id, addr, e);
+                    self.addr_manager.failed(&addr);
+                    self.backoff(addr);
+                }
+            }
+        }
+    }
+
+    /// Process received messages from all peers and disconnect peers which closed connection or
+    /// sent invalid messages. Disconnections are emitted and addresses are banned after the chain
+    /// state and broadcast transactions are unlocked.
+    fn poll_peers(&mut self) {
+        let removed = self.poll_peers_locked();
+        for peer in removed {
+            self.on_disconnected(peer);
+        }
+    }
+
+    /// Process received messages while the chain state and broadcast transactions are locked, and
+    /// return peers which were removed.
+    fn poll_peers_locked(&mut self) -> Vec<Peer<T>> {
+        let chain_state = self.chain_state.clone();
+        let mut chain_state = chain_state.lock().unwrap();
+        let chain_active = chain_state.borrow_mut_chain_active();
+        let broadcast = self.broadcast.clone();
+        let mut broadcast = broadcast.lock().unwrap();
+
+        let mut removed = vec![];
+        let mut disconnected = vec![];
+        for (id, peer) in self.peers.iter_mut() {
+            match Self::poll_peer(
+                peer,
+                chain_active,
+                &mut self.header_download,
+                &mut self.transaction_download,
+                &mut broadcast,
+                &mut self.addr_manager,
+            ) {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("Peer {} closed connection.", id);
+                    disconnected.push(*id);
+                }
+                Err(e) => {
+                    warn!("Disconnect peer {}: {:?}", id, e);
+                    disconnected.push(*id);
+                }
+            }
+        }
+
+        for id in disconnected {
+            removed.extend(self.peers.remove(&id));
+        }
+
+        self.header_download.request(&mut self.peers, chain_active);
+        self.transaction_download.request(
+            &mut self.peers,
+            chain_active,
+            self.header_download.is_synced(),
+        );
+        broadcast.announce(&mut self.peers);
+
+        // Peers can be found misbehaving while responses of other peers are processed.
+        let banned: Vec<PeerID> = self
+            .peers
+            .values()
+            .filter(|peer| peer.ban_score >= BAN_SCORE_THRESHOLD)
+            .map(|peer| peer.id)
+            .collect();
+        for id in banned {
+            warn!("Disconnect peer {}: ban score reached the threshold.", id);
+            removed.extend(self.peers.remove(&id));
+        }
+
+        for peer in self.peers.values_mut() {
+            peer.flush();
+        }
+        removed
+    }
+
+    /// Emit disconnection of the removed peer, and ban its address if its ban score reached
+    /// BAN_SCORE_THRESHOLD.
+    fn on_disconnected(&mut self, peer: Peer<T>) {
+        self.events.emit(Event::PeerDisconnected {
+            peer_id: peer.id,
+            addr: peer.addr,
+        });
+        if peer.ban_score >= BAN_SCORE_THRESHOLD {
+            if let Err(e) = self.ban_list.ban(peer.addr.ip(), DEFAULT_BAN_DURATION) {
+                error!("Can not save ban list: {:?}", e);
+            }
+        }
+        self.backoff(peer.addr);
+    }
+
+    /// Process all received messages from the peer.
+    /// Return false if the peer closed connection. Return error if the connection should be
+    /// closed.
+    fn poll_peer(
+        peer: &mut Peer<T>,
+        chain_active: &mut Chain<S>,
+        header_download: &mut BlockHeaderDownload,
+        transaction_download: &mut TransactionDownload,
+        broadcast: &mut TransactionBroadcast,
+        addr_manager: &mut AddrManager,
+    ) -> Result<bool, Error> {
+        loop {
+            let message = match peer.poll() {
+                Ok(Async::Ready(Some(message))) => message,
+                Ok(Async::Ready(None)) => return Ok(false),
+                Ok(Async::NotReady) => return Ok(true),
+                Err(e) => {
+                    // The stream can not be continued anyway.
+                    if let Error::MaliciousPeer(_, ref cause) = e {
+                        peer.misbehaving(cause);
+                    }
+                    return Err(e);
+                }
+            };
+
+            if let Err(e) = Self::process_message(
+                peer,
+                chain_active,
+                header_download,
+                transaction_download,
+                broadcast,
+                addr_manager,
+                message,
+            ) {
+                match e {
+                    Error::MaliciousPeer(_, ref cause) => {
+                        if peer.misbehaving(cause) {
+                            return Err(e);
+                        }
+                    }
+                    e => return Err(e),
+                }
+            }
+        }
+    }
+
+    fn process_message(
+        peer: &mut Peer<T>,
+        chain_active: &mut Chain<S>,
+        header_download: &mut BlockHeaderDownload,
+        transaction_download: &mut TransactionDownload,
+        broadcast: &mut TransactionBroadcast,
+        addr_manager: &mut AddrManager,
+        message: Message,
+    ) -> Result<(), Error> {
+        match message {
+            Message::Network(NetworkMessage::Headers(headers)) => {
+                header_download.on_headers(peer, chain_active, headers)?;
+            }
+            Message::Network(NetworkMessage::Inv(inventory)) => {
+                header_download.on_inv(peer, chain_active, &inventory);
+            }
+            Message::Network(NetworkMessage::Ping(nonce)) => {
+                peer.start_send(NetworkMessage::Pong(nonce));
+            }
+            Message::Network(NetworkMessage::GetData(inventory)) => {
+                broadcast.on_getdata(peer, &inventory);
+            }
+            Message::Reject(reject) => {
+                broadcast.on_reject(peer.id, reject);
+            }
+            Message::SendHeaders => {
+                peer.send_headers = true;
+            }
+            Message::Network(NetworkMessage::Addr(addrs)) => {
+                let addrs = addrs
+                    .into_iter()
+                    .filter_map(|(time, addr)| addr.socket_addr().ok().map(|a| (a, time)));
+                Self::on_addrs(peer, addr_manager, addrs.collect())?;
+            }
+            Message::AddrV2(addrs) => {
+                let addrs = addrs
+                    .into_iter()
+                    .filter_map(|addr| addr.socket_addr().map(|a| (a, addr.time)));
+                Self::on_addrs(peer, addr_manager, addrs.collect())?;
+            }
+            message => {
+                transaction_download.on_message(peer, chain_active, message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add addresses which the peer announced with the time when they were seen.
+    fn on_addrs(
+        peer: &Peer<T>,
+        addr_manager: &mut AddrManager,
+        addrs: Vec<(SocketAddr, u32)>,
+    ) -> Result<(), Error> {
+        if addrs.len() > MAX_ADDR_TO_SEND {
+            return Err(Error::MaliciousPeer(
+                peer.id,
+                MaliciousPeerCause::OversizedAddr,
+            ));
+        }
+
+        let added = addr_manager.add(
+            addrs
+                .into_iter()
+                .filter(|(addr, _)| addr.port() != 0)
+                .map(|(addr, time)| (addr, u64::from(time))),
+        );
+        debug!("Peer {} announced {} new addresses.", peer.id, added);
+        Ok(())
+    }
+
+    /// Save known addresses every ADDR_SAVE_INTERVAL. It is called after the chain state and
+    /// broadcast transactions are unlocked, so that writing the file doesn't block them.
+    fn save_addrs(&mut self) {
+        if self.addrs_saved_at.elapsed() < ADDR_SAVE_INTERVAL {
+            return;
+        }
+        self.addrs_saved_at = Instant::now();
+        if let Err(e) = self.addr_manager.flush() {
+            error!("Can not save addresses: {:?}", e);
+        }
+    }
+
+    /// Emit events if the tip of the active chain has changed since the last call. Events are
+    /// emitted after the chain state is unlocked.
+    fn notify_tip(&mut self) {
+        let mut events = vec![];
+        {
+            let chain_state = self.chain_state.lock().unwrap();
+            let chain_active = chain_state.borrow_chain_active();
+            let tip = chain_active.tip();
+            let hash = tip.header.bitcoin_hash();
+            if self.notified_tip == Some(hash) {
+                return;
+            }
+
+            if let Some(last) = self.notified_tip {
+                if !chain_active.contains(&last) {
+                    // The last tip may have been pruned from side branches. Blocks can't be
+                    // reorganized below the base of the chain.
+                    let fork_height = chain_active
+                        .find_fork(&last)
+                        .unwrap_or_else(|| chain_active.base_height());
+                    events.push(Event::Reorg {
+                        fork_height,
+                        height: tip.height,
+                        hash,
+                    });
+                }
+            }
+            events.push(Event::NewTip {
+                height: tip.height,
+                hash,
+            });
+            events.push(Event::SyncProgress {
+                height: tip.height,
+                target_height: self.target_height(tip.height),
+            });
+            self.notified_tip = Some(hash);
+        }
+
+        for event in events {
+            self.events.emit(event);
+        }
+    }
+
+    /// Delay next connection to the address.
+    fn backoff(&mut self, addr: SocketAddr) {
+        let backoff = self.backoffs.entry(addr).or_insert(Backoff {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+
+        let delay = cmp::min(
+            INITIAL_RECONNECT_DELAY * 2u32.pow(cmp::min(backoff.failures, 16)),
+            MAX_RECONNECT_DELAY,
+        );
+        backoff.failures += 1;
+        backoff.retry_at = Instant::now() + delay;
+
+        debug!("Reconnect to {} after {} seconds.", addr, delay.as_secs());
+    }
+}
+
+impl<T, S, C> Future for PeerManager<T, S, C>
+where
+    T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    S: ChainStore,
+    C: Fn(PeerID, SocketAddr) -> ConnectFuture<T>,
+    Error: From<T::Error>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        // The interval wakes up this task periodically to retry connections and to check
+        // request timeouts.
+        while let Async::Ready(Some(_)) = self.interval.poll()? {}
+
+        self.connect_peers();
+        self.poll_connecting();
+        self.poll_peers();
+        self.notify_tip();
+        self.save_addrs();
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::WatchList;
+    use crate::test_helper::{
+        channel, create_signed_header, get_chain, get_test_genesis_block, get_test_headers,
+        TwoWayChannel,
+    };
+    use bitcoin_hashes::sha256d;
+    use tapyrus::network::address::Address;
+    use tapyrus::network::message_blockdata::{GetHeadersMessage, InvType, Inventory};
+    use tapyrus::{BitcoinHash, Network};
+    use tokio::prelude::future;
+
+    /// Connector which returns peers connected with the given channels in order. It fails when
+    /// all channels are used.
+    fn channel_connector(
+        channels: Vec<TwoWayChannel<RawMessage>>,
+        ids: Arc<Mutex<Vec<PeerID>>>,
+    ) -> impl Fn(PeerID, SocketAddr) -> ConnectFuture<TwoWayChannel<RawMessage>> {
+        let channels = Mutex::new(channels.into_iter().map(Some).collect::<Vec<_>>());
+        move |id, addr| {
+            ids.lock().unwrap().push(id);
+            let mut channels = channels.lock().unwrap();
+            match channels.iter_mut().find(|c| c.is_some()) {
+                Some(channel) => {
+                    let peer =
+                        Peer::new(id, channel.take().unwrap(), addr, Network::Regtest.magic());
+                    Box::new(future::ok(peer))
+                }
+                None => Box::new(future::err(Error::from(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionRefused,
+                )))),
+            }
+        }
+    }
+
+    /// Respond to a getheaders message with the headers. `expected_locator` is checked if it is
+    /// given.
+    fn respond_headers(
+        msg: Option<RawMessage>,
+        here: &mut TwoWayChannel<RawMessage>,
+        expected_locator: Option<Vec<sha256d::Hash>>,
+        headers: Vec<tapyrus::BlockHeader>,
+    ) {
+        if let Some(RawMessage {
+            payload: Message::Network(NetworkMessage::GetHeaders(getheaders_msg)),
+            ..
+        }) = msg
+        {
+            match getheaders_msg {
+                GetHeadersMessage {
+                    locator_hashes,
+                    stop_hash,
+                    ..
+                } => {
+                    if let Some(expected) = expected_locator {
+                        assert_eq!(locator_hashes, expected);
+                    }
+                    assert_eq!(stop_hash, sha256d::Hash::default());
+                }
+            }
+        } else {
+            assert!(false, "Peer should send getheaders message.");
+        }
+
+        let headers_message = RawMessage {
+            magic: Network::Regtest.magic(),
+            payload: NetworkMessage::Headers(headers).into(),
+        };
+        let _ = here.start_send(headers_message);
+    }
+
+    /// Build remote peer for testing block headers download.
+    /// Remote peer checks and responds messages from local peer.
+    ///
+    /// ## Situation
+    /// Local peer has only genesis block. Remote peer has 24 blocks includes genesis block.
+    ///
+    /// ## Flow
+    /// 1st message round trip, local peer send getheaders message and get 10 blocks from remote.
+    /// 2nd message round trip, local peer send getheaders message and get 10 blocks from remote.
+    /// 3rd message round trip, local peer send getheaders message and get 3 blocks from remote.
+    /// And finish sending getheaders message.
+    fn remote_peer(stream: TwoWayChannel<RawMessage>) -> impl Future<Item = (), Error = ()> {
+        stream
+            .into_future()
+            .and_then(|(msg, mut here)| {
+                // 1st message round trip.
+                let expected = vec![get_test_genesis_block().header.bitcoin_hash()];
+                respond_headers(msg, &mut here, Some(expected), get_test_headers(1, 10));
+                here.into_future()
+            })
+            .and_then(|(msg, mut here)| {
+                // 2nd message round trip.
+                let expected: Vec<sha256d::Hash> = get_test_headers(0, 11)
+                    .into_iter()
+                    .rev()
+                    .map(|v| v.bitcoin_hash())
+                    .collect();
+                respond_headers(msg, &mut here, Some(expected), get_test_headers(11, 10));
+                here.into_future()
+            })
+            .map(|(msg, mut here)| {
+                // 3rd message round trip.
+                respond_headers(msg, &mut here, None, get_test_headers(21, 3));
+            })
+            .map_err(|_| {})
+    }
+
+    /// Build remote peer which responds only one getheaders message. The connection is kept
+    /// until local peer closes it, and local peer must not send any more getheaders message.
+    fn one_shot_remote_peer(
+        stream: TwoWayChannel<RawMessage>,
+        headers: Vec<tapyrus::BlockHeader>,
+    ) -> impl Future<Item = (), Error = ()> {
+        stream
+            .into_future()
+            .and_then(move |(msg, mut here)| {
+                respond_headers(msg, &mut here, None, headers);
+                here.into_future()
+            })
+            .map(|(msg, _)| {
+                assert!(
+                    msg.is_none(),
+                    "Peer should not send getheaders message again."
+                );
+            })
+            .map_err(|_| {})
+    }
+
+    /// Build remote peer which closes the connection after responding one getheaders message.
+    fn closing_remote_peer(
+        stream: TwoWayChannel<RawMessage>,
+        headers: Vec<tapyrus::BlockHeader>,
+    ) -> impl Future<Item = (), Error = ()> {
+        stream
+            .into_future()
+            .map(move |(msg, mut here)| {
+                respond_headers(msg, &mut here, None, headers);
+            })
+            .map_err(|_| {})
+    }
+
+    /// Build remote peer which announces new blocks after responding to the first getheaders
+    /// message with `headers`.
+    fn announcing_remote_peer(
+        stream: TwoWayChannel<RawMessage>,
+        headers: Vec<tapyrus::BlockHeader>,
+        announcements: Vec<NetworkMessage>,
+        missing_headers: Vec<tapyrus::BlockHeader>,
+    ) -> impl Future<Item = (), Error = ()> {
+        stream
+            .into_future()
+            .and_then(move |(msg, mut here)| {
+                respond_headers(msg, &mut here, None, headers);
+                for announcement in announcements {
+                    let _ = here.start_send(RawMessage {
+                        magic: Network::Regtest.magic(),
+                        payload: announcement.into(),
+                    });
+                }
+                here.into_future()
+            })
+            .and_then(move |(msg, mut here)| {
+                // local peer requests the announced headers.
+                respond_headers(msg, &mut here, None, missing_headers);
+                here.into_future()
+            })
+            .map(|_| {})
+            .map_err(|_| {})
+    }
+
+    type TestChain = Chain<crate::chain::store::OnMemoryChainStore>;
+
+    /// Run PeerManager until `until` returns true.
+    fn run_manager<F>(
+        remotes: Vec<Box<dyn Future<Item = (), Error = ()>>>,
+        channels: Vec<TwoWayChannel<RawMessage>>,
+        addrs: Vec<SocketAddr>,
+        chain_state: Arc<Mutex<ChainState<crate::chain::store::OnMemoryChainStore>>>,
+        ids: Arc<Mutex<Vec<PeerID>>>,
+        until: F,
+    ) where
+        F: Fn(&TestChain) -> bool + 'static,
+    {
+        let future = future::lazy(move || {
+            for remote in remotes {
+                tokio::runtime::current_thread::spawn(remote);
+            }
+
+            let mut manager = PeerManager::new(
+                channel_connector(channels, ids),
+                addrs,
+                8,
+                BanList::new(),
+                chain_state.clone(),
+                TransactionDownload::Bloom(MerkleBlockDownload::new(
+                    Arc::new(Mutex::new(WatchList::new())),
+                    tokio::sync::mpsc::unbounded_channel().0,
+                )),
+                Arc::new(Mutex::new(TransactionBroadcast::new())),
+            );
+            manager.header_download = BlockHeaderDownload::with_max_headers_results(10);
+
+            future::poll_fn(move || {
+                if let Err(e) = manager.poll() {
+                    panic!("{:?}", e);
+                }
+
+                let chain_state = chain_state.lock().unwrap();
+                if until(chain_state.borrow_chain_active()) {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            })
+        });
+
+        tokio::runtime::current_thread::run(future);
+    }
+
+    #[test]
+    fn test_block_header_download() {
+        let (here, there) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+
+        run_manager(
+            vec![Box::new(remote_peer(here))],
+            vec![there],
+            vec!["0.0.0.0:1".parse().unwrap()],
+            chain_state.clone(),
+            ids.clone(),
+            |chain| chain.height() == 23,
+        );
+
+        let chain_state = chain_state.lock().unwrap();
+        assert_eq!(chain_state.borrow_chain_active().height(), 23);
+        assert_eq!(*ids.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_spread_header_requests() {
+        let (here1, there1) = channel::<RawMessage>();
+        let (here2, there2) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+
+        // Each remote peer responds only once, so that the download can not be finished
+        // unless the requests are sent to both peers.
+        run_manager(
+            vec![
+                Box::new(one_shot_remote_peer(here1, get_test_headers(1, 10))),
+                Box::new(one_shot_remote_peer(here2, get_test_headers(11, 3))),
+            ],
+            vec![there1, there2],
+            vec!["0.0.0.0:1".parse().unwrap(), "0.0.0.0:2".parse().unwrap()],
+            chain_state.clone(),
+            ids.clone(),
+            |chain| chain.height() == 13,
+        );
+
+        let chain_state = chain_state.lock().unwrap();
+        assert_eq!(chain_state.borrow_chain_active().height(), 13);
+
+        // each peer has unique id.
+        assert_eq!(*ids.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_reconnect_after_disconnection() {
+        let (here1, there1) = channel::<RawMessage>();
+        let (here2, there2) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+
+        // The first connection is closed after responding once. The second connection to the same
+        // address finishes the download.
+        let first = closing_remote_peer(here1, get_test_headers(1, 10));
+        let second = closing_remote_peer(here2, get_test_headers(11, 3));
+
+        run_manager(
+            vec![Box::new(first), Box::new(second)],
+            vec![there1, there2],
+            vec!["0.0.0.0:1".parse().unwrap()],
+            chain_state.clone(),
+            ids.clone(),
+            |chain| chain.height() == 13,
+        );
+
+        let chain_state = chain_state.lock().unwrap();
+        assert_eq!(chain_state.borrow_chain_active().height(), 13);
+        assert_eq!(*ids.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_follow_tip_by_inv() {
+        let (here, there) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+
+        let inv = Inventory {
+            inv_type: InvType::Block,
+            hash: get_test_headers(8, 1)[0].bitcoin_hash(),
+        };
+        let remote = announcing_remote_peer(
+            here,
+            get_test_headers(1, 5),
+            vec![NetworkMessage::Inv(vec![inv])],
+            get_test_headers(6, 3),
+        );
+
+        run_manager(
+            vec![Box::new(remote)],
+            vec![there],
+            vec!["0.0.0.0:1".parse().unwrap()],
+            chain_state.clone(),
+            ids,
+            |chain| chain.height() == 8,
+        );
+
+        let chain_state = chain_state.lock().unwrap();
+        assert_eq!(chain_state.borrow_chain_active().height(), 8);
+    }
+
+    #[test]
+    fn test_follow_tip_by_headers_announcement() {
+        let (here, there) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+
+        // header 6 is connected directly. header 8 doesn't connect, so local peer requests
+        // missing headers.
+        let remote = announcing_remote_peer(
+            here,
+            get_test_headers(1, 5),
+            vec![
+                NetworkMessage::Headers(get_test_headers(6, 1)),
+                NetworkMessage::Headers(get_test_headers(8, 1)),
+            ],
+            get_test_headers(7, 2),
+        );
+
+        run_manager(
+            vec![Box::new(remote)],
+            vec![there],
+            vec!["0.0.0.0:1".parse().unwrap()],
+            chain_state.clone(),
+            ids,
+            |chain| chain.height() == 8,
+        );
+
+        let chain_state = chain_state.lock().unwrap();
+        assert_eq!(chain_state.borrow_chain_active().height(), 8);
+    }
+
+    #[test]
+    fn test_answer_ping() {
+        let (here, there) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+        let received_pong = Arc::new(Mutex::new(false));
+        let received_pong_in_remote = received_pong.clone();
+
+        let remote = here
+            .into_future()
+            .and_then(|(msg, mut here)| {
+                respond_headers(msg, &mut here, None, vec![]);
+                let _ = here.start_send(RawMessage {
+                    magic: Network::Regtest.magic(),
+                    payload: NetworkMessage::Ping(42).into(),
+                });
+                here.into_future()
+            })
+            .map(move |(msg, _)| match msg {
+                Some(RawMessage {
+                    payload: Message::Network(NetworkMessage::Pong(nonce)),
+                    ..
+                }) => {
+                    assert_eq!(nonce, 42);
+                    *received_pong_in_remote.lock().unwrap() = true;
+                }
+                _ => assert!(false, "Peer should send pong message."),
+            })
+            .map_err(|_| {});
+
+        run_manager(
+            vec![Box::new(remote)],
+            vec![there],
+            vec!["0.0.0.0:1".parse().unwrap()],
+            chain_state,
+            ids,
+            move |_| *received_pong.lock().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_ban_misbehaving_peer() {
+        let (here1, there1) = channel::<RawMessage>();
+        let (here2, there2) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+
+        // The first peer sends a fork which has invalid proof at the height of its tip.
+        let mut invalid_headers = get_test_headers(1, 2);
+        let mut fork2 = create_signed_header(&invalid_headers[0], 1);
+        fork2.proof = invalid_headers[1].proof.clone();
+        invalid_headers.push(fork2);
+
+        let future = future::lazy(move || {
+            tokio::runtime::current_thread::spawn(one_shot_remote_peer(here1, invalid_headers));
+            tokio::runtime::current_thread::spawn(one_shot_remote_peer(
+                here2,
+                get_test_headers(1, 3),
+            ));
+
+            let mut manager = PeerManager::new(
+                channel_connector(vec![there1, there2], ids),
+                vec![
+                    "127.0.0.1:1".parse().unwrap(),
+                    "127.0.0.2:1".parse().unwrap(),
+                ],
+                8,
+                BanList::new(),
+                chain_state.clone(),
+                TransactionDownload::Bloom(MerkleBlockDownload::new(
+                    Arc::new(Mutex::new(WatchList::new())),
+                    tokio::sync::mpsc::unbounded_channel().0,
+                )),
+                Arc::new(Mutex::new(TransactionBroadcast::new())),
+            );
+            manager.header_download = BlockHeaderDownload::with_max_headers_results(10);
+
+            future::poll_fn(move || {
+                if let Err(e) = manager.poll() {
+                    panic!("{:?}", e);
+                }
+
+                if chain_state.lock().unwrap().borrow_chain_active().height() < 3 {
+                    return Ok(Async::NotReady);
+                }
+
+                assert!(manager.ban_list.is_banned(&"127.0.0.1".parse().unwrap()));
+                assert!(!manager.ban_list.is_banned(&"127.0.0.2".parse().unwrap()));
+
+                // banned address is not connected again.
+                manager.backoffs.clear();
+                manager.connect_peers();
+                assert!(manager.connecting.is_empty());
+                Ok(Async::Ready(()))
+            })
+        });
+
+        tokio::runtime::current_thread::run(future);
+    }
+
+    #[test]
+    fn test_emit_disconnection_without_locks() {
+        let (here, there) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let broadcast = Arc::new(Mutex::new(TransactionBroadcast::new()));
+        let events = EventBus::new();
+
+        // The subscriber reads the tip like SPV::tip, and records whether the locks were free.
+        let unlocked = Arc::new(Mutex::new(vec![]));
+        {
+            let chain_state = chain_state.clone();
+            let broadcast = broadcast.clone();
+            let unlocked = unlocked.clone();
+            events.subscribe_fn(move |event| {
+                if let Event::PeerDisconnected { .. } = event {
+                    let tip = chain_state
+                        .try_lock()
+                        .ok()
+                        .map(|chain_state| chain_state.borrow_chain_active().height());
+                    let free = tip.is_some() && broadcast.try_lock().is_ok();
+                    unlocked.lock().unwrap().push(free);
+                }
+            });
+        }
+
+        let disconnected = unlocked.clone();
+        let future = future::lazy(move || {
+            tokio::runtime::current_thread::spawn(closing_remote_peer(
+                here,
+                get_test_headers(1, 3),
+            ));
+
+            let mut manager = PeerManager::new(
+                channel_connector(vec![there], Arc::new(Mutex::new(vec![]))),
+                vec!["127.0.0.1:1".parse().unwrap()],
+                8,
+                BanList::new(),
+                chain_state,
+                TransactionDownload::Bloom(MerkleBlockDownload::new(
+                    Arc::new(Mutex::new(WatchList::new())),
+                    tokio::sync::mpsc::unbounded_channel().0,
+                )),
+                broadcast,
+            );
+            manager.set_event_bus(events);
+
+            future::poll_fn(move || {
+                if let Err(e) = manager.poll() {
+                    panic!("{:?}", e);
+                }
+                if disconnected.lock().unwrap().is_empty() {
+                    Ok(Async::NotReady)
+                } else {
+                    Ok(Async::Ready(()))
+                }
+            })
+        });
+
+        tokio::runtime::current_thread::run(future);
+        assert_eq!(*unlocked.lock().unwrap(), vec![true]);
+    }
+
+    #[test]
+    fn test_connect_to_learned_address() {
+        let (here1, there1) = channel::<RawMessage>();
+        let (here2, there2) = channel::<RawMessage>();
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let ids = Arc::new(Mutex::new(vec![]));
+        let learned: SocketAddr = "127.0.0.2:2357".parse().unwrap();
+
+        // The first peer announces an address after responding to getheaders.
+        let remote = here1
+            .into_future()
+            .and_then(move |(msg, mut here)| {
+                respond_headers(msg, &mut here, None, vec![]);
+                let _ = here.start_send(RawMessage {
+                    magic: Network::Regtest.magic(),
+                    payload: NetworkMessage::Addr(vec![(1, Address::new(&learned, 0))]).into(),
+                });
+                here.into_future()
+            })
+            .map(|_| {})
+            .map_err(|_| {});
+
+        let future = future::lazy(move || {
+            tokio::runtime::current_thread::spawn(remote);
+
+            let mut manager = PeerManager::new(
+                channel_connector(vec![there1, there2], ids),
+                vec!["127.0.0.1:2357".parse().unwrap()],
+                8,
+                BanList::new(),
+                chain_state,
+                TransactionDownload::Bloom(MerkleBlockDownload::new(
+                    Arc::new(Mutex::new(WatchList::new())),
+                    tokio::sync::mpsc::unbounded_channel().0,
+                )),
+                Arc::new(Mutex::new(TransactionBroadcast::new())),
+            );
+
+            future::poll_fn(move || {
+                let _ = &here2;
+                if let Err(e) = manager.poll() {
+                    panic!("{:?}", e);
+                }
+
+                // the learned address is connected and moved to the tried bucket.
+                if manager.addr_manager.tried_len() < 2 {
+                    return Ok(Async::NotReady);
+                }
+                assert_eq!(manager.addr_manager.len(), 2);
+                assert_eq!(manager.connecting.len() + manager.peers.len(), 2);
+                Ok(Async::Ready(()))
+            })
+        });
+
+        tokio::runtime::current_thread::run(future);
+    }
+
+    #[test]
+    fn test_notify_tip() {
+        let chain_state = Arc::new(Mutex::new(ChainState::new(get_chain())));
+        let mut manager = PeerManager::new(
+            channel_connector(vec![], Arc::new(Mutex::new(vec![]))),
+            vec![],
+            8,
+            BanList::new(),
+            chain_state.clone(),
+            TransactionDownload::Bloom(MerkleBlockDownload::new(
+                Arc::new(Mutex::new(WatchList::new())),
+                tokio::sync::mpsc::unbounded_channel().0,
+            )),
+            Arc::new(Mutex::new(TransactionBroadcast::new())),
+        );
+        let events = EventBus::new();
+        let receiver = events.subscribe();
+        manager.set_event_bus(events);
+
+        let genesis = get_test_genesis_block().header;
+        let block1 = create_signed_header(&genesis, 0);
+        let fork1 = create_signed_header(&genesis, 1);
+        let fork2 = create_signed_header(&fork1, 1);
+
+        manager.notify_tip();
+        chain_state
+            .lock()
+            .unwrap()
+            .borrow_mut_chain_active()
+            .connect_block_header(block1.clone())
+            .unwrap();
+        manager.notify_tip();
+        // nothing is emitted while the tip is not changed.
+        manager.notify_tip();
+        {
+            let mut chain_state = chain_state.lock().unwrap();
+            let chain_active = chain_state.borrow_mut_chain_active();
+            chain_active.connect_block_header(fork1).unwrap();
+            chain_active.connect_block_header(fork2.clone()).unwrap();
+        }
+        manager.notify_tip();
+        drop(manager);
+
+        let events: Vec<Event> = receiver.collect().wait().unwrap();
+        assert_eq!(
+            events,
+            vec![
+                Event::NewTip {
+                    height: 0,
+                    hash: genesis.bitcoin_hash(),
+                },
+                Event::SyncProgress {
+                    height: 0,
+                    target_height: 0,
+                },
+                Event::NewTip {
+                    height: 1,
+                    hash: block1.bitcoin_hash(),
+                },
+                Event::SyncProgress {
+                    height: 1,
+                    target_height: 1,
+                },
+                Event::Reorg {
+                    fork_height: 0,
+                    height: 2,
+                    hash: fork2.bitcoin_hash(),
+                },
+                Event::NewTip {
+                    height: 2,
+                    hash: fork2.bitcoin_hash(),
+                },
+                Event::SyncProgress {
+                    height: 2,
+                    target_height: 2,
+                },
+            ]
+        );
+    }
+}
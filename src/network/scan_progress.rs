@@ -0,0 +1,116 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::chain::{Chain, ChainStore};
+use crate::network::FilteredBlock;
+use bitcoin_hashes::sha256d;
+use tapyrus::BitcoinHash;
+
+/// Progress of scanning blocks which is sent to the receiver of found transactions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScanEvent {
+    /// Transactions which match the watch list were found in the block.
+    Found(FilteredBlock),
+    /// Blocks above the height were removed from the active chain by reorganization. Blocks in
+    /// the new active chain are scanned again from the height.
+    Rollback(i32),
+}
+
+/// The last block which has been scanned for transactions of the watch list.
+///
+/// Since the first block of the chain can't be reorganized, scanning starts from it. The first
+/// block is the genesis block or a trusted checkpoint.
+pub struct ScanProgress {
+    height: i32,
+    /// Hash of the block at `height`. None means the first block of the chain.
+    hash: Option<sha256d::Hash>,
+}
+
+impl ScanProgress {
+    pub fn new() -> ScanProgress {
+        ScanProgress {
+            height: 0,
+            hash: None,
+        }
+    }
+
+    /// Return height of the last scanned block.
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// Record that blocks until `hash` at `height` have been scanned.
+    pub fn advance(&mut self, height: i32, hash: sha256d::Hash) {
+        self.height = height;
+        self.hash = Some(hash);
+    }
+
+    /// If the last scanned block was removed from the active chain, rewind to the fork point and
+    /// return its height. When the fork point is unknown, scanning restarts from the first block
+    /// of the chain.
+    pub fn rewind<S: ChainStore>(&mut self, chain_active: &Chain<S>) -> Option<i32> {
+        let hash = match self.hash {
+            Some(hash) => hash,
+            None => {
+                self.height = chain_active.base_height();
+                return None;
+            }
+        };
+        if chain_active.contains(&hash) {
+            return None;
+        }
+
+        let fork_height = chain_active
+            .find_fork(&hash)
+            .unwrap_or_else(|| chain_active.base_height());
+        info!(
+            "Scanned block {} was removed from the active chain. Rescan from height {}.",
+            hash, fork_height
+        );
+        self.height = fork_height;
+        self.hash = chain_active
+            .get(fork_height)
+            .map(|index| index.header.bitcoin_hash());
+        Some(fork_height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{create_signed_header, get_chain, get_chain_from};
+
+    #[test]
+    fn test_rewind_scan_progress() {
+        let mut chain_active = get_chain();
+        let genesis = chain_active.tip().header;
+        let block1 = create_signed_header(&genesis, 0);
+        let block2 = create_signed_header(&block1, 0);
+        chain_active.connect_block_header(block1.clone()).unwrap();
+        chain_active.connect_block_header(block2.clone()).unwrap();
+
+        let mut progress = ScanProgress::new();
+        assert_eq!(progress.rewind(&chain_active), None);
+        progress.advance(2, block2.bitcoin_hash());
+        assert_eq!(progress.rewind(&chain_active), None);
+
+        // block 2 is replaced by a longer branch from block 1.
+        let fork2 = create_signed_header(&block1, 1);
+        let fork3 = create_signed_header(&fork2, 1);
+        chain_active.connect_block_header(fork2).unwrap();
+        chain_active.connect_block_header(fork3).unwrap();
+
+        assert_eq!(progress.rewind(&chain_active), Some(1));
+        assert_eq!(progress.height(), 1);
+        assert_eq!(progress.rewind(&chain_active), None);
+    }
+
+    #[test]
+    fn test_scan_from_checkpoint() {
+        let chain_active = get_chain_from(10);
+        let mut progress = ScanProgress::new();
+        assert_eq!(progress.rewind(&chain_active), None);
+        assert_eq!(progress.height(), 10);
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::network::message::{RawMessage, RejectMessage};
+use crate::network::{Peer, PeerID};
+use bitcoin_hashes::sha256d;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tapyrus::blockdata::transaction::Transaction;
+use tapyrus::network::message::NetworkMessage;
+use tapyrus::network::message_blockdata::{InvType, Inventory};
+use tokio::prelude::{Sink, Stream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Transactions which are not confirmed in this duration are announced to all peers again.
+pub const REBROADCAST_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Reason of rejection which is sent in `reject` message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+    /// The transaction couldn't be decoded.
+    Malformed,
+    /// The transaction is invalid.
+    Invalid,
+    /// The version of the transaction is obsolete.
+    Obsolete,
+    /// The transaction is already known or conflicts with another transaction.
+    Duplicate,
+    /// The transaction is not standard.
+    NonStandard,
+    /// The transaction has dust outputs.
+    Dust,
+    /// The fee is too low.
+    InsufficientFee,
+    /// The transaction conflicts with a checkpoint.
+    Checkpoint,
+    /// Unknown reject code.
+    Unknown(u8),
+}
+
+impl From<u8> for RejectReason {
+    fn from(ccode: u8) -> RejectReason {
+        match ccode {
+            0x01 => RejectReason::Malformed,
+            0x10 => RejectReason::Invalid,
+            0x11 => RejectReason::Obsolete,
+            0x12 => RejectReason::Duplicate,
+            0x40 => RejectReason::NonStandard,
+            0x41 => RejectReason::Dust,
+            0x42 => RejectReason::InsufficientFee,
+            0x43 => RejectReason::Checkpoint,
+            ccode => RejectReason::Unknown(ccode),
+        }
+    }
+}
+
+/// Errors in broadcasting a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub enum BroadcastError {
+    /// The majority of peers which received the transaction rejected it. The last rejection is
+    /// reported.
+    Rejected {
+        /// The peer which sent `reject` message.
+        peer_id: PeerID,
+        /// Reason code of rejection.
+        reason: RejectReason,
+        /// Human readable reason which the peer sent.
+        message: String,
+    },
+    /// Broadcast was abandoned before the transaction was confirmed.
+    Cancelled,
+}
+
+/// Transaction which is being broadcast.
+struct PendingTransaction {
+    tx: Transaction,
+    sender: UnboundedSender<Result<(), BroadcastError>>,
+    /// Peers which the transaction was announced to since the last rebroadcast.
+    announced: HashSet<PeerID>,
+    /// Peers which requested the transaction with getdata.
+    requested: HashSet<PeerID>,
+    /// Peers which rejected the transaction and their reasons.
+    rejected: HashMap<PeerID, BroadcastError>,
+    announced_at: Instant,
+}
+
+impl PendingTransaction {
+    /// Return whether the majority of peers which received the transaction rejected it.
+    fn is_rejected(&self) -> bool {
+        let received = self
+            .requested
+            .iter()
+            .filter(|id| !self.rejected.contains_key(id))
+            .count()
+            + self.rejected.len();
+        self.rejected.len() * 2 > received
+    }
+}
+
+/// Broadcast transactions until they are confirmed.
+///
+/// Each transaction is announced to peers with inv message, and it is sent to peers which request
+/// it with getdata. A transaction is announced to all connected peers again every
+/// REBROADCAST_INTERVAL until it is found in a block of the active chain. The result is sent to
+/// the receiver which is returned by `add`: success when the transaction is confirmed, or the
+/// reason when the majority of peers which received it reject it. Other peers may relay the
+/// transaction even if some peers reject it, so it is broadcast until then.
+#[derive(Default)]
+pub struct TransactionBroadcast {
+    pending: HashMap<sha256d::Hash, PendingTransaction>,
+}
+
+impl TransactionBroadcast {
+    pub fn new() -> TransactionBroadcast {
+        TransactionBroadcast::default()
+    }
+
+    /// Start broadcasting the transaction. Return receiver of the result which is sent only once.
+    pub fn add(&mut self, tx: Transaction) -> UnboundedReceiver<Result<(), BroadcastError>> {
+        let (sender, receiver) = unbounded_channel();
+        let txid = tx.txid();
+        info!("Broadcast transaction {}.", txid);
+        self.pending.insert(
+            txid,
+            PendingTransaction {
+                tx,
+                sender,
+                announced: HashSet::new(),
+                requested: HashSet::new(),
+                rejected: HashMap::new(),
+                announced_at: Instant::now(),
+            },
+        );
+        receiver
+    }
+
+    /// Return the number of peers which requested the transaction. None if the transaction is
+    /// not being broadcast.
+    pub fn requested_peers(&self, txid: &sha256d::Hash) -> Option<usize> {
+        self.pending
+            .get(txid)
+            .map(|pending| pending.requested.len())
+    }
+
+    /// Announce transactions to peers which they have not been announced to.
+    pub fn announce<T>(&mut self, peers: &mut HashMap<PeerID, Peer<T>>)
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        let now = Instant::now();
+        for (txid, pending) in self.pending.iter_mut() {
+            if now.duration_since(pending.announced_at) >= REBROADCAST_INTERVAL {
+                debug!("Rebroadcast transaction {}.", txid);
+                pending.announced.clear();
+                pending.announced_at = now;
+            }
+
+            for (id, peer) in peers.iter_mut() {
+                if pending.announced.insert(*id) {
+                    peer.start_send(NetworkMessage::Inv(vec![Inventory {
+                        inv_type: InvType::Transaction,
+                        hash: *txid,
+                    }]));
+                }
+            }
+        }
+    }
+
+    /// Send transactions which the peer requested.
+    pub fn on_getdata<T>(&mut self, peer: &mut Peer<T>, inventory: &[Inventory])
+    where
+        T: Sink<SinkItem = RawMessage> + Stream<Item = RawMessage>,
+    {
+        for inv in inventory {
+            if inv.inv_type != InvType::Transaction {
+                continue;
+            }
+            if let Some(pending) = self.pending.get_mut(&inv.hash) {
+                debug!("Peer {} requested transaction {}.", peer.id, inv.hash);
+                pending.requested.insert(peer.id);
+                peer.start_send(NetworkMessage::Tx(pending.tx.clone()));
+            }
+        }
+    }
+
+    /// Record that the peer rejected the transaction. Broadcast is stopped and the reason is
+    /// reported when the majority of peers which received the transaction rejected it. Duplicate
+    /// transactions are ignored since the peer already has it.
+    pub fn on_reject(&mut self, peer_id: PeerID, reject: RejectMessage) {
+        if reject.message != "tx" {
+            return;
+        }
+        let txid = match reject.hash {
+            Some(txid) => txid,
+            None => return,
+        };
+        let pending = match self.pending.get_mut(&txid) {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        let reason = RejectReason::from(reject.ccode);
+        if reason == RejectReason::Duplicate {
+            debug!("Peer {} already has transaction {}.", peer_id, txid);
+            return;
+        }
+        warn!(
+            "Peer {} rejected transaction {}: {}",
+            peer_id, txid, reject.reason
+        );
+        pending.rejected.insert(
+            peer_id,
+            BroadcastError::Rejected {
+                peer_id,
+                reason,
+                message: reject.reason,
+            },
+        );
+        if !pending.is_rejected() {
+            return;
+        }
+
+        let mut pending = self.pending.remove(&txid).unwrap();
+        warn!("Stop broadcasting rejected transaction {}.", txid);
+        let error = pending.rejected.remove(&peer_id).unwrap();
+        let _ = pending.sender.try_send(Err(error));
+    }
+
+    /// Stop broadcasting the transaction which was found in a block.
+    pub fn on_confirmed(&mut self, txid: &sha256d::Hash) {
+        if let Some(mut pending) = self.pending.remove(txid) {
+            info!("Broadcast transaction {} was confirmed.", txid);
+            let _ = pending.sender.try_send(Ok(()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Message;
+    use crate::test_helper::{channel, get_test_transactions, TwoWayChannel};
+    use tapyrus::Network;
+    use tokio::prelude::Future;
+
+    fn next_message<S: Stream<Item = RawMessage>>(stream: S) -> (Message, S) {
+        match stream.into_future().wait() {
+            Ok((Some(message), stream)) => (message.payload, stream),
+            _ => panic!("Peer should send message."),
+        }
+    }
+
+    fn inv(txid: sha256d::Hash) -> Message {
+        Message::Network(NetworkMessage::Inv(vec![Inventory {
+            inv_type: InvType::Transaction,
+            hash: txid,
+        }]))
+    }
+
+    fn get_peers(
+        count: u64,
+    ) -> (
+        HashMap<PeerID, Peer<TwoWayChannel<RawMessage>>>,
+        Vec<TwoWayChannel<RawMessage>>,
+    ) {
+        let mut peers = HashMap::new();
+        let mut remotes = vec![];
+        for id in 1..=count {
+            let (here, there) = channel::<RawMessage>();
+            peers.insert(
+                id,
+                Peer::new(
+                    id,
+                    there,
+                    "0.0.0.0:0".parse().unwrap(),
+                    Network::Regtest.magic(),
+                ),
+            );
+            remotes.push(here);
+        }
+        (peers, remotes)
+    }
+
+    #[test]
+    fn test_broadcast_transaction() {
+        let (mut peers, mut remotes) = get_peers(2);
+        let tx = get_test_transactions(1).pop().unwrap();
+        let txid = tx.txid();
+
+        let mut broadcast = TransactionBroadcast::new();
+        let result = broadcast.add(tx.clone());
+        broadcast.announce(&mut peers);
+        let here2 = remotes.pop().unwrap();
+        let here1 = remotes.pop().unwrap();
+        let (message, here1) = next_message(here1);
+        assert_eq!(message, inv(txid));
+        let (message, _here2) = next_message(here2);
+        assert_eq!(message, inv(txid));
+
+        // transaction is not announced again until REBROADCAST_INTERVAL.
+        broadcast.announce(&mut peers);
+        broadcast.pending.get_mut(&txid).unwrap().announced_at -= REBROADCAST_INTERVAL;
+        broadcast.announce(&mut peers);
+        let (message, here1) = next_message(here1);
+        assert_eq!(message, inv(txid));
+
+        let getdata = [Inventory {
+            inv_type: InvType::Transaction,
+            hash: txid,
+        }];
+        broadcast.on_getdata(peers.get_mut(&1).unwrap(), &getdata);
+        let (message, _here1) = next_message(here1);
+        assert_eq!(message, Message::Network(NetworkMessage::Tx(tx)));
+        assert_eq!(broadcast.requested_peers(&txid), Some(1));
+
+        broadcast.on_confirmed(&txid);
+        let (result, _) = result.into_future().wait().ok().unwrap();
+        assert_eq!(result, Some(Ok(())));
+        assert_eq!(broadcast.requested_peers(&txid), None);
+    }
+
+    #[test]
+    fn test_reject_transaction() {
+        let tx = get_test_transactions(1).pop().unwrap();
+        let txid = tx.txid();
+        let mut broadcast = TransactionBroadcast::new();
+        let result = broadcast.add(tx);
+
+        // rejection of other messages is ignored.
+        let mut reject = RejectMessage {
+            message: "block".to_string(),
+            ccode: 0x10,
+            reason: "invalid".to_string(),
+            hash: Some(txid),
+        };
+        broadcast.on_reject(2, reject.clone());
+        assert_eq!(broadcast.requested_peers(&txid), Some(0));
+
+        reject.message = "tx".to_string();
+        reject.ccode = 0x42;
+        reject.reason = "min relay fee not met".to_string();
+        broadcast.on_reject(2, reject);
+        assert_eq!(
+            result.into_future().wait().ok().unwrap().0.unwrap(),
+            Err(BroadcastError::Rejected {
+                peer_id: 2,
+                reason: RejectReason::InsufficientFee,
+                message: "min relay fee not met".to_string(),
+            })
+        );
+        assert_eq!(broadcast.requested_peers(&txid), None);
+    }
+
+    #[test]
+    fn test_relay_transaction_rejected_by_some_peers() {
+        let (mut peers, _remotes) = get_peers(3);
+        let tx = get_test_transactions(1).pop().unwrap();
+        let txid = tx.txid();
+        let mut broadcast = TransactionBroadcast::new();
+        let result = broadcast.add(tx);
+
+        let getdata = [Inventory {
+            inv_type: InvType::Transaction,
+            hash: txid,
+        }];
+        broadcast.on_getdata(peers.get_mut(&1).unwrap(), &getdata);
+        broadcast.on_getdata(peers.get_mut(&2).unwrap(), &getdata);
+
+        // peer 1 rejects, but peer 2 may relay the transaction.
+        let reject = RejectMessage {
+            message: "tx".to_string(),
+            ccode: 0x40,
+            reason: "non-mandatory-script-verify-flag".to_string(),
+            hash: Some(txid),
+        };
+        broadcast.on_reject(1, reject.clone());
+        // peer 3 already has the transaction.
+        broadcast.on_reject(
+            3,
+            RejectMessage {
+                ccode: 0x12,
+                reason: "txn-already-known".to_string(),
+                ..reject.clone()
+            },
+        );
+        assert_eq!(broadcast.requested_peers(&txid), Some(2));
+
+        broadcast.on_confirmed(&txid);
+        let (result, _) = result.into_future().wait().ok().unwrap();
+        assert_eq!(result, Some(Ok(())));
+
+        // rejection by all peers which requested the transaction stops broadcast.
+        let tx = get_test_transactions(2).pop().unwrap();
+        let txid = tx.txid();
+        let result = broadcast.add(tx);
+        let getdata = [Inventory {
+            inv_type: InvType::Transaction,
+            hash: txid,
+        }];
+        broadcast.on_getdata(peers.get_mut(&1).unwrap(), &getdata);
+        broadcast.on_getdata(peers.get_mut(&2).unwrap(), &getdata);
+        let reject = RejectMessage {
+            hash: Some(txid),
+            ..reject
+        };
+        broadcast.on_reject(1, reject.clone());
+        assert_eq!(broadcast.requested_peers(&txid), Some(2));
+        broadcast.on_reject(2, reject);
+        assert_eq!(
+            result.into_future().wait().ok().unwrap().0.unwrap(),
+            Err(BroadcastError::Rejected {
+                peer_id: 2,
+                reason: RejectReason::NonStandard,
+                message: "non-mandatory-script-verify-flag".to_string(),
+            })
+        );
+        assert_eq!(broadcast.requested_peers(&txid), None);
+    }
+}
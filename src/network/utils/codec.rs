@@ -3,18 +3,29 @@
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
 use super::bytes::BytesMut;
+use crate::network::message::{Message, RawMessage};
+use bitcoin_hashes::{sha256d, Hash};
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::{io, io::ErrorKind};
+use std::{io, io::ErrorKind, io::Write};
 use tapyrus::{
-    consensus::{deserialize_partial, encode, Encodable},
+    consensus::{deserialize_partial, encode},
     network::message::RawNetworkMessage,
 };
 use tokio::codec::{Decoder, Encoder};
 
+/// The maximum length of payload in a message.
+pub const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4 * 1000 * 1000;
+
+/// Size of message header. magic(4bytes) + command string(12bytes) + length(4bytes) +
+/// checksum(4bytes)
+const MESSAGE_HEADER_SIZE: usize = 24;
+
 #[derive(Debug)]
 pub enum Error {
     Encode(encode::Error),
     Io(io::Error),
+    /// The length of payload in message header is over MAX_PROTOCOL_MESSAGE_LENGTH.
+    OversizedMessage(usize),
 }
 
 impl std::convert::From<std::io::Error> for Error {
@@ -33,15 +44,72 @@ impl NetworkMessagesCodec {
     }
 }
 
+impl NetworkMessagesCodec {
+    /// Decode message which is not supported by rust-tapyrus.
+    fn decode_extension(
+        &mut self,
+        src: &mut bytes::BytesMut,
+        cmd: &str,
+        payload_size: usize,
+    ) -> Result<Option<RawMessage>, Error> {
+        if src.len() < MESSAGE_HEADER_SIZE + payload_size {
+            return Ok(None);
+        }
+
+        let magic = {
+            let mut decoder = io::Cursor::new(&src[0..4]);
+            ReadBytesExt::read_u32::<LittleEndian>(&mut decoder)?
+        };
+
+        let payload = &src[MESSAGE_HEADER_SIZE..MESSAGE_HEADER_SIZE + payload_size];
+        let checksum = sha256d::Hash::hash(payload);
+        if checksum[0..4] != src[20..24] {
+            let mut expected = [0u8; 4];
+            let mut actual = [0u8; 4];
+            expected.copy_from_slice(&checksum[0..4]);
+            actual.copy_from_slice(&src[20..24]);
+            return Err(Error::Encode(encode::Error::InvalidChecksum {
+                expected,
+                actual,
+            }));
+        }
+
+        let payload = Message::decode(cmd, payload).map_err(Error::Encode)?;
+        src.advance(MESSAGE_HEADER_SIZE + payload_size);
+        Ok(Some(RawMessage { magic, payload }))
+    }
+}
+
 impl Decoder for NetworkMessagesCodec {
-    type Item = RawNetworkMessage;
+    type Item = RawMessage;
     type Error = Error;
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<RawNetworkMessage>, Error> {
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<RawMessage>, Error> {
+        if src.len() >= MESSAGE_HEADER_SIZE {
+            let payload_size = {
+                let mut decoder = io::Cursor::new(&src[16..20]);
+                ReadBytesExt::read_u32::<LittleEndian>(&mut decoder)? as usize
+            };
+
+            // Reject oversized message before whole message is buffered.
+            if payload_size > MAX_PROTOCOL_MESSAGE_LENGTH {
+                return Err(Error::OversizedMessage(payload_size));
+            }
+
+            let cmd: String = src[4..16]
+                .iter()
+                .take_while(|b| **b != 0)
+                .map(|b| *b as char)
+                .collect();
+            if Message::is_extension_command(&cmd) {
+                return self.decode_extension(src, &cmd, payload_size);
+            }
+        }
+
         match deserialize_partial::<RawNetworkMessage>(&src) {
             Ok((raw_msg, consumed)) => {
                 src.advance(consumed);
-                Ok(Some(raw_msg))
+                Ok(Some(RawMessage::from(raw_msg)))
             }
             Err(encode::Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
             Err(encode::Error::UnrecognizedNetworkCommand(cmd)) => {
@@ -65,25 +133,28 @@ impl Decoder for NetworkMessagesCodec {
 }
 
 impl Encoder for NetworkMessagesCodec {
-    type Item = RawNetworkMessage;
+    type Item = RawMessage;
     type Error = io::Error;
 
     fn encode(
         &mut self,
-        message: RawNetworkMessage,
+        message: RawMessage,
         buf: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
-        let mut buf = BytesMut::new(buf);
+        let bytes = message
+            .into_bytes()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
 
-        message.consensus_encode(&mut buf).unwrap();
-        Ok(())
+        buf.reserve(bytes.len());
+        let mut buf = BytesMut::new(buf);
+        buf.write_all(&bytes)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::network::peer::version_message;
+    use crate::network::peer::{version_message, BITCOIN_PROTOCOL_VERSION};
     use bytes::BufMut;
     use tapyrus::network::constants::Network;
     use tapyrus::network::message::NetworkMessage;
@@ -108,8 +179,8 @@ mod tests {
         let mut buf = bytes::BytesMut::with_capacity(1024);
         buf.put_slice(&data);
 
-        if let Ok(Some(RawNetworkMessage {
-            payload: NetworkMessage::Version(msg),
+        if let Ok(Some(RawMessage {
+            payload: Message::Network(NetworkMessage::Version(msg)),
             ..
         })) = codec.decode(&mut buf)
         {
@@ -144,6 +215,53 @@ mod tests {
         }
     }
 
+    #[test]
+    fn decode_extension_message() {
+        let message = RawMessage {
+            magic: Network::Regtest.magic(),
+            payload: Message::FilterAdd(vec![1, 2, 3]),
+        };
+        let data = message.clone().into_bytes().unwrap();
+
+        let mut codec = NetworkMessagesCodec::new();
+        let mut buf = bytes::BytesMut::with_capacity(1024);
+
+        // incomplete message.
+        buf.put_slice(&data[..data.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.put_slice(&data[data.len() - 1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(message));
+        assert_eq!(buf.len(), 0);
+
+        // broken checksum.
+        let mut data = data;
+        data[20] ^= 0xff;
+        buf.put_slice(&data);
+        match codec.decode(&mut buf) {
+            Err(Error::Encode(encode::Error::InvalidChecksum { .. })) => {}
+            _ => assert!(false, "decode should fail"),
+        }
+    }
+
+    #[test]
+    fn decode_oversized_message() {
+        // version message header which has 0x00ffffff bytes payload.
+        let data: [u8; 24] = [
+            0x0b, 0x11, 0x09, 0x07, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0xff, 0xff, 0x00, 0x3e, 0x1d, 0xe1, 0x69,
+        ];
+
+        let mut codec = NetworkMessagesCodec::new();
+        let mut buf = bytes::BytesMut::with_capacity(1024);
+        buf.put_slice(&data);
+
+        match codec.decode(&mut buf) {
+            Err(Error::OversizedMessage(size)) => assert_eq!(size, 0x00ffffff),
+            _ => assert!(false, "decode should fail"),
+        }
+    }
+
     #[test]
     fn decode_incompleteness_data_test() {
         let data: [u8; 24] = [
@@ -161,9 +279,10 @@ mod tests {
 
     #[test]
     fn encode_test() {
-        let msg = RawNetworkMessage {
+        let msg = RawMessage {
             magic: Network::Regtest.magic(),
-            payload: NetworkMessage::Version(version_message()),
+            payload: NetworkMessage::Version(version_message(BITCOIN_PROTOCOL_VERSION.version, 0))
+                .into(),
         };
 
         let mut codec = NetworkMessagesCodec::new();
@@ -0,0 +1,206 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::script::{colored_script, split_colored_script, ColorIdentifier};
+use tapyrus::blockdata::script::{Instruction, Script};
+use tapyrus::blockdata::transaction::{OutPoint, Transaction};
+use tapyrus::consensus::serialize;
+
+/// Scripts and outpoints which the SPV node is interested in.
+///
+/// Watched items are converted into data elements of bloom filter. A script is converted into
+/// its data pushes, because peers test each data push in output scripts against the filter. An
+/// outpoint is converted into its serialization, so that transactions spending it are matched.
+///
+/// Colored outputs which pay to a watched script are also matched. Since a colored script has the
+/// same data pushes as the script and the color identifier, bloom filters match them without
+/// extra elements. Colors are watched to build colored scripts for compact block filters.
+///
+/// Items are only appended, so that peers can be updated with filteradd message. `clear` starts
+/// new generation and peers have to reload the filter.
+#[derive(Debug, Default)]
+pub struct WatchList {
+    scripts: Vec<Script>,
+    outpoints: Vec<OutPoint>,
+    colors: Vec<ColorIdentifier>,
+    elements: Vec<Vec<u8>>,
+    generation: u64,
+}
+
+impl WatchList {
+    /// Create empty watch list.
+    pub fn new() -> WatchList {
+        WatchList::default()
+    }
+
+    /// Watch transactions which have an output paying to the script.
+    pub fn add_script(&mut self, script: Script) {
+        if self.scripts.contains(&script) {
+            return;
+        }
+
+        for instruction in script.iter(false) {
+            if let Instruction::PushBytes(data) = instruction {
+                if !data.is_empty() && !self.elements.iter().any(|e| &e[..] == data) {
+                    self.elements.push(data.to_vec());
+                }
+            }
+        }
+        self.scripts.push(script);
+    }
+
+    /// Watch transactions which spend the outpoint.
+    pub fn add_outpoint(&mut self, outpoint: OutPoint) {
+        if self.outpoints.contains(&outpoint) {
+            return;
+        }
+
+        self.elements.push(serialize(&outpoint));
+        self.outpoints.push(outpoint);
+    }
+
+    /// Watch colored outputs of the color in compact block filters.
+    pub fn add_color(&mut self, color_id: ColorIdentifier) {
+        if !self.colors.contains(&color_id) {
+            self.colors.push(color_id);
+        }
+    }
+
+    /// Remove all watched items.
+    pub fn clear(&mut self) {
+        self.scripts.clear();
+        self.outpoints.clear();
+        self.colors.clear();
+        self.elements.clear();
+        self.generation += 1;
+    }
+
+    /// Return true if nothing is watched.
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Return watched scripts and their colored scripts of watched colors.
+    pub fn scripts(&self) -> Vec<Script> {
+        let mut scripts = self.scripts.clone();
+        for color_id in &self.colors {
+            scripts.extend(
+                self.scripts
+                    .iter()
+                    .map(|script| colored_script(color_id, script)),
+            );
+        }
+        scripts
+    }
+
+    /// Return data elements of bloom filter.
+    pub fn elements(&self) -> &[Vec<u8>] {
+        &self.elements
+    }
+
+    /// Return generation which is incremented when watched items are removed.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Return true if the script or the script without color is watched.
+    pub fn contains_script(&self, script: &Script) -> bool {
+        self.scripts.contains(script)
+            || split_colored_script(script).map_or(false, |(_, s)| self.scripts.contains(&s))
+    }
+
+    /// Return true if the transaction pays to watched script or spends watched outpoint.
+    pub fn matches(&self, tx: &Transaction) -> bool {
+        tx.output
+            .iter()
+            .any(|output| self.contains_script(&output.script_pubkey))
+            || tx
+                .input
+                .iter()
+                .any(|input| self.outpoints.contains(&input.previous_output))
+    }
+
+    /// Return transactions which match in order. Outputs of matched transactions which pay to
+    /// watched scripts are watched, so that transactions spending them are also matched.
+    pub fn filter_transactions(&mut self, transactions: &[Transaction]) -> Vec<Transaction> {
+        let mut matched = vec![];
+        for tx in transactions {
+            if !self.matches(tx) {
+                continue;
+            }
+
+            // Inputs refer to malleability-fixed txid in Tapyrus.
+            let txid = tx.malfix_txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                if self.contains_script(&output.script_pubkey) {
+                    self.add_outpoint(OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    });
+                }
+            }
+            matched.push(tx.clone());
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+
+    #[test]
+    fn test_watch_list() {
+        let coinbase = get_test_genesis_block().txdata[0].clone();
+        let script = coinbase.output[0].script_pubkey.clone();
+
+        let mut watch_list = WatchList::new();
+        assert!(watch_list.is_empty());
+        assert!(!watch_list.matches(&coinbase));
+
+        // P2PKH script has only pubkey hash as data push.
+        watch_list.add_script(script.clone());
+        watch_list.add_script(script.clone());
+        assert_eq!(watch_list.elements().len(), 1);
+        assert_eq!(&watch_list.elements()[0][..], &script[3..23]);
+        assert!(watch_list.matches(&coinbase));
+
+        let outpoint = OutPoint {
+            txid: coinbase.txid(),
+            vout: 0,
+        };
+        watch_list.add_outpoint(outpoint);
+        assert_eq!(watch_list.elements().len(), 2);
+        assert_eq!(watch_list.elements()[1], serialize(&outpoint));
+
+        // output of matched transaction is watched, and the transaction spending it matches.
+        let mut spending = coinbase.clone();
+        spending.input[0].previous_output = OutPoint {
+            txid: coinbase.malfix_txid(),
+            vout: 0,
+        };
+        spending.output.clear();
+        let matched = watch_list.filter_transactions(&[spending.clone(), coinbase.clone()]);
+        assert_eq!(matched, vec![coinbase.clone()]);
+        assert_eq!(watch_list.elements().len(), 3);
+        assert!(watch_list.matches(&spending));
+
+        // colored output which pays to the watched script matches.
+        let color_id = ColorIdentifier::reissuable(&script);
+        let mut colored = coinbase.clone();
+        colored.output[0].script_pubkey = colored_script(&color_id, &script);
+        assert!(watch_list.matches(&colored));
+        assert_eq!(watch_list.scripts(), vec![script.clone()]);
+        watch_list.add_color(color_id);
+        assert_eq!(
+            watch_list.scripts(),
+            vec![script.clone(), colored_script(&color_id, &script)]
+        );
+
+        watch_list.clear();
+        assert!(watch_list.is_empty());
+        assert_eq!(watch_list.generation(), 1);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use bitcoin_hashes::{sha256, Hash};
+use std::fmt;
+use tapyrus::consensus::serialize;
+use tapyrus::{OutPoint, Script};
+
+/// Opcode which marks the output as colored coin. It follows the push of color identifier at the
+/// beginning of the script.
+pub const OP_COLOR: u8 = 0xbc;
+
+/// Length of serialized color identifier.
+const COLOR_IDENTIFIER_SIZE: usize = 33;
+
+/// Type of token which decides how its color identifier is derived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TokenType {
+    /// Token which can be issued repeatedly with the same script.
+    Reissuable,
+    /// Token which is issued only once.
+    NonReissuable,
+    /// Non-fungible token.
+    Nft,
+}
+
+impl TokenType {
+    fn to_u8(self) -> u8 {
+        match self {
+            TokenType::Reissuable => 0xc1,
+            TokenType::NonReissuable => 0xc2,
+            TokenType::Nft => 0xc3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<TokenType> {
+        match value {
+            0xc1 => Some(TokenType::Reissuable),
+            0xc2 => Some(TokenType::NonReissuable),
+            0xc3 => Some(TokenType::Nft),
+            _ => None,
+        }
+    }
+}
+
+/// Errors in parsing color identifier
+#[derive(Debug, PartialEq)]
+pub enum ColorError {
+    /// The data is not 33 bytes.
+    InvalidLength(usize),
+    /// The first byte is not a known token type.
+    UnknownTokenType(u8),
+}
+
+/// Color identifier which distinguishes tokens. It consists of token type and SHA256 hash of the
+/// data which the token was issued with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ColorIdentifier {
+    /// Type of the token.
+    pub token_type: TokenType,
+    /// SHA256 hash of the script or the outpoint which the token was issued with.
+    pub payload: sha256::Hash,
+}
+
+impl ColorIdentifier {
+    /// Return color identifier of reissuable token which is issued by spending an output of
+    /// `script_pubkey`.
+    pub fn reissuable(script_pubkey: &Script) -> ColorIdentifier {
+        ColorIdentifier {
+            token_type: TokenType::Reissuable,
+            payload: sha256::Hash::hash(script_pubkey.as_bytes()),
+        }
+    }
+
+    /// Return color identifier of non-reissuable token which is issued by spending `outpoint`.
+    pub fn non_reissuable(outpoint: &OutPoint) -> ColorIdentifier {
+        ColorIdentifier {
+            token_type: TokenType::NonReissuable,
+            payload: sha256::Hash::hash(&serialize(outpoint)),
+        }
+    }
+
+    /// Return color identifier of NFT which is issued by spending `outpoint`.
+    pub fn nft(outpoint: &OutPoint) -> ColorIdentifier {
+        ColorIdentifier {
+            token_type: TokenType::Nft,
+            payload: sha256::Hash::hash(&serialize(outpoint)),
+        }
+    }
+
+    /// Parse serialized color identifier.
+    pub fn from_slice(data: &[u8]) -> Result<ColorIdentifier, ColorError> {
+        if data.len() != COLOR_IDENTIFIER_SIZE {
+            return Err(ColorError::InvalidLength(data.len()));
+        }
+        let token_type =
+            TokenType::from_u8(data[0]).ok_or_else(|| ColorError::UnknownTokenType(data[0]))?;
+        Ok(ColorIdentifier {
+            token_type,
+            payload: sha256::Hash::from_slice(&data[1..]).unwrap(),
+        })
+    }
+
+    /// Serialize as token type followed by payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(COLOR_IDENTIFIER_SIZE);
+        data.push(self.token_type.to_u8());
+        data.extend_from_slice(&self.payload[..]);
+        data
+    }
+}
+
+impl fmt::Display for ColorIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.to_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Return colored script which pays the token of `color_id` to `script`. P2PKH and P2SH scripts
+/// become CP2PKH and CP2SH respectively.
+pub fn colored_script(color_id: &ColorIdentifier, script: &Script) -> Script {
+    let mut data = Vec::with_capacity(COLOR_IDENTIFIER_SIZE + 2 + script.len());
+    data.push(COLOR_IDENTIFIER_SIZE as u8);
+    data.extend(color_id.to_bytes());
+    data.push(OP_COLOR);
+    data.extend_from_slice(script.as_bytes());
+    Script::from(data)
+}
+
+/// If the script is colored, return its color identifier and the script without color.
+pub fn split_colored_script(script: &Script) -> Option<(ColorIdentifier, Script)> {
+    let data = script.as_bytes();
+    let prefix = COLOR_IDENTIFIER_SIZE + 2;
+    if data.len() < prefix
+        || data[0] != COLOR_IDENTIFIER_SIZE as u8
+        || data[COLOR_IDENTIFIER_SIZE + 1] != OP_COLOR
+    {
+        return None;
+    }
+
+    let color_id = ColorIdentifier::from_slice(&data[1..=COLOR_IDENTIFIER_SIZE]).ok()?;
+    Some((color_id, Script::from(data[prefix..].to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_genesis_block;
+
+    #[test]
+    fn test_color_identifier() {
+        let coinbase = &get_test_genesis_block().txdata[0];
+        let script = coinbase.output[0].script_pubkey.clone();
+        let outpoint = OutPoint {
+            txid: coinbase.malfix_txid(),
+            vout: 0,
+        };
+
+        let reissuable = ColorIdentifier::reissuable(&script);
+        assert_eq!(reissuable.token_type, TokenType::Reissuable);
+        assert_eq!(reissuable.payload, sha256::Hash::hash(script.as_bytes()));
+
+        let non_reissuable = ColorIdentifier::non_reissuable(&outpoint);
+        let nft = ColorIdentifier::nft(&outpoint);
+        assert_eq!(non_reissuable.payload, nft.payload);
+        assert_ne!(non_reissuable, nft);
+
+        let bytes = nft.to_bytes();
+        assert_eq!(bytes[0], 0xc3);
+        assert_eq!(ColorIdentifier::from_slice(&bytes), Ok(nft));
+        assert_eq!(nft.to_string(), hex::encode(&bytes));
+
+        assert_eq!(
+            ColorIdentifier::from_slice(&bytes[1..]),
+            Err(ColorError::InvalidLength(32))
+        );
+        let mut unknown = bytes.clone();
+        unknown[0] = 0xc4;
+        assert_eq!(
+            ColorIdentifier::from_slice(&unknown),
+            Err(ColorError::UnknownTokenType(0xc4))
+        );
+    }
+
+    #[test]
+    fn test_colored_script() {
+        let script = get_test_genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+        let color_id = ColorIdentifier::reissuable(&script);
+
+        // CP2PKH
+        let colored = colored_script(&color_id, &script);
+        assert_eq!(colored.len(), 60);
+        assert_eq!(colored[34..35].to_vec(), vec![OP_COLOR]);
+        assert_eq!(
+            split_colored_script(&colored),
+            Some((color_id, script.clone()))
+        );
+
+        // CP2SH
+        let p2sh = script.to_p2sh();
+        let colored = colored_script(&color_id, &p2sh);
+        assert_eq!(colored.len(), 58);
+        assert_eq!(split_colored_script(&colored), Some((color_id, p2sh)));
+
+        assert_eq!(split_colored_script(&script), None);
+    }
+}
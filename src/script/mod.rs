@@ -0,0 +1,14 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! # Script module
+//!
+//! This is a module for Tapyrus specific scripts. Colored coins are outputs whose script starts
+//! with a color identifier and OP_COLOR.
+
+mod color;
+
+pub use color::{
+    colored_script, split_colored_script, ColorError, ColorIdentifier, TokenType, OP_COLOR,
+};
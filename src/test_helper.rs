@@ -2,12 +2,20 @@
 // Distributed under the MIT software license, see the accompanying
 // file COPYING or http://www.opensource.org/licenses/mit-license.php.
 
+use crate::chain::proof::{header_hash_for_sign, to_32_bytes, CURVE_ORDER, FIELD_SIZE};
 use crate::chain::store::OnMemoryChainStore;
 use crate::chain::{BlockIndex, Chain, ChainStore};
 use crate::network::Error;
-use bitcoin_hashes::sha256d;
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::{sha256, sha256d, Hash, HashEngine};
 use hex::decode as hex_decode;
+use num_bigint::BigUint;
+use std::path::{Path, PathBuf};
+use tapyrus::blockdata::script::Builder;
+use tapyrus::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
 use tapyrus::consensus::deserialize;
+use tapyrus::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use tapyrus::util::hash::bitcoin_merkle_root;
 use tapyrus::{BitcoinHash, Block, BlockHeader};
 use tokio::prelude::*;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
@@ -137,6 +145,166 @@ pub fn get_test_genesis_block() -> Block {
     deserialize(&bytes).unwrap()
 }
 
+/// The aggregated public key which signs blocks in HEADER_STRINGS.
+pub static AGGREGATED_PUBLIC_KEY_HEX: &str =
+    "02260b9be70a87125fd0e2da368db857a2d8ee1cb85a3c8b81490f4f35f99b212a";
+
+/// The private key of AGGREGATED_PUBLIC_KEY_HEX.
+pub static AGGREGATED_PRIVATE_KEY_HEX: &str =
+    "9b90c1704259341b5d08a585abe3544f8b4a10dfdc97b402d274220c06da28a2";
+
+pub fn get_test_aggregated_public_key() -> PublicKey {
+    PublicKey::from_slice(&hex_decode(AGGREGATED_PUBLIC_KEY_HEX).unwrap()).unwrap()
+}
+
+/// The private key of another federation which replaces the test aggregated key.
+pub static NEXT_AGGREGATED_PRIVATE_KEY_HEX: &str =
+    "c8a3bda1d3eef1a1d8c6e0b9a4f2c5e7b3d1f0a9e8c7b6a5d4c3b2a1f0e9d8c7";
+
+pub fn get_test_next_aggregated_public_key() -> PublicKey {
+    let private_key = hex_decode(NEXT_AGGREGATED_PRIVATE_KEY_HEX).unwrap();
+    PublicKey::from_secret_key(
+        &Secp256k1::new(),
+        &SecretKey::from_slice(&private_key).unwrap(),
+    )
+}
+
+/// Create a block header on top of `prev` which is signed with the test aggregated key.
+/// Headers which have the same `prev` differ when `salt` differs.
+pub fn create_signed_header(prev: &BlockHeader, salt: u8) -> BlockHeader {
+    create_signed_header_with_key(prev, salt, AGGREGATED_PRIVATE_KEY_HEX)
+}
+
+/// Create a block header on top of `prev` which is signed with the private key in hex.
+pub fn create_signed_header_with_key(
+    prev: &BlockHeader,
+    salt: u8,
+    private_key_hex: &str,
+) -> BlockHeader {
+    let mut header = prev.clone();
+    header.prev_blockhash = prev.bitcoin_hash();
+    header.merkle_root = sha256d::Hash::hash(&[salt]);
+    header.im_merkle_root = sha256d::Hash::hash(&[salt]);
+    header.time = prev.time + 600;
+
+    let message = header_hash_for_sign(&header).unwrap().into_inner();
+    header.proof = sign_schnorr(&message, private_key_hex).to_vec();
+    header
+}
+
+/// Create a coinbase and `count - 1` transactions which pay to the script of genesis coinbase.
+/// Transactions have scriptSig, so that txid and malleability-fixed txid differ.
+pub fn get_test_transactions(count: usize) -> Vec<Transaction> {
+    let script_pubkey = get_test_genesis_block().txdata[0].output[0]
+        .script_pubkey
+        .clone();
+    (0..count)
+        .map(|i| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: if i == 0 {
+                    OutPoint::null()
+                } else {
+                    OutPoint {
+                        txid: sha256d::Hash::hash(&[i as u8]),
+                        vout: 0,
+                    }
+                },
+                script_sig: Builder::new().push_int(i as i64 + 1).into_script(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 5_000_000_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        })
+        .collect()
+}
+
+/// Create a block on top of `prev` which has the transactions. The header has both merkle roots
+/// and is signed with the test aggregated key.
+pub fn create_signed_block(prev: &BlockHeader, txdata: Vec<Transaction>) -> Block {
+    let mut header = create_signed_header(prev, 0);
+    header.merkle_root = bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid()).collect());
+    header.im_merkle_root = bitcoin_merkle_root(txdata.iter().map(|tx| tx.malfix_txid()).collect());
+    let message = header_hash_for_sign(&header).unwrap().into_inner();
+    header.proof = sign_schnorr(&message, AGGREGATED_PRIVATE_KEY_HEX).to_vec();
+
+    Block { header, txdata }
+}
+
+/// Txids of block 100000 in bitcoin mainnet, which is a well known test vector of merkle tree.
+pub static TXIDS_HEX: [&str; 4] = [
+    "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+    "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+    "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+    "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+];
+
+/// Merkle root of TXIDS_HEX.
+pub static MERKLE_ROOT_HEX: &str =
+    "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766";
+
+pub fn get_test_txids() -> Vec<sha256d::Hash> {
+    TXIDS_HEX
+        .iter()
+        .map(|hex| sha256d::Hash::from_hex(hex).unwrap())
+        .collect()
+}
+
+pub fn get_test_merkle_root() -> sha256d::Hash {
+    sha256d::Hash::from_hex(MERKLE_ROOT_HEX).unwrap()
+}
+
+/// BIP39 seed of mnemonic "abandon abandon abandon abandon abandon abandon abandon abandon abandon
+/// abandon abandon about" without passphrase.
+pub static WALLET_SEED_HEX: &str = "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4";
+
+pub fn get_test_wallet_seed() -> Vec<u8> {
+    hex_decode(WALLET_SEED_HEX).unwrap()
+}
+
+/// Sign with Tapyrus Schnorr signature scheme by the private key in hex.
+fn sign_schnorr(message: &[u8; 32], private_key_hex: &str) -> [u8; 64] {
+    let secp = Secp256k1::new();
+    let private_key = hex_decode(private_key_hex).unwrap();
+    let public_key =
+        PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&private_key).unwrap());
+    let n = BigUint::from_bytes_be(&CURVE_ORDER);
+    let p = BigUint::from_bytes_be(&FIELD_SIZE);
+
+    // k = SHA256(private key || message)
+    let mut engine = sha256::Hash::engine();
+    engine.input(&private_key);
+    engine.input(message);
+    let mut k = BigUint::from_bytes_be(&sha256::Hash::from_engine(engine).into_inner()) % &n;
+
+    // R = k*G, and k is negated if y of R is not quadratic residue.
+    let r_point =
+        PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&to_32_bytes(&k)).unwrap())
+            .serialize_uncompressed();
+    let y = BigUint::from_bytes_be(&r_point[33..65]);
+    if y.modpow(&((&p - BigUint::from(1u32)) >> 1), &p) != BigUint::from(1u32) {
+        k = &n - k;
+    }
+    let r = &r_point[1..33];
+
+    // s = k + e*x
+    let mut engine = sha256::Hash::engine();
+    engine.input(r);
+    engine.input(&public_key.serialize());
+    engine.input(message);
+    let e = BigUint::from_bytes_be(&sha256::Hash::from_engine(engine).into_inner()) % &n;
+    let s = (k + e * BigUint::from_bytes_be(&private_key)) % &n;
+
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(r);
+    sig[32..].copy_from_slice(&to_32_bytes(&s));
+    sig
+}
+
 pub fn get_test_block_hash(height: usize) -> sha256d::Hash {
     get_test_headers(height, 1).first().unwrap().bitcoin_hash()
 }
@@ -164,9 +332,38 @@ pub fn get_test_headers(start: usize, count: usize) -> Vec<BlockHeader> {
 
 // return initialized chain
 pub fn get_chain() -> Chain<OnMemoryChainStore> {
+    get_chain_from(0)
+}
+
+/// Return chain which starts from the test block at `base_height` as a trusted checkpoint.
+pub fn get_chain_from(base_height: i32) -> Chain<OnMemoryChainStore> {
     let mut store = OnMemoryChainStore::new();
-    store.initialize(get_test_genesis_block());
-    Chain::new(store)
+    store.initialize(get_test_block_index(base_height)).unwrap();
+    Chain::new(store, get_test_aggregated_public_key())
+}
+
+/// Temporary directory which is removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path =
+            std::env::temp_dir().join(format!("tapyrus-spv-{}-{}", name, rand::random::<u32>()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
 }
 
 pub struct TwoWayChannel<T> {
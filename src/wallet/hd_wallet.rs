@@ -0,0 +1,511 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::script::split_colored_script;
+use crate::wallet::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tapyrus::blockdata::script::Builder;
+use tapyrus::secp256k1::{All, Message, Secp256k1};
+use tapyrus::util::address::Address;
+use tapyrus::util::bip32::{ChildNumber, ExtendedPrivKey};
+use tapyrus::util::key::{PrivateKey, PublicKey};
+use tapyrus::{Network, Script, SigHashType, Transaction, TxOut};
+
+/// Purpose field of BIP44 paths.
+const BIP44_PURPOSE: u32 = 44;
+
+/// Coin type of BIP44 paths of Tapyrus production network (SLIP-0044).
+pub const TAPYRUS_COIN_TYPE: u32 = 1165;
+
+/// Coin type of BIP44 paths which is shared by all test networks (SLIP-0044).
+pub const TESTNET_COIN_TYPE: u32 = 1;
+
+/// Prefix of wallet file names in datadir. The fingerprint of the account key follows it.
+const WALLET_FILE_PREFIX: &str = "wallet-";
+
+/// The default number of unused keys which are derived beyond the last used key.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// Type of addresses which are produced from derived keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressType {
+    /// Pay to public key hash.
+    P2PKH,
+    /// Pay to script hash whose redeem script is P2PKH script of the key.
+    P2SH,
+}
+
+/// Chain of keys in BIP44 account.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyChain {
+    /// Keys for addresses which are given to others to receive payments.
+    External,
+    /// Keys for change outputs of own transactions.
+    Internal,
+}
+
+impl KeyChain {
+    fn index(self) -> u32 {
+        match self {
+            KeyChain::External => 0,
+            KeyChain::Internal => 1,
+        }
+    }
+}
+
+/// Parameters for Wallet
+#[derive(Clone, Debug)]
+pub struct WalletOptions {
+    /// Network which addresses are produced for.
+    pub network: Network,
+    /// Coin type in BIP44 path m/44'/coin_type'/account'/change/index.
+    pub coin_type: u32,
+    /// Account in BIP44 path.
+    pub account: u32,
+    /// Type of produced addresses.
+    pub address_type: AddressType,
+    /// The number of unused keys which are derived beyond the last used key in each key chain.
+    pub gap_limit: u32,
+}
+
+impl WalletOptions {
+    /// Return options for the first account which produces P2PKH addresses with
+    /// DEFAULT_GAP_LIMIT.
+    pub fn new(network: Network, coin_type: u32) -> WalletOptions {
+        WalletOptions {
+            network,
+            coin_type,
+            account: 0,
+            address_type: AddressType::P2PKH,
+            gap_limit: DEFAULT_GAP_LIMIT,
+        }
+    }
+}
+
+/// Key which is derived from the seed of the wallet.
+#[derive(Clone, Debug)]
+pub struct DerivedKey {
+    /// Key chain which the key belongs to.
+    pub key_chain: KeyChain,
+    /// Index of the key in the key chain.
+    pub index: u32,
+    /// Private key.
+    pub private_key: PrivateKey,
+    /// Public key.
+    pub public_key: PublicKey,
+    /// Redeem script if the address is P2SH.
+    pub redeem_script: Option<Script>,
+    /// Address of the key.
+    pub address: Address,
+    /// Script which pays to the address.
+    pub script_pubkey: Script,
+}
+
+struct DerivedKeys {
+    /// Extended private key of m/44'/coin_type'/account'/change.
+    xprv: ExtendedPrivKey,
+    keys: Vec<DerivedKey>,
+    /// Index of the last key which has received payments.
+    last_used: Option<u32>,
+}
+
+/// HD wallet which derives keys with BIP32 along BIP44 paths.
+///
+/// Each key chain has `gap_limit` unused keys beyond the last used key. When transactions pay to
+/// a derived key, more keys are derived so that the gap is kept. Scripts of derived keys should
+/// be watched to find transactions of the wallet.
+///
+/// If the wallet is opened in datadir, the index of the last used key in each key chain is saved
+/// into the file whenever it changes, so that the keys are derived again after restart. Each line
+/// of the file is the key chain and the index, separated by a space.
+pub struct Wallet {
+    options: WalletOptions,
+    secp: Secp256k1<All>,
+    external: DerivedKeys,
+    internal: DerivedKeys,
+    path: Option<PathBuf>,
+}
+
+impl Wallet {
+    /// Create wallet from the seed and derive keys up to the gap limit.
+    pub fn new(seed: &[u8], options: WalletOptions) -> Result<Wallet, Error> {
+        let secp = Secp256k1::new();
+        let account = ExtendedPrivKey::new_master(options.network, seed)?
+            .ckd_priv(&secp, ChildNumber::from_hardened_idx(BIP44_PURPOSE)?)?
+            .ckd_priv(&secp, ChildNumber::from_hardened_idx(options.coin_type)?)?
+            .ckd_priv(&secp, ChildNumber::from_hardened_idx(options.account)?)?;
+
+        let mut key_chains = vec![];
+        for key_chain in &[KeyChain::External, KeyChain::Internal] {
+            let xprv = account.ckd_priv(&secp, ChildNumber::from_normal_idx(key_chain.index())?)?;
+            key_chains.push(DerivedKeys {
+                xprv,
+                keys: vec![],
+                last_used: None,
+            });
+        }
+        let internal = key_chains.pop().unwrap();
+        let external = key_chains.pop().unwrap();
+
+        let mut wallet = Wallet {
+            options,
+            secp,
+            external,
+            internal,
+            path: None,
+        };
+        wallet.fill_gap(KeyChain::External)?;
+        wallet.fill_gap(KeyChain::Internal)?;
+        Ok(wallet)
+    }
+
+    /// Create wallet from the seed and load indexes of the last used keys from the file in
+    /// `datadir`. Keys are derived up to the gap limit beyond them.
+    pub fn open(seed: &[u8], options: WalletOptions, datadir: &Path) -> Result<Wallet, Error> {
+        fs::create_dir_all(datadir)?;
+
+        let mut wallet = Wallet::new(seed, options)?;
+        let fingerprint = hex::encode(&wallet.external.xprv.parent_fingerprint[..]);
+        let path = datadir.join(format!("{}{}.dat", WALLET_FILE_PREFIX, fingerprint));
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match parse_line(&line) {
+                        Some((key_chain, index)) => {
+                            wallet.derived_keys_mut(key_chain).last_used = Some(index)
+                        }
+                        None => warn!("Ignore broken line in wallet file: \"{}\"", line),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        wallet.fill_gap(KeyChain::External)?;
+        wallet.fill_gap(KeyChain::Internal)?;
+        wallet.path = Some(path);
+        Ok(wallet)
+    }
+
+    /// Return network which addresses are produced for.
+    pub fn network(&self) -> Network {
+        self.options.network
+    }
+
+    /// Return the first unused address in the external key chain.
+    pub fn receive_address(&self) -> Address {
+        self.first_unused(KeyChain::External).address.clone()
+    }
+
+    /// Return script of the first unused key in the internal key chain.
+    pub fn change_script(&self) -> Script {
+        self.first_unused(KeyChain::Internal).script_pubkey.clone()
+    }
+
+    /// Return all derived keys.
+    pub fn keys(&self) -> impl Iterator<Item = &DerivedKey> {
+        self.external.keys.iter().chain(self.internal.keys.iter())
+    }
+
+    /// Return scripts of all derived keys.
+    pub fn scripts(&self) -> Vec<Script> {
+        self.keys().map(|key| key.script_pubkey.clone()).collect()
+    }
+
+    /// Return derived key whose script is `script`. Colored scripts are matched by the script
+    /// without color.
+    pub fn find_key(&self, script: &Script) -> Option<&DerivedKey> {
+        let uncolored = split_colored_script(script).map(|(_, script)| script);
+        let script = uncolored.as_ref().unwrap_or(script);
+        self.keys().find(|key| key.script_pubkey == *script)
+    }
+
+    /// Mark keys which the transactions pay to as used, and derive keys to keep the gap. Return
+    /// scripts of newly derived keys which should be watched. Indexes of the last used keys are
+    /// saved if they changed.
+    pub fn on_transactions(&mut self, transactions: &[Transaction]) -> Result<Vec<Script>, Error> {
+        let used: Vec<(KeyChain, u32)> = transactions
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .filter_map(|output| self.find_key(&output.script_pubkey))
+            .map(|key| (key.key_chain, key.index))
+            .collect();
+
+        let mut changed = false;
+        for (key_chain, index) in used {
+            let keys = self.derived_keys_mut(key_chain);
+            if keys.last_used.map_or(true, |last| last < index) {
+                keys.last_used = Some(index);
+                changed = true;
+            }
+        }
+
+        let mut scripts = self.fill_gap(KeyChain::External)?;
+        scripts.extend(self.fill_gap(KeyChain::Internal)?);
+        if changed {
+            self.save()?;
+        }
+        Ok(scripts)
+    }
+
+    /// Sign inputs with SIGHASH_ALL. `prevouts` are outputs which the inputs spend in order, and
+    /// each of them must pay to a derived key with or without color.
+    pub fn sign_transaction(&self, tx: &mut Transaction, prevouts: &[TxOut]) -> Result<(), Error> {
+        for (index, prevout) in prevouts.iter().enumerate() {
+            let key = self
+                .find_key(&prevout.script_pubkey)
+                .ok_or_else(|| Error::UnknownScript(prevout.script_pubkey.clone()))?;
+            let script_code = key.redeem_script.as_ref().unwrap_or(&prevout.script_pubkey);
+
+            let hash = tx.signature_hash(index, script_code, SigHashType::All.as_u32());
+            let message = Message::from_slice(&hash[..]).expect("sighash should be 32 bytes.");
+            let mut signature = self
+                .secp
+                .sign(&message, &key.private_key.key)
+                .serialize_der()
+                .to_vec();
+            signature.push(SigHashType::All.as_u32() as u8);
+
+            let mut builder = Builder::new()
+                .push_slice(&signature)
+                .push_slice(&key.public_key.to_bytes());
+            if let Some(ref redeem_script) = key.redeem_script {
+                builder = builder.push_slice(redeem_script.as_bytes());
+            }
+            tx.input[index].script_sig = builder.into_script();
+        }
+        Ok(())
+    }
+
+    /// Write indexes of the last used keys into the file. The file is replaced atomically by
+    /// renaming a temporary file.
+    fn save(&self) -> Result<(), Error> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            for (name, keys) in &[("external", &self.external), ("internal", &self.internal)] {
+                if let Some(index) = keys.last_used {
+                    writeln!(file, "{} {}", name, index)?;
+                }
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn first_unused(&self, key_chain: KeyChain) -> &DerivedKey {
+        let keys = match key_chain {
+            KeyChain::External => &self.external,
+            KeyChain::Internal => &self.internal,
+        };
+        let index = keys.last_used.map_or(0, |last| last + 1);
+        &keys.keys[index as usize]
+    }
+
+    fn derived_keys_mut(&mut self, key_chain: KeyChain) -> &mut DerivedKeys {
+        match key_chain {
+            KeyChain::External => &mut self.external,
+            KeyChain::Internal => &mut self.internal,
+        }
+    }
+
+    /// Derive keys until there are `gap_limit` unused keys beyond the last used key. At least one
+    /// unused key is kept. Return scripts of derived keys.
+    fn fill_gap(&mut self, key_chain: KeyChain) -> Result<Vec<Script>, Error> {
+        let secp = &self.secp;
+        let options = &self.options;
+        let keys = match key_chain {
+            KeyChain::External => &mut self.external,
+            KeyChain::Internal => &mut self.internal,
+        };
+
+        let target = keys.last_used.map_or(0, |last| last + 1) + options.gap_limit.max(1);
+        let mut scripts = vec![];
+        while (keys.keys.len() as u32) < target {
+            let index = keys.keys.len() as u32;
+            let key = derive_key(secp, options, &keys.xprv, key_chain, index)?;
+            scripts.push(key.script_pubkey.clone());
+            keys.keys.push(key);
+        }
+        Ok(scripts)
+    }
+}
+
+/// Parse a line of the wallet file.
+fn parse_line(line: &str) -> Option<(KeyChain, u32)> {
+    let mut iter = line.split(' ');
+    let key_chain = match iter.next()? {
+        "external" => KeyChain::External,
+        "internal" => KeyChain::Internal,
+        _ => return None,
+    };
+    let index = iter.next()?.parse().ok()?;
+    if iter.next().is_some() {
+        return None;
+    }
+    Some((key_chain, index))
+}
+
+fn derive_key(
+    secp: &Secp256k1<All>,
+    options: &WalletOptions,
+    xprv: &ExtendedPrivKey,
+    key_chain: KeyChain,
+    index: u32,
+) -> Result<DerivedKey, Error> {
+    let private_key = xprv
+        .ckd_priv(secp, ChildNumber::from_normal_idx(index)?)?
+        .private_key;
+    let public_key = private_key.public_key(secp);
+    let p2pkh = Address::p2pkh(&public_key, options.network);
+
+    let (address, redeem_script) = match options.address_type {
+        AddressType::P2PKH => (p2pkh, None),
+        AddressType::P2SH => {
+            let redeem_script = p2pkh.script_pubkey();
+            (
+                Address::p2sh(&redeem_script, options.network),
+                Some(redeem_script),
+            )
+        }
+    };
+
+    Ok(DerivedKey {
+        key_chain,
+        index,
+        private_key,
+        public_key,
+        redeem_script,
+        script_pubkey: address.script_pubkey(),
+        address,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::{colored_script, ColorIdentifier};
+    use crate::test_helper::{get_test_transactions, get_test_wallet_seed, TempDir};
+
+    #[test]
+    fn test_derive_bip44_addresses() {
+        // m/44'/1165'/0'/0/0 of the seed of "abandon ... about" mnemonic.
+        let options = WalletOptions::new(Network::Bitcoin, TAPYRUS_COIN_TYPE);
+        let wallet = Wallet::new(&get_test_wallet_seed(), options).unwrap();
+        let secp = Secp256k1::new();
+        let expected = ExtendedPrivKey::new_master(Network::Bitcoin, &get_test_wallet_seed())
+            .unwrap()
+            .derive_priv(
+                &secp,
+                &[
+                    ChildNumber::from_hardened_idx(44).unwrap(),
+                    ChildNumber::from_hardened_idx(1165).unwrap(),
+                    ChildNumber::from_hardened_idx(0).unwrap(),
+                    ChildNumber::from_normal_idx(0).unwrap(),
+                    ChildNumber::from_normal_idx(0).unwrap(),
+                ],
+            )
+            .unwrap();
+        let public_key = expected.private_key.public_key(&secp);
+        assert_eq!(
+            wallet.receive_address(),
+            Address::p2pkh(&public_key, Network::Bitcoin)
+        );
+
+        // m/44'/1'/0'/0/0
+        let options = WalletOptions::new(Network::Testnet, TESTNET_COIN_TYPE);
+        let wallet = Wallet::new(&get_test_wallet_seed(), options).unwrap();
+        assert_eq!(
+            wallet.receive_address().to_string(),
+            "mkpZhYtJu2r87Js3pDiWJDmPte2NRZ8bJV"
+        );
+        assert_eq!(wallet.keys().count(), DEFAULT_GAP_LIMIT as usize * 2);
+
+        let change = wallet.find_key(&wallet.change_script()).unwrap();
+        assert_eq!(change.key_chain, KeyChain::Internal);
+        assert_eq!(change.index, 0);
+    }
+
+    #[test]
+    fn test_p2sh_address() {
+        let mut options = WalletOptions::new(Network::Testnet, TESTNET_COIN_TYPE);
+        options.address_type = AddressType::P2SH;
+        let wallet = Wallet::new(&get_test_wallet_seed(), options).unwrap();
+
+        let key = wallet.keys().next().unwrap();
+        let redeem_script = key.redeem_script.as_ref().unwrap();
+        assert!(redeem_script.is_p2pkh());
+        assert!(key.script_pubkey.is_p2sh());
+        assert_eq!(key.script_pubkey, redeem_script.to_p2sh());
+        assert_eq!(
+            redeem_script,
+            &Address::p2pkh(&key.public_key, Network::Testnet).script_pubkey()
+        );
+    }
+
+    #[test]
+    fn test_keep_gap_limit() {
+        let mut options = WalletOptions::new(Network::Testnet, TESTNET_COIN_TYPE);
+        options.gap_limit = 5;
+        let mut wallet = Wallet::new(&get_test_wallet_seed(), options).unwrap();
+        assert_eq!(wallet.scripts().len(), 10);
+
+        // a transaction which pays to external key at index 2.
+        let script = wallet.external.keys[2].script_pubkey.clone();
+        let mut tx = get_test_transactions(1).pop().unwrap();
+        tx.output[0].script_pubkey = script;
+        let scripts = wallet.on_transactions(&[tx.clone()]).unwrap();
+        assert_eq!(scripts.len(), 3);
+        assert_eq!(scripts[0], wallet.external.keys[5].script_pubkey);
+        assert_eq!(wallet.receive_address(), wallet.external.keys[3].address);
+
+        // nothing is derived for keys which were already used.
+        assert!(wallet.on_transactions(&[tx]).unwrap().is_empty());
+        assert_eq!(wallet.scripts().len(), 13);
+
+        // colored output which pays to external key at index 4.
+        let script = wallet.external.keys[4].script_pubkey.clone();
+        let color_id = ColorIdentifier::reissuable(&script);
+        let mut tx = get_test_transactions(1).pop().unwrap();
+        tx.output[0].script_pubkey = colored_script(&color_id, &script);
+        assert_eq!(wallet.on_transactions(&[tx]).unwrap().len(), 2);
+        assert_eq!(wallet.receive_address(), wallet.external.keys[5].address);
+    }
+
+    #[test]
+    fn test_persist_last_used_keys() {
+        let dir = TempDir::new("hd_wallet_test_persist_last_used_keys");
+        let mut options = WalletOptions::new(Network::Testnet, TESTNET_COIN_TYPE);
+        options.gap_limit = 5;
+        let receive_address = {
+            let mut wallet =
+                Wallet::open(&get_test_wallet_seed(), options.clone(), dir.path()).unwrap();
+            let mut tx = get_test_transactions(1).pop().unwrap();
+            tx.output[0].script_pubkey = wallet.external.keys[4].script_pubkey.clone();
+            wallet.on_transactions(&[tx]).unwrap();
+            assert_eq!(wallet.scripts().len(), 15);
+            wallet.receive_address()
+        };
+
+        // keys beyond the last used key are derived again after restart.
+        let wallet = Wallet::open(&get_test_wallet_seed(), options.clone(), dir.path()).unwrap();
+        assert_eq!(wallet.scripts().len(), 15);
+        assert_eq!(wallet.receive_address(), receive_address);
+
+        // other account has its own file.
+        options.account = 1;
+        let wallet = Wallet::open(&get_test_wallet_seed(), options, dir.path()).unwrap();
+        assert_eq!(wallet.scripts().len(), 10);
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+//! # Wallet module
+//!
+//! This is a module for keys, addresses and outputs of the SPV node. Keys are derived from a seed
+//! with BIP32 along BIP44 paths, and scripts of derived keys are watched to find transactions.
+//! Outputs of found transactions are tracked in the UTXO set, and transactions which spend them
+//! are built and signed with derived keys.
+
+mod hd_wallet;
+mod tx_builder;
+mod utxo_set;
+
+use crate::script::ColorIdentifier;
+use tapyrus::Script;
+
+pub use hd_wallet::{
+    AddressType, DerivedKey, KeyChain, Wallet, WalletOptions, DEFAULT_GAP_LIMIT, TAPYRUS_COIN_TYPE,
+    TESTNET_COIN_TYPE,
+};
+pub use tx_builder::{TransactionBuilder, DEFAULT_FEE_RATE};
+pub use utxo_set::{Balance, HistoryEntry, Utxo, UtxoSet};
+
+/// Errors in wallet module
+#[derive(Debug)]
+pub enum Error {
+    /// Key derivation failed.
+    Bip32Error(tapyrus::util::bip32::Error),
+    /// Unspent outputs don't have enough TPC for payments and the fee.
+    InsufficientFunds {
+        /// Total value of payments and the fee.
+        required: u64,
+        /// Total value of unspent outputs.
+        available: u64,
+    },
+    /// Unspent colored outputs don't have enough tokens.
+    InsufficientTokens {
+        /// Color of the token.
+        color_id: ColorIdentifier,
+        /// Amount of transferred and burned tokens.
+        required: u64,
+        /// Amount of tokens in unspent outputs.
+        available: u64,
+    },
+    /// Amount of issued tokens is invalid for the token type.
+    InvalidTokenAmount(u64),
+    /// The output to be spent doesn't pay to a derived key.
+    UnknownScript(Script),
+    /// No wallet is loaded.
+    NoWallet,
+    /// Reading or writing the wallet file failed.
+    IoError(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<tapyrus::util::bip32::Error> for Error {
+    fn from(e: tapyrus::util::bip32::Error) -> Error {
+        Error::Bip32Error(e)
+    }
+}
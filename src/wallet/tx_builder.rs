@@ -0,0 +1,437 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::script::{colored_script, split_colored_script, ColorIdentifier, TokenType};
+use crate::wallet::{Error, Utxo, Wallet};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use tapyrus::consensus::serialize;
+use tapyrus::{Script, Transaction, TxIn, TxOut};
+
+/// The default fee rate in tapyrus per 1000 bytes.
+pub const DEFAULT_FEE_RATE: u64 = 1000;
+
+/// TPC change which is smaller than this is added to the fee instead of creating an output.
+const DUST_THRESHOLD: u64 = 546;
+
+/// Size of script_sig which spends P2PKH output. It pushes DER signature of at most 72 bytes
+/// with sighash type and compressed public key.
+const P2PKH_SCRIPT_SIG_SIZE: usize = 1 + 72 + 1 + 1 + 33;
+
+/// Builder of signed transactions which spend unspent outputs of the wallet.
+///
+/// Outputs of the wallet are selected from the largest one until they cover the payments and the
+/// fee. Tokens are selected from colored outputs of the same color, so that the amount of each
+/// color in inputs equals the amount in outputs except burned tokens. The fee is always paid in
+/// TPC. Change is sent to the internal key chain of the wallet.
+pub struct TransactionBuilder<'a> {
+    wallet: &'a Wallet,
+    utxos: Vec<Utxo>,
+    fee_rate: u64,
+    outputs: Vec<TxOut>,
+    issue: Option<(TokenType, Script, u64)>,
+    burns: Vec<(ColorIdentifier, u64)>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    /// Create builder which spends `utxos`. Outputs whose keys are not derived by the wallet are
+    /// ignored.
+    pub fn new(wallet: &'a Wallet, utxos: Vec<Utxo>) -> TransactionBuilder<'a> {
+        TransactionBuilder {
+            wallet,
+            utxos,
+            fee_rate: DEFAULT_FEE_RATE,
+            outputs: vec![],
+            issue: None,
+            burns: vec![],
+        }
+    }
+
+    /// Set fee rate in tapyrus per 1000 bytes.
+    pub fn fee_rate(&mut self, fee_rate: u64) -> &mut Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    /// Pay `value` TPC to the script.
+    pub fn pay(&mut self, script_pubkey: Script, value: u64) -> &mut Self {
+        self.outputs.push(TxOut {
+            value,
+            script_pubkey,
+        });
+        self
+    }
+
+    /// Transfer `value` tokens of the color to the script.
+    pub fn transfer(
+        &mut self,
+        color_id: &ColorIdentifier,
+        script_pubkey: &Script,
+        value: u64,
+    ) -> &mut Self {
+        self.pay(colored_script(color_id, script_pubkey), value)
+    }
+
+    /// Issue `value` new tokens to the script. The first input of the transaction decides the
+    /// color identifier, which can be read from the issued output with `split_colored_script`.
+    /// NFT must be issued with value 1.
+    pub fn issue(&mut self, token_type: TokenType, script_pubkey: Script, value: u64) -> &mut Self {
+        self.issue = Some((token_type, script_pubkey, value));
+        self
+    }
+
+    /// Burn `value` tokens of the color by spending them without outputs.
+    pub fn burn(&mut self, color_id: ColorIdentifier, value: u64) -> &mut Self {
+        self.burns.push((color_id, value));
+        self
+    }
+
+    /// Select inputs, add change outputs and sign the transaction.
+    pub fn build(&self) -> Result<Transaction, Error> {
+        let mut candidates: Vec<&Utxo> = self
+            .utxos
+            .iter()
+            .filter(|utxo| self.wallet.find_key(&utxo.txout.script_pubkey).is_some())
+            .collect();
+        candidates.sort_by_key(|utxo| Reverse(utxo.txout.value));
+        let (colored, mut uncolored): (Vec<&Utxo>, Vec<&Utxo>) = candidates
+            .into_iter()
+            .partition(|utxo| utxo.color_id.is_some());
+
+        let mut inputs: Vec<&Utxo> = vec![];
+        let mut outputs = self.outputs.clone();
+
+        if let Some((token_type, ref script_pubkey, value)) = self.issue {
+            if token_type == TokenType::Nft && value != 1 {
+                return Err(Error::InvalidTokenAmount(value));
+            }
+            if uncolored.is_empty() {
+                return Err(Error::InsufficientFunds {
+                    required: self.fee(&self.unsigned(&inputs, &outputs), &inputs),
+                    available: 0,
+                });
+            }
+            let utxo = uncolored.remove(0);
+            let color_id = match token_type {
+                TokenType::Reissuable => ColorIdentifier::reissuable(&utxo.txout.script_pubkey),
+                TokenType::NonReissuable => ColorIdentifier::non_reissuable(&utxo.outpoint),
+                TokenType::Nft => ColorIdentifier::nft(&utxo.outpoint),
+            };
+            inputs.push(utxo);
+            outputs.push(TxOut {
+                value,
+                script_pubkey: colored_script(&color_id, script_pubkey),
+            });
+        }
+
+        let mut tokens: BTreeMap<ColorIdentifier, u64> = BTreeMap::new();
+        for output in &self.outputs {
+            if let Some((color_id, _)) = split_colored_script(&output.script_pubkey) {
+                *tokens.entry(color_id).or_insert(0) += output.value;
+            }
+        }
+        for (color_id, value) in &self.burns {
+            *tokens.entry(*color_id).or_insert(0) += value;
+        }
+
+        let change_script = self.wallet.change_script();
+        for (color_id, required) in tokens {
+            let mut available = 0;
+            for utxo in colored
+                .iter()
+                .filter(|utxo| utxo.color_id == Some(color_id))
+            {
+                if available >= required {
+                    break;
+                }
+                available += utxo.txout.value;
+                inputs.push(utxo);
+            }
+            if available < required {
+                return Err(Error::InsufficientTokens {
+                    color_id,
+                    required,
+                    available,
+                });
+            }
+            if available > required {
+                outputs.push(TxOut {
+                    value: available - required,
+                    script_pubkey: colored_script(&color_id, &change_script),
+                });
+            }
+        }
+
+        let target: u64 = outputs
+            .iter()
+            .filter(|output| split_colored_script(&output.script_pubkey).is_none())
+            .map(|output| output.value)
+            .sum();
+        let mut uncolored = uncolored.into_iter();
+        loop {
+            let available: u64 = inputs
+                .iter()
+                .filter(|utxo| utxo.color_id.is_none())
+                .map(|utxo| utxo.txout.value)
+                .sum();
+            let fee = self.fee(&self.unsigned(&inputs, &outputs), &inputs);
+            if available >= target + fee {
+                let mut change = TxOut {
+                    value: 0,
+                    script_pubkey: change_script.clone(),
+                };
+                outputs.push(change.clone());
+                let fee = self.fee(&self.unsigned(&inputs, &outputs), &inputs);
+                outputs.pop();
+                if available >= target + fee + DUST_THRESHOLD {
+                    change.value = available - target - fee;
+                    outputs.push(change);
+                }
+                break;
+            }
+
+            match uncolored.next() {
+                Some(utxo) => inputs.push(utxo),
+                None => {
+                    return Err(Error::InsufficientFunds {
+                        required: target + fee,
+                        available,
+                    })
+                }
+            }
+        }
+
+        let mut tx = self.unsigned(&inputs, &outputs);
+        let prevouts: Vec<TxOut> = inputs.iter().map(|utxo| utxo.txout.clone()).collect();
+        self.wallet.sign_transaction(&mut tx, &prevouts)?;
+        Ok(tx)
+    }
+
+    fn unsigned(&self, inputs: &[&Utxo], outputs: &[TxOut]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs.to_vec(),
+        }
+    }
+
+    /// Return fee of the transaction whose size is estimated as if the inputs were signed.
+    fn fee(&self, tx: &Transaction, inputs: &[&Utxo]) -> u64 {
+        let script_sig_size: usize = inputs
+            .iter()
+            .filter_map(|utxo| self.wallet.find_key(&utxo.txout.script_pubkey))
+            .map(|key| {
+                key.redeem_script
+                    .as_ref()
+                    .map_or(P2PKH_SCRIPT_SIG_SIZE, |redeem_script| {
+                        P2PKH_SCRIPT_SIG_SIZE + 1 + redeem_script.len()
+                    })
+            })
+            .sum();
+        let size = (serialize(tx).len() + script_sig_size) as u64;
+        (size * self.fee_rate + 999) / 1000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::get_test_wallet_seed;
+    use crate::wallet::{WalletOptions, TESTNET_COIN_TYPE};
+    use bitcoin_hashes::{sha256d, Hash};
+    use tapyrus::blockdata::script::Instruction;
+    use tapyrus::secp256k1::{Message, Secp256k1, Signature};
+    use tapyrus::{Network, OutPoint, SigHashType};
+
+    fn get_wallet() -> Wallet {
+        let options = WalletOptions::new(Network::Testnet, TESTNET_COIN_TYPE);
+        Wallet::new(&get_test_wallet_seed(), options).unwrap()
+    }
+
+    fn utxo(seed: &[u8], script_pubkey: Script, value: u64) -> Utxo {
+        Utxo {
+            outpoint: OutPoint {
+                txid: sha256d::Hash::hash(seed),
+                vout: 0,
+            },
+            color_id: split_colored_script(&script_pubkey).map(|(color_id, _)| color_id),
+            txout: TxOut {
+                value,
+                script_pubkey,
+            },
+            height: Some(1),
+        }
+    }
+
+    fn outpoint(tx: &Transaction, vout: u32) -> OutPoint {
+        OutPoint {
+            txid: tx.malfix_txid(),
+            vout,
+        }
+    }
+
+    fn verify_signatures(tx: &Transaction, prevouts: &[TxOut]) {
+        let secp = Secp256k1::new();
+        for (index, prevout) in prevouts.iter().enumerate() {
+            let pushes: Vec<Vec<u8>> = tx.input[index]
+                .script_sig
+                .iter(true)
+                .filter_map(|instruction| match instruction {
+                    Instruction::PushBytes(data) => Some(data.to_vec()),
+                    _ => None,
+                })
+                .collect();
+            let (hash_type, signature) = pushes[0].split_last().unwrap();
+            assert_eq!(*hash_type as u32, SigHashType::All.as_u32());
+
+            let public_key = tapyrus::PublicKey::from_slice(&pushes[1]).unwrap();
+            let hash = tx.signature_hash(index, &prevout.script_pubkey, SigHashType::All.as_u32());
+            let message = Message::from_slice(&hash[..]).unwrap();
+            let signature = Signature::from_der(signature).unwrap();
+            assert!(secp.verify(&message, &signature, &public_key.key).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_build_payment() {
+        let wallet = get_wallet();
+        let mine = wallet.receive_address().script_pubkey();
+        let to = Script::from(vec![0x51]);
+        let utxos = vec![
+            utxo(b"small", mine.clone(), 10_000),
+            utxo(b"large", mine.clone(), 100_000),
+            utxo(b"other", to.clone(), 1_000_000),
+        ];
+
+        let tx = TransactionBuilder::new(&wallet, utxos.clone())
+            .pay(to.clone(), 50_000)
+            .build()
+            .unwrap();
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, utxos[1].outpoint);
+        assert_eq!(tx.output.len(), 2);
+        assert_eq!(tx.output[0].script_pubkey, to);
+        assert_eq!(tx.output[1].script_pubkey, wallet.change_script());
+
+        // fee is about 226 bytes at 1 tapyrus per byte.
+        let fee = 100_000 - 50_000 - tx.output[1].value;
+        assert!(fee >= serialize(&tx).len() as u64 && fee <= 230);
+        verify_signatures(&tx, &[utxos[1].txout.clone()]);
+
+        // change below dust is added to the fee.
+        let tx = TransactionBuilder::new(&wallet, utxos.clone())
+            .pay(to.clone(), 109_300)
+            .build()
+            .unwrap();
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 1);
+
+        match TransactionBuilder::new(&wallet, utxos)
+            .pay(to, 110_000)
+            .build()
+        {
+            Err(Error::InsufficientFunds {
+                available: 110_000, ..
+            }) => {}
+            _ => panic!("should be insufficient funds"),
+        }
+    }
+
+    #[test]
+    fn test_build_token_transactions() {
+        let wallet = get_wallet();
+        let mine = wallet.receive_address().script_pubkey();
+        let to = Script::from(vec![0x51]);
+        let tpc = utxo(b"tpc", mine.clone(), 100_000);
+
+        // issue
+        let tx = TransactionBuilder::new(&wallet, vec![tpc.clone()])
+            .issue(TokenType::NonReissuable, mine.clone(), 1_000)
+            .build()
+            .unwrap();
+        let color_id = ColorIdentifier::non_reissuable(&tpc.outpoint);
+        assert_eq!(tx.input[0].previous_output, tpc.outpoint);
+        assert_eq!(tx.output[0].script_pubkey, colored_script(&color_id, &mine));
+        assert_eq!(tx.output[0].value, 1_000);
+        verify_signatures(&tx, &[tpc.txout.clone()]);
+
+        let mut utxos = vec![
+            Utxo {
+                outpoint: outpoint(&tx, 0),
+                txout: tx.output[0].clone(),
+                height: Some(2),
+                color_id: Some(color_id),
+            },
+            Utxo {
+                outpoint: outpoint(&tx, 1),
+                txout: tx.output[1].clone(),
+                height: Some(2),
+                color_id: None,
+            },
+        ];
+
+        // transfer with token change
+        let tx = TransactionBuilder::new(&wallet, utxos.clone())
+            .transfer(&color_id, &to, 300)
+            .build()
+            .unwrap();
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output[0].script_pubkey, colored_script(&color_id, &to));
+        assert_eq!(tx.output[1].value, 700);
+        assert_eq!(
+            tx.output[1].script_pubkey,
+            colored_script(&color_id, &wallet.change_script())
+        );
+        assert_eq!(tx.output[2].script_pubkey, wallet.change_script());
+        verify_signatures(&tx, &[utxos[0].txout.clone(), utxos[1].txout.clone()]);
+
+        // burn
+        let tx = TransactionBuilder::new(&wallet, utxos.clone())
+            .burn(color_id, 1_000)
+            .build()
+            .unwrap();
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(tx.output[0].script_pubkey, wallet.change_script());
+
+        match TransactionBuilder::new(&wallet, utxos.clone())
+            .transfer(&color_id, &to, 1_001)
+            .build()
+        {
+            Err(Error::InsufficientTokens {
+                required: 1_001,
+                available: 1_000,
+                ..
+            }) => {}
+            _ => panic!("should be insufficient tokens"),
+        }
+
+        // fee can't be paid with tokens.
+        utxos.pop();
+        match TransactionBuilder::new(&wallet, utxos)
+            .transfer(&color_id, &to, 1_000)
+            .build()
+        {
+            Err(Error::InsufficientFunds { available: 0, .. }) => {}
+            _ => panic!("should be insufficient funds"),
+        }
+
+        match TransactionBuilder::new(&wallet, vec![tpc])
+            .issue(TokenType::Nft, mine, 2)
+            .build()
+        {
+            Err(Error::InvalidTokenAmount(2)) => {}
+            _ => panic!("NFT should be issued with value 1"),
+        }
+    }
+}
@@ -0,0 +1,392 @@
+// Copyright (c) 2019 Chaintope Inc.
+// Distributed under the MIT software license, see the accompanying
+// file COPYING or http://www.opensource.org/licenses/mit-license.php.
+
+use crate::script::{split_colored_script, ColorIdentifier};
+use bitcoin_hashes::sha256d;
+use std::collections::{BTreeMap, HashMap};
+use tapyrus::{OutPoint, Script, Transaction, TxOut};
+
+/// Unspent output which pays to a watched script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utxo {
+    /// Outpoint which refers to the output with malleability-fixed txid.
+    pub outpoint: OutPoint,
+    /// The output.
+    pub txout: TxOut,
+    /// Height of the block which contains the transaction. None if it is unconfirmed.
+    pub height: Option<i32>,
+    /// Color identifier if the output is colored coin. The value is amount of the token.
+    pub color_id: Option<ColorIdentifier>,
+}
+
+impl Utxo {
+    /// Return the number of confirmations when the height of the active chain is `tip_height`.
+    pub fn confirmations(&self, tip_height: i32) -> u32 {
+        match self.height {
+            Some(height) if height <= tip_height => (tip_height - height + 1) as u32,
+            _ => 0,
+        }
+    }
+}
+
+/// Balance of unspent outputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Balance {
+    /// Total value of outputs which have at least the required confirmations.
+    pub confirmed: u64,
+    /// Total value of outputs which are unconfirmed or don't have enough confirmations.
+    pub unconfirmed: u64,
+}
+
+/// Change of balance of a coin by a transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// Malleability-fixed txid of the transaction.
+    pub txid: sha256d::Hash,
+    /// Height of the block which contains the transaction. None if it is unconfirmed.
+    pub height: Option<i32>,
+    /// Color identifier of the token. None for TPC.
+    pub color_id: Option<ColorIdentifier>,
+    /// Total value of outputs which pay to watched scripts.
+    pub received: u64,
+    /// Total value of watched outputs which the transaction spends.
+    pub sent: u64,
+}
+
+/// Transaction which spent an output.
+#[derive(Clone, Debug)]
+struct Spent {
+    /// Height of the block which contains the transaction. None if it is unconfirmed.
+    height: Option<i32>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    txout: TxOut,
+    height: Option<i32>,
+    color_id: Option<ColorIdentifier>,
+    spent: Option<Spent>,
+}
+
+/// Outputs which pay to watched scripts and whether they have been spent.
+///
+/// Outputs are added from transactions which were found in blocks, and they are marked as spent
+/// when transactions spending them are found. Outputs and spends in blocks which were removed by
+/// reorganization are rolled back.
+///
+/// Colored outputs are tracked separately by color identifier, and history of each coin is
+/// recorded for every transaction.
+#[derive(Debug, Default)]
+pub struct UtxoSet {
+    entries: HashMap<OutPoint, Entry>,
+    history: Vec<HistoryEntry>,
+}
+
+impl UtxoSet {
+    /// Create empty UTXO set.
+    pub fn new() -> UtxoSet {
+        UtxoSet::default()
+    }
+
+    /// Add outputs of the transaction which pay to scripts `is_mine` accepts, and mark outputs
+    /// which the transaction spends as spent. `height` is None if the transaction is unconfirmed.
+    pub fn add_transaction<F>(&mut self, tx: &Transaction, height: Option<i32>, is_mine: F)
+    where
+        F: Fn(&Script) -> bool,
+    {
+        // Inputs refer to malleability-fixed txid in Tapyrus.
+        let txid = tx.malfix_txid();
+
+        // The transaction was already added as unconfirmed.
+        if self.history.iter().any(|h| h.txid == txid) {
+            for entry in self.history.iter_mut().filter(|h| h.txid == txid) {
+                entry.height = height;
+            }
+            for input in &tx.input {
+                if let Some(entry) = self.entries.get_mut(&input.previous_output) {
+                    entry.spent = Some(Spent { height });
+                }
+            }
+            for (outpoint, entry) in self.entries.iter_mut() {
+                if outpoint.txid == txid {
+                    entry.height = height;
+                }
+            }
+            return;
+        }
+
+        let mut changes: BTreeMap<Option<ColorIdentifier>, (u64, u64)> = BTreeMap::new();
+
+        for input in &tx.input {
+            if let Some(entry) = self.entries.get_mut(&input.previous_output) {
+                entry.spent = Some(Spent { height });
+                changes.entry(entry.color_id).or_insert((0, 0)).1 += entry.txout.value;
+            }
+        }
+
+        for (vout, output) in tx.output.iter().enumerate() {
+            if !is_mine(&output.script_pubkey) {
+                continue;
+            }
+            let color_id =
+                split_colored_script(&output.script_pubkey).map(|(color_id, _)| color_id);
+            changes.entry(color_id).or_insert((0, 0)).0 += output.value;
+
+            let outpoint = OutPoint {
+                txid,
+                vout: vout as u32,
+            };
+            self.entries.insert(
+                outpoint,
+                Entry {
+                    txout: output.clone(),
+                    height,
+                    color_id,
+                    spent: None,
+                },
+            );
+        }
+
+        for (color_id, (received, sent)) in changes {
+            self.history.push(HistoryEntry {
+                txid,
+                height,
+                color_id,
+                received,
+                sent,
+            });
+        }
+    }
+
+    /// Roll back outputs and spends in blocks above `fork_height`.
+    pub fn rollback(&mut self, fork_height: i32) {
+        self.entries
+            .retain(|_, entry| entry.height.map_or(true, |height| height <= fork_height));
+        self.history
+            .retain(|entry| entry.height.map_or(true, |height| height <= fork_height));
+
+        for entry in self.entries.values_mut() {
+            let rolled_back = match entry.spent {
+                Some(Spent {
+                    height: Some(height),
+                    ..
+                }) => height > fork_height,
+                _ => false,
+            };
+            if rolled_back {
+                entry.spent = None;
+            }
+        }
+    }
+
+    /// Return unspent outputs.
+    pub fn unspent(&self) -> Vec<Utxo> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.spent.is_none())
+            .map(|(outpoint, entry)| Utxo {
+                outpoint: *outpoint,
+                txout: entry.txout.clone(),
+                height: entry.height,
+                color_id: entry.color_id,
+            })
+            .collect()
+    }
+
+    /// Return TPC balance of unspent outputs. Outputs which have `min_confirmations` when the
+    /// height of the active chain is `tip_height` are confirmed.
+    pub fn balance(&self, tip_height: i32, min_confirmations: u32) -> Balance {
+        self.balances(tip_height, min_confirmations)
+            .remove(&None)
+            .unwrap_or_default()
+    }
+
+    /// Return balances of tokens by color identifier.
+    pub fn token_balances(
+        &self,
+        tip_height: i32,
+        min_confirmations: u32,
+    ) -> BTreeMap<ColorIdentifier, Balance> {
+        self.balances(tip_height, min_confirmations)
+            .into_iter()
+            .filter_map(|(color_id, balance)| color_id.map(|color_id| (color_id, balance)))
+            .collect()
+    }
+
+    fn balances(
+        &self,
+        tip_height: i32,
+        min_confirmations: u32,
+    ) -> BTreeMap<Option<ColorIdentifier>, Balance> {
+        let mut balances: BTreeMap<Option<ColorIdentifier>, Balance> = BTreeMap::new();
+        for utxo in self.unspent() {
+            let balance = balances.entry(utxo.color_id).or_default();
+            if utxo.height.is_some() && utxo.confirmations(tip_height) >= min_confirmations {
+                balance.confirmed += utxo.txout.value;
+            } else {
+                balance.unconfirmed += utxo.txout.value;
+            }
+        }
+        balances
+    }
+
+    /// Return history of the coin in order of addition. `color_id` is None for TPC.
+    pub fn history(&self, color_id: Option<&ColorIdentifier>) -> Vec<HistoryEntry> {
+        self.history
+            .iter()
+            .filter(|entry| entry.color_id.as_ref() == color_id)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::colored_script;
+    use crate::test_helper::{get_test_genesis_block, get_test_transactions};
+
+    #[test]
+    fn test_utxo_set() {
+        let script = get_test_genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+        let is_mine = |s: &Script| *s == script;
+
+        // coinbase pays to the watched script.
+        let coinbase = get_test_transactions(1).pop().unwrap();
+        let outpoint = OutPoint {
+            txid: coinbase.malfix_txid(),
+            vout: 0,
+        };
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_transaction(&coinbase, Some(1), is_mine);
+        assert_eq!(utxo_set.unspent().len(), 1);
+        assert_eq!(utxo_set.unspent()[0].confirmations(10), 10);
+        assert_eq!(
+            utxo_set.balance(10, 1),
+            Balance {
+                confirmed: 5_000_000_000,
+                unconfirmed: 0,
+            }
+        );
+        assert_eq!(
+            utxo_set.balance(10, 11),
+            Balance {
+                confirmed: 0,
+                unconfirmed: 5_000_000_000,
+            }
+        );
+
+        // unconfirmed transaction spends the output and pays a part to the watched script.
+        let mut spending = get_test_transactions(2).pop().unwrap();
+        spending.input[0].previous_output = outpoint;
+        spending.output[0].value = 1_000;
+        spending.output.push(TxOut {
+            value: 4_000,
+            script_pubkey: Script::new(),
+        });
+        utxo_set.add_transaction(&spending, None, is_mine);
+        let unspent = utxo_set.unspent();
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].outpoint.txid, spending.malfix_txid());
+        assert_eq!(
+            utxo_set.balance(10, 1),
+            Balance {
+                confirmed: 0,
+                unconfirmed: 1_000,
+            }
+        );
+
+        // the transaction is confirmed at height 11.
+        utxo_set.add_transaction(&spending, Some(11), is_mine);
+        assert_eq!(utxo_set.balance(11, 1).confirmed, 1_000);
+
+        // reorganization removes the transaction and the output becomes unspent again.
+        utxo_set.rollback(10);
+        assert_eq!(utxo_set.balance(10, 1).confirmed, 5_000_000_000);
+        let unspent = utxo_set.unspent();
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].outpoint, outpoint);
+
+        utxo_set.rollback(0);
+        assert!(utxo_set.unspent().is_empty());
+    }
+
+    #[test]
+    fn test_token_balances_and_history() {
+        let script = get_test_genesis_block().txdata[0].output[0]
+            .script_pubkey
+            .clone();
+        let is_mine = |s: &Script| {
+            *s == script || split_colored_script(s).map_or(false, |(_, s)| s == script)
+        };
+
+        // issue token and pay TPC at the same time.
+        let mut issue = get_test_transactions(1).pop().unwrap();
+        let color_id = ColorIdentifier::non_reissuable(&issue.input[0].previous_output);
+        issue.output.push(TxOut {
+            value: 100,
+            script_pubkey: colored_script(&color_id, &script),
+        });
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_transaction(&issue, Some(1), is_mine);
+
+        assert_eq!(utxo_set.balance(1, 1).confirmed, 5_000_000_000);
+        let balances = utxo_set.token_balances(1, 1);
+        assert_eq!(balances.len(), 1);
+        assert_eq!(balances[&color_id].confirmed, 100);
+
+        // send 30 tokens to others.
+        let mut transfer = get_test_transactions(2).pop().unwrap();
+        transfer.input[0].previous_output = OutPoint {
+            txid: issue.malfix_txid(),
+            vout: 1,
+        };
+        transfer.output = vec![
+            TxOut {
+                value: 70,
+                script_pubkey: colored_script(&color_id, &script),
+            },
+            TxOut {
+                value: 30,
+                script_pubkey: colored_script(&color_id, &Script::new()),
+            },
+        ];
+        utxo_set.add_transaction(&transfer, None, is_mine);
+        assert_eq!(
+            utxo_set.token_balances(1, 1)[&color_id],
+            Balance {
+                confirmed: 0,
+                unconfirmed: 70,
+            }
+        );
+
+        // confirmation doesn't duplicate history.
+        utxo_set.add_transaction(&transfer, Some(2), is_mine);
+        assert_eq!(utxo_set.token_balances(2, 1)[&color_id].confirmed, 70);
+
+        let history = utxo_set.history(Some(&color_id));
+        assert_eq!(
+            history,
+            vec![
+                HistoryEntry {
+                    txid: issue.malfix_txid(),
+                    height: Some(1),
+                    color_id: Some(color_id),
+                    received: 100,
+                    sent: 0,
+                },
+                HistoryEntry {
+                    txid: transfer.malfix_txid(),
+                    height: Some(2),
+                    color_id: Some(color_id),
+                    received: 70,
+                    sent: 100,
+                },
+            ]
+        );
+        assert_eq!(utxo_set.history(None).len(), 1);
+    }
+}